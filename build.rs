@@ -34,10 +34,25 @@ enum FieldType {
     Time,
     Voltage,
     Unitless,
+    Enum,
+    Flags,
+    Text,
+    Version,
 }
 
 use FieldType::*;
 
+/// Duplicate of crate::fields::Requirement
+#[derive(Deserialize, Debug, Clone)]
+enum Requirement {
+    Pv2,
+    Pv3,
+    SplitPhase,
+    ThreePhase,
+    Generator,
+    SmartLoad,
+}
+
 #[derive(Deserialize, Clone)]
 struct Record {
     field_type: FieldType,
@@ -49,18 +64,22 @@ struct Record {
     offset2: Option<u32>,
     reg: Option<i16>,
     reg2: Option<i16>,
+    signed: Option<bool>,
+    requires: Option<Requirement>,
+    labels: Option<String>,
+    bit: Option<u8>,
 }
 
 fn write_fields<W>(w: &mut W, header: &str, records: &[Record]) -> Result<(), Box<dyn Error>>
 where
     W: Write,
 {
-    writeln!(w, "use crate::fields::{{Field, FieldType}};")?;
+    writeln!(w, "use crate::fields::{{Field, FieldType, Requirement}};")?;
     writeln!(w, "{header}")?;
-    writeln!(w, "const FIELDS: &[Field] = &[")?;
+    writeln!(w, "pub(crate) const FIELDS: &[Field] = &[")?;
     for record in records.iter() {
         let default_scale = match record.field_type {
-            Charge | Power | StateOfCharge | Unitless => Some(1.0),
+            Charge | Power | StateOfCharge | Unitless | Enum | Flags | Text | Version => Some(1.0),
             Energy | Temperature => Some(0.1),
             Frequency => Some(0.01),
             Current | Voltage => None,
@@ -80,9 +99,19 @@ where
             Temperature => "°C",
             Time => "s",
             Voltage => "V",
-            Unitless => "",
+            Unitless | Enum | Flags | Text | Version => "",
         };
+        let default_signed = matches!(record.field_type, Current | Power);
         let scale = record.scale.or(default_scale).unwrap();
+        let signed = record.signed.unwrap_or(default_signed);
+        // Labels are given as value=label pairs separated by semicolons
+        let mut labels = vec![];
+        for item in record.labels.iter().flat_map(|l| l.split(';')) {
+            let (value, label) = item
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid label {item:?} for {}", record.id))?;
+            labels.push((value.parse::<i64>()?, label));
+        }
         writeln!(
             w,
             r#"    Field {{
@@ -92,9 +121,21 @@ where
         id: {:?},
         scale: {scale:?},
         bias: {bias:?},
+        signed: {signed:?},
         unit: {unit:?},
+        requires: {requires},
+        labels: &{labels:?},
+        bit: {bit:?},
     }},"#,
-            record.field_type, record.group, record.name, record.id
+            record.field_type,
+            record.group,
+            record.name,
+            record.id,
+            bit = record.bit,
+            requires = match &record.requires {
+                Some(r) => format!("Some(Requirement::{r:?})"),
+                None => "None".to_string(),
+            }
         )?;
     }
     writeln!(w, "];")?;
@@ -114,19 +155,53 @@ where
     Ok(())
 }
 
+/// Write the fields that have registers, and their registers, to `path`
+fn write_modbus(path: &Path, header: &str, records: &[Record]) -> Result<(), Box<dyn Error>> {
+    let mut modbus_records = vec![];
+    let mut modbus_regs = vec![];
+    for record in records.iter() {
+        if let Some(reg) = record.reg {
+            modbus_records.push(record.clone());
+            let mut regs = vec![];
+            if reg >= 0 {
+                regs.push(reg);
+                if let Some(reg2) = record.reg2 {
+                    regs.push(reg2);
+                }
+            }
+            modbus_regs.push(regs);
+        }
+    }
+
+    let mut modbus_writer = fs::File::create(path)?;
+    write_fields(&mut modbus_writer, header, &modbus_records)?;
+    writeln!(&mut modbus_writer, "/// Registers corresponding to fields")?;
+    writeln!(
+        &mut modbus_writer,
+        "pub(crate) const REGISTERS: &[&[u16]] = &["
+    )?;
+    for regs in modbus_regs.into_iter() {
+        writeln!(&mut modbus_writer, "    &{:?},", regs.as_slice())?;
+    }
+    writeln!(&mut modbus_writer, "];")?;
+    Ok(())
+}
+
+/// Read the fields from a CSV file
+fn read_records(path: &str) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(fs::File::open(path)?);
+    Ok(reader.deserialize().collect::<Result<_, _>>()?)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let out_path = Path::new(&out_dir);
     let pcap_path = out_path.join("pcap_fields.rs");
-    let modbus_path = out_path.join("modbus_fields.rs");
-    let mut reader = csv::Reader::from_reader(fs::File::open("fields.csv")?);
+    let records = read_records("fields.csv")?;
 
     let mut pcap_records = vec![];
     let mut pcap_offsets = vec![];
-    let mut modbus_records = vec![];
-    let mut modbus_regs = vec![];
-    for result in reader.deserialize() {
-        let record: Record = result?;
+    for record in records.iter() {
         if let Some(offset) = record.offset {
             pcap_records.push(record.clone());
             let mut offsets = vec![offset];
@@ -135,17 +210,6 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
             pcap_offsets.push(offsets);
         }
-        if let Some(reg) = record.reg {
-            modbus_records.push(record.clone());
-            let mut regs = vec![];
-            if reg >= 0 {
-                regs.push(reg);
-                if let Some(reg2) = record.reg2 {
-                    regs.push(reg2);
-                }
-            }
-            modbus_regs.push(regs);
-        }
     }
 
     let mut pcap_writer = fs::File::create(pcap_path)?;
@@ -162,21 +226,19 @@ fn main() -> Result<(), Box<dyn Error>> {
     writeln!(&mut pcap_writer, "];")?;
     drop(pcap_writer);
 
-    let mut modbus_writer = fs::File::create(modbus_path)?;
-    write_fields(
-        &mut modbus_writer,
+    write_modbus(
+        &out_path.join("modbus_fields.rs"),
         "/// Fields retrieved by modbus protocol",
-        &modbus_records,
+        &records,
+    )?;
+    write_modbus(
+        &out_path.join("modbus_fields_three_phase.rs"),
+        "/// Fields retrieved by modbus protocol from three-phase inverters",
+        &read_records("fields_three_phase.csv")?,
     )?;
-    writeln!(&mut modbus_writer, "/// Registers corresponding to fields")?;
-    writeln!(&mut modbus_writer, "const REGISTERS: &[&[u16]] = &[")?;
-    for regs in modbus_regs.into_iter() {
-        writeln!(&mut modbus_writer, "    &{:?},", regs.as_slice())?;
-    }
-    writeln!(&mut modbus_writer, "];")?;
-    drop(modbus_writer);
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=fields.csv");
+    println!("cargo:rerun-if-changed=fields_three_phase.csv");
     Ok(())
 }
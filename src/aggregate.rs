@@ -0,0 +1,217 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Downsampling of updates by aggregating them over fixed time windows
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::fields::{Field, FieldType};
+use super::receiver::{Update, UpdateItem};
+use super::transform::Transform;
+
+/// How values in a window are combined
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    #[default]
+    Mean,
+    Min,
+    Max,
+    Last,
+}
+
+/// Structure corresponding to the `aggregate` option of a backend. It is
+/// constructed from the config file by serde.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Length of each window, in seconds
+    window: u64,
+    /// Method for measurements (such as power)
+    #[serde(default)]
+    method: Method,
+    /// Methods for individual fields, keyed by ID
+    #[serde(default)]
+    fields: HashMap<String, Method>,
+}
+
+impl Config {
+    fn method(&self, field: &Field<'_>) -> Method {
+        if let Some(method) = self.fields.get(field.id) {
+            return *method;
+        }
+        match field.field_type {
+            // Totals, codes and flags cannot be meaningfully averaged
+            FieldType::Energy
+            | FieldType::Enum
+            | FieldType::Flags
+            | FieldType::Text
+            | FieldType::Version => Method::Last,
+            _ => self.method,
+        }
+    }
+}
+
+/// Accumulated values for one inverter in the current window
+struct Window {
+    /// Index of the window (time divided by the window length)
+    index: i64,
+    /// Latest update in the window
+    last: UpdateItem,
+    methods: Vec<Method>,
+    /// Accumulator for each field: the sum, minimum or maximum
+    values: Vec<f64>,
+    /// Number of finite values of each field
+    counts: Vec<usize>,
+}
+
+impl Window {
+    fn new(index: i64, update: UpdateItem, config: &Config) -> Self {
+        let methods: Vec<Method> = update.fields.iter().map(|f| config.method(f)).collect();
+        let mut window = Self {
+            index,
+            last: Arc::clone(&update),
+            methods,
+            values: vec![f64::NAN; update.fields.len()],
+            counts: vec![0; update.fields.len()],
+        };
+        window.add(update);
+        window
+    }
+
+    /// Whether an update can be added to the window
+    fn accepts(&self, index: i64, update: &Update<'_>) -> bool {
+        index == self.index
+            && std::ptr::eq(update.fields, self.last.fields)
+            && std::ptr::eq(update.text_fields, self.last.text_fields)
+    }
+
+    fn add(&mut self, update: UpdateItem) {
+        for (i, value) in update.values.iter().enumerate() {
+            if !value.is_finite() {
+                continue;
+            }
+            let acc = &mut self.values[i];
+            *acc = match (self.counts[i], self.methods[i]) {
+                (0, _) | (_, Method::Last) => *value,
+                (_, Method::Mean) => *acc + value,
+                (_, Method::Min) => acc.min(*value),
+                (_, Method::Max) => acc.max(*value),
+            };
+            self.counts[i] += 1;
+        }
+        self.last = update;
+    }
+
+    /// Combine the values into a single update
+    fn finish(self) -> UpdateItem {
+        let values = self
+            .values
+            .iter()
+            .zip(self.counts.iter())
+            .zip(self.methods.iter())
+            .map(|((value, count), method)| match (count, method) {
+                (0, _) => f64::NAN,
+                (_, Method::Mean) => value / *count as f64,
+                _ => *value,
+            })
+            .collect();
+        Arc::new(
+            Update::new(
+                self.last.timestamp,
+                self.last.serial.clone(),
+                self.last.fields,
+                values,
+            )
+            .with_text(self.last.text_fields, self.last.text.clone()),
+        )
+    }
+}
+
+/// Transform that combines the updates from each inverter in each time
+/// window into one. Since it cannot act on a timer, the result for a window
+/// is produced when the first update from the next window arrives.
+pub struct Aggregator {
+    config: Config,
+    /// Window length in nanoseconds
+    window: i64,
+    windows: HashMap<String, Window>,
+}
+
+impl Aggregator {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            window: (config.window as i64).saturating_mul(1_000_000_000).max(1),
+            windows: HashMap::new(),
+        }
+    }
+}
+
+impl Transform for Aggregator {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        let index = update.timestamp.div_euclid(self.window);
+        match self.windows.remove(&update.serial) {
+            Some(mut window) if window.accepts(index, &update) => {
+                window.add(update);
+                self.windows.insert(window.last.serial.clone(), window);
+                None
+            }
+            previous => {
+                let window = Window::new(index, update, &self.config);
+                self.windows.insert(window.last.serial.clone(), window);
+                previous.map(Window::finish)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{field, leak};
+
+    #[test]
+    fn test_aggregate() {
+        let config: Config =
+            toml::from_str("window = 30\nfields = { battery_soc = \"min\", grid_power = \"max\" }")
+                .unwrap();
+        let mut aggregator = Aggregator::new(&config);
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::StateOfCharge, "battery_soc"),
+            field(FieldType::Power, "grid_power"),
+            field(FieldType::Energy, "pv_energy_today"),
+        ]);
+        let mut apply = |seconds: i64, serial: &str, values: Vec<f64>| {
+            let update = Update::new(seconds * 1_000_000_000, serial, fields, values);
+            aggregator.apply(Arc::new(update))
+        };
+        assert!(apply(0, "1234", vec![1000.0, 80.0, 100.0, 1.0]).is_none());
+        assert!(apply(10, "1234", vec![2000.0, 79.0, 300.0, 1.1]).is_none());
+        assert!(apply(15, "5678", vec![500.0, 50.0, 0.0, 2.0]).is_none());
+        assert!(apply(20, "1234", vec![f64::NAN, 81.0, 200.0, 1.2]).is_none());
+        let update = apply(30, "1234", vec![0.0, 0.0, 0.0, 0.0]).unwrap();
+        assert_eq!(update.timestamp, 20_000_000_000);
+        assert_eq!(update.serial, "1234");
+        assert_eq!(update.values, vec![1500.0, 79.0, 300.0, 1.2]);
+        let update = apply(45, "5678", vec![0.0, 0.0, 0.0, 0.0]).unwrap();
+        assert_eq!(update.serial, "5678");
+        assert_eq!(update.values, vec![500.0, 50.0, 0.0, 2.0]);
+    }
+}
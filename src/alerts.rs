@@ -0,0 +1,482 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Alert conditions shared by the notification backends. Each alert is a
+//! condition on one or more fields (such as the battery SOC being below a
+//! threshold), or the absence of updates for some time. A notification is
+//! generated when an alert fires for an inverter and when it clears, with a
+//! minimum interval between notifications for the same alert so that a
+//! fluctuating value does not cause a flood of them.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::iter::zip;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::health::record_write;
+use super::receiver::Update;
+use super::rotate::utc;
+
+/// Interval between checks for inverters that have stopped sending updates
+const NO_DATA_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    Below(f64),
+    Above(f64),
+    Equals(f64),
+}
+
+impl Condition {
+    fn matches(&self, value: f64) -> bool {
+        match self {
+            Condition::Below(threshold) => value < *threshold,
+            Condition::Above(threshold) => value > *threshold,
+            Condition::Equals(target) => value == *target,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    /// Fires when any of the selected fields satisfies the condition
+    Value {
+        field: Option<String>,
+        group: Option<String>,
+        condition: Condition,
+    },
+    /// Fires when there have been no updates for this long
+    NoData(Duration),
+}
+
+#[derive(Debug)]
+struct Alert {
+    name: String,
+    kind: Kind,
+}
+
+impl Alert {
+    fn new(config: &AlertConfig) -> Result<Self, String> {
+        let conditions: Vec<Condition> = [
+            config.below.map(Condition::Below),
+            config.above.map(Condition::Above),
+            config.equals.map(Condition::Equals),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let selected = config.field.is_some() || config.group.is_some();
+        let kind = match (config.no_data, conditions.as_slice()) {
+            (Some(minutes), []) if !selected => Kind::NoData(Duration::from_secs(minutes * 60)),
+            (None, [condition]) if selected => Kind::Value {
+                field: config.field.clone(),
+                group: config.group.clone(),
+                condition: *condition,
+            },
+            _ => {
+                return Err(format!(
+                    "Alert {:?} needs either no_data, or field/group and one of below/above/equals",
+                    config.name
+                ))
+            }
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            kind,
+        })
+    }
+
+    /// Evaluate a value alert against an update. Returns `None` if the
+    /// update has no values for the alert, otherwise whether the alert is
+    /// active and a description of the values.
+    fn evaluate(&self, update: &Update<'_>) -> Option<(bool, String)> {
+        let Kind::Value {
+            field: id,
+            group,
+            condition,
+        } = &self.kind
+        else {
+            return None;
+        };
+        let mut seen = false;
+        let mut active = false;
+        let mut details = String::new();
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            let selected = id.as_ref().is_some_and(|id| id == field.id)
+                || group.as_ref().is_some_and(|group| group == field.group);
+            if !selected || !value.is_finite() {
+                continue;
+            }
+            seen = true;
+            // For groups, only list the fields that match
+            if condition.matches(*value) {
+                active = true;
+            } else if group.is_some() {
+                continue;
+            }
+            let value = match field.label(*value) {
+                Some(label) => label.to_owned(),
+                None => format!("{value} {}", field.unit).trim_end().to_owned(),
+            };
+            writeln!(details, "{} {}: {value}", field.group, field.name).unwrap();
+        }
+        seen.then_some((active, details))
+    }
+}
+
+/// Alert state for one alert and inverter
+#[derive(Default)]
+struct State {
+    active: bool,
+    /// Whether a notification was sent when the alert fired
+    notified: bool,
+    last_sent: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    /// An alert fired
+    Fired,
+    /// An alert cleared
+    Cleared,
+    /// Not related to an alert (such as a summary)
+    #[allow(dead_code)] // Not every notification backend generates these
+    Info,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Notification {
+    pub level: Level,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Tracks the state of a set of alerts for each inverter
+pub(crate) struct Alerts {
+    alerts: Vec<Alert>,
+    min_interval: Duration,
+    /// State for each alert (by index) and inverter
+    states: HashMap<(usize, String), State>,
+    /// Time of the last update from each inverter
+    last_seen: HashMap<String, (Instant, i64)>,
+}
+
+impl Alerts {
+    pub fn new(configs: &[AlertConfig], min_interval: u64) -> Result<Self, String> {
+        let alerts = configs
+            .iter()
+            .map(Alert::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            alerts,
+            min_interval: Duration::from_secs(min_interval),
+            states: HashMap::new(),
+            last_seen: HashMap::new(),
+        })
+    }
+
+    /// Update the state of an alert, returning a notification if one should
+    /// be sent
+    fn transition(
+        &mut self,
+        index: usize,
+        serial: &str,
+        active: bool,
+        details: &str,
+        now: Instant,
+    ) -> Option<Notification> {
+        let min_interval = self.min_interval;
+        let alert = &self.alerts[index];
+        let state = self.states.entry((index, serial.to_owned())).or_default();
+        if active == state.active {
+            return None;
+        }
+        state.active = active;
+        let (level, subject, body) = if active {
+            let due = state
+                .last_sent
+                .is_none_or(|last| now.duration_since(last) >= min_interval);
+            state.notified = due;
+            if !due {
+                info!(
+                    "Not sending notification for {} on {serial}: too soon",
+                    alert.name
+                );
+                return None;
+            }
+            state.last_sent = Some(now);
+            let subject = format!("{} on {serial}", alert.name);
+            (
+                Level::Fired,
+                subject,
+                format!("Alert {:?} fired.\n\n{details}", alert.name),
+            )
+        } else {
+            if !std::mem::take(&mut state.notified) {
+                return None;
+            }
+            let subject = format!("{} on {serial} cleared", alert.name);
+            (
+                Level::Cleared,
+                subject,
+                format!("Alert {:?} cleared.\n\n{details}", alert.name),
+            )
+        };
+        let body = format!("Inverter: {serial}\n{body}");
+        Some(Notification {
+            level,
+            subject,
+            body,
+        })
+    }
+
+    pub fn handle_update(&mut self, update: &Update<'_>, now: Instant) -> Vec<Notification> {
+        let mut notifications = vec![];
+        self.last_seen
+            .insert(update.serial.clone(), (now, update.timestamp));
+        let time = utc(update.timestamp).format("%Y-%m-%d %H:%M:%S UTC");
+        for index in 0..self.alerts.len() {
+            let (active, details) = match self.alerts[index].kind {
+                Kind::Value { .. } => match self.alerts[index].evaluate(update) {
+                    Some(result) => result,
+                    None => continue,
+                },
+                Kind::NoData(_) => (false, String::new()),
+            };
+            let details = format!("Time: {time}\n\n{details}");
+            notifications.extend(self.transition(index, &update.serial, active, &details, now));
+        }
+        notifications
+    }
+
+    /// Fire the `no_data` alerts for inverters that have stopped sending
+    pub fn check_no_data(&mut self, now: Instant) -> Vec<Notification> {
+        let mut notifications = vec![];
+        let last_seen: Vec<(String, Instant, i64)> = self
+            .last_seen
+            .iter()
+            .map(|(serial, (seen, timestamp))| (serial.clone(), *seen, *timestamp))
+            .collect();
+        for index in 0..self.alerts.len() {
+            let Kind::NoData(timeout) = self.alerts[index].kind else {
+                continue;
+            };
+            for (serial, seen, timestamp) in last_seen.iter() {
+                if now.duration_since(*seen) >= timeout {
+                    let time = utc(*timestamp).format("%Y-%m-%d %H:%M:%S UTC");
+                    let details = format!("Last update: {time}\n");
+                    notifications.extend(self.transition(index, serial, true, &details, now));
+                }
+            }
+        }
+        notifications
+    }
+}
+
+/// A channel over which notifications are delivered
+#[async_trait]
+pub(crate) trait Notifier: Send {
+    async fn notify(&self, notification: &Notification) -> Result<(), String>;
+
+    /// Additional notifications to send for an update (such as periodic
+    /// summaries)
+    fn extra(&mut self, _update: &Update<'_>) -> Vec<Notification> {
+        vec![]
+    }
+}
+
+/// Evaluate alerts against updates and deliver the resulting notifications
+pub(crate) async fn run_alerts<'a, N: Notifier>(
+    notifier: &mut N,
+    alerts: &mut Alerts,
+    mut receiver: UnboundedReceiver<Arc<Update<'a>>>,
+) {
+    let mut check = tokio::time::interval(NO_DATA_CHECK_INTERVAL);
+    loop {
+        let notifications = tokio::select! {
+            update = receiver.next() => match update {
+                Some(update) => {
+                    let mut notifications = alerts.handle_update(&update, Instant::now());
+                    notifications.extend(notifier.extra(&update));
+                    notifications
+                }
+                None => break,
+            },
+            _ = check.tick() => alerts.check_no_data(Instant::now()),
+        };
+        for notification in notifications.iter() {
+            match notifier.notify(notification).await {
+                Ok(()) => {
+                    info!("Sent notification: {}", notification.subject);
+                    record_write(None);
+                }
+                Err(err) => {
+                    warn!("Failed to send notification ({err})");
+                    record_write(Some(err));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub name: String,
+    /// Field ID to check
+    pub field: Option<String>,
+    /// Field group to check (fires if any field in the group matches)
+    pub group: Option<String>,
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    pub equals: Option<f64>,
+    /// Fire when there have been no updates for this many minutes
+    pub no_data: Option<u64>,
+}
+
+pub(crate) fn default_min_interval() -> u64 {
+    3600
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+    use crate::test_util::{field, leak};
+
+    fn alerts(toml: &str) -> Alerts {
+        #[derive(Deserialize)]
+        struct Config {
+            alerts: Vec<AlertConfig>,
+        }
+        let config: Config = toml::from_str(&format!("alerts = [{toml}]")).unwrap();
+        Alerts::new(&config.alerts, 600).unwrap()
+    }
+
+    fn fields() -> &'static [Field<'static>] {
+        leak([
+            Field {
+                group: "Battery",
+                ..field(FieldType::StateOfCharge, "battery_soc")
+            },
+            Field {
+                group: "Fault",
+                ..field(FieldType::Flags, "fault_no_grid")
+            },
+            Field {
+                group: "Fault",
+                ..field(FieldType::Flags, "fault_dc_overcurrent")
+            },
+        ])
+    }
+
+    #[test]
+    fn test_alert_config() {
+        let config = |toml: &str| -> AlertConfig {
+            toml::from_str(&format!("name = \"test\"\n{toml}")).unwrap()
+        };
+        assert_eq!(
+            Alert::new(&config("no_data = 5")).unwrap().kind,
+            Kind::NoData(Duration::from_secs(300))
+        );
+        for bad in [
+            "",
+            "field = \"battery_soc\"",
+            "field = \"battery_soc\"\nbelow = 10\nabove = 90",
+            "below = 10",
+            "no_data = 5\nfield = \"battery_soc\"",
+        ] {
+            assert!(Alert::new(&config(bad)).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_value_alerts() {
+        let mut alerts = alerts(
+            "{ name = \"Low battery\", field = \"battery_soc\", below = 20 },\
+             { name = \"Fault\", group = \"Fault\", above = 0 }",
+        );
+        let fields = fields();
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut send = |values: Vec<f64>, minutes: u32| {
+            let update = Update::new(1_700_000_000_000_000_000, "1234", fields, values);
+            alerts
+                .handle_update(&update, start + minute * minutes)
+                .into_iter()
+                .map(|notification| notification.subject)
+                .collect::<Vec<_>>()
+        };
+        assert!(send(vec![50.0, 0.0, 0.0], 0).is_empty());
+        assert_eq!(
+            send(vec![15.0, 0.0, 1.0], 1),
+            vec!["Low battery on 1234", "Fault on 1234"]
+        );
+        assert!(send(vec![15.0, 0.0, 1.0], 2).is_empty());
+        assert_eq!(
+            send(vec![25.0, 0.0, 1.0], 3),
+            vec!["Low battery on 1234 cleared"]
+        );
+        // Too soon after the last notification, so both firing and clearing
+        // are suppressed
+        assert!(send(vec![15.0, 0.0, 1.0], 4).is_empty());
+        assert!(send(vec![25.0, 0.0, 1.0], 5).is_empty());
+        assert_eq!(send(vec![15.0, 0.0, 1.0], 11), vec!["Low battery on 1234"]);
+        // Missing values do not change the state
+        assert!(send(vec![f64::NAN, f64::NAN, f64::NAN], 12).is_empty());
+    }
+
+    #[test]
+    fn test_details() {
+        let alerts = alerts("{ name = \"Fault\", group = \"Fault\", above = 0 }");
+        let update = Update::new(0, "1234", fields(), vec![15.0, 1.0, 0.0]);
+        assert_eq!(
+            alerts.alerts[0].evaluate(&update),
+            Some((true, "Fault fault_no_grid: 1\n".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_no_data() {
+        let mut alerts = alerts("{ name = \"No data\", no_data = 10 }");
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let update = Update::new(
+            1_700_000_000_000_000_000,
+            "1234",
+            fields(),
+            vec![50.0, 0.0, 0.0],
+        );
+        assert!(alerts.handle_update(&update, start).is_empty());
+        assert!(alerts.check_no_data(start + minute * 9).is_empty());
+        let notifications = alerts.check_no_data(start + minute * 10);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].subject, "No data on 1234");
+        assert!(notifications[0]
+            .body
+            .contains("Last update: 2023-11-14 22:13:20 UTC"));
+        assert!(alerts.check_no_data(start + minute * 11).is_empty());
+        let notifications = alerts.handle_update(&update, start + minute * 12);
+        assert_eq!(notifications[0].subject, "No data on 1234 cleared");
+    }
+}
@@ -0,0 +1,631 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that publishes updates to an AMQP 0.9.1 exchange (such as
+//! RabbitMQ), using publisher confirms.
+//!
+//! Only the small part of the protocol needed to publish is implemented.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::json::UpdateRecord;
+use super::receiver::{Receiver, Update};
+use super::tls::TlsConfig;
+
+/// Time to wait for the server to respond, unless overridden by the
+/// `timeout` in the retry configuration
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest frame we are willing to send
+const MAX_FRAME_SIZE: u32 = 131072;
+/// Channel used for publishing (channel 0 is for the connection itself)
+const CHANNEL: u16 = 1;
+
+const FRAME_METHOD: u8 = 1;
+const FRAME_HEADER: u8 = 2;
+const FRAME_BODY: u8 = 3;
+const FRAME_END: u8 = 0xce;
+
+/// (class ID, method ID) pairs
+mod method {
+    pub const CONNECTION_START: (u16, u16) = (10, 10);
+    pub const CONNECTION_START_OK: (u16, u16) = (10, 11);
+    pub const CONNECTION_TUNE: (u16, u16) = (10, 30);
+    pub const CONNECTION_TUNE_OK: (u16, u16) = (10, 31);
+    pub const CONNECTION_OPEN: (u16, u16) = (10, 40);
+    pub const CONNECTION_OPEN_OK: (u16, u16) = (10, 41);
+    pub const CONNECTION_CLOSE: (u16, u16) = (10, 50);
+    pub const CONNECTION_CLOSE_OK: (u16, u16) = (10, 51);
+    pub const CHANNEL_OPEN: (u16, u16) = (20, 10);
+    pub const CHANNEL_OPEN_OK: (u16, u16) = (20, 11);
+    pub const CHANNEL_CLOSE: (u16, u16) = (20, 40);
+    pub const CHANNEL_CLOSE_OK: (u16, u16) = (20, 41);
+    pub const EXCHANGE_DECLARE: (u16, u16) = (40, 10);
+    pub const EXCHANGE_DECLARE_OK: (u16, u16) = (40, 11);
+    pub const BASIC_PUBLISH: (u16, u16) = (60, 40);
+    pub const BASIC_ACK: (u16, u16) = (60, 80);
+    pub const BASIC_NACK: (u16, u16) = (60, 120);
+    pub const CONFIRM_SELECT: (u16, u16) = (85, 10);
+    pub const CONFIRM_SELECT_OK: (u16, u16) = (85, 11);
+}
+
+/// Builder for the arguments of a method or content header
+#[derive(Default)]
+struct Args(Vec<u8>);
+
+impl Args {
+    fn method(method: (u16, u16)) -> Self {
+        Self::default().short(method.0).short(method.1)
+    }
+
+    fn octet(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    fn short(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn long(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn longlong(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn shortstr(self, value: &str) -> Self {
+        // Longer strings cannot be represented, so truncate them
+        let value = &value.as_bytes()[..value.len().min(255)];
+        let mut out = self.octet(value.len() as u8);
+        out.0.extend_from_slice(value);
+        out
+    }
+
+    fn longstr(self, value: &[u8]) -> Self {
+        let mut out = self.long(value.len() as u32);
+        out.0.extend_from_slice(value);
+        out
+    }
+
+    /// Field table with string values
+    fn table(self, entries: &[(&str, &str)]) -> Self {
+        let mut table = Args::default();
+        for (key, value) in entries.iter() {
+            table = table.shortstr(key).octet(b'S').longstr(value.as_bytes());
+        }
+        self.longstr(&table.0)
+    }
+}
+
+/// Append a frame to `out`
+fn frame(out: &mut Vec<u8>, frame_type: u8, channel: u16, payload: &[u8]) {
+    out.push(frame_type);
+    out.extend_from_slice(&channel.to_be_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out.push(FRAME_END);
+}
+
+/// Encode a `Basic.Publish` method, with its content header and body
+fn publish(out: &mut Vec<u8>, exchange: &str, message: &Message, persistent: bool, frame_max: u32) {
+    let method = Args::method(method::BASIC_PUBLISH)
+        .short(0)
+        .shortstr(exchange)
+        .shortstr(&message.routing_key)
+        .octet(0); // Not mandatory or immediate
+    frame(out, FRAME_METHOD, CHANNEL, &method.0);
+    // Properties: content-type (bit 15) and delivery-mode (bit 12)
+    let header = Args::default()
+        .short(method::BASIC_PUBLISH.0)
+        .short(0)
+        .longlong(message.payload.len() as u64)
+        .short(0x9000)
+        .shortstr("application/json")
+        .octet(if persistent { 2 } else { 1 });
+    frame(out, FRAME_HEADER, CHANNEL, &header.0);
+    // Frames have 8 bytes of overhead
+    for chunk in message.payload.chunks(frame_max as usize - 8) {
+        frame(out, FRAME_BODY, CHANNEL, chunk);
+    }
+}
+
+/// A frame received from the server
+#[derive(Debug, PartialEq)]
+struct Frame {
+    frame_type: u8,
+    channel: u16,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Class and method IDs of a method frame
+    fn method(&self) -> Option<(u16, u16)> {
+        if self.frame_type != FRAME_METHOD || self.payload.len() < 4 {
+            return None;
+        }
+        let p = &self.payload;
+        Some((
+            u16::from_be_bytes([p[0], p[1]]),
+            u16::from_be_bytes([p[2], p[3]]),
+        ))
+    }
+
+    /// Arguments of a method frame
+    fn args(&self) -> Parser<'_> {
+        Parser(self.payload.get(4..).unwrap_or_default())
+    }
+}
+
+/// Decoder for method arguments
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated method"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn octet(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn short(&mut self) -> std::io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn long(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn longlong(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn shortstr(&mut self) -> std::io::Result<String> {
+        let len = self.octet()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Frame> {
+    let mut header = [0u8; 7];
+    reader.read_exact(&mut header).await?;
+    let size = u32::from_be_bytes(header[3..7].try_into().unwrap());
+    // Include the frame-end octet
+    let mut payload = vec![0u8; size as usize + 1];
+    reader.read_exact(&mut payload).await?;
+    if payload.pop() != Some(FRAME_END) {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid frame end"));
+    }
+    Ok(Frame {
+        frame_type: header[0],
+        channel: u16::from_be_bytes([header[1], header[2]]),
+        payload,
+    })
+}
+
+/// Routing key and body of a message to publish
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Message {
+    routing_key: String,
+    payload: Vec<u8>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    frame_max: u32,
+    /// Delivery tag of the last message published
+    last_tag: u64,
+}
+
+impl Connection {
+    async fn send_method(&mut self, channel: u16, args: Args) -> std::io::Result<()> {
+        let mut out = vec![];
+        frame(&mut out, FRAME_METHOD, channel, &args.0);
+        self.stream.write_all(&out).await?;
+        self.stream.flush().await
+    }
+
+    /// Wait for a method, skipping heartbeats. If the server closes the
+    /// connection or channel, it is turned into an error.
+    async fn next_method(&mut self) -> std::io::Result<Frame> {
+        loop {
+            let frame = read_frame(&mut self.stream).await?;
+            let Some(method) = frame.method() else {
+                continue;
+            };
+            if method == method::CONNECTION_CLOSE || method == method::CHANNEL_CLOSE {
+                let mut args = frame.args();
+                let code = args.short()?;
+                let text = args.shortstr()?;
+                let reply = if method == method::CONNECTION_CLOSE {
+                    method::CONNECTION_CLOSE_OK
+                } else {
+                    method::CHANNEL_CLOSE_OK
+                };
+                // The connection is abandoned anyway, so ignore errors
+                let _ = self.send_method(frame.channel, Args::method(reply)).await;
+                return Err(Error::other(format!("closed by server: {code} {text}")));
+            }
+            return Ok(frame);
+        }
+    }
+
+    async fn expect(&mut self, expected: (u16, u16)) -> std::io::Result<Frame> {
+        let frame = self.next_method().await?;
+        if frame.method() != Some(expected) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "expected method {expected:?}, received {:?}",
+                    frame.method()
+                ),
+            ));
+        }
+        Ok(frame)
+    }
+
+    /// Publish messages and wait for the server to confirm them
+    async fn publish(
+        &mut self,
+        exchange: &str,
+        messages: &[Message],
+        persistent: bool,
+    ) -> std::io::Result<()> {
+        let mut out = vec![];
+        let mut pending = BTreeSet::new();
+        for message in messages.iter() {
+            publish(&mut out, exchange, message, persistent, self.frame_max);
+            self.last_tag += 1;
+            pending.insert(self.last_tag);
+        }
+        self.stream.write_all(&out).await?;
+        self.stream.flush().await?;
+        while !pending.is_empty() {
+            let frame = self.next_method().await?;
+            let method = frame.method();
+            if method == Some(method::BASIC_ACK) || method == Some(method::BASIC_NACK) {
+                let mut args = frame.args();
+                let tag = args.longlong()?;
+                let multiple = args.octet()? & 1 != 0;
+                if method == Some(method::BASIC_NACK) {
+                    return Err(Error::other("message rejected by server"));
+                }
+                if multiple {
+                    pending = pending.split_off(&(tag + 1));
+                } else {
+                    pending.remove(&tag);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct AmqpReceiver {
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+    vhost: String,
+    username: String,
+    password: String,
+    exchange: String,
+    exchange_type: Option<String>,
+    routing_key: String,
+    persistent: bool,
+    batch: Options,
+    /// Created on first use, and discarded after an error
+    connection: Mutex<Option<Connection>>,
+}
+
+impl AmqpReceiver {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let tls = match &config.tls {
+            Some(tls) => Some(TlsConnector::from(Arc::new(tls.client_config()?))),
+            None => None,
+        };
+        let default_port = if tls.is_some() { 5671 } else { 5672 };
+        Ok(Self {
+            host: config.host.clone(),
+            port: config.port.unwrap_or(default_port),
+            tls,
+            vhost: config.vhost.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            exchange: config.exchange.clone(),
+            exchange_type: config.exchange_type.clone(),
+            routing_key: config.routing_key.clone(),
+            persistent: config.persistent,
+            batch: config.batch.clone(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> std::io::Result<Connection> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let stream: Box<dyn Stream> = match &self.tls {
+            Some(connector) => {
+                let name = ServerName::try_from(self.host.clone())
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+                Box::new(connector.connect(name, tcp).await?)
+            }
+            None => Box::new(tcp),
+        };
+        let mut connection = Connection {
+            stream: BufReader::new(stream),
+            frame_max: MAX_FRAME_SIZE,
+            last_tag: 0,
+        };
+
+        connection.stream.write_all(b"AMQP\x00\x00\x09\x01").await?;
+        connection.stream.flush().await?;
+        connection.expect(method::CONNECTION_START).await?;
+        let response = format!("\0{}\0{}", self.username, self.password);
+        let start_ok = Args::method(method::CONNECTION_START_OK)
+            .table(&[
+                ("product", "sunsniff"),
+                ("version", env!("CARGO_PKG_VERSION")),
+            ])
+            .shortstr("PLAIN")
+            .longstr(response.as_bytes())
+            .shortstr("en_US");
+        connection.send_method(0, start_ok).await?;
+
+        let tune = connection.expect(method::CONNECTION_TUNE).await?;
+        let mut args = tune.args();
+        let channel_max = args.short()?;
+        let frame_max = args.long()?;
+        if frame_max != 0 {
+            connection.frame_max = frame_max.min(MAX_FRAME_SIZE);
+        }
+        // Heartbeats are disabled, since we only read while publishing
+        let tune_ok = Args::method(method::CONNECTION_TUNE_OK)
+            .short(channel_max)
+            .long(connection.frame_max)
+            .short(0);
+        connection.send_method(0, tune_ok).await?;
+        let open = Args::method(method::CONNECTION_OPEN)
+            .shortstr(&self.vhost)
+            .shortstr("")
+            .octet(0);
+        connection.send_method(0, open).await?;
+        connection.expect(method::CONNECTION_OPEN_OK).await?;
+
+        let open = Args::method(method::CHANNEL_OPEN).shortstr("");
+        connection.send_method(CHANNEL, open).await?;
+        connection.expect(method::CHANNEL_OPEN_OK).await?;
+        if let Some(exchange_type) = &self.exchange_type {
+            let declare = Args::method(method::EXCHANGE_DECLARE)
+                .short(0)
+                .shortstr(&self.exchange)
+                .shortstr(exchange_type)
+                .octet(0x02) // Durable
+                .table(&[]);
+            connection.send_method(CHANNEL, declare).await?;
+            connection.expect(method::EXCHANGE_DECLARE_OK).await?;
+        }
+        let select = Args::method(method::CONFIRM_SELECT).octet(0);
+        connection.send_method(CHANNEL, select).await?;
+        connection.expect(method::CONFIRM_SELECT_OK).await?;
+        info!("Connected to AMQP server at {}:{}", self.host, self.port);
+        Ok(connection)
+    }
+
+    async fn write_inner(
+        &self,
+        connection: &mut Option<Connection>,
+        messages: &[Message],
+    ) -> std::io::Result<()> {
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        connection
+            .as_mut()
+            .unwrap()
+            .publish(&self.exchange, messages, self.persistent)
+            .await
+    }
+}
+
+/// Make a string safe to use as a single word of a routing key
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c == '.' || c == '*' || c == '#' || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Fill in the placeholders in a routing key template
+fn render(template: &str, serial: &str, group: &str, id: &str) -> String {
+    template
+        .replace("{serial}", &sanitize(serial))
+        .replace("{group}", &sanitize(group))
+        .replace("{id}", &sanitize(id))
+}
+
+#[async_trait]
+impl BatchWriter for AmqpReceiver {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Message> {
+        UpdateRecord::new(update)
+            .split(|meta| render(&self.routing_key, &update.serial, &meta.group, &meta.id))
+            .into_iter()
+            .map(|(routing_key, record)| Message {
+                routing_key,
+                payload: serde_json::to_vec(&record).unwrap(),
+            })
+            .collect()
+    }
+
+    async fn write(&self, messages: Vec<Message>) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().await;
+        let timeout = self
+            .batch
+            .retry
+            .timeout
+            .map_or(TIMEOUT, Duration::from_secs);
+        let result = tokio::time::timeout(timeout, self.write_inner(&mut connection, &messages))
+            .await
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "timed out")));
+        if result.is_err() {
+            *connection = None; // Reconnect for the next attempt
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Receiver for AmqpReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Port of the server (defaults to 5671 with TLS and 5672 without)
+    pub port: Option<u16>,
+    /// Use TLS for the connection
+    pub tls: Option<TlsConfig>,
+    #[serde(default = "default_vhost")]
+    pub vhost: String,
+    #[serde(default = "default_credential")]
+    pub username: String,
+    #[serde(default = "default_credential")]
+    pub password: String,
+    #[serde(default = "default_exchange")]
+    pub exchange: String,
+    /// If given, declare the exchange with this type
+    pub exchange_type: Option<String>,
+    /// Routing key, with optional `{serial}`, `{group}` and `{id}` placeholders
+    #[serde(default = "default_routing_key")]
+    pub routing_key: String,
+    /// Ask the server to store messages on disk
+    #[serde(default = "default_persistent")]
+    pub persistent: bool,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_vhost() -> String {
+    "/".to_string()
+}
+
+fn default_credential() -> String {
+    "guest".to_string()
+}
+
+fn default_exchange() -> String {
+    "amq.topic".to_string()
+}
+
+fn default_routing_key() -> String {
+    "sunsniff.{serial}.{group}.{id}".to_string()
+}
+
+fn default_persistent() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let message = Message {
+            routing_key: "a.b".to_owned(),
+            payload: b"0123456789".to_vec(),
+        };
+        let mut out = vec![];
+        publish(&mut out, "ex", &message, true, 12);
+        let mut expected = vec![];
+        expected.extend_from_slice(b"\x01\x00\x01\x00\x00\x00\x0e\x00\x3c\x00\x28\x00\x00");
+        expected.extend_from_slice(b"\x02ex\x03a.b\x00\xce");
+        expected.extend_from_slice(b"\x02\x00\x01\x00\x00\x00\x20\x00\x3c\x00\x00");
+        expected.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x0a\x90\x00");
+        expected.extend_from_slice(b"\x10application/json\x02\xce");
+        // Body is split into frames of at most 4 bytes
+        expected.extend_from_slice(b"\x03\x00\x01\x00\x00\x00\x040123\xce");
+        expected.extend_from_slice(b"\x03\x00\x01\x00\x00\x00\x044567\xce");
+        expected.extend_from_slice(b"\x03\x00\x01\x00\x00\x00\x0289\xce");
+        assert_eq!(out, expected);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let data: &[u8] = b"\x01\x00\x01\x00\x00\x00\x0d\x00\x3c\x00\x50\
+                            \x00\x00\x00\x00\x00\x00\x00\x05\x01\xce\x08\x00\x00";
+        let mut reader = data;
+        let frame = read_frame(&mut reader).await.unwrap();
+        assert_eq!(frame.channel, 1);
+        assert_eq!(frame.method(), Some(method::BASIC_ACK));
+        let mut args = frame.args();
+        assert_eq!(args.longlong().unwrap(), 5);
+        assert_eq!(args.octet().unwrap(), 1);
+        assert!(args.octet().is_err());
+        // Truncated heartbeat frame
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("solar.{serial}.{group}.{id}", "12#34", "PV 1", "pv_power"),
+            "solar.12_34.PV_1.pv_power"
+        );
+    }
+}
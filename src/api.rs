@@ -0,0 +1,301 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that serves the latest values, the field descriptions and recent
+//! history as JSON over HTTP.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::oneshot;
+use futures::stream::StreamExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::iter::zip;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use super::health::ServerStatus;
+use super::json::FieldMeta;
+use super::receiver::{Receiver, Update};
+
+/// Values of an update, keyed by field ID
+#[derive(Serialize, Debug, PartialEq)]
+struct Snapshot {
+    /// Nanoseconds since UNIX epoch
+    timestamp: i64,
+    serial: String,
+    values: BTreeMap<String, f64>,
+    text: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    fn new(update: &Update<'_>) -> Self {
+        Self {
+            timestamp: update.timestamp,
+            serial: update.serial.clone(),
+            values: zip(update.fields.iter(), update.values.iter())
+                .map(|(field, value)| (field.id.to_owned(), *value))
+                .collect(),
+            text: zip(update.text_fields.iter(), update.text.iter())
+                .map(|(field, text)| (field.id.to_owned(), text.clone()))
+                .collect(),
+        }
+    }
+}
+
+struct State {
+    /// Latest update for each inverter, keyed by serial number
+    latest: BTreeMap<String, Arc<Snapshot>>,
+    /// Descriptions of all the fields seen, keyed by ID
+    fields: BTreeMap<String, FieldMeta>,
+    /// Recent updates, oldest first
+    history: VecDeque<Arc<Snapshot>>,
+    /// Maximum length of `history`
+    history_size: usize,
+}
+
+impl State {
+    fn new(history_size: usize) -> Self {
+        Self {
+            latest: BTreeMap::new(),
+            fields: BTreeMap::new(),
+            history: VecDeque::new(),
+            history_size,
+        }
+    }
+
+    fn update(&mut self, update: &Update<'_>) {
+        for field in update.fields.iter().chain(update.text_fields.iter()) {
+            if !self.fields.contains_key(field.id) {
+                self.fields
+                    .insert(field.id.to_owned(), FieldMeta::new(field));
+            }
+        }
+        let snapshot = Arc::new(Snapshot::new(update));
+        self.latest
+            .insert(update.serial.clone(), Arc::clone(&snapshot));
+        if self.history_size > 0 {
+            if self.history.len() >= self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(snapshot);
+        }
+    }
+
+    fn latest(&self) -> BTreeMap<&str, &Snapshot> {
+        self.latest
+            .iter()
+            .map(|(serial, snapshot)| (serial.as_str(), snapshot.as_ref()))
+            .collect()
+    }
+
+    /// Updates in the history that are later than `since`
+    fn history(&self, since: i64) -> Vec<&Snapshot> {
+        // The history is not necessarily sorted, since multiple inverters
+        // may have different clocks.
+        self.history
+            .iter()
+            .filter(|snapshot| snapshot.timestamp > since)
+            .map(|snapshot| snapshot.as_ref())
+            .collect()
+    }
+}
+
+/// Parse the `since` query parameter, which is either nanoseconds since the
+/// UNIX epoch or an RFC 3339 time. If absent, all the history is returned.
+fn parse_since(query: Option<&str>) -> Result<i64, String> {
+    let value = query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "since")
+        .map(|(_, value)| value);
+    let Some(value) = value else {
+        return Ok(i64::MIN);
+    };
+    if let Ok(ns) = value.parse::<i64>() {
+        return Ok(ns);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .and_then(|time| time.timestamp_nanos_opt())
+        .ok_or_else(|| format!("Invalid value for since: {value}\n"))
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn handle(state: &Mutex<State>, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Only GET is supported\n".to_owned(),
+        );
+    }
+    let state = state.lock().unwrap();
+    match request.uri().path() {
+        "/api/latest" => json_response(&state.latest()),
+        "/api/fields" => json_response(&state.fields),
+        "/api/history" => match parse_since(request.uri().query()) {
+            Ok(since) => json_response(&state.history(since)),
+            Err(message) => error_response(StatusCode::BAD_REQUEST, message),
+        },
+        _ => error_response(StatusCode::NOT_FOUND, "Not found\n".to_owned()),
+    }
+}
+
+pub struct ApiReceiver {
+    state: Arc<Mutex<State>>,
+    /// Whether the server has failed
+    status: ServerStatus,
+    /// Stops the server when dropped
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl ApiReceiver {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = Arc::new(Mutex::new(State::new(config.history)));
+        let server_state = Arc::clone(&state);
+        let make_service = make_service_fn(move |_conn| {
+            let state = Arc::clone(&server_state);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = handle(&state, request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&config.listen)?.serve(make_service);
+        info!("Serving HTTP API on {}", server.local_addr());
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            let _ = stop.await;
+        });
+        let status = ServerStatus::default();
+        let server_status = status.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("HTTP API server failed: {err}");
+                server_status.fail(err.to_string());
+            }
+        });
+        Ok(Self {
+            state,
+            status,
+            _shutdown: shutdown,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for ApiReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            self.state.lock().unwrap().update(&update);
+            self.status.record_write();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Address on which to serve the API
+    pub listen: SocketAddr,
+    /// Number of updates to keep for `/api/history`
+    #[serde(default = "default_history")]
+    pub history: usize,
+}
+
+fn default_history() -> usize {
+    1000
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_state() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let mut state = State::new(2);
+        for (timestamp, serial) in [(1, "1234"), (2, "5678"), (3, "1234")] {
+            state.update(&Update::new(
+                timestamp,
+                serial,
+                fields,
+                vec![timestamp as f64],
+            ));
+        }
+        assert_eq!(
+            serde_json::to_value(state.latest()).unwrap(),
+            serde_json::json!({
+                "1234": {"timestamp": 3, "serial": "1234", "values": {"grid_power": 3.0}, "text": {}},
+                "5678": {"timestamp": 2, "serial": "5678", "values": {"grid_power": 2.0}, "text": {}},
+            })
+        );
+        assert_eq!(state.fields.len(), 1);
+        assert_eq!(state.fields["grid_power"].unit, "W");
+        // The oldest update has been discarded
+        let timestamps: Vec<i64> = state
+            .history(i64::MIN)
+            .iter()
+            .map(|s| s.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![2, 3]);
+        let timestamps: Vec<i64> = state.history(2).iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![3]);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since(None), Ok(i64::MIN));
+        assert_eq!(parse_since(Some("foo=1&since=1234")), Ok(1234));
+        assert_eq!(
+            parse_since(Some("since=2023-11-14T22:13:20Z")),
+            Ok(1_700_000_000_000_000_000)
+        );
+        assert!(parse_since(Some("since=yesterday")).is_err());
+    }
+}
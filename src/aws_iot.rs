@@ -0,0 +1,233 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that publishes updates to [AWS IoT
+//! Core](https://aws.amazon.com/iot-core/) over MQTT, authenticating with an
+//! X.509 client certificate. It can also update the thing's device shadow.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::warn;
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS, TlsConfiguration, Transport};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::iter::zip;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::health::record_write;
+use super::json::UpdateRecord;
+use super::mqtt::{check_template, disconnect, expand_topic, spawn_event_loop};
+use super::receiver::{Receiver, Update};
+use super::tls::TlsConfig;
+
+/// ALPN protocol that lets AWS IoT Core accept MQTT on port 443
+const ALPN_PROTOCOL: &[u8] = b"x-amzn-mqtt-ca";
+
+/// Topic for updating the classic shadow, or a named shadow
+fn shadow_topic(thing_name: &str, shadow_name: Option<&str>) -> String {
+    match shadow_name {
+        Some(name) => format!("$aws/things/{thing_name}/shadow/name/{name}/update"),
+        None => format!("$aws/things/{thing_name}/shadow/update"),
+    }
+}
+
+/// Shadow document reporting the values in an update. Values are reported
+/// per inverter, so that several inverters can share a thing.
+fn shadow_payload(update: &Update<'_>) -> Value {
+    let mut values = Map::new();
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if let Some(number) = serde_json::Number::from_f64(*value) {
+            values.insert(field.id.to_owned(), Value::Number(number));
+        }
+    }
+    for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+        values.insert(field.id.to_owned(), Value::String(text.clone()));
+    }
+    values.insert("timestamp".to_owned(), json!(update.timestamp / 1_000_000));
+    json!({"state": {"reported": {update.serial.as_str(): values}}})
+}
+
+/// Number of messages that can be queued while AWS IoT Core is unreachable
+const QUEUE_CAPACITY: usize = 100;
+
+pub struct AwsIotReceiver {
+    client: AsyncClient,
+    /// Connection to AWS IoT Core, until [Receiver::run] starts polling it
+    event_loop: Option<EventLoop>,
+    topic: String,
+    shadow_topic: Option<String>,
+}
+
+impl AwsIotReceiver {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        check_template(&config.topic, &["serial"])?;
+        let tls = TlsConfig {
+            ca_file: config.ca_file.clone(),
+            client_cert: Some(config.client_cert.clone()),
+            client_key: Some(config.client_key.clone()),
+        };
+        let mut tls = tls.client_config()?;
+        if config.alpn {
+            tls.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        }
+        let port = config.port.unwrap_or(if config.alpn { 443 } else { 8883 });
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| config.thing_name.clone());
+        let mut options = MqttOptions::new(client_id, &config.endpoint, port);
+        options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+            Arc::new(tls),
+        )));
+        let (client, event_loop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        Ok(Self {
+            client,
+            event_loop: Some(event_loop),
+            topic: config.topic.clone(),
+            shadow_topic: config
+                .shadow
+                .then(|| shadow_topic(&config.thing_name, config.shadow_name.as_deref())),
+        })
+    }
+
+    fn publish(&self, topic: &str, payload: Vec<u8>) {
+        // AWS IoT Core does not support QoS 2 or retained messages
+        let result = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, false, payload);
+        if let Err(e) = result {
+            warn!("Sending update to {} failed: {}", topic, e);
+            record_write(Some(e.to_string()));
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for AwsIotReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        let event_loop = self.event_loop.take().expect("receiver is only run once");
+        let driver = spawn_event_loop(event_loop, || {});
+        while let Some(update) = receiver.next().await {
+            let topic = expand_topic(&self.topic, &update.serial, None);
+            let record = UpdateRecord::new(&update);
+            self.publish(&topic, serde_json::to_vec(&record).unwrap());
+            if let Some(topic) = &self.shadow_topic {
+                let payload = shadow_payload(&update).to_string().into_bytes();
+                self.publish(topic, payload);
+            }
+        }
+        disconnect(&self.client, driver).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Device data endpoint (`xxxxxxxx-ats.iot.<region>.amazonaws.com`)
+    pub endpoint: String,
+    /// Port (defaults to 443 with ALPN, otherwise 8883)
+    pub port: Option<u16>,
+    /// Use ALPN to connect on port 443, for networks that block 8883
+    #[serde(default)]
+    pub alpn: bool,
+    pub thing_name: String,
+    /// MQTT client ID (defaults to the thing name)
+    pub client_id: Option<String>,
+    /// PEM file with the CA certificates (defaults to the built-in roots,
+    /// which include the Amazon roots)
+    pub ca_file: Option<PathBuf>,
+    /// PEM file with the device certificate
+    pub client_cert: PathBuf,
+    /// PEM file with the private key for `client_cert`
+    pub client_key: PathBuf,
+    /// Topic for update messages, with an optional `{serial}` placeholder
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    /// Also report the values to the device shadow
+    #[serde(default)]
+    pub shadow: bool,
+    /// Name of the shadow to update (defaults to the classic shadow)
+    pub shadow_name: Option<String>,
+}
+
+fn default_topic() -> String {
+    "sunsniff/{serial}".to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_shadow_topic() {
+        assert_eq!(
+            shadow_topic("inverter", None),
+            "$aws/things/inverter/shadow/update"
+        );
+        assert_eq!(
+            shadow_topic("inverter", Some("power")),
+            "$aws/things/inverter/shadow/name/power/update"
+        );
+    }
+
+    #[test]
+    fn test_shadow_payload() {
+        let field = |field_type, id| Field {
+            field_type,
+            group: "Test",
+            name: id,
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        };
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Power, "load_power"),
+        ]));
+        let text_fields: &'static [Field<'static>] =
+            Box::leak(Box::new([field(FieldType::Text, "fault")]));
+        let mut update = Update::new(
+            1_700_000_000_500_000_000,
+            "1234",
+            fields,
+            vec![1500.0, f64::NAN],
+        );
+        update.text_fields = text_fields;
+        update.text = vec!["OK".to_owned()];
+        assert_eq!(
+            shadow_payload(&update),
+            json!({
+                "state": {
+                    "reported": {
+                        "1234": {
+                            "pv_power": 1500.0,
+                            "fault": "OK",
+                            "timestamp": 1_700_000_000_500i64,
+                        }
+                    }
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,287 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends device-to-cloud messages to [Azure IoT
+//! Hub](https://azure.microsoft.com/products/iot-hub), using the HTTPS
+//! device API. It authenticates with a device connection string (from which
+//! SAS tokens are generated as needed) or with a pre-generated SAS token.
+
+use async_trait::async_trait;
+use base64::Engine;
+use futures::channel::mpsc::UnboundedReceiver;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::json::UpdateRecord;
+use super::receiver::{Receiver, Update};
+
+const API_VERSION: &str = "2020-03-13";
+/// Characters that are escaped in SAS tokens (everything except the
+/// unreserved characters of RFC 3986)
+const ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+fn escape(s: &str) -> String {
+    utf8_percent_encode(s, ESCAPE).to_string()
+}
+
+/// Generate a SAS token for a resource URI
+fn sas_token(resource: &str, key: &[u8], expiry: u64) -> String {
+    let resource = escape(resource);
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    let signature = ring::hmac::sign(&key, format!("{resource}\n{expiry}").as_bytes());
+    let signature = base64::engine::general_purpose::STANDARD.encode(signature);
+    format!(
+        "SharedAccessSignature sr={resource}&sig={}&se={expiry}",
+        escape(&signature)
+    )
+}
+
+enum Credentials {
+    /// Device key, from which tokens are generated
+    Key(Vec<u8>),
+    /// Fixed SAS token
+    Token(String),
+}
+
+/// Connection details, from a connection string or SAS token
+struct Device {
+    host: String,
+    device_id: String,
+    credentials: Credentials,
+}
+
+impl Device {
+    /// Parse a device connection string
+    /// (`HostName=...;DeviceId=...;SharedAccessKey=...`)
+    fn from_connection_string(connection_string: &str) -> Result<Self, String> {
+        let mut host = None;
+        let mut device_id = None;
+        let mut key = None;
+        for part in connection_string.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid connection string element {part:?}"))?;
+            match name {
+                "HostName" => host = Some(value.to_owned()),
+                "DeviceId" => device_id = Some(value.to_owned()),
+                "SharedAccessKey" => key = Some(value),
+                // Other elements (such as GatewayHostName) are not needed
+                _ => {}
+            }
+        }
+        let missing = |name| format!("Connection string has no {name}");
+        let key = key.ok_or_else(|| missing("SharedAccessKey"))?;
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key)
+            .map_err(|err| format!("Invalid SharedAccessKey: {err}"))?;
+        Ok(Self {
+            host: host.ok_or_else(|| missing("HostName"))?,
+            device_id: device_id.ok_or_else(|| missing("DeviceId"))?,
+            credentials: Credentials::Key(key),
+        })
+    }
+
+    /// Extract the host and device ID from the resource of a SAS token
+    fn from_sas_token(token: &str) -> Result<Self, String> {
+        let invalid = || "SAS token has no valid sr= resource".to_owned();
+        let resource = token
+            .strip_prefix("SharedAccessSignature ")
+            .unwrap_or(token)
+            .split('&')
+            .find_map(|part| part.strip_prefix("sr="))
+            .ok_or_else(invalid)?;
+        let resource = percent_encoding::percent_decode_str(resource)
+            .decode_utf8()
+            .map_err(|_| invalid())?;
+        let (host, device_id) = resource.split_once("/devices/").ok_or_else(invalid)?;
+        Ok(Self {
+            host: host.to_owned(),
+            device_id: device_id.to_owned(),
+            credentials: Credentials::Token(token.to_owned()),
+        })
+    }
+
+    /// Get a token that is valid for at least another `ttl` seconds
+    fn token(&self, ttl: u64) -> String {
+        match &self.credentials {
+            Credentials::Key(key) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let resource = format!("{}/devices/{}", self.host, self.device_id);
+                sas_token(&resource, key, now + ttl)
+            }
+            Credentials::Token(token) => token.clone(),
+        }
+    }
+}
+
+/// One telemetry message
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Message {
+    serial: String,
+    body: String,
+}
+
+pub struct AzureIotReceiver {
+    client: Client,
+    url: String,
+    device: Device,
+    token_ttl: u64,
+    batch: Options,
+}
+
+impl AzureIotReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let device = match (&config.connection_string, &config.sas_token) {
+            (Some(connection_string), None) => Device::from_connection_string(connection_string)?,
+            (None, Some(token)) => Device::from_sas_token(token)?,
+            _ => {
+                return Err(
+                    "Azure IoT Hub needs exactly one of connection_string and sas_token".to_owned(),
+                )
+            }
+        };
+        Ok(Self {
+            client: Client::new(),
+            url: format!(
+                "https://{}/devices/{}/messages/events?api-version={API_VERSION}",
+                device.host,
+                escape(&device.device_id)
+            ),
+            device,
+            token_ttl: config.token_ttl,
+            batch: config.batch.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl BatchWriter for AzureIotReceiver {
+    type Item = Message;
+    type Error = String;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Message> {
+        let record = UpdateRecord::new(update);
+        vec![Message {
+            serial: update.serial.clone(),
+            body: serde_json::to_string(&record).unwrap(),
+        }]
+    }
+
+    async fn write(&self, messages: Vec<Message>) -> Result<(), String> {
+        let token = self.device.token(self.token_ttl);
+        // The HTTPS API takes one message per request
+        for message in messages.into_iter() {
+            self.client
+                .post(&self.url)
+                .header("Authorization", &token)
+                .header("Content-Type", "application/json")
+                .header("iothub-contenttype", "application/json")
+                .header("iothub-contentencoding", "utf-8")
+                .header("iothub-app-serial", &message.serial)
+                .body(message.body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for AzureIotReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Device connection string
+    pub connection_string: Option<String>,
+    /// Pre-generated SAS token (instead of `connection_string`)
+    pub sas_token: Option<String>,
+    /// Lifetime of generated SAS tokens, in seconds
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_token_ttl() -> u64 {
+    3600
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sas_token() {
+        // Checked against the Python implementation in the Azure IoT Hub
+        // documentation
+        let key = base64::engine::general_purpose::STANDARD
+            .decode("c2VjcmV0IGtleSBmb3IgdGVzdGluZw==")
+            .unwrap();
+        assert_eq!(
+            sas_token("myhub.azure-devices.net/devices/inverter", &key, 1700000000),
+            "SharedAccessSignature \
+             sr=myhub.azure-devices.net%2Fdevices%2Finverter\
+             &sig=9cApe2KxQhFQt%2Fy9ZomYKk3N0YyOlkttP70jg2qE30k%3D&se=1700000000"
+        );
+    }
+
+    #[test]
+    fn test_connection_string() {
+        let device = Device::from_connection_string(
+            "HostName=myhub.azure-devices.net;DeviceId=inverter;\
+             SharedAccessKey=c2VjcmV0IGtleSBmb3IgdGVzdGluZw==",
+        )
+        .unwrap();
+        assert_eq!(device.host, "myhub.azure-devices.net");
+        assert_eq!(device.device_id, "inverter");
+        assert!(
+            matches!(device.credentials, Credentials::Key(key) if key == b"secret key for testing")
+        );
+        assert!(Device::from_connection_string("HostName=myhub;DeviceId=inverter").is_err());
+        assert!(Device::from_connection_string("HostName").is_err());
+    }
+
+    #[test]
+    fn test_from_sas_token() {
+        let token = "SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Finverter\
+                     &sig=abc%3D&se=1700000000";
+        let device = Device::from_sas_token(token).unwrap();
+        assert_eq!(device.host, "myhub.azure-devices.net");
+        assert_eq!(device.device_id, "inverter");
+        assert_eq!(device.token(60), token);
+        assert!(Device::from_sas_token("SharedAccessSignature sig=abc").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Options that apply to every backend, regardless of its type
+
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use super::aggregate::{self, Aggregator};
+use super::deadband::{self, ChangeFilter};
+use super::filter::FieldFilter;
+use super::tags::Tags;
+use super::transform::Transform;
+
+/// Options that can be given in the section of any backend
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    /// Glob patterns for the IDs of fields to send (all if empty)
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns for the IDs of fields not to send
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Combine updates over time windows before sending them
+    pub aggregate: Option<aggregate::Config>,
+    /// Only send fields when they change by more than a deadband
+    pub deadband: Option<deadband::Config>,
+    /// Static tags, in addition to those in the global `[tags]` section
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Keys of [Options], which are separated from the backend-specific
+/// configuration
+const OPTION_KEYS: &[&str] = &["include", "exclude", "aggregate", "deadband", "tags"];
+
+impl Options {
+    /// Transforms to apply to updates before they are sent to the backend.
+    /// The `tags` are the global tags, which are overridden by the tags of
+    /// the backend.
+    pub fn transforms(&self, tags: &BTreeMap<String, String>) -> Vec<Box<dyn Transform>> {
+        let mut transforms: Vec<Box<dyn Transform>> = vec![];
+        if !self.include.is_empty() || !self.exclude.is_empty() {
+            transforms.push(Box::new(FieldFilter::new(&self.include, &self.exclude)));
+        }
+        if let Some(aggregate) = &self.aggregate {
+            transforms.push(Box::new(Aggregator::new(aggregate)));
+        }
+        if let Some(deadband) = &self.deadband {
+            transforms.push(Box::new(ChangeFilter::new(deadband)));
+        }
+        // Tags are added after filtering, so that they are not filtered out
+        let mut tags = tags.clone();
+        tags.extend(self.tags.clone());
+        if !tags.is_empty() {
+            transforms.push(Box::new(Tags::new(&tags)));
+        }
+        transforms
+    }
+}
+
+/// Configuration of a backend: the backend-specific configuration `C`,
+/// together with the common [Options]. It dereferences to `C`.
+pub struct Backend<C> {
+    pub config: C,
+    pub options: Options,
+}
+
+impl<C> Deref for Backend<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.config
+    }
+}
+
+impl<'de, C: DeserializeOwned> Deserialize<'de> for Backend<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // The backend configurations reject unknown fields, so the options
+        // cannot simply be flattened into them.
+        let mut table = toml::Table::deserialize(deserializer)?;
+        let mut options = toml::Table::new();
+        for key in OPTION_KEYS {
+            if let Some(value) = table.remove(*key) {
+                options.insert(key.to_string(), value);
+            }
+        }
+        let options =
+            Options::deserialize(toml::Value::Table(options)).map_err(D::Error::custom)?;
+        let config = C::deserialize(toml::Value::Table(table)).map_err(D::Error::custom)?;
+        Ok(Self { config, options })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Config {
+        url: String,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let backend: Backend<Config> = toml::from_str(
+            "url = \"http://localhost\"\ninclude = [\"pv_*\"]\ntags = { site = \"Cabin\" }",
+        )
+        .unwrap();
+        assert_eq!(backend.url, "http://localhost");
+        assert_eq!(backend.options.include, vec!["pv_*"]);
+        assert!(backend.options.exclude.is_empty());
+        assert_eq!(backend.options.tags["site"], "Cabin");
+        assert!(toml::from_str::<Backend<Config>>("url = \"x\"\nfoo = 1").is_err());
+    }
+}
@@ -0,0 +1,709 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Batched delivery of updates to a remote service, with configurable
+//! retries and a bounded buffer.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::json::UpdateRecord;
+use crate::receiver::Update;
+use crate::spool::Spool;
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_max_buffer() -> usize {
+    10000
+}
+
+fn default_max_spool_bytes() -> u64 {
+    1 << 30
+}
+
+fn default_min_delay() -> u64 {
+    1
+}
+
+/// Deserialize `min_delay`, which must not be zero because the exponential
+/// backoff would then never increase it
+fn deserialize_min_delay<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let delay = u64::deserialize(deserializer)?;
+    if delay == 0 {
+        return Err(D::Error::custom("min_delay must be at least 1 second"));
+    }
+    Ok(delay)
+}
+
+fn default_max_delay() -> u64 {
+    300
+}
+
+/// How the delay between retries changes
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backoff {
+    /// Double the delay after each failure, up to the maximum
+    #[default]
+    Exponential,
+    /// Always wait the minimum delay
+    Fixed,
+}
+
+/// Structure corresponding to the `retry` option of batched backends. It is
+/// constructed from the config file by serde.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    /// Number of times to retry a failed write before discarding the batch
+    /// (unlimited if not given)
+    pub attempts: Option<u32>,
+    #[serde(default)]
+    pub backoff: Backoff,
+    /// Delay before the first retry, in seconds
+    #[serde(
+        default = "default_min_delay",
+        deserialize_with = "deserialize_min_delay"
+    )]
+    pub min_delay: u64,
+    /// Longest delay between retries, in seconds
+    #[serde(default = "default_max_delay")]
+    pub max_delay: u64,
+    /// Time limit for each write, in seconds
+    pub timeout: Option<u64>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: None,
+            backoff: Backoff::default(),
+            min_delay: default_min_delay(),
+            max_delay: default_max_delay(),
+            timeout: None,
+        }
+    }
+}
+
+impl Retry {
+    fn next_delay(&self, delay: Duration) -> Duration {
+        match self.backoff {
+            Backoff::Exponential => (delay * 2).min(Duration::from_secs(self.max_delay)),
+            Backoff::Fixed => delay,
+        }
+    }
+}
+
+/// Options shared by the batched backends. It is flattened into the
+/// configuration section of each backend by serde.
+#[derive(Clone, Deserialize)]
+pub struct Options {
+    /// Maximum number of updates to write at once
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Maximum number of updates to hold while the server is unavailable
+    #[serde(default = "default_max_buffer")]
+    pub max_buffer: usize,
+    /// File in which to hold updates while the server is unavailable, so
+    /// that they also survive a restart
+    pub spool: Option<PathBuf>,
+    /// Size beyond which the spool may not grow, in bytes
+    #[serde(default = "default_max_spool_bytes")]
+    pub max_spool_bytes: u64,
+    /// How failed writes are retried
+    #[serde(default)]
+    pub retry: Retry,
+    /// File to which to append updates that could not be written
+    pub dead_letter: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            batch_size: default_batch_size(),
+            max_buffer: default_max_buffer(),
+            spool: None,
+            max_spool_bytes: default_max_spool_bytes(),
+            retry: Retry::default(),
+            dead_letter: None,
+        }
+    }
+}
+
+/// Whether an HTTP status indicates that the request was rejected, such that
+/// sending it again will not help
+#[cfg(any(
+    feature = "influxdb1",
+    feature = "influxdb2",
+    feature = "otlp",
+    feature = "victoriametrics"
+))]
+pub(crate) fn is_fatal_status(status: u16) -> bool {
+    // Timeouts and rate limiting are temporary
+    (400..500).contains(&status) && status != 408 && status != 429
+}
+
+/// A backend that writes batches of items
+#[async_trait]
+pub(crate) trait BatchWriter {
+    /// The unit of data written, such as a point
+    type Item: Clone + Send + Sync;
+    type Error: Debug + Send;
+
+    /// Convert an update to the items to write
+    fn encode(&self, update: &Update<'_>) -> Vec<Self::Item>;
+
+    /// Write a batch of items
+    async fn write(&self, items: Vec<Self::Item>) -> Result<(), Self::Error>;
+
+    /// Whether an error means that the batch was rejected, so that it should
+    /// be discarded rather than retried
+    fn is_fatal(&self, _err: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// Reason that a write failed
+enum Failure<E> {
+    Error(E),
+    Timeout,
+}
+
+impl<E: Debug> Debug for Failure<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Error(err) => err.fmt(f),
+            Failure::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+/// Updates that have been received but not yet written
+struct Pending<'a> {
+    updates: VecDeque<Arc<Update<'a>>>,
+    /// Maximum number of updates to hold
+    max_buffer: usize,
+    /// Queue on disk, which takes over from `updates` once a write has
+    /// failed, until everything in it has been written. Everything in it is
+    /// older than everything in `updates`.
+    spool: Option<Spool>,
+    /// Whether the last write to the spool failed
+    spool_failed: bool,
+}
+
+impl<'a> Pending<'a> {
+    async fn new(max_buffer: usize, spool: Option<&Path>, max_spool_bytes: u64) -> Self {
+        let mut pending = Self {
+            updates: VecDeque::new(),
+            max_buffer,
+            spool: None,
+            spool_failed: false,
+        };
+        if let Some(path) = spool {
+            let owned = path.to_owned();
+            match tokio::task::spawn_blocking(move || Spool::open(&owned, max_spool_bytes))
+                .await
+                .unwrap()
+            {
+                Ok(spool) => pending.spool = Some(spool),
+                Err(err) => warn!("Could not open spool {path:?}; buffering in memory ({err})"),
+            }
+        }
+        pending
+    }
+
+    fn is_empty(&self) -> bool {
+        self.updates.is_empty() && self.spool.as_ref().is_none_or(Spool::is_empty)
+    }
+
+    fn spooled(&self) -> bool {
+        self.spool.as_ref().is_some_and(|spool| !spool.is_empty())
+    }
+
+    /// Run `f` on the spool (if there is one), on a thread that may block
+    async fn with_spool<T, F>(&mut self, f: F) -> Option<io::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Spool) -> io::Result<T> + Send + 'static,
+    {
+        let mut spool = self.spool.take()?;
+        let (spool, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut spool);
+            (spool, result)
+        })
+        .await
+        .unwrap();
+        self.spool = Some(spool);
+        Some(result)
+    }
+
+    /// Append updates to the spool, returning whether it succeeded. Only the
+    /// first of a run of failures is logged.
+    async fn spool_push(&mut self, records: Vec<UpdateRecord>) -> bool {
+        match self.with_spool(move |spool| spool.push(&records)).await {
+            Some(Ok(())) => {
+                self.spool_failed = false;
+                true
+            }
+            Some(Err(err)) => {
+                if !self.spool_failed {
+                    let path = self.spool.as_ref().unwrap().path();
+                    warn!("Could not write to spool {path:?}; buffering in memory ({err})");
+                    self.spool_failed = true;
+                }
+                false
+            }
+            None => false,
+        }
+    }
+
+    async fn push(&mut self, update: Arc<Update<'a>>) {
+        // Updates already waiting in memory must be written first, so the
+        // spool can only be used if there are none
+        if self.updates.is_empty()
+            && self.spooled()
+            && self.spool_push(vec![UpdateRecord::new(&update)]).await
+        {
+            return;
+        }
+        if self.updates.len() >= self.max_buffer {
+            warn!("Buffer is full; discarding the oldest update");
+            self.updates.pop_front();
+        }
+        self.updates.push_back(update);
+    }
+
+    /// Move the updates held in memory to the spool (if there is one), after
+    /// a failed write
+    async fn spill(&mut self) {
+        if self.spool.is_some() && !self.updates.is_empty() {
+            let records = self.updates.iter().map(|u| UpdateRecord::new(u)).collect();
+            if self.spool_push(records).await {
+                self.updates.clear();
+            }
+        }
+    }
+
+    /// Up to `n` updates from the front of the queue, and the number of them
+    /// that are held in memory (zero if they came from the spool)
+    async fn batch(&mut self, n: usize) -> (Vec<Arc<Update<'a>>>, usize) {
+        if self.spooled() {
+            match self.with_spool(move |spool| spool.read(n)).await.unwrap() {
+                Ok(updates) => return (updates.into_iter().map(Arc::new).collect(), 0),
+                Err(err) => {
+                    let path = self.spool.take().unwrap().path().to_owned();
+                    // Corrupt updates are skipped by the spool itself, so
+                    // this is a failure of the file
+                    warn!("Could not read spool {path:?}; no longer using it ({err})");
+                }
+            }
+        }
+        let n = self.updates.len().min(n);
+        (self.updates.range(..n).cloned().collect(), n)
+    }
+
+    /// Remove the updates returned by [Pending::batch]
+    async fn commit(&mut self, n: usize) {
+        if n > 0 {
+            self.updates.drain(..n);
+        } else if let Some(Err(err)) = self.with_spool(Spool::commit).await {
+            let path = self.spool.as_ref().unwrap().path();
+            warn!("Could not update spool {path:?} ({err})");
+        }
+    }
+}
+
+/// Line of the dead-letter file: an update that could not be written (in
+/// the same form as for the `jsonl` backend), together with the error
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    error: &'a str,
+    #[serde(flatten)]
+    record: UpdateRecord,
+}
+
+/// Append updates that were discarded to the dead-letter file at `path`.
+/// The file is written on a thread that may block.
+async fn write_dead_letters(
+    path: &Path,
+    updates: &[Arc<Update<'_>>],
+    error: &str,
+) -> io::Result<()> {
+    let mut lines = vec![];
+    for update in updates {
+        let record = UpdateRecord::new(update);
+        serde_json::to_writer(&mut lines, &DeadLetter { error, record })?;
+        lines.push(b'\n');
+    }
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        file.write_all(&lines)
+    })
+    .await
+    .unwrap()
+}
+
+/// Receive updates and write them in batches of up to `options.batch_size`
+/// updates. Failed writes are retried according to `options.retry`, unless
+/// the writer reports the error as fatal. While waiting, up to
+/// `options.max_buffer` updates are held, after which the oldest are
+/// discarded. If a spool file is given, updates are instead held in it while
+/// waiting (until it reaches `options.max_spool_bytes`, after which they are
+/// held in memory behind it), and it is drained in order once writes succeed
+/// again. Batches that are discarded are appended to the dead-letter file, if
+/// given.
+///
+/// This returns once the input has closed and all updates have been written
+/// (or, with a spool, once writing fails after the input has closed).
+pub(crate) async fn run_batched<W: BatchWriter + Sync>(
+    writer: &W,
+    mut receiver: UnboundedReceiver<Arc<Update<'_>>>,
+    options: &Options,
+) {
+    let retry = &options.retry;
+    let mut pending = Pending::new(
+        options.max_buffer,
+        options.spool.as_deref(),
+        options.max_spool_bytes,
+    )
+    .await;
+    let mut closed = false;
+    let min_delay = Duration::from_secs(retry.min_delay);
+    let mut delay = min_delay;
+    // Number of failed attempts to write the current batch
+    let mut failures = 0;
+    loop {
+        if pending.is_empty() {
+            if closed {
+                break;
+            }
+            match receiver.next().await {
+                Some(update) => pending.push(update).await,
+                None => closed = true,
+            }
+        }
+        // Collect anything else that is already waiting
+        while !closed {
+            match receiver.next().now_or_never() {
+                Some(Some(update)) => pending.push(update).await,
+                Some(None) => closed = true,
+                None => break, // No more updates ready
+            }
+        }
+        if pending.is_empty() {
+            continue;
+        }
+
+        let (updates, n) = pending.batch(options.batch_size.max(1)).await;
+        let items: Vec<W::Item> = updates.iter().flat_map(|u| writer.encode(u)).collect();
+        let result = if items.is_empty() {
+            Ok(())
+        } else if let Some(timeout) = retry.timeout {
+            match tokio::time::timeout(Duration::from_secs(timeout), writer.write(items)).await {
+                Ok(result) => result.map_err(Failure::Error),
+                Err(_) => Err(Failure::Timeout),
+            }
+        } else {
+            writer.write(items).await.map_err(Failure::Error)
+        };
+        crate::health::record_write(result.as_ref().err().map(|err| format!("{err:?}")));
+        let fatal = matches!(&result, Err(Failure::Error(err)) if writer.is_fatal(err));
+        match result {
+            Ok(()) => {
+                pending.commit(n).await;
+                delay = min_delay;
+                failures = 0;
+            }
+            Err(err) if fatal || retry.attempts.is_some_and(|a| failures >= a) => {
+                warn!("Error writing batch; discarding it ({err:?})");
+                if let Some(path) = &options.dead_letter {
+                    let error = format!("{err:?}");
+                    if let Err(err) = write_dead_letters(path, &updates, &error).await {
+                        warn!("Could not write to dead-letter file {path:?} ({err})");
+                    }
+                }
+                pending.commit(n).await;
+                delay = min_delay;
+                failures = 0;
+            }
+            Err(err) => {
+                failures += 1;
+                pending.spill().await;
+                if closed && pending.updates.is_empty() && pending.spool.is_some() {
+                    info!("Error writing batch; leaving the rest in the spool ({err:?})");
+                    break;
+                }
+                info!("Error writing batch; trying again in {delay:?} ({err:?})");
+                // Keep receiving updates while waiting
+                let sleep = tokio::time::sleep(delay);
+                tokio::pin!(sleep);
+                loop {
+                    tokio::select! {
+                        _ = &mut sleep => break,
+                        update = receiver.next(), if !closed => match update {
+                            Some(update) => pending.push(update).await,
+                            None => closed = true,
+                        },
+                    }
+                }
+                delay = retry.next_delay(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc;
+    use std::sync::Mutex;
+
+    /// Writer that fails a given number of times, then records the batches
+    struct TestWriter {
+        failures: Mutex<usize>,
+        /// Whether the failures are fatal
+        fatal: bool,
+        batches: Mutex<Vec<Vec<i64>>>,
+    }
+
+    #[async_trait]
+    impl BatchWriter for TestWriter {
+        type Item = i64;
+        type Error = bool;
+
+        fn encode(&self, update: &Update<'_>) -> Vec<i64> {
+            vec![update.timestamp]
+        }
+
+        async fn write(&self, items: Vec<i64>) -> Result<(), bool> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(self.fatal);
+            }
+            self.batches.lock().unwrap().push(items);
+            Ok(())
+        }
+
+        fn is_fatal(&self, err: &bool) -> bool {
+            *err
+        }
+    }
+
+    struct Run<'a> {
+        failures: usize,
+        fatal: bool,
+        updates: i64,
+        batch_size: usize,
+        max_buffer: usize,
+        spool: Option<&'a Path>,
+        retry: Retry,
+        dead_letter: Option<&'a Path>,
+    }
+
+    impl Default for Run<'_> {
+        fn default() -> Self {
+            Self {
+                failures: 0,
+                fatal: false,
+                updates: 5,
+                batch_size: 2,
+                max_buffer: 100,
+                spool: None,
+                retry: Retry::default(),
+                dead_letter: None,
+            }
+        }
+    }
+
+    fn run_with(args: Run<'_>) -> Vec<Vec<i64>> {
+        let writer = TestWriter {
+            failures: Mutex::new(args.failures),
+            fatal: args.fatal,
+            batches: Mutex::new(vec![]),
+        };
+        let (sender, receiver) = mpsc::unbounded();
+        for i in 0..args.updates {
+            sender
+                .unbounded_send(Arc::new(Update::new(i, "1234", &[], vec![])))
+                .unwrap();
+        }
+        drop(sender);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let options = Options {
+            batch_size: args.batch_size,
+            max_buffer: args.max_buffer,
+            spool: args.spool.map(Path::to_path_buf),
+            max_spool_bytes: default_max_spool_bytes(),
+            retry: args.retry,
+            dead_letter: args.dead_letter.map(Path::to_path_buf),
+        };
+        rt.block_on(run_batched(&writer, receiver, &options));
+        writer.batches.into_inner().unwrap()
+    }
+
+    fn run(failures: usize, batch_size: usize, max_buffer: usize) -> Vec<Vec<i64>> {
+        run_with(Run {
+            failures,
+            batch_size,
+            max_buffer,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_batches() {
+        assert_eq!(run(0, 2, 100), vec![vec![0, 1], vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn test_retry() {
+        assert_eq!(run(3, 10, 100), vec![vec![0, 1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn test_max_buffer() {
+        assert_eq!(run(0, 10, 3), vec![vec![2, 3, 4]]);
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = std::env::temp_dir().join(format!("sunsniff-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spool.jsonl");
+        // The spool is kept when the input closes while the backend is
+        // failing, and drained in order on the next run
+        let spooled = |failures, updates| {
+            run_with(Run {
+                failures,
+                updates,
+                spool: Some(&path),
+                ..Default::default()
+            })
+        };
+        assert!(spooled(1, 5).is_empty());
+        assert_eq!(spooled(0, 0), vec![vec![0, 1], vec![2, 3], vec![4]]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool_full() {
+        let dir = std::env::temp_dir().join(format!("sunsniff-full-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spool.jsonl");
+        let update = |i| Arc::new(Update::new(i, "1234", &[], vec![]));
+        let line = serde_json::to_vec(&UpdateRecord::new(&update(0)))
+            .unwrap()
+            .len()
+            + 1;
+        // Room for two updates in the spool
+        let mut pending = Pending::new(100, Some(&path), 2 * line as u64).await;
+        pending.push(update(0)).await;
+        pending.spill().await;
+        assert!(pending.updates.is_empty());
+        for i in 1..5 {
+            pending.push(update(i)).await;
+        }
+        assert_eq!(pending.updates.len(), 3);
+        // The spooled updates are written first
+        let mut order = vec![];
+        while !pending.is_empty() {
+            let (updates, n) = pending.batch(3).await;
+            order.extend(updates.iter().map(|update| update.timestamp));
+            pending.commit(n).await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(order, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_attempts() {
+        let retry: Retry = toml::from_str("attempts = 1\nbackoff = \"fixed\"").unwrap();
+        let batches = run_with(Run {
+            failures: 3,
+            retry,
+            ..Default::default()
+        });
+        // The first batch is discarded after one retry
+        assert_eq!(batches, vec![vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn test_fatal() {
+        let dir = std::env::temp_dir().join(format!("sunsniff-dead-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dead.jsonl");
+        let batches = run_with(Run {
+            failures: 1,
+            fatal: true,
+            dead_letter: Some(&path),
+            ..Default::default()
+        });
+        assert_eq!(batches, vec![vec![2, 3], vec![4]]);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["error"], "true");
+        assert_eq!(lines[1]["timestamp"], 1);
+        assert_eq!(lines[1]["serial"], "1234");
+    }
+
+    #[test]
+    fn test_options() {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Config {
+            #[allow(dead_code)]
+            host: String,
+            #[serde(flatten)]
+            batch: Options,
+        }
+
+        let config: Config = toml::from_str("host = \"x\"\nbatch_size = 5").unwrap();
+        assert_eq!(config.batch.batch_size, 5);
+        assert_eq!(config.batch.max_buffer, default_max_buffer());
+        assert_eq!(config.batch.max_spool_bytes, 1 << 30);
+        assert!(toml::from_str::<Config>("host = \"x\"\nbatch = 5").is_err());
+        let config: Config = toml::from_str("host = \"x\"\nretry = { attempts = 2 }").unwrap();
+        assert_eq!(config.batch.retry.attempts, Some(2));
+        let config: Config = toml::from_str("host = \"x\"\nretry = { min_delay = 5 }").unwrap();
+        assert_eq!(config.batch.retry.min_delay, 5);
+        assert!(toml::from_str::<Config>("host = \"x\"\nretry = { min_delay = 0 }").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that posts alerts (see [super::alerts]) to a Slack or Discord
+//! channel through an incoming webhook. Messages are formatted as a Slack
+//! attachment or a Discord embed, coloured according to whether the alert
+//! fired or cleared.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use super::alerts::AlertConfig;
+use super::alerts::{default_min_interval, run_alerts, Alerts, Level, Notification, Notifier};
+use super::receiver::{Receiver, Update};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Service {
+    Slack,
+    Discord,
+}
+
+/// Colour for the side bar of a message (as RGB)
+fn colour(level: Level) -> u32 {
+    match level {
+        Level::Fired => 0xd00000,
+        Level::Cleared => 0x2eb886,
+        Level::Info => 0x439fe0,
+    }
+}
+
+/// Generate the JSON payload for a notification
+fn payload(service: Service, mention: Option<&str>, notification: &Notification) -> Value {
+    // Only mention people when an alert fires
+    let mention = mention.filter(|_| notification.level == Level::Fired);
+    let colour = colour(notification.level);
+    match service {
+        Service::Slack => {
+            let text = match mention {
+                Some(mention) => format!("{mention} {}", notification.subject),
+                None => notification.subject.clone(),
+            };
+            json!({
+                "text": text,
+                "attachments": [{
+                    "color": format!("#{colour:06x}"),
+                    "title": notification.subject,
+                    "text": notification.body,
+                    "footer": "sunsniff",
+                }],
+            })
+        }
+        Service::Discord => {
+            let mut payload = json!({
+                "username": "sunsniff",
+                "embeds": [{
+                    "title": notification.subject,
+                    "description": notification.body,
+                    "color": colour,
+                }],
+            });
+            if let Some(mention) = mention {
+                payload["content"] = Value::String(mention.to_owned());
+            }
+            payload
+        }
+    }
+}
+
+struct Webhook {
+    client: Client,
+    service: Service,
+    url: String,
+    mention: Option<String>,
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let body = payload(self.service, self.mention.as_deref(), notification);
+        self.client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("{:?} webhook: {err}", self.service))?;
+        Ok(())
+    }
+}
+
+pub struct ChatReceiver {
+    webhook: Webhook,
+    alerts: Alerts,
+}
+
+impl ChatReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(Self {
+            webhook: Webhook {
+                client: Client::builder()
+                    .timeout(TIMEOUT)
+                    .build()
+                    .map_err(|err| err.to_string())?,
+                service: config.service,
+                url: config.url.clone(),
+                mention: config.mention.clone(),
+            },
+            alerts: Alerts::new(&config.alerts, config.min_interval)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for ChatReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_alerts(&mut self.webhook, &mut self.alerts, receiver).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub service: Service,
+    /// Incoming webhook URL
+    pub url: String,
+    /// Text to prepend when an alert fires (such as `<!channel>` for Slack or
+    /// `@here` for Discord)
+    pub mention: Option<String>,
+    /// Minimum number of seconds between messages for the same alert and
+    /// inverter
+    #[serde(default = "default_min_interval")]
+    pub min_interval: u64,
+    pub alerts: Vec<AlertConfig>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn notification(level: Level) -> Notification {
+        Notification {
+            level,
+            subject: "Fault on 1234".to_owned(),
+            body: "Inverter: 1234\n".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_slack() {
+        assert_eq!(
+            payload(Service::Slack, Some("<!here>"), &notification(Level::Fired)),
+            json!({
+                "text": "<!here> Fault on 1234",
+                "attachments": [{
+                    "color": "#d00000",
+                    "title": "Fault on 1234",
+                    "text": "Inverter: 1234\n",
+                    "footer": "sunsniff",
+                }],
+            })
+        );
+        let cleared = payload(
+            Service::Slack,
+            Some("<!here>"),
+            &notification(Level::Cleared),
+        );
+        assert_eq!(cleared["text"], "Fault on 1234");
+        assert_eq!(cleared["attachments"][0]["color"], "#2eb886");
+    }
+
+    #[test]
+    fn test_discord() {
+        assert_eq!(
+            payload(Service::Discord, None, &notification(Level::Fired)),
+            json!({
+                "username": "sunsniff",
+                "embeds": [{
+                    "title": "Fault on 1234",
+                    "description": "Inverter: 1234\n",
+                    "color": 0xd00000,
+                }],
+            })
+        );
+        let mentioned = payload(Service::Discord, Some("@here"), &notification(Level::Fired));
+        assert_eq!(mentioned["content"], "@here");
+    }
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            "service = \"discord\"\nurl = \"https://discord.com/api/webhooks/1/x\"\n\
+             alerts = [{ name = \"Fault\", group = \"Fault\", above = 0 }]",
+        )
+        .unwrap();
+        assert_eq!(config.service, Service::Discord);
+        assert!(ChatReceiver::new(&config).is_ok());
+        assert!(toml::from_str::<Config>("service = \"irc\"\nurl = \"x\"\nalerts = []").is_err());
+    }
+}
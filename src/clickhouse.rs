@@ -0,0 +1,262 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that inserts updates into a [ClickHouse](https://clickhouse.com)
+//! table through the HTTP interface. Rows are sent in the `JSONEachRow`
+//! format, optionally as asynchronous inserts so that the server can combine
+//! small inserts from many inverters.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::iter::zip;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+use super::rotate::utc;
+
+/// Quote an identifier (such as a table name)
+fn quote_ident(ident: &str) -> String {
+    format!("`{}`", ident.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+pub struct ClickhouseReceiver {
+    client: Client,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    /// Database and table, quoted
+    table: String,
+    async_insert: bool,
+    /// Whether the table still needs to be created
+    create: AtomicBool,
+    batch: Options,
+}
+
+impl ClickhouseReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            url: config.url.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            table: format!(
+                "{}.{}",
+                quote_ident(&config.database),
+                quote_ident(&config.table)
+            ),
+            async_insert: config.async_insert,
+            create: AtomicBool::new(config.create_table),
+            batch: config.batch.clone(),
+        }
+    }
+
+    fn create_query(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             time DateTime64(9, 'UTC'), \
+             serial LowCardinality(String), \
+             id LowCardinality(String), \
+             value Nullable(Float64), \
+             text Nullable(String)\
+             ) ENGINE = MergeTree ORDER BY (serial, id, time)",
+            self.table
+        )
+    }
+
+    fn request(&self, query: &str) -> RequestBuilder {
+        let mut request = self.client.post(&self.url).query(&[("query", query)]);
+        if let Some(username) = &self.username {
+            request = request.header("X-ClickHouse-User", username);
+        }
+        if let Some(password) = &self.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+        request
+    }
+}
+
+/// Turn an error response into an error, including the message from the
+/// server
+async fn check(response: reqwest::Response) -> Result<(), String> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        let message = response.text().await.unwrap_or_default();
+        Err(format!("{status}: {}", message.trim_end()))
+    }
+}
+
+#[async_trait]
+impl BatchWriter for ClickhouseReceiver {
+    type Item = Value;
+    type Error = String;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Value> {
+        let time = utc(update.timestamp)
+            .format("%Y-%m-%d %H:%M:%S%.9f")
+            .to_string();
+        let numeric = zip(update.fields.iter(), update.values.iter()).map(|(field, value)| {
+            json!({
+                "time": time,
+                "serial": update.serial,
+                "id": field.id,
+                "value": Some(*value).filter(|value| value.is_finite()),
+                "text": field.label(*value),
+            })
+        });
+        let text = zip(update.text_fields.iter(), update.text.iter()).map(|(field, text)| {
+            json!({
+                "time": time,
+                "serial": update.serial,
+                "id": field.id,
+                "value": null,
+                "text": text,
+            })
+        });
+        numeric.chain(text).collect()
+    }
+
+    async fn write(&self, rows: Vec<Value>) -> Result<(), String> {
+        if self.create.load(Ordering::Relaxed) {
+            let response = self
+                .request(&self.create_query())
+                .send()
+                .await
+                .map_err(|err| err.to_string())?;
+            check(response).await?;
+            self.create.store(false, Ordering::Relaxed);
+        }
+        let mut body = String::new();
+        for row in rows.iter() {
+            body.push_str(&row.to_string());
+            body.push('\n');
+        }
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", self.table);
+        let mut request = self.request(&query).body(body);
+        if self.async_insert {
+            // Wait for the data to be flushed, so that errors are reported
+            request = request.query(&[("async_insert", "1"), ("wait_for_async_insert", "1")]);
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        check(response).await
+    }
+}
+
+#[async_trait]
+impl Receiver for ClickhouseReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// URL of the HTTP interface
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_database")]
+    pub database: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Create the table if it does not exist
+    #[serde(default = "default_true")]
+    pub create_table: bool,
+    /// Use asynchronous inserts
+    #[serde(default = "default_true")]
+    pub async_insert: bool,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_url() -> String {
+    "http://localhost:8123".to_owned()
+}
+
+fn default_database() -> String {
+    "default".to_owned()
+}
+
+fn default_table() -> String {
+    "sunsniff".to_owned()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+    use crate::test_util::{field, leak};
+
+    fn update() -> Update<'static> {
+        let fields = leak([Field {
+            labels: &[(2, "Normal")],
+            ..field(FieldType::Enum, "inverter_state")
+        }]);
+        let text_fields = leak([field(FieldType::Text, "version")]);
+        Update::new(0, "1234", fields, vec![2.0]).with_text(text_fields, vec!["1.0".to_owned()])
+    }
+
+    fn receiver() -> ClickhouseReceiver {
+        ClickhouseReceiver::new(&toml::from_str("table = \"my`table\"").unwrap())
+    }
+
+    #[test]
+    fn test_encode() {
+        let rows = receiver().encode(&update());
+        assert_eq!(
+            rows,
+            vec![
+                json!({
+                    "time": "1970-01-01 00:00:00.000000000",
+                    "serial": "1234",
+                    "id": "inverter_state",
+                    "value": 2.0,
+                    "text": "Normal",
+                }),
+                json!({
+                    "time": "1970-01-01 00:00:00.000000000",
+                    "serial": "1234",
+                    "id": "version",
+                    "value": null,
+                    "text": "1.0",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_query() {
+        let receiver = receiver();
+        assert_eq!(receiver.table, "`default`.`my\\`table`");
+        assert!(receiver
+            .create_query()
+            .starts_with("CREATE TABLE IF NOT EXISTS `default`.`my\\`table` (time DateTime64"));
+    }
+}
@@ -0,0 +1,220 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that appends a row per update to CSV files, starting a new file
+//! each day (UTC) and optionally when the file reaches a maximum size.
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::iter::zip;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::health::record_write;
+use super::receiver::{Receiver, Update};
+use super::rotate::{utc, Rotation};
+
+/// The file currently being written
+struct Output {
+    writer: csv::Writer<File>,
+    /// Day on which the file was started
+    day: NaiveDate,
+    /// Header row
+    header: Vec<String>,
+}
+
+pub struct CsvReceiver {
+    rotation: Rotation,
+    output: Option<Output>,
+}
+
+/// Header and record for an update
+fn row(update: &Update<'_>) -> (Vec<String>, Vec<String>) {
+    let mut header = vec!["time".to_owned(), "serial".to_owned()];
+    let mut record = vec![
+        utc(update.timestamp)
+            .format("%Y-%m-%dT%H:%M:%S%.fZ")
+            .to_string(),
+        update.serial.clone(),
+    ];
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        header.push(field.id.to_owned());
+        record.push(value.to_string());
+    }
+    for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+        header.push(field.id.to_owned());
+        record.push(text.clone());
+    }
+    (header, record)
+}
+
+impl CsvReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rotation: Rotation {
+                directory: config.directory.clone(),
+                prefix: config.prefix.clone(),
+                extension: "csv".to_owned(),
+                max_size: config.max_size,
+            },
+            output: None,
+        }
+    }
+
+    /// Whether a new file must be started for a row
+    fn must_rotate(&self, time: NaiveDateTime, header: &[String]) -> std::io::Result<bool> {
+        let Some(output) = &self.output else {
+            return Ok(true);
+        };
+        let size = output.writer.get_ref().metadata()?.len();
+        Ok(output.header != header || self.rotation.expired(output.day, size, time))
+    }
+
+    fn write(&mut self, update: &Update<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        let (header, record) = row(update);
+        let start = utc(update.timestamp);
+        if self.must_rotate(start, &header)? {
+            self.output = None;
+            let path = self.rotation.path(start);
+            info!("Writing to {}", path.display());
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let is_empty = file.metadata()?.len() == 0;
+            let mut writer = csv::Writer::from_writer(file);
+            if is_empty {
+                writer.write_record(&header)?;
+            }
+            self.output = Some(Output {
+                writer,
+                day: start.date(),
+                header,
+            });
+        }
+        let writer = &mut self.output.as_mut().unwrap().writer;
+        writer.write_record(&record)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for CsvReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            match self.write(&update) {
+                Ok(()) => record_write(None),
+                Err(err) => {
+                    warn!("Failed to write CSV row: {err}");
+                    // Start a new file next time, in case this one is broken
+                    self.output = None;
+                    record_write(Some(err.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory in which to write the files
+    pub directory: PathBuf,
+    /// Start of each filename
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Size in bytes after which to start a new file
+    pub max_size: Option<u64>,
+}
+
+fn default_prefix() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    const DAY: i64 = 86_400_000_000_000;
+
+    fn read_dir(dir: &std::path::Path) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_rotate() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let dir = std::env::temp_dir().join(format!("sunsniff-csv-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut receiver = CsvReceiver::new(&Config {
+            directory: dir.clone(),
+            prefix: "test".to_owned(),
+            max_size: None,
+        });
+        receiver
+            .write(&Update::new(0, "1234", fields, vec![1.0]))
+            .unwrap();
+        receiver
+            .write(&Update::new(1_500_000_000, "1234", fields, vec![-2.5]))
+            .unwrap();
+        receiver
+            .write(&Update::new(DAY, "1234", fields, vec![3.0]))
+            .unwrap();
+        let files = read_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "test-19700101-000000.csv".to_owned(),
+                    "time,serial,grid_power\n\
+                     1970-01-01T00:00:00Z,1234,1\n\
+                     1970-01-01T00:00:01.500Z,1234,-2.5\n"
+                        .to_owned()
+                ),
+                (
+                    "test-19700102-000000.csv".to_owned(),
+                    "time,serial,grid_power\n1970-01-02T00:00:00Z,1234,3\n".to_owned()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,294 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Publishing fields only when they change by more than a deadband
+
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::fields::{leak_fields, Field, FieldType};
+use super::receiver::{Update, UpdateItem};
+use super::transform::{FieldListCache, Transform};
+
+/// Most subsets of a field list to keep. Each subset needs a new field list,
+/// which is never freed, so after this many, all fields are sent.
+const MAX_SUBSETS: usize = 4096;
+
+/// Size of the change needed to publish a field
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Band {
+    /// Absolute change
+    #[serde(default)]
+    absolute: f64,
+    /// Change relative to the last published value, as a percentage
+    #[serde(default)]
+    percent: f64,
+}
+
+impl Band {
+    fn exceeded(&self, old: f64, new: f64) -> bool {
+        if !old.is_finite() || !new.is_finite() {
+            // Only publish if the value is newly (in)valid
+            return old.is_finite() != new.is_finite();
+        }
+        let band = self.absolute.max(self.percent * 0.01 * old.abs());
+        (new - old).abs() > band
+    }
+}
+
+/// Structure corresponding to the `deadband` option of a backend. It is
+/// constructed from the config file by serde.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Band for all fields (except enums and flags, which are published
+    /// whenever they change)
+    #[serde(default)]
+    absolute: f64,
+    #[serde(default)]
+    percent: f64,
+    /// Bands for individual fields, keyed by ID
+    #[serde(default)]
+    fields: HashMap<String, Band>,
+    /// Maximum time (in seconds) between publishing each field
+    heartbeat: Option<u64>,
+}
+
+impl Config {
+    fn band(&self, field: &Field<'_>) -> Band {
+        match self.fields.get(field.id) {
+            Some(band) => *band,
+            None => match field.field_type {
+                FieldType::Enum | FieldType::Flags => Band::default(),
+                _ => Band {
+                    absolute: self.absolute,
+                    percent: self.percent,
+                },
+            },
+        }
+    }
+}
+
+/// Last published value of a field, and when it was published
+struct Published<T> {
+    value: T,
+    timestamp: i64,
+}
+
+/// Subsets of a field list, keyed by which fields are included
+#[derive(Default)]
+struct Subsets {
+    lists: HashMap<Vec<bool>, &'static [Field<'static>]>,
+    /// Whether a warning has been logged about reaching [MAX_SUBSETS]
+    warned: bool,
+}
+
+impl Subsets {
+    /// Field list containing the fields of `fields` where `keep` is true, or
+    /// `None` if there are too many subsets already
+    fn get(
+        &mut self,
+        fields: &'static [Field<'static>],
+        keep: &[bool],
+    ) -> Option<&'static [Field<'static>]> {
+        if keep.iter().all(|k| *k) {
+            return Some(fields);
+        }
+        if let Some(subset) = self.lists.get(keep) {
+            return Some(subset);
+        }
+        if self.lists.len() >= MAX_SUBSETS {
+            if !self.warned {
+                warn!("Too many combinations of changed fields; sending all fields");
+                self.warned = true;
+            }
+            return None;
+        }
+        let subset = leak_fields(filter(fields, keep));
+        self.lists.insert(keep.to_vec(), subset);
+        Some(subset)
+    }
+}
+
+/// Transform that removes fields that have not changed enough since they
+/// were last published. Updates with no fields left are dropped.
+pub struct ChangeFilter {
+    config: Config,
+    /// Heartbeat in nanoseconds
+    heartbeat: Option<i64>,
+    /// Last published values, keyed by serial number and field ID
+    values: HashMap<String, HashMap<&'static str, Published<f64>>>,
+    text: HashMap<String, HashMap<&'static str, Published<String>>>,
+    subsets: FieldListCache<Subsets>,
+}
+
+impl ChangeFilter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            heartbeat: config
+                .heartbeat
+                .map(|secs| (secs as i64).saturating_mul(1_000_000_000)),
+            values: HashMap::new(),
+            text: HashMap::new(),
+            subsets: FieldListCache::new(),
+        }
+    }
+}
+
+/// Remove the values where `keep` is false
+fn filter<T: Clone>(values: &[T], keep: &[bool]) -> Vec<T> {
+    values
+        .iter()
+        .zip(keep.iter())
+        .filter(|(_, keep)| **keep)
+        .map(|(value, _)| value.clone())
+        .collect()
+}
+
+/// Decide which fields to publish, updating the last published values
+fn select<T: Clone>(
+    fields: &[Field<'static>],
+    values: &[T],
+    timestamp: i64,
+    heartbeat: Option<i64>,
+    last: &mut HashMap<&'static str, Published<T>>,
+    changed: impl Fn(&Field<'static>, &T, &T) -> bool,
+) -> Vec<bool> {
+    fields
+        .iter()
+        .zip(values.iter())
+        .map(|(field, value)| {
+            let publish = match last.get(field.id) {
+                None => true,
+                Some(published) => {
+                    changed(field, &published.value, value)
+                        || heartbeat.is_some_and(|h| timestamp - published.timestamp >= h)
+                }
+            };
+            if publish {
+                let published = Published {
+                    value: value.clone(),
+                    timestamp,
+                };
+                last.insert(field.id, published);
+            }
+            publish
+        })
+        .collect()
+}
+
+impl Transform for ChangeFilter {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        let config = &self.config;
+        let keep_values = select(
+            update.fields,
+            &update.values,
+            update.timestamp,
+            self.heartbeat,
+            self.values.entry(update.serial.clone()).or_default(),
+            |field, old, new| config.band(field).exceeded(*old, *new),
+        );
+        let keep_text = select(
+            update.text_fields,
+            &update.text,
+            update.timestamp,
+            self.heartbeat,
+            self.text.entry(update.serial.clone()).or_default(),
+            |_, old, new| old != new,
+        );
+        if !keep_values.contains(&true) && !keep_text.contains(&true) {
+            return None;
+        }
+        let subsets = self.subsets.get_mut(update.fields, |_| Subsets::default());
+        let (fields, values) = match subsets.get(update.fields, &keep_values) {
+            Some(fields) => (fields, filter(&update.values, &keep_values)),
+            None => (update.fields, update.values.clone()),
+        };
+        let subsets = self
+            .subsets
+            .get_mut(update.text_fields, |_| Subsets::default());
+        let (text_fields, text) = match subsets.get(update.text_fields, &keep_text) {
+            Some(fields) => (fields, filter(&update.text, &keep_text)),
+            None => (update.text_fields, update.text.clone()),
+        };
+        let new_update = Update::new(update.timestamp, update.serial.clone(), fields, values)
+            .with_text(text_fields, text);
+        Some(Arc::new(new_update))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{field, leak};
+
+    #[test]
+    fn test_band() {
+        let band = Band {
+            absolute: 10.0,
+            percent: 5.0,
+        };
+        assert!(!band.exceeded(100.0, 110.0));
+        assert!(band.exceeded(100.0, 110.5));
+        assert!(!band.exceeded(1000.0, 1050.0));
+        assert!(band.exceeded(1000.0, 949.0));
+        assert!(band.exceeded(f64::NAN, 0.0));
+        assert!(!band.exceeded(f64::NAN, f64::NAN));
+        assert!(Band::default().exceeded(1.0, 1.001));
+    }
+
+    #[test]
+    fn test_change_filter() {
+        let config: Config =
+            toml::from_str("absolute = 50.0\nheartbeat = 60\nfields = { battery_soc = {} }")
+                .unwrap();
+        let mut filter = ChangeFilter::new(&config);
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::StateOfCharge, "battery_soc"),
+            field(FieldType::Enum, "inverter_state"),
+        ]);
+        let text_fields = leak([field(FieldType::Text, "version")]);
+        let mut apply = |seconds: i64, values: Vec<f64>, version: &str| {
+            let update = Update::new(seconds * 1_000_000_000, "1234", fields, values)
+                .with_text(text_fields, vec![version.to_owned()]);
+            filter.apply(Arc::new(update))
+        };
+        let ids = |update: &Update<'static>| -> Vec<&'static str> {
+            let fields = update.fields.iter().chain(update.text_fields.iter());
+            fields.map(|field| field.id).collect()
+        };
+
+        // Everything is published the first time
+        let update = apply(0, vec![1000.0, 80.0, 2.0], "1.0").unwrap();
+        assert_eq!(ids(&update).len(), 4);
+        // Nothing has changed enough
+        assert!(apply(10, vec![1040.0, 80.0, 2.0], "1.0").is_none());
+        let update = apply(20, vec![1060.0, 81.0, 2.0], "1.0").unwrap();
+        assert_eq!(ids(&update), vec!["pv_power", "battery_soc"]);
+        assert_eq!(update.values, vec![1060.0, 81.0]);
+        let update = apply(30, vec![1060.0, 81.0, 3.0], "1.1").unwrap();
+        assert_eq!(ids(&update), vec!["inverter_state", "version"]);
+        assert_eq!(update.text, vec!["1.1"]);
+        // Heartbeat for the fields last published at 20 s
+        let update = apply(80, vec![1060.0, 81.0, 3.0], "1.1").unwrap();
+        assert_eq!(ids(&update), vec!["pv_power", "battery_soc"]);
+    }
+}
@@ -0,0 +1,357 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Fields computed from other fields
+
+use log::error;
+use serde::Deserialize;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::sync::Arc;
+
+use super::fields::{leak_fields, leak_str, Field, FieldType};
+use super::receiver::UpdateItem;
+use super::transform::{replace_values, FieldListCache, Transform};
+
+/// Structure corresponding to a `[[derived]]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    field_type: FieldType,
+    group: String,
+    name: String,
+    id: String,
+    unit: Option<String>,
+    expression: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Arithmetic expression. `F` is the representation of a field reference:
+/// either the ID, or the index into the list of fields.
+#[derive(Debug, PartialEq)]
+enum Expr<F> {
+    Const(f64),
+    Field(F),
+    Neg(Box<Expr<F>>),
+    Binary(Op, Box<Expr<F>>, Box<Expr<F>>),
+}
+
+impl Expr<String> {
+    /// Replace field IDs by indices into `fields`
+    fn bind(&self, fields: &[Field<'_>]) -> Result<Expr<usize>, String> {
+        Ok(match self {
+            Expr::Const(x) => Expr::Const(*x),
+            Expr::Field(id) => Expr::Field(
+                fields
+                    .iter()
+                    .position(|f| f.id == id)
+                    .ok_or_else(|| format!("Unknown field {id}"))?,
+            ),
+            Expr::Neg(a) => Expr::Neg(Box::new(a.bind(fields)?)),
+            Expr::Binary(op, a, b) => {
+                Expr::Binary(*op, Box::new(a.bind(fields)?), Box::new(b.bind(fields)?))
+            }
+        })
+    }
+}
+
+impl Expr<usize> {
+    /// Evaluate the expression. Division by zero gives zero.
+    fn eval(&self, values: &[f64]) -> f64 {
+        match self {
+            Expr::Const(x) => *x,
+            Expr::Field(idx) => values[*idx],
+            Expr::Neg(a) => -a.eval(values),
+            Expr::Binary(op, a, b) => {
+                let a = a.eval(values);
+                let b = b.eval(values);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div if b == 0.0 => 0.0,
+                    Op::Div => a / b,
+                }
+            }
+        }
+    }
+}
+
+/// Recursive descent parser for expressions. The grammar is
+///
+/// ```text
+/// expr   := term (('+' | '-') term)*
+/// term   := factor (('*' | '/') factor)*
+/// factor := '-' factor | number | field_id | '(' expr ')'
+/// ```
+struct Parser<'a> {
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            chars: text.char_indices().peekable(),
+        }
+    }
+
+    /// Skip whitespace and return the next character without consuming it
+    fn peek(&mut self) -> Option<char> {
+        while let Some((_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                return Some(*c);
+            }
+            self.chars.next();
+        }
+        None
+    }
+
+    /// Consume characters while `pred` holds, returning them
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let start = self.chars.peek().map_or(self.text.len(), |(i, _)| *i);
+        let mut end = start;
+        while let Some((i, c)) = self.chars.peek() {
+            if !pred(*c) {
+                break;
+            }
+            end = i + c.len_utf8();
+            self.chars.next();
+        }
+        &self.text[start..end]
+    }
+
+    fn parse(mut self) -> Result<Expr<String>, String> {
+        let expr = self.expr()?;
+        match self.peek() {
+            None => Ok(expr),
+            Some(c) => Err(format!("Unexpected {c:?} in expression {:?}", self.text)),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr<String>, String> {
+        let mut expr = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => Op::Add,
+                Some('-') => Op::Sub,
+                _ => return Ok(expr),
+            };
+            self.chars.next();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr<String>, String> {
+        let mut expr = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                Some('*') => Op::Mul,
+                Some('/') => Op::Div,
+                _ => return Ok(expr),
+            };
+            self.chars.next();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr<String>, String> {
+        match self.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.chars.next();
+                let expr = self.expr()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(expr)
+                    }
+                    _ => Err(format!("Missing ')' in expression {:?}", self.text)),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse()
+                    .map(Expr::Const)
+                    .map_err(|_| format!("Invalid number {number:?}"))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let id = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                Ok(Expr::Field(id.to_owned()))
+            }
+            Some(c) => Err(format!("Unexpected {c:?} in expression {:?}", self.text)),
+            None => Err(format!("Unexpected end of expression {:?}", self.text)),
+        }
+    }
+}
+
+/// Transform that appends derived fields to each update
+pub struct DerivedFields {
+    derived: Vec<(Field<'static>, Expr<String>)>,
+    /// For each list of fields: the list with derived fields appended, and
+    /// the bound expressions for the derived fields that could be computed
+    cache: FieldListCache<(&'static [Field<'static>], Vec<Expr<usize>>)>,
+}
+
+impl DerivedFields {
+    pub fn new(configs: &[Config]) -> Result<Self, String> {
+        let mut derived = vec![];
+        for config in configs.iter() {
+            let expr = Parser::new(&config.expression).parse()?;
+            let field = Field {
+                field_type: config.field_type,
+                group: leak_str(&config.group),
+                name: leak_str(&config.name),
+                id: leak_str(&config.id),
+                scale: 1.0,
+                bias: 0.0,
+                signed: true,
+                unit: match &config.unit {
+                    Some(unit) => leak_str(unit),
+                    None => config.field_type.unit(),
+                },
+                requires: None,
+                labels: &[],
+                bit: None,
+            };
+            derived.push((field, expr));
+        }
+        Ok(Self {
+            derived,
+            cache: FieldListCache::new(),
+        })
+    }
+}
+
+impl Transform for DerivedFields {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        if self.derived.is_empty() {
+            return Some(update);
+        }
+        let derived = &self.derived;
+        let (fields, exprs) = self.cache.get(update.fields, |fields| {
+            let mut all_fields = fields.to_vec();
+            let mut exprs = vec![];
+            for (field, expr) in derived.iter() {
+                if fields.iter().any(|f| f.id == field.id) {
+                    error!("Derived field {} is already defined", field.id);
+                    continue;
+                }
+                match expr.bind(&all_fields) {
+                    Ok(expr) => {
+                        all_fields.push(field.clone());
+                        exprs.push(expr);
+                    }
+                    Err(err) => error!("Cannot compute derived field {}: {err}", field.id),
+                }
+            }
+            let all_fields = leak_fields(all_fields);
+            (all_fields, exprs)
+        });
+        let mut values = update.values.clone();
+        for expr in exprs.iter() {
+            // Derived fields may refer to earlier derived fields
+            values.push(expr.eval(&values));
+        }
+        Some(Arc::new(replace_values(&update, fields, values)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::receiver::Update;
+    use crate::test_util::{field, leak};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_parse() {
+        let expr = Parser::new("a + 2 * -(b - c) / 4").parse().unwrap();
+        let fields = [
+            field(FieldType::Power, "a"),
+            field(FieldType::Power, "b"),
+            field(FieldType::Power, "c"),
+        ];
+        let expr = expr.bind(&fields).unwrap();
+        assert_eq!(expr.eval(&[1.0, 5.0, 3.0]), 0.0);
+        assert!(Parser::new("a + ").parse().is_err());
+        assert!(Parser::new("(a").parse().is_err());
+        assert!(Parser::new("a b").parse().is_err());
+        assert!(Parser::new("d").parse().unwrap().bind(&fields).is_err());
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let expr = Parser::new("100 * a / b").parse().unwrap();
+        let fields = [field(FieldType::Power, "a"), field(FieldType::Power, "b")];
+        let expr = expr.bind(&fields).unwrap();
+        assert_eq!(expr.eval(&[1.0, 4.0]), 25.0);
+        assert_eq!(expr.eval(&[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_derived_fields() {
+        let configs: Vec<Config> = toml::from_str::<toml::Table>(
+            r#"
+            [[derived]]
+            field_type = "Power"
+            group = "Load"
+            name = "Home load"
+            id = "home_load"
+            expression = "pv_power + battery_power + grid_power"
+
+            [[derived]]
+            field_type = "Unitless"
+            group = "Load"
+            name = "Self-consumption"
+            id = "self_consumption"
+            unit = "%"
+            expression = "100 * (home_load - grid_power) / home_load"
+            "#,
+        )
+        .unwrap()["derived"]
+            .clone()
+            .try_into()
+            .unwrap();
+        let mut derived = DerivedFields::new(&configs).unwrap();
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Power, "battery_power"),
+            field(FieldType::Power, "grid_power"),
+        ]);
+        let update = Arc::new(Update::new(0, "1234", fields, vec![1000.0, -300.0, 50.0]));
+        let update = derived.apply(update).unwrap();
+        assert_eq!(update.fields.len(), 5);
+        assert_eq!(update.fields[3].id, "home_load");
+        assert_eq!(update.fields[3].unit, "W");
+        assert_eq!(update.values[3], 750.0);
+        assert_eq!(update.fields[4].unit, "%");
+        assert_approx_eq!(update.values[4], 100.0 * 700.0 / 750.0);
+    }
+}
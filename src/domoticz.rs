@@ -0,0 +1,260 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that updates devices in [Domoticz](https://www.domoticz.com)
+//! through its JSON API. Each device is either a single value, or a
+//! power/energy pair for an "Electric (Instant+Counter)" (kWh) device.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::info;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+
+use super::fields::FieldType;
+use super::health::record_write;
+use super::receiver::{Receiver, Update};
+use super::units::to_default_unit;
+
+/// Source of the value for a device
+#[derive(Debug)]
+enum Source {
+    /// A single field
+    Field(String),
+    /// Power and energy fields for a kWh device
+    Meter { power: String, energy: String },
+}
+
+#[derive(Debug)]
+struct Device {
+    idx: u32,
+    source: Source,
+}
+
+pub struct DomoticzReceiver {
+    client: Client,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    serial: Option<String>,
+    devices: Vec<Device>,
+}
+
+/// Format a value for an `svalue`, without excess digits
+fn format_value(value: f64) -> String {
+    ((value * 1000.0).round() / 1000.0).to_string()
+}
+
+impl DomoticzReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let mut devices = vec![];
+        for device in config.devices.iter() {
+            let source = match (&device.field, &device.power, &device.energy) {
+                (Some(field), None, None) => Source::Field(field.clone()),
+                (None, Some(power), Some(energy)) => Source::Meter {
+                    power: power.clone(),
+                    energy: energy.clone(),
+                },
+                _ => {
+                    return Err(format!(
+                        "Domoticz device {} must have either field or both power and energy",
+                        device.idx
+                    ));
+                }
+            };
+            devices.push(Device {
+                idx: device.idx,
+                source,
+            });
+        }
+        Ok(Self {
+            client: Client::new(),
+            url: format!("{}/json.htm", config.url.trim_end_matches('/')),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            serial: config.serial.clone(),
+            devices,
+        })
+    }
+
+    /// Compute the `idx` and `svalue` for each device that has values in
+    /// the update.
+    fn encode(&self, update: &Update<'_>) -> Vec<(u32, String)> {
+        if self
+            .serial
+            .as_ref()
+            .is_some_and(|serial| *serial != update.serial)
+        {
+            return vec![];
+        }
+        // Values in default units, or None if missing
+        let lookup = |id: &str| {
+            update
+                .fields
+                .iter()
+                .position(|field| field.id == id)
+                .map(|i| (&update.fields[i], update.values[i]))
+                .filter(|(_, value)| value.is_finite())
+                .map(|(field, value)| (field, to_default_unit(field, value)))
+        };
+        let mut result = vec![];
+        for device in self.devices.iter() {
+            let svalue = match &device.source {
+                Source::Field(id) => lookup(id).map(|(_, value)| format_value(value)),
+                Source::Meter { power, energy } => lookup(power)
+                    .zip(lookup(energy))
+                    .filter(|(_, (field, _))| field.field_type == FieldType::Energy)
+                    .map(|((_, power), (_, energy))| {
+                        // Domoticz wants W and Wh
+                        format!("{};{}", format_value(power), format_value(energy * 1000.0))
+                    }),
+            };
+            if let Some(svalue) = svalue {
+                result.push((device.idx, svalue));
+            }
+        }
+        result
+    }
+
+    async fn send(&self, idx: u32, svalue: &str) -> Result<(), String> {
+        let mut request = self.client.get(&self.url).query(&[
+            ("type", "command"),
+            ("param", "udevice"),
+            ("idx", &idx.to_string()),
+            ("nvalue", "0"),
+            ("svalue", svalue),
+        ]);
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())?;
+        let body = response.text().await.map_err(|err| err.to_string())?;
+        let body: Value = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        match body["status"].as_str() {
+            Some("OK") => Ok(()),
+            _ => Err(body["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+                .to_owned()),
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for DomoticzReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        // Domoticz does not accept timestamps, so failed updates are
+        // dropped rather than retried.
+        while let Some(update) = receiver.next().await {
+            for (idx, svalue) in self.encode(&update) {
+                let result = self.send(idx, &svalue).await;
+                if let Err(err) = &result {
+                    info!("Error updating Domoticz device {idx} ({err})");
+                }
+                record_write(result.err().map(|err| err.to_string()));
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Device index in Domoticz
+    pub idx: u32,
+    /// Field ID for a single-value device
+    pub field: Option<String>,
+    /// Power field ID for a kWh device
+    pub power: Option<String>,
+    /// Energy field ID for a kWh device
+    pub energy: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Base URL of the Domoticz server
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Only send values from the inverter with this serial number
+    pub serial: Option<String>,
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::Field;
+    use crate::test_util::{field, leak};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!("url = \"http://domoticz:8080/\"\n{toml}")).unwrap()
+    }
+
+    #[test]
+    fn test_config() {
+        let receiver = DomoticzReceiver::new(&config("devices = []")).unwrap();
+        assert_eq!(receiver.url, "http://domoticz:8080/json.htm");
+        for devices in [
+            "{ idx = 1 }",
+            "{ idx = 1, field = \"pv_power\", power = \"pv_power\" }",
+            "{ idx = 1, power = \"pv_power\" }",
+        ] {
+            let config = config(&format!("devices = [{devices}]"));
+            assert!(DomoticzReceiver::new(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let receiver = DomoticzReceiver::new(&config(
+            r#"
+            serial = "1234"
+            devices = [
+                { idx = 3, field = "battery_soc" },
+                { idx = 4, power = "pv_power", energy = "pv_production_total" },
+                { idx = 5, field = "inverter_temperature_dc" },
+                { idx = 6, field = "missing" },
+            ]
+            "#,
+        ))
+        .unwrap();
+        let fields = leak([
+            field(FieldType::StateOfCharge, "battery_soc"),
+            Field {
+                unit: "kW",
+                ..field(FieldType::Power, "pv_power")
+            },
+            field(FieldType::Energy, "pv_production_total"),
+            field(FieldType::Temperature, "inverter_temperature_dc"),
+        ]);
+        let update = Update::new(0, "1234", fields, vec![75.0, 1.5, 1234.5, f64::NAN]);
+        assert_eq!(
+            receiver.encode(&update),
+            vec![(3, "75".to_owned()), (4, "1500;1234500".to_owned())]
+        );
+        let update = Update::new(0, "5678", fields, vec![75.0, 1.5, 1234.5, 30.0]);
+        assert!(receiver.encode(&update).is_empty());
+    }
+}
@@ -0,0 +1,306 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that indexes updates into Elasticsearch or OpenSearch with the
+//! bulk API. Each update becomes a document in a daily index (named
+//! `{prefix}-YYYY.MM.DD`), and an index template is installed so that the
+//! indices get consistent mappings.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use log::warn;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::iter::zip;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+use super::rotate::utc;
+
+pub struct ElasticsearchReceiver {
+    client: Client,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    api_key: Option<String>,
+    index_prefix: String,
+    ilm_policy: Option<String>,
+    /// Whether the index template still needs to be installed
+    install_template: AtomicBool,
+    batch: Options,
+}
+
+impl ElasticsearchReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            url: config.url.trim_end_matches('/').to_owned(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            api_key: config.api_key.clone(),
+            index_prefix: config.index_prefix.clone(),
+            ilm_policy: config.ilm_policy.clone(),
+            install_template: AtomicBool::new(config.template),
+            batch: config.batch.clone(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let mut request = self.client.request(method, format!("{}/{path}", self.url));
+        if let Some(api_key) = &self.api_key {
+            request = request.header("Authorization", format!("ApiKey {api_key}"));
+        } else if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        request
+    }
+
+    /// Index template covering the daily indices
+    fn template(&self) -> Value {
+        let mut settings = Map::new();
+        if let Some(policy) = &self.ilm_policy {
+            settings.insert("index.lifecycle.name".to_owned(), json!(policy));
+        }
+        json!({
+            "index_patterns": [format!("{}-*", self.index_prefix)],
+            "template": {
+                "settings": settings,
+                "mappings": {
+                    // By default, numbers would be mapped as float (or as
+                    // long, if the first value is a whole number)
+                    "dynamic_templates": [
+                        {"floats": {"match_mapping_type": "double", "mapping": {"type": "double"}}},
+                        {"integers": {"match_mapping_type": "long", "mapping": {"type": "double"}}},
+                        {"strings": {"match_mapping_type": "string", "mapping": {"type": "keyword"}}},
+                    ],
+                    "properties": {
+                        "@timestamp": {"type": "date_nanos"},
+                        "serial": {"type": "keyword"},
+                    },
+                },
+            },
+        })
+    }
+}
+
+/// Turn an error response into an error, including the message from the
+/// server, and otherwise return the response body
+async fn check(response: reqwest::Response) -> Result<String, String> {
+    let status = response.status();
+    let body = response.text().await.map_err(|err| err.to_string())?;
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(format!("{status}: {}", body.trim_end()))
+    }
+}
+
+/// Find the first failed item in a bulk response, if any
+fn bulk_error(response: &Value) -> Option<String> {
+    if response["errors"] != Value::Bool(true) {
+        return None;
+    }
+    let items = response["items"].as_array()?;
+    let error = items
+        .iter()
+        .filter_map(|item| item.as_object()?.values().next())
+        .map(|result| &result["error"])
+        .find(|error| !error.is_null());
+    match error {
+        Some(error) => Some(error.to_string()),
+        None => Some("bulk request failed".to_owned()),
+    }
+}
+
+#[async_trait]
+impl BatchWriter for ElasticsearchReceiver {
+    /// Bulk action and document
+    type Item = (Value, Value);
+    type Error = String;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<(Value, Value)> {
+        let time = utc(update.timestamp);
+        let index = format!("{}-{}", self.index_prefix, time.format("%Y.%m.%d"));
+        let mut doc = Map::new();
+        doc.insert(
+            "@timestamp".to_owned(),
+            json!(time.format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string()),
+        );
+        doc.insert("serial".to_owned(), json!(update.serial));
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            if value.is_finite() {
+                doc.insert(field.id.to_owned(), json!(value));
+            }
+        }
+        for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            doc.insert(field.id.to_owned(), json!(text));
+        }
+        vec![(json!({"create": {"_index": index}}), Value::Object(doc))]
+    }
+
+    async fn write(&self, items: Vec<(Value, Value)>) -> Result<(), String> {
+        if self.install_template.load(Ordering::Relaxed) {
+            let response = self
+                .request(
+                    reqwest::Method::PUT,
+                    &format!("_index_template/{}", self.index_prefix),
+                )
+                .header("Content-Type", "application/json")
+                .body(self.template().to_string())
+                .send()
+                .await
+                .map_err(|err| err.to_string())?;
+            check(response).await?;
+            self.install_template.store(false, Ordering::Relaxed);
+        }
+        let mut body = String::new();
+        for (action, doc) in items.iter() {
+            body.push_str(&action.to_string());
+            body.push('\n');
+            body.push_str(&doc.to_string());
+            body.push('\n');
+        }
+        let response = self
+            .request(reqwest::Method::POST, "_bulk")
+            .header("Content-Type", "application/x-ndjson")
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let body = check(response).await?;
+        let response: Value = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        // Documents that failed are not retried, since resending the batch
+        // would duplicate those that succeeded.
+        if let Some(error) = bulk_error(&response) {
+            warn!("Failed to index some documents ({error})");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for ElasticsearchReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Encoded API key (takes precedence over username and password)
+    pub api_key: Option<String>,
+    /// Indices are named `{index_prefix}-YYYY.MM.DD`
+    #[serde(default = "default_index_prefix")]
+    pub index_prefix: String,
+    /// Install an index template for the indices
+    #[serde(default = "default_template")]
+    pub template: bool,
+    /// Index lifecycle policy to set in the index template
+    pub ilm_policy: Option<String>,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_url() -> String {
+    "http://localhost:9200".to_owned()
+}
+
+fn default_index_prefix() -> String {
+    "sunsniff".to_owned()
+}
+
+fn default_template() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    fn receiver() -> ElasticsearchReceiver {
+        ElasticsearchReceiver::new(
+            &toml::from_str("url = \"http://es:9200/\"\nilm_policy = \"solar\"").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1_700_000_000_123_456_789, "1234", fields, vec![1500.0]);
+        assert_eq!(
+            receiver().encode(&update),
+            vec![(
+                json!({"create": {"_index": "sunsniff-2023.11.14"}}),
+                json!({
+                    "@timestamp": "2023-11-14T22:13:20.123456789Z",
+                    "serial": "1234",
+                    "pv_power": 1500.0,
+                })
+            )]
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let receiver = receiver();
+        assert_eq!(receiver.url, "http://es:9200");
+        let template = receiver.template();
+        assert_eq!(template["index_patterns"], json!(["sunsniff-*"]));
+        assert_eq!(
+            template["template"]["settings"]["index.lifecycle.name"],
+            "solar"
+        );
+    }
+
+    #[test]
+    fn test_bulk_error() {
+        assert_eq!(bulk_error(&json!({"errors": false, "items": []})), None);
+        let response = json!({
+            "errors": true,
+            "items": [
+                {"create": {"status": 201}},
+                {"create": {"status": 400, "error": {"type": "mapper_parsing_exception"}}},
+            ],
+        });
+        assert_eq!(
+            bulk_error(&response).unwrap(),
+            "{\"type\":\"mapper_parsing_exception\"}"
+        );
+    }
+}
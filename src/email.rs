@@ -0,0 +1,329 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends email alerts over SMTP. An email is sent when an alert
+//! fires for an inverter and when it clears (see [super::alerts]).
+
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::Utc;
+use futures::channel::mpsc::UnboundedReceiver;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+pub use super::alerts::AlertConfig;
+use super::alerts::{default_min_interval, run_alerts, Alerts, Notification, Notifier};
+use super::receiver::{Receiver, Update};
+use super::tls::TlsConfig;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Format a header value, using RFC 2047 encoding if it is not ASCII
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_owned()
+    } else {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(value);
+        format!("=?utf-8?b?{encoded}?=")
+    }
+}
+
+/// Extract the address from a mailbox such as `Name <user@example.com>`
+fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// Encode the body for the DATA command, with CRLF line endings and
+/// dot-stuffing
+fn encode_body(body: &str) -> String {
+    let mut out = String::new();
+    for line in body.lines() {
+        if line.starts_with('.') {
+            out.push('.');
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Read a (possibly multi-line) SMTP reply, returning the code and text
+async fn read_reply<S: AsyncBufRead + Unpin>(stream: &mut S) -> std::io::Result<(u16, String)> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid SMTP reply {line:?}"),
+                )
+            })?;
+        text.push_str(line.get(4..).unwrap_or_default().trim_end());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, text));
+        }
+        text.push('\n');
+    }
+}
+
+/// Send a command (if any) and check that the reply has the expected code
+async fn command<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    line: Option<&str>,
+    expected: u16,
+) -> std::io::Result<String> {
+    if let Some(line) = line {
+        stream.write_all(line.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+    }
+    let (code, text) = read_reply(stream).await?;
+    if code != expected {
+        let command = line.and_then(|line| line.split(' ').next()).unwrap_or("");
+        return Err(Error::other(format!(
+            "SMTP error after {command:?}: {code} {text}"
+        )));
+    }
+    Ok(text)
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+struct Smtp {
+    host: String,
+    port: u16,
+    security: Security,
+    tls: Option<TlsConnector>,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+}
+
+impl Smtp {
+    async fn start_tls(&self, tcp: TcpStream) -> std::io::Result<Box<dyn Stream>> {
+        let name = ServerName::try_from(self.host.clone())
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        Ok(Box::new(
+            self.tls.as_ref().unwrap().connect(name, tcp).await?,
+        ))
+    }
+
+    async fn send(&self, email: &Notification) -> std::io::Result<()> {
+        let ehlo = "EHLO sunsniff";
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let mut stream = match self.security {
+            Security::Tls => BufReader::new(self.start_tls(tcp).await?),
+            Security::Starttls => {
+                let mut plain = BufReader::new(tcp);
+                command(&mut plain, None, 220).await?;
+                command(&mut plain, Some(ehlo), 250).await?;
+                command(&mut plain, Some("STARTTLS"), 220).await?;
+                let mut stream = BufReader::new(self.start_tls(plain.into_inner()).await?);
+                command(&mut stream, Some(ehlo), 250).await?;
+                stream
+            }
+            Security::None => BufReader::new(Box::new(tcp) as Box<dyn Stream>),
+        };
+        if self.security != Security::Starttls {
+            command(&mut stream, None, 220).await?;
+            command(&mut stream, Some(ehlo), 250).await?;
+        }
+        if let Some(username) = &self.username {
+            let password = self.password.as_deref().unwrap_or_default();
+            let credentials = base64::engine::general_purpose::STANDARD
+                .encode(format!("\0{username}\0{password}"));
+            command(&mut stream, Some(&format!("AUTH PLAIN {credentials}")), 235).await?;
+        }
+        let mail_from = format!("MAIL FROM:<{}>", address(&self.from));
+        command(&mut stream, Some(&mail_from), 250).await?;
+        for to in self.to.iter() {
+            let rcpt_to = format!("RCPT TO:<{}>", address(to));
+            command(&mut stream, Some(&rcpt_to), 250).await?;
+        }
+        command(&mut stream, Some("DATA"), 354).await?;
+        let message = self.message(email);
+        stream.write_all(message.as_bytes()).await?;
+        command(&mut stream, Some("."), 250).await?;
+        // The email has been accepted, so errors from here are irrelevant
+        let _ = command(&mut stream, Some("QUIT"), 221).await;
+        Ok(())
+    }
+
+    /// Format the message (headers and body) for the DATA command
+    fn message(&self, email: &Notification) -> String {
+        let headers = [
+            ("From", self.from.clone()),
+            ("To", self.to.join(", ")),
+            (
+                "Subject",
+                encode_header(&format!("[sunsniff] {}", email.subject)),
+            ),
+            ("Date", Utc::now().to_rfc2822()),
+            ("MIME-Version", "1.0".to_owned()),
+            ("Content-Type", "text/plain; charset=utf-8".to_owned()),
+            ("Content-Transfer-Encoding", "8bit".to_owned()),
+        ];
+        let mut message = String::new();
+        for (name, value) in headers.iter() {
+            write!(message, "{name}: {value}\r\n").unwrap();
+        }
+        message.push_str("\r\n");
+        message.push_str(&encode_body(&email.body));
+        message
+    }
+}
+
+pub struct EmailReceiver {
+    smtp: Smtp,
+    alerts: Alerts,
+}
+
+impl EmailReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let security = config.security;
+        let tls = match security {
+            Security::None => None,
+            _ => {
+                let tls = config
+                    .tls
+                    .as_ref()
+                    .unwrap_or(&TlsConfig::default())
+                    .client_config()?;
+                Some(TlsConnector::from(Arc::new(tls)))
+            }
+        };
+        let default_port = match security {
+            Security::None => 25,
+            Security::Starttls => 587,
+            Security::Tls => 465,
+        };
+        if config.to.is_empty() {
+            return Err("Email backend needs at least one recipient".to_owned());
+        }
+        Ok(Self {
+            smtp: Smtp {
+                host: config.host.clone(),
+                port: config.port.unwrap_or(default_port),
+                security,
+                tls,
+                username: config.username.clone(),
+                password: config.password.clone(),
+                from: config.from.clone(),
+                to: config.to.clone(),
+            },
+            alerts: Alerts::new(&config.alerts, config.min_interval)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Smtp {
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        match tokio::time::timeout(TIMEOUT, self.send(notification)).await {
+            Ok(result) => result.map_err(|err| format!("email: {err}")),
+            Err(_) => Err("timed out sending email".to_owned()),
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for EmailReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_alerts(&mut self.smtp, &mut self.alerts, receiver).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// SMTP server
+    pub host: String,
+    /// SMTP port (defaults to 25, 587 or 465 depending on `security`)
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: Security,
+    pub tls: Option<TlsConfig>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Minimum number of seconds between emails for the same alert and
+    /// inverter
+    #[serde(default = "default_min_interval")]
+    pub min_interval: u64,
+    pub alerts: Vec<AlertConfig>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(
+            address("sunsniff <inverter@example.com>"),
+            "inverter@example.com"
+        );
+        assert_eq!(address(" me@example.com "), "me@example.com");
+        assert_eq!(encode_header("Low battery"), "Low battery");
+        assert_eq!(encode_header("25 °C"), "=?utf-8?b?MjUgwrBD?=");
+        assert_eq!(encode_body("a\n.b\n..c"), "a\r\n..b\r\n...c\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_reply() {
+        let mut input: &[u8] =
+            b"250-smtp.example.com\r\n250-PIPELINING\r\n250 STARTTLS\r\n354 Go\r\n";
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            (250, "smtp.example.com\nPIPELINING\nSTARTTLS".to_owned())
+        );
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            (354, "Go".to_owned())
+        );
+        assert!(read_reply(&mut input).await.is_err());
+    }
+}
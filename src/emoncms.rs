@@ -0,0 +1,191 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that posts values to the `input/post` API of
+//! [EmonCMS](https://emoncms.org).
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::iter::zip;
+use std::sync::Arc;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+
+/// The values of one update
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Input {
+    node: String,
+    /// Seconds since the UNIX epoch
+    time: i64,
+    /// JSON object mapping input names to values
+    values: String,
+}
+
+pub struct EmoncmsReceiver {
+    client: Client,
+    url: String,
+    api_key: String,
+    node: String,
+    batch: Options,
+}
+
+impl EmoncmsReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            url: format!("{}/input/post", config.url.trim_end_matches('/')),
+            api_key: config.api_key.clone(),
+            node: config.node.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+
+    async fn post(&self, input: &Input) -> Result<(), String> {
+        let time = input.time.to_string();
+        let response = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .form(&[
+                ("node", input.node.as_str()),
+                ("time", &time),
+                ("fulljson", &input.values),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())?;
+        let body = response.text().await.map_err(|err| err.to_string())?;
+        // Errors are reported with a 200 status and a JSON body
+        match serde_json::from_str::<Value>(&body) {
+            Ok(value) if value["success"] == Value::Bool(false) => {
+                Err(value["message"].as_str().unwrap_or(&body).to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for EmoncmsReceiver {
+    type Item = Input;
+    type Error = String;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Input> {
+        // EmonCMS inputs are numeric, so text fields are skipped
+        let values: Map<String, Value> = zip(update.fields.iter(), update.values.iter())
+            .filter_map(|(field, value)| {
+                serde_json::Number::from_f64(*value)
+                    .map(|number| (field.id.to_owned(), Value::Number(number)))
+            })
+            .collect();
+        if values.is_empty() {
+            return vec![];
+        }
+        vec![Input {
+            node: self.node.replace("{serial}", &update.serial),
+            time: update.timestamp.div_euclid(1_000_000_000),
+            values: Value::Object(values).to_string(),
+        }]
+    }
+
+    async fn write(&self, inputs: Vec<Input>) -> Result<(), String> {
+        // The input/post API takes one update at a time
+        for input in inputs.iter() {
+            self.post(input).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for EmoncmsReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Base URL of the EmonCMS instance
+    pub url: String,
+    /// Read & write API key
+    pub api_key: String,
+    /// Node name, with an optional `{serial}` placeholder
+    #[serde(default = "default_node")]
+    pub node: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_node() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let receiver = EmoncmsReceiver::new(&Config {
+            url: "http://emonpi/emoncms/".to_owned(),
+            api_key: "key".to_owned(),
+            node: "solar_{serial}".to_owned(),
+            batch: Options::default(),
+        });
+        assert_eq!(receiver.url, "http://emonpi/emoncms/input/post");
+        let field = |id| Field {
+            field_type: FieldType::Power,
+            group: "Test",
+            name: id,
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        };
+        let fields: &'static [Field<'static>] =
+            Box::leak(Box::new([field("pv_power"), field("load_power")]));
+        let update = Update::new(
+            1_700_000_000_500_000_000,
+            "1234",
+            fields,
+            vec![1500.0, f64::NAN],
+        );
+        assert_eq!(
+            receiver.encode(&update),
+            vec![Input {
+                node: "solar_1234".to_owned(),
+                time: 1_700_000_000,
+                values: r#"{"pv_power":1500.0}"#.to_owned(),
+            }]
+        );
+        let update = Update::new(0, "1234", fields, vec![f64::NAN, f64::NAN]);
+        assert!(receiver.encode(&update).is_empty());
+    }
+}
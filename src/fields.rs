@@ -14,8 +14,15 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
 /// Type of quantity stored in a field
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
 pub enum FieldType {
     Charge,
     Current,
@@ -27,10 +34,153 @@ pub enum FieldType {
     Time,
     Voltage,
     Unitless,
+    /// Code with an associated label (see [Field::labels])
+    Enum,
+    /// Single bit from a register of flags (see [Field::bit]), either 0 or 1
+    Flags,
+    /// ASCII text spanning several words (see [Field::text_from_u16s])
+    Text,
+    /// Version number with one part per hex digit, published as text (see
+    /// [Field::text_from_u16s])
+    Version,
+}
+
+impl FieldType {
+    /// Scale to use if none is specified. This must match build.rs.
+    pub fn default_scale(&self) -> Option<f64> {
+        match self {
+            FieldType::Charge
+            | FieldType::Power
+            | FieldType::StateOfCharge
+            | FieldType::Unitless
+            | FieldType::Enum
+            | FieldType::Flags
+            | FieldType::Text
+            | FieldType::Version => Some(1.0),
+            FieldType::Energy | FieldType::Temperature => Some(0.1),
+            FieldType::Frequency => Some(0.01),
+            FieldType::Current | FieldType::Voltage => None,
+            FieldType::Time => Some(60.0),
+        }
+    }
+
+    /// Bias to use if none is specified. This must match build.rs.
+    pub fn default_bias(&self) -> f64 {
+        match self {
+            FieldType::Temperature => -100.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Whether the raw value is signed if not specified. This must match build.rs.
+    pub fn default_signed(&self) -> bool {
+        matches!(self, FieldType::Current | FieldType::Power)
+    }
+
+    /// Unit of measurement. This must match build.rs.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            FieldType::Charge => "Ah",
+            FieldType::Current => "A",
+            FieldType::Energy => "kWh",
+            FieldType::Frequency => "Hz",
+            FieldType::Power => "W",
+            FieldType::StateOfCharge => "%",
+            FieldType::Temperature => "°C",
+            FieldType::Time => "s",
+            FieldType::Voltage => "V",
+            FieldType::Unitless
+            | FieldType::Enum
+            | FieldType::Flags
+            | FieldType::Text
+            | FieldType::Version => "",
+        }
+    }
+}
+
+/// Hardware that must be present for a field to be reported
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+pub enum Requirement {
+    /// A second PV string (MPPT)
+    Pv2,
+    /// A third PV string (MPPT)
+    Pv3,
+    /// A split-phase (L1/L2) inverter
+    SplitPhase,
+    /// A three-phase (L1/L2/L3) inverter
+    ThreePhase,
+    /// A generator connected to the GEN port
+    Generator,
+    /// A smart load connected to the GEN port
+    SmartLoad,
+}
+
+/// Wiring of the AC side of the inverter
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    #[default]
+    SinglePhase,
+    SplitPhase,
+    /// Three phases, which use a different register map
+    ThreePhase,
+}
+
+/// Use of the GEN port of the inverter
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GenPort {
+    #[default]
+    Generator,
+    SmartLoad,
+    None,
+}
+
+/// Structure corresponding to the `[inverter]` section of the configuration
+/// file. It describes optional hardware, and is used to select which fields
+/// to report.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InverterConfig {
+    #[serde(default = "default_pv_strings")]
+    pub pv_strings: u8,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub gen_port: GenPort,
+}
+
+fn default_pv_strings() -> u8 {
+    2
+}
+
+impl Default for InverterConfig {
+    fn default() -> Self {
+        Self {
+            pv_strings: default_pv_strings(),
+            layout: Layout::default(),
+            gen_port: GenPort::default(),
+        }
+    }
+}
+
+impl InverterConfig {
+    /// Whether a field should be reported for this inverter
+    pub fn includes(&self, field: &Field<'_>) -> bool {
+        match field.requires {
+            None => true,
+            Some(Requirement::Pv2) => self.pv_strings >= 2,
+            Some(Requirement::Pv3) => self.pv_strings >= 3,
+            Some(Requirement::SplitPhase) => self.layout == Layout::SplitPhase,
+            Some(Requirement::ThreePhase) => self.layout == Layout::ThreePhase,
+            Some(Requirement::Generator) => self.gen_port == GenPort::Generator,
+            Some(Requirement::SmartLoad) => self.gen_port == GenPort::SmartLoad,
+        }
+    }
 }
 
 /// Static description of a field in the data
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Field<'a> {
     pub field_type: FieldType,
     pub group: &'a str,
@@ -40,10 +190,23 @@ pub struct Field<'a> {
     pub scale: f64,
     /// Amount to add to the value, after scaling
     pub bias: f64,
+    /// Whether the raw integer is two's complement (e.g. power that can flow
+    /// in either direction)
+    pub signed: bool,
     pub unit: &'a str,
+    /// Hardware needed for the field to be meaningful
+    pub requires: Option<Requirement>,
+    /// Human-readable descriptions of values (for [FieldType::Enum] and
+    /// [FieldType::Flags])
+    pub labels: &'a [(i64, &'a str)],
+    /// Bit to extract from the raw value (only for [FieldType::Flags])
+    pub bit: Option<u8>,
 }
 
 impl<'a> Field<'a> {
+    /// Convert raw words to a value. Values wider than 16 bits (such as
+    /// energy totals) are split across several words, which are given least
+    /// significant first.
     pub fn from_u16s(&self, parts: impl IntoIterator<Item = u16>) -> f64 {
         let mut raw: i64 = 0;
         let mut shift: u32 = 0;
@@ -51,10 +214,14 @@ impl<'a> Field<'a> {
             raw += (part as i64) << shift;
             shift += 16;
         }
-        let wrap: i64 = 1i64 << (shift - 1);
-        // Convert to signed (TODO: most registers are actually unsigned)
-        if raw >= wrap {
-            raw -= 2 * wrap;
+        if self.signed {
+            let wrap: i64 = 1i64 << (shift - 1);
+            if raw >= wrap {
+                raw -= 2 * wrap;
+            }
+        }
+        if let Some(bit) = self.bit {
+            raw = (raw >> bit) & 1;
         }
         // Special handling for time fields: HH:MM is encoded as HH*100+MM.
         if self.field_type == FieldType::Time {
@@ -64,6 +231,514 @@ impl<'a> Field<'a> {
         }
         (raw as f64) * self.scale + self.bias
     }
+
+    /// Convert raw words to text, for [FieldType::Text] and
+    /// [FieldType::Version] fields. For text, each word holds two ASCII
+    /// characters (most significant first); the text is terminated by a NUL,
+    /// and surrounding whitespace is removed. For a version, each hex digit
+    /// is one part, so 0x3405 is "3.4.0.5".
+    pub fn text_from_u16s(&self, parts: impl IntoIterator<Item = u16>) -> String {
+        if self.field_type == FieldType::Version {
+            return parts
+                .into_iter()
+                .flat_map(|part| format!("{part:04x}").chars().collect::<Vec<_>>())
+                .map(String::from)
+                .collect::<Vec<_>>()
+                .join(".");
+        }
+        let bytes: Vec<u8> = parts
+            .into_iter()
+            .flat_map(|part| part.to_be_bytes())
+            .take_while(|&b| b != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).trim().to_owned()
+    }
+
+    /// Look up the label for a value, if there is one
+    pub fn label(&self, value: f64) -> Option<&'a str> {
+        self.labels
+            .iter()
+            .find(|(v, _)| *v as f64 == value)
+            .map(|(_, label)| *label)
+    }
+}
+
+/// User-defined field, corresponding to an entry in the `[[fields]]` section
+/// of the configuration file. It is constructed from the config file by serde.
+///
+/// The addressing is frontend-specific: `offset` and `offset2` are used by
+/// the pcap frontend and `reg` and `reg2` by the modbus frontend. Frontends
+/// ignore fields that don't have an address for them. Text fields use
+/// `length` consecutive words instead of `offset2` or `reg2`.
+///
+/// Entries in a field map file (see [load_field_map]) are also converted to
+/// this structure.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraField {
+    field_type: FieldType,
+    group: String,
+    name: String,
+    id: String,
+    scale: Option<f64>,
+    bias: Option<f64>,
+    signed: Option<bool>,
+    requires: Option<Requirement>,
+    labels: Option<String>,
+    bit: Option<u8>,
+    pub offset: Option<usize>,
+    pub offset2: Option<usize>,
+    pub reg: Option<u16>,
+    pub reg2: Option<u16>,
+    pub length: Option<u16>,
+    /// Whether the value is computed by the modbus frontend rather than
+    /// read from a register. This can only be set in a field map file.
+    #[serde(skip)]
+    pub computed: bool,
+}
+
+/// Strings leaked by [leak_str]
+static STRINGS: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+/// Leak a string to give it a static lifetime. This is used for fields
+/// loaded from the configuration, which may be loaded again when it is
+/// reloaded, so each distinct string is only leaked once.
+pub(crate) fn leak_str(s: &str) -> &'static str {
+    let mut strings = STRINGS.lock().unwrap();
+    if let Some(leaked) = strings.get(s) {
+        return leaked;
+    }
+    let leaked: &'static str = Box::leak(s.to_owned().into_boxed_str());
+    strings.insert(leaked);
+    leaked
+}
+
+/// Contents of a [Field], to find field lists that are identical
+#[derive(PartialEq, Eq, Hash)]
+struct FieldKey {
+    field_type: FieldType,
+    group: &'static str,
+    name: &'static str,
+    id: &'static str,
+    /// Bits of the [f64]s
+    scale: u64,
+    bias: u64,
+    signed: bool,
+    unit: &'static str,
+    requires: Option<Requirement>,
+    labels: &'static [(i64, &'static str)],
+    bit: Option<u8>,
+}
+
+impl FieldKey {
+    fn new(field: &Field<'static>) -> Self {
+        Self {
+            field_type: field.field_type,
+            group: field.group,
+            name: field.name,
+            id: field.id,
+            scale: field.scale.to_bits(),
+            bias: field.bias.to_bits(),
+            signed: field.signed,
+            unit: field.unit,
+            requires: field.requires,
+            labels: field.labels,
+            bit: field.bit,
+        }
+    }
+}
+
+/// Field lists leaked by [leak_fields], keyed by their contents
+static FIELD_LISTS: LazyLock<Mutex<HashMap<Vec<FieldKey>, &'static [Field<'static>]>>> =
+    LazyLock::new(Default::default);
+
+/// Leak a list of fields to give it a static lifetime. Transforms build new
+/// lists when they are created, which happens again each time the
+/// configuration is reloaded, so each distinct list is only leaked once.
+pub(crate) fn leak_fields(fields: Vec<Field<'static>>) -> &'static [Field<'static>] {
+    let key = fields.iter().map(FieldKey::new).collect();
+    FIELD_LISTS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Box::leak(fields.into_boxed_slice()))
+}
+
+/// Parse labels given as value=label pairs separated by semicolons. This
+/// must match build.rs.
+fn parse_labels(id: &str, labels: &str) -> Result<&'static [(i64, &'static str)], String> {
+    let mut parsed = vec![];
+    for item in labels.split(';') {
+        let (value, label) = item
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid label {item:?} for {id}"))?;
+        let value = value
+            .parse::<i64>()
+            .map_err(|err| format!("Invalid label {item:?} for {id}: {err}"))?;
+        parsed.push((value, leak_str(label)));
+    }
+    Ok(Box::leak(parsed.into_boxed_slice()))
+}
+
+impl ExtraField {
+    /// Compute the addresses of the words holding the value, given the
+    /// address of the first word, the address of the second word (for
+    /// numeric fields) and the step between consecutive words (for text
+    /// fields).
+    pub fn addresses<T>(&self, first: Option<T>, second: Option<T>, step: T) -> Option<Vec<T>>
+    where
+        T: Copy + std::ops::Add<Output = T> + std::ops::Mul<Output = T> + From<u16>,
+    {
+        first.map(|first| match self.length {
+            Some(length) => (0..length).map(|i| first + step * T::from(i)).collect(),
+            None => std::iter::once(first).chain(second).collect(),
+        })
+    }
+
+    /// Check that the field can be decoded: text fields must have at least
+    /// one word and must not run past the last address, versions must be a
+    /// single word, numeric fields at most two, and a bit must be within a
+    /// word.
+    fn validate(&self) -> Result<(), String> {
+        match (self.field_type, self.length) {
+            (FieldType::Text, Some(0)) => {
+                return Err(format!(
+                    "Field {} must have a length of at least 1",
+                    self.id
+                ));
+            }
+            (FieldType::Text, _) | (_, None) => {}
+            (_, Some(_)) => {
+                return Err(format!(
+                    "Field {} cannot have a length (only text fields can), use offset2 or reg2 \
+                     for a two-word value",
+                    self.id
+                ));
+            }
+        }
+        if let Some(length) = self.length {
+            let last = length.saturating_sub(1);
+            let reg_overflow = self.reg.is_some_and(|reg| reg.checked_add(last).is_none());
+            let offset_overflow = self
+                .offset
+                .is_some_and(|offset| offset.checked_add(2 * usize::from(last)).is_none());
+            if reg_overflow || offset_overflow {
+                return Err(format!(
+                    "Field {} runs past the last address (length {length})",
+                    self.id
+                ));
+            }
+        }
+        if self.field_type == FieldType::Version && (self.reg2.is_some() || self.offset2.is_some())
+        {
+            return Err(format!(
+                "Field {} is a version, so cannot have offset2 or reg2",
+                self.id
+            ));
+        }
+        if let Some(bit) = self.bit {
+            if bit >= 16 {
+                return Err(format!(
+                    "Bit {bit} of field {} must be less than 16",
+                    self.id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert to a [Field], applying defaults for the field type.
+    pub fn to_field(&self) -> Result<Field<'static>, String> {
+        self.validate()?;
+        let scale = self
+            .scale
+            .or(self.field_type.default_scale())
+            .ok_or_else(|| format!("Field {} must specify a scale", self.id))?;
+        Ok(Field {
+            field_type: self.field_type,
+            group: leak_str(&self.group),
+            name: leak_str(&self.name),
+            id: leak_str(&self.id),
+            scale,
+            bias: self.bias.unwrap_or(self.field_type.default_bias()),
+            signed: self.signed.unwrap_or(self.field_type.default_signed()),
+            unit: self.field_type.unit(),
+            requires: self.requires,
+            labels: match &self.labels {
+                Some(labels) => parse_labels(&self.id, labels)?,
+                None => &[],
+            },
+            bit: self.bit,
+        })
+    }
+}
+
+/// Row of a field map file. The columns are the same as for `fields.csv`
+/// (see build.rs), with optional `bias` and `length` columns.
+#[derive(Deserialize)]
+struct MapRecord {
+    field_type: FieldType,
+    group: String,
+    name: String,
+    id: String,
+    scale: Option<f64>,
+    bias: Option<f64>,
+    offset: Option<usize>,
+    offset2: Option<usize>,
+    /// A register of -1 indicates a value computed by the modbus frontend
+    reg: Option<i32>,
+    reg2: Option<u16>,
+    signed: Option<bool>,
+    requires: Option<Requirement>,
+    labels: Option<String>,
+    bit: Option<u8>,
+    length: Option<u16>,
+}
+
+impl TryFrom<MapRecord> for ExtraField {
+    type Error = String;
+
+    fn try_from(record: MapRecord) -> Result<Self, String> {
+        let reg = record
+            .reg
+            .filter(|&reg| reg >= 0)
+            .map(u16::try_from)
+            .transpose()
+            .map_err(|_| format!("Register for field {} is out of range", record.id))?;
+        Ok(ExtraField {
+            field_type: record.field_type,
+            group: record.group,
+            name: record.name,
+            id: record.id,
+            scale: record.scale,
+            bias: record.bias,
+            signed: record.signed,
+            requires: record.requires,
+            labels: record.labels,
+            bit: record.bit,
+            offset: record.offset,
+            offset2: record.offset2,
+            reg,
+            reg2: record.reg2,
+            length: record.length,
+            computed: record.reg == Some(-1),
+        })
+    }
+}
+
+/// Parse a field map in CSV format (see [load_field_map]).
+fn parse_field_map(reader: impl Read) -> Result<Vec<ExtraField>, String> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let mut fields = vec![];
+    for result in reader.deserialize() {
+        let record: MapRecord = result.map_err(|err| err.to_string())?;
+        fields.push(record.try_into()?);
+    }
+    Ok(fields)
+}
+
+/// Parse a field map in YAML format (see [load_field_map]).
+fn parse_yaml_field_map(reader: impl Read) -> Result<Vec<ExtraField>, String> {
+    let records: Vec<MapRecord> = serde_yaml::from_reader(reader).map_err(|err| err.to_string())?;
+    records.into_iter().map(ExtraField::try_from).collect()
+}
+
+/// Load a field map file, which replaces the built-in table of fields. It
+/// is a CSV file with the same columns as `fields.csv`, or (if the name ends
+/// in `.yaml` or `.yml`) a YAML list of entries with the same keys.
+pub fn load_field_map(path: &Path) -> Result<Vec<ExtraField>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format!("Could not open field map {}: {err}", path.display()))?;
+    let yaml = path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml");
+    let fields = if yaml {
+        parse_yaml_field_map(file)
+    } else {
+        parse_field_map(file)
+    };
+    fields.map_err(|err| format!("Error in field map {}: {err}", path.display()))
+}
+
+/// Changes to a field, corresponding to an entry in the `[field_overrides]`
+/// section of the configuration file.
+#[derive(Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FieldOverride {
+    scale: Option<f64>,
+    bias: Option<f64>,
+    unit: Option<String>,
+    name: Option<String>,
+    #[serde(default)]
+    disabled: bool,
+}
+
+impl FieldOverride {
+    /// Apply the override to a field, returning `None` if it is disabled
+    fn apply(&self, field: &Field<'static>) -> Option<Field<'static>> {
+        if self.disabled {
+            return None;
+        }
+        let mut field = field.clone();
+        if let Some(scale) = self.scale {
+            field.scale = scale;
+        }
+        if let Some(bias) = self.bias {
+            field.bias = bias;
+        }
+        if let Some(unit) = &self.unit {
+            field.unit = leak_str(unit);
+        }
+        if let Some(name) = &self.name {
+            field.name = leak_str(name);
+        }
+        Some(field)
+    }
+}
+
+fn default_packet_header() -> String {
+    "a5".to_owned()
+}
+
+/// Structure corresponding to each `[[packet_layouts]]` section of the
+/// configuration file. It describes a layout of the packets sent by the
+/// dongle, in addition to the built-in ones, which has the same fields as the
+/// built-in layout but at different offsets.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacketLayoutConfig {
+    /// Name used in log messages
+    pub name: String,
+    /// Length of the packet (TCP payload)
+    pub length: usize,
+    /// Expected first bytes of the packet, in hex
+    #[serde(default = "default_packet_header")]
+    pub header: String,
+    /// Offset of the 10-byte inverter serial number
+    pub serial_offset: usize,
+    /// Offset of the 6-byte timestamp
+    pub datetime_offset: usize,
+    /// Number of bytes to add to the offset of each field
+    #[serde(default)]
+    pub shift: isize,
+}
+
+/// Parts of the configuration file that determine which fields are reported
+/// and how they are decoded.
+#[derive(Clone, Deserialize, Default)]
+pub struct FieldConfig {
+    /// CSV or YAML file to load in place of the built-in fields
+    pub field_map: Option<PathBuf>,
+    #[serde(default)]
+    pub fields: Vec<ExtraField>,
+    #[serde(default)]
+    pub inverter: InverterConfig,
+    #[serde(default)]
+    pub field_overrides: HashMap<String, FieldOverride>,
+    /// Packet layouts to recognise in addition to the built-in ones
+    #[serde(default)]
+    pub packet_layouts: Vec<PacketLayoutConfig>,
+}
+
+impl FieldConfig {
+    /// Load the field map, if one is configured. This is done once by each
+    /// frontend, and the result passed to [merge_fields].
+    pub fn load_field_map(&self) -> Result<Option<Vec<ExtraField>>, String> {
+        self.field_map.as_deref().map(load_field_map).transpose()
+    }
+}
+
+/// Fields used by a frontend, together with their frontend-specific
+/// addresses (e.g. offsets or registers). Text fields are kept separately
+/// from numeric fields.
+#[derive(Clone)]
+pub struct FieldSet<A> {
+    pub fields: &'static [Field<'static>],
+    pub addresses: Vec<A>,
+    pub text_fields: &'static [Field<'static>],
+    pub text_addresses: Vec<A>,
+}
+
+/// Append user-defined fields to the built-in fields that apply to the
+/// inverter, and apply overrides. The `address` function extracts the
+/// frontend-specific address (offsets or registers) from a user-defined
+/// field, returning `None` if the field does not apply to the frontend.
+///
+/// If a field map is given (see [FieldConfig::load_field_map]), it is used
+/// instead of the built-in fields.
+pub fn merge_fields<A: Clone>(
+    fields: &'static [Field<'static>],
+    addresses: &[A],
+    config: &FieldConfig,
+    field_map: Option<&[ExtraField]>,
+    address: impl Fn(&ExtraField) -> Option<A>,
+) -> Result<FieldSet<A>, String> {
+    let mut all_fields = vec![];
+    let mut all_addresses = vec![];
+    match field_map {
+        Some(map_fields) => {
+            for map_field in map_fields.iter() {
+                let field = map_field.to_field()?;
+                if let Some(addr) = address(map_field) {
+                    if all_fields.iter().any(|f: &Field| f.id == field.id) {
+                        return Err(format!("Field {} is defined twice", field.id));
+                    }
+                    if config.inverter.includes(&field) {
+                        all_fields.push(field);
+                        all_addresses.push(addr);
+                    }
+                }
+            }
+        }
+        None => {
+            for (field, addr) in fields.iter().zip(addresses.iter()) {
+                if config.inverter.includes(field) {
+                    all_fields.push(field.clone());
+                    all_addresses.push(addr.clone());
+                }
+            }
+        }
+    }
+    for extra_field in config.fields.iter() {
+        // Validate first, so that the addresses can be computed safely
+        let field = extra_field.to_field()?;
+        if let Some(addr) = address(extra_field) {
+            if all_fields.iter().any(|f| f.id == extra_field.id) {
+                return Err(format!("Field {} is already defined", extra_field.id));
+            }
+            all_fields.push(field);
+            all_addresses.push(addr);
+        }
+    }
+    for id in config.field_overrides.keys() {
+        if !all_fields.iter().any(|f| f.id == id) {
+            warn!("Override for unknown field {id} ignored");
+        }
+    }
+    let mut numeric = (vec![], vec![]);
+    let mut text = (vec![], vec![]);
+    for (field, addr) in all_fields.into_iter().zip(all_addresses) {
+        let field = match config.field_overrides.get(field.id) {
+            Some(field_override) => match field_override.apply(&field) {
+                Some(field) => field,
+                None => continue,
+            },
+            None => field,
+        };
+        let part = if matches!(field.field_type, FieldType::Text | FieldType::Version) {
+            &mut text
+        } else {
+            &mut numeric
+        };
+        part.0.push(field);
+        part.1.push(addr);
+    }
+    Ok(FieldSet {
+        fields: leak_fields(numeric.0),
+        addresses: numeric.1,
+        text_fields: leak_fields(text.0),
+        text_addresses: text.1,
+    })
 }
 
 #[cfg(test)]
@@ -71,7 +746,7 @@ mod test {
     use super::*;
     use assert_approx_eq::assert_approx_eq;
 
-    fn field() -> Field<'static> {
+    fn field(signed: bool) -> Field<'static> {
         Field {
             field_type: FieldType::Energy,
             group: "Grid",
@@ -79,22 +754,200 @@ mod test {
             id: "grid_import",
             scale: 0.1,
             bias: -10.0, // Not realistic, but useful to test the feature
+            signed,
             unit: "kWh",
+            requires: None,
+            labels: &[],
+            bit: None,
         }
     }
 
     #[test]
     fn test_from_u16s_one() {
-        let f = field();
+        let f = field(true);
         assert_approx_eq!(f.from_u16s([12345]), 1224.5);
         assert_approx_eq!(f.from_u16s([55536]), -1010.0);
     }
 
     #[test]
     fn test_from_u16s_two() {
-        let f = field();
+        let f = field(true);
         assert_approx_eq!(f.from_u16s([12345, 4321]), 28319330.1);
         assert_approx_eq!(f.from_u16s([55536, 4321]), 28323649.2);
         assert_approx_eq!(f.from_u16s([55536, 55536]), -65530456.4);
     }
+
+    #[test]
+    fn test_from_u16s_unsigned() {
+        let f = field(false);
+        assert_approx_eq!(f.from_u16s([55536]), 5543.6);
+        assert_approx_eq!(f.from_u16s([55536, 55536]), 363966273.2);
+    }
+
+    #[test]
+    fn test_label() {
+        let f = Field {
+            field_type: FieldType::Enum,
+            labels: &[(0, "Off"), (1, "On")],
+            ..field(false)
+        };
+        assert_eq!(f.label(1.0), Some("On"));
+        assert_eq!(f.label(2.0), None);
+    }
+
+    #[test]
+    fn test_flags() {
+        let f = Field {
+            field_type: FieldType::Flags,
+            scale: 1.0,
+            bias: 0.0,
+            labels: &[(0, "OK"), (1, "Fault")],
+            bit: Some(3),
+            ..field(false)
+        };
+        assert_eq!(f.from_u16s([0x0008]), 1.0);
+        assert_eq!(f.from_u16s([0xfff7]), 0.0);
+        assert_eq!(f.label(f.from_u16s([0x0008])), Some("Fault"));
+    }
+
+    #[test]
+    fn test_text() {
+        let f = Field {
+            field_type: FieldType::Text,
+            ..field(false)
+        };
+        assert_eq!(f.text_from_u16s([0x4142, 0x4320, 0x0044]), "ABC");
+        assert_eq!(f.text_from_u16s([0x2031, 0x2e32]), "1.2");
+    }
+
+    #[test]
+    fn test_version() {
+        let f = Field {
+            field_type: FieldType::Version,
+            ..field(false)
+        };
+        assert_eq!(f.text_from_u16s([0x3405]), "3.4.0.5");
+        assert_eq!(f.text_from_u16s([0x10ab]), "1.0.a.b");
+    }
+
+    #[test]
+    fn test_override() {
+        let f = field(false);
+        let field_override = FieldOverride {
+            scale: Some(0.01),
+            name: Some("Import".to_owned()),
+            ..Default::default()
+        };
+        let g = field_override.apply(&f).unwrap();
+        assert_eq!(g.scale, 0.01);
+        assert_eq!(g.bias, -10.0);
+        assert_eq!(g.name, "Import");
+        assert_eq!(g.unit, "kWh");
+        let field_override = FieldOverride {
+            disabled: true,
+            ..Default::default()
+        };
+        assert!(field_override.apply(&f).is_none());
+    }
+
+    #[test]
+    fn test_leak() {
+        let name = String::from("Total import");
+        assert!(std::ptr::eq(leak_str(&name), leak_str("Total import")));
+
+        let fields = leak_fields(vec![field(false), field(true)]);
+        let same = leak_fields(vec![field(false), field(true)]);
+        assert!(std::ptr::eq(fields, same));
+        let scaled = Field {
+            scale: 0.01,
+            ..field(false)
+        };
+        let other = leak_fields(vec![scaled, field(true)]);
+        assert!(!std::ptr::eq(fields, other));
+        assert_eq!(other[0].scale, 0.01);
+    }
+
+    #[test]
+    fn test_parse_field_map() {
+        let map = "\
+field_type,group,name,id,scale,offset,offset2,reg,reg2,signed,requires,labels,bit
+Energy,Grid,Total import,grid_import_total,,120,122,78,80,,,,
+Voltage,PV,PV2 voltage,pv_voltage_2,0.1,,,111,,,Pv2,,
+Enum,Inverter,Mode,inverter_mode,,,,-1,,,,0=Off;1=On,
+";
+        let fields = parse_field_map(map.as_bytes()).unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(
+            fields[0].addresses(fields[0].offset, fields[0].offset2, 2),
+            Some(vec![120, 122])
+        );
+        assert_eq!(
+            fields[0].addresses(fields[0].reg, fields[0].reg2, 1),
+            Some(vec![78, 80])
+        );
+        assert!(!fields[0].computed);
+        let f = fields[1].to_field().unwrap();
+        assert_eq!(f.scale, 0.1);
+        assert_eq!(f.requires, Some(Requirement::Pv2));
+        assert_eq!(fields[1].offset, None);
+        assert!(fields[2].computed);
+        assert_eq!(fields[2].reg, None);
+        let f = fields[2].to_field().unwrap();
+        assert_eq!(f.label(1.0), Some("On"));
+    }
+
+    #[test]
+    fn test_parse_yaml_field_map() {
+        let map = "\
+- field_type: Energy
+  group: Grid
+  name: Total import
+  id: grid_import_total
+  offset: 120
+  offset2: 122
+- field_type: Enum
+  group: Inverter
+  name: Mode
+  id: inverter_mode
+  reg: -1
+  labels: 0=Off;1=On
+";
+        let fields = parse_yaml_field_map(map.as_bytes()).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(
+            fields[0].addresses(fields[0].offset, fields[0].offset2, 2),
+            Some(vec![120, 122])
+        );
+        assert_eq!(fields[0].reg, None);
+        assert!(fields[1].computed);
+        let f = fields[1].to_field().unwrap();
+        assert_eq!(f.label(1.0), Some("On"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let extra_field = |extra: &str| -> ExtraField {
+            let text = format!("group = \"Test\"\nname = \"Test\"\nid = \"test\"\n{extra}");
+            toml::from_str(&text).unwrap()
+        };
+        let text = extra_field("field_type = \"Text\"\nreg = 10\nlength = 4");
+        assert!(text.to_field().is_ok());
+        let empty = extra_field("field_type = \"Text\"\nreg = 10\nlength = 0");
+        assert!(empty.to_field().unwrap_err().contains("at least 1"));
+        let long = extra_field("field_type = \"Energy\"\nreg = 10\nlength = 3");
+        assert!(long
+            .to_field()
+            .unwrap_err()
+            .contains("cannot have a length"));
+        let bit = extra_field("field_type = \"Flags\"\nreg = 10\nbit = 15");
+        assert!(bit.to_field().is_ok());
+        let bit = extra_field("field_type = \"Flags\"\nreg = 10\nbit = 16");
+        assert!(bit.to_field().unwrap_err().contains("less than 16"));
+        let last = extra_field("field_type = \"Text\"\nreg = 65534\nlength = 2");
+        assert!(last.to_field().is_ok());
+        let past = extra_field("field_type = \"Text\"\nreg = 65535\nlength = 2");
+        assert!(past.to_field().unwrap_err().contains("past the last"));
+        let version = extra_field("field_type = \"Version\"\nreg = 13\nreg2 = 14");
+        assert!(version.to_field().unwrap_err().contains("cannot have"));
+    }
 }
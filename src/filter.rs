@@ -0,0 +1,179 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Selection of the fields sent to a backend, using glob patterns on the
+//! field IDs
+
+use std::sync::Arc;
+
+use super::fields::{leak_fields, Field};
+use super::receiver::{Update, UpdateItem};
+use super::transform::{FieldListCache, Transform};
+
+/// Match a glob pattern, in which `*` matches any sequence of characters and
+/// `?` matches a single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Position in each after the last `*`, to backtrack to
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the `*` match one more character
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Fields to keep from a field list, with their indices in the list
+struct Selection {
+    fields: &'static [Field<'static>],
+    indices: Vec<usize>,
+}
+
+impl Selection {
+    fn select<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.indices.iter().map(|i| values[*i].clone()).collect()
+    }
+}
+
+/// Include and exclude patterns. An empty include list includes all fields.
+struct Patterns {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Patterns {
+    fn matches(&self, id: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, id)))
+            && !self.exclude.iter().any(|p| glob_match(p, id))
+    }
+
+    fn select(&self, fields: &'static [Field<'static>]) -> Selection {
+        let indices: Vec<usize> = (0..fields.len())
+            .filter(|i| self.matches(fields[*i].id))
+            .collect();
+        let selected: Vec<Field<'static>> = indices.iter().map(|i| fields[*i].clone()).collect();
+        Selection {
+            fields: leak_fields(selected),
+            indices,
+        }
+    }
+}
+
+/// Transform that keeps only the fields matching an include list and not
+/// matching an exclude list. Updates with no fields left are dropped.
+pub struct FieldFilter {
+    patterns: Patterns,
+    numeric: FieldListCache<Selection>,
+    text: FieldListCache<Selection>,
+}
+
+impl FieldFilter {
+    /// Create a filter. An empty `include` list includes all fields.
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        Self {
+            patterns: Patterns {
+                include: include.to_vec(),
+                exclude: exclude.to_vec(),
+            },
+            numeric: FieldListCache::new(),
+            text: FieldListCache::new(),
+        }
+    }
+}
+
+impl Transform for FieldFilter {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        let patterns = &self.patterns;
+        if patterns.include.is_empty() && patterns.exclude.is_empty() {
+            return Some(update);
+        }
+        let numeric = self
+            .numeric
+            .get(update.fields, |fields| patterns.select(fields));
+        let text = self
+            .text
+            .get(update.text_fields, |fields| patterns.select(fields));
+        if numeric.fields.is_empty() && text.fields.is_empty() {
+            return None;
+        }
+        let new_update = Update::new(
+            update.timestamp,
+            update.serial.clone(),
+            numeric.fields,
+            numeric.select(&update.values),
+        )
+        .with_text(text.fields, text.select(&update.text));
+        Some(Arc::new(new_update))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::FieldType;
+    use crate::test_util::{field, leak};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("battery_*", "battery_soc"));
+        assert!(glob_match("*_today", "pv_energy_today"));
+        assert!(glob_match("pv?_power", "pv1_power"));
+        assert!(glob_match("*energy*", "grid_energy_import_total"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("battery_*", "pv_power"));
+        assert!(!glob_match("pv?_power", "pv_power"));
+        assert!(!glob_match("*_today", "pv_energy_total"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = FieldFilter::new(&["*_energy_*".to_owned()], &["*_today".to_owned()]);
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Energy, "pv_energy_today"),
+            field(FieldType::Energy, "pv_energy_total"),
+        ]);
+        let text_fields = leak([field(FieldType::Text, "version")]);
+        let update = Update::new(0, "1234", fields, vec![1500.0, 10.0, 1000.0])
+            .with_text(text_fields, vec!["1.0".to_owned()]);
+        let update = filter.apply(Arc::new(update)).unwrap();
+        assert_eq!(update.fields.len(), 1);
+        assert_eq!(update.fields[0].id, "pv_energy_total");
+        assert_eq!(update.values, vec![1000.0]);
+        assert!(update.text_fields.is_empty());
+
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Energy, "pv_energy_today"),
+        ]);
+        let update = Update::new(0, "1234", fields, vec![1500.0, 10.0]);
+        assert!(filter.apply(Arc::new(update)).is_none());
+    }
+}
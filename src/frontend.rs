@@ -0,0 +1,145 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Trait implemented by the configurations of frontends, which produce the
+//! decoded updates. Backends implement [Receiver](crate::receiver::Receiver).
+
+use async_trait::async_trait;
+use log::{error, info};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use super::fields::FieldConfig;
+use super::receiver::UpdateStream;
+
+/// Field configuration of a running frontend, which is replaced when the
+/// configuration file is reloaded
+pub type FieldWatch = watch::Receiver<Arc<FieldConfig>>;
+
+type Build<T> = Arc<dyn Fn(&FieldConfig) -> Result<T, String> + Send + Sync>;
+
+/// Something that a frontend builds from the field configuration (such as
+/// the fields to decode), which is rebuilt when the field configuration is
+/// replaced
+#[derive(Clone)]
+pub struct Reloading<T> {
+    value: T,
+    reload: Option<(FieldWatch, Build<T>)>,
+}
+
+impl<T> Reloading<T> {
+    /// Build the value from the current field configuration
+    pub fn new(
+        field_config: FieldWatch,
+        build: impl Fn(&FieldConfig) -> Result<T, String> + Send + Sync + 'static,
+    ) -> Result<Self, String> {
+        let value = build(&field_config.borrow())?;
+        Ok(Self {
+            value,
+            reload: Some((field_config, Arc::new(build))),
+        })
+    }
+
+    /// Wrap a value that is never rebuilt
+    pub fn fixed(value: T) -> Self {
+        Self {
+            value,
+            reload: None,
+        }
+    }
+
+    /// Get the value, first rebuilding it if the field configuration has
+    /// been replaced. If rebuilding fails, the error is logged and the old
+    /// value is kept.
+    pub fn get(&mut self) -> &T {
+        if let Some((field_config, build)) = &mut self.reload {
+            // An error means that the configuration can no longer change
+            if field_config.has_changed().unwrap_or(false) {
+                let field_config = Arc::clone(&field_config.borrow_and_update());
+                match build(&field_config) {
+                    Ok(value) => {
+                        self.value = value;
+                        info!("Applied the new field configuration");
+                    }
+                    Err(err) => error!("Could not apply the new field configuration: {err}"),
+                }
+            }
+        }
+        &self.value
+    }
+}
+
+/// Trait implemented by the configuration of each frontend
+#[async_trait(?Send)]
+pub trait Frontend {
+    /// Start the frontend, returning a stream of decoded updates. The
+    /// `field_config` determines which fields are decoded (frontends that
+    /// receive updates that are already decoded ignore it), and changes to
+    /// it apply to the running frontend (see [Reloading]).
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>>;
+
+    /// Check the configuration, building the fields to decode as
+    /// [Frontend::create_stream] does, but without starting the frontend
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reloading() {
+        let (sender, receiver) = watch::channel(Arc::new(FieldConfig::default()));
+        let mut reloading =
+            Reloading::new(receiver, |field_config| match field_config.fields.len() {
+                0 => Ok(field_config.packet_layouts.len()),
+                _ => Err("no fields allowed".to_owned()),
+            })
+            .unwrap();
+        assert_eq!(*reloading.get(), 0);
+        let field_config: FieldConfig = toml::from_str(
+            r#"
+            [[packet_layouts]]
+            name = "padded"
+            length = 296
+            serial_offset = 11
+            datetime_offset = 37
+            "#,
+        )
+        .unwrap();
+        sender.send_replace(Arc::new(field_config));
+        assert_eq!(*reloading.get(), 1);
+        // A configuration that cannot be applied is ignored
+        let field_config: FieldConfig = toml::from_str(
+            r#"
+            [[fields]]
+            field_type = "Power"
+            group = "X"
+            name = "X"
+            id = "x"
+            "#,
+        )
+        .unwrap();
+        sender.send_replace(Arc::new(field_config));
+        assert_eq!(*reloading.get(), 1);
+        drop(sender);
+        assert_eq!(*reloading.get(), 1);
+        assert_eq!(*Reloading::fixed(2).get(), 2);
+    }
+}
@@ -0,0 +1,130 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that pushes updates to [Grafana
+//! Live](https://grafana.com/docs/grafana/latest/setup-grafana/set-up-grafana-live/),
+//! so that dashboards update as soon as data arrives. Each update is sent as
+//! soon as possible; since only the latest data matters for a live view,
+//! updates that fail to send are dropped rather than retried.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use log::warn;
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::health::record_write;
+use super::line_protocol;
+use super::receiver::{Receiver, Update};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct GrafanaLiveReceiver {
+    client: Client,
+    url: String,
+    token: String,
+    measurement: String,
+}
+
+impl GrafanaLiveReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(Self {
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .map_err(|err| err.to_string())?,
+            url: format!(
+                "{}/api/live/push/{}",
+                config.url.trim_end_matches('/'),
+                config.stream_id
+            ),
+            token: config.token.clone(),
+            measurement: config.measurement.clone(),
+        })
+    }
+
+    async fn send(&self, lines: String) {
+        let result = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.token)
+            .body(lines)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = &result {
+            warn!("Failed to push to Grafana Live ({err})");
+        }
+        record_write(result.err().map(|err| err.to_string()));
+    }
+}
+
+#[async_trait]
+impl Receiver for GrafanaLiveReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            let mut lines = line_protocol::encode_wide(&update, &self.measurement);
+            // If updates arrived while the previous request was in flight,
+            // send them together
+            while let Some(Some(update)) = receiver.next().now_or_never() {
+                lines.push_str(&line_protocol::encode_wide(&update, &self.measurement));
+            }
+            if !lines.is_empty() {
+                self.send(lines).await;
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Base URL of the Grafana server
+    pub url: String,
+    /// Service account token (needs permission to publish to Live)
+    pub token: String,
+    /// Stream ID: data appears in the channel `stream/{stream_id}/{measurement}`
+    #[serde(default = "default_stream_id")]
+    pub stream_id: String,
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+}
+
+fn default_stream_id() -> String {
+    "sunsniff".to_owned()
+}
+
+fn default_measurement() -> String {
+    "inverter".to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config =
+            toml::from_str("url = \"http://grafana:3000/\"\ntoken = \"glsa_abc\"").unwrap();
+        let receiver = GrafanaLiveReceiver::new(&config).unwrap();
+        assert_eq!(receiver.url, "http://grafana:3000/api/live/push/sunsniff");
+        assert_eq!(receiver.measurement, "inverter");
+    }
+}
@@ -0,0 +1,263 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends values to Graphite (Carbon), using either the
+//! plaintext or the pickle protocol.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::iter::zip;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Plaintext,
+    Pickle,
+}
+
+impl Protocol {
+    fn default_port(&self) -> u16 {
+        match self {
+            Protocol::Plaintext => 2003,
+            Protocol::Pickle => 2004,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Metric {
+    path: String,
+    /// Seconds since the UNIX epoch
+    timestamp: i64,
+    value: f64,
+}
+
+/// Make a string safe to use as a single component of a metric path
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn encode_plaintext(metrics: &[Metric]) -> Vec<u8> {
+    let mut out = String::new();
+    for metric in metrics.iter() {
+        writeln!(out, "{} {} {}", metric.path, metric.value, metric.timestamp).unwrap();
+    }
+    out.into_bytes()
+}
+
+/// Encode a list of `(path, (timestamp, value))` tuples as a pickle (protocol
+/// 2), preceded by its length as Carbon expects.
+fn encode_pickle(metrics: &[Metric]) -> Vec<u8> {
+    let mut pickle = vec![0x80, 2]; // PROTO 2
+    pickle.extend_from_slice(b"]("); // EMPTY_LIST, MARK
+    for metric in metrics.iter() {
+        pickle.push(b'X'); // BINUNICODE
+        pickle.extend_from_slice(&(metric.path.len() as u32).to_le_bytes());
+        pickle.extend_from_slice(metric.path.as_bytes());
+        pickle.push(b'J'); // BININT
+        pickle.extend_from_slice(&(metric.timestamp as i32).to_le_bytes());
+        pickle.push(b'G'); // BINFLOAT
+        pickle.extend_from_slice(&metric.value.to_be_bytes());
+        pickle.extend_from_slice(&[0x86, 0x86]); // TUPLE2, TUPLE2
+    }
+    pickle.extend_from_slice(b"e."); // APPENDS, STOP
+    let mut out = (pickle.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(&pickle);
+    out
+}
+
+pub struct GraphiteReceiver {
+    address: String,
+    prefix: String,
+    protocol: Protocol,
+    batch: Options,
+}
+
+impl GraphiteReceiver {
+    pub fn new(config: &Config) -> Self {
+        let port = config.port.unwrap_or(config.protocol.default_port());
+        Self {
+            address: format!("{}:{}", config.host, port),
+            prefix: config.prefix.clone(),
+            protocol: config.protocol,
+            batch: config.batch.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for GraphiteReceiver {
+    type Item = Metric;
+    type Error = std::io::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Metric> {
+        let mut base = String::new();
+        if !self.prefix.is_empty() {
+            base = self.prefix.clone() + ".";
+        }
+        base += &sanitize(&update.serial);
+        let timestamp = update.timestamp.div_euclid(1_000_000_000);
+        // Graphite only stores numbers, so text fields are skipped
+        zip(update.fields.iter(), update.values.iter())
+            .filter(|(_, value)| value.is_finite())
+            .map(|(field, value)| Metric {
+                path: format!("{base}.{}", sanitize(field.id)),
+                timestamp,
+                value: *value,
+            })
+            .collect()
+    }
+
+    async fn write(&self, metrics: Vec<Metric>) -> std::io::Result<()> {
+        let data = match self.protocol {
+            Protocol::Plaintext => encode_plaintext(&metrics),
+            Protocol::Pickle => encode_pickle(&metrics),
+        };
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(&data).await?;
+        stream.shutdown().await
+    }
+}
+
+#[async_trait]
+impl Receiver for GraphiteReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Port of the Carbon receiver (defaults to the standard port for the
+    /// protocol)
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// First component of each metric path (may be empty)
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_prefix() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    fn receiver(prefix: &str) -> GraphiteReceiver {
+        GraphiteReceiver::new(&Config {
+            host: default_host(),
+            port: None,
+            protocol: Protocol::Plaintext,
+            prefix: prefix.to_owned(),
+            batch: Options::default(),
+        })
+    }
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            Field {
+                field_type: FieldType::Power,
+                group: "Grid",
+                name: "Power",
+                id: "grid_power",
+                scale: 1.0,
+                bias: 0.0,
+                signed: true,
+                unit: "W",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+            Field {
+                field_type: FieldType::Temperature,
+                group: "Battery",
+                name: "Temperature",
+                id: "battery.temperature",
+                scale: 0.1,
+                bias: -100.0,
+                signed: false,
+                unit: "°C",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+        ]));
+        let update = Update::new(1_700_000_000_500_000_000, "1234", fields, vec![-2.5, 20.0]);
+        let metrics = receiver("home.solar").encode(&update);
+        assert_eq!(
+            encode_plaintext(&metrics),
+            b"home.solar.1234.grid_power -2.5 1700000000\n\
+              home.solar.1234.battery_temperature 20 1700000000\n"
+        );
+        let update = Update::new(0, "1234", fields, vec![f64::NAN, 20.0]);
+        let metrics = receiver("").encode(&update);
+        assert_eq!(
+            metrics,
+            vec![Metric {
+                path: "1234.battery_temperature".to_owned(),
+                timestamp: 0,
+                value: 20.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pickle() {
+        let metrics = vec![Metric {
+            path: "a.b".to_owned(),
+            timestamp: 1,
+            value: 2.0,
+        }];
+        let mut expected = vec![0, 0, 0, 30, 0x80, 2, b']', b'('];
+        expected.extend_from_slice(b"X\x03\x00\x00\x00a.bJ\x01\x00\x00\x00G");
+        expected.extend_from_slice(&2.0f64.to_be_bytes());
+        expected.extend_from_slice(b"\x86\x86e.");
+        assert_eq!(encode_pickle(&metrics), expected);
+    }
+}
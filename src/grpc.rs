@@ -0,0 +1,287 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that serves a gRPC API with a server-streaming `Subscribe` RPC
+//! (see `proto/sunsniff.proto`).
+//!
+//! The messages are simple enough that they are encoded by hand, rather than
+//! generating code from the schema.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::oneshot;
+use futures::stream::StreamExt;
+use hyper::body::{Bytes, Sender};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::iter::zip;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use super::health::ServerStatus;
+use super::receiver::{Receiver, Update};
+
+const SUBSCRIBE_PATH: &str = "/sunsniff.Sunsniff/Subscribe";
+/// Number of messages to hold for a slow client before it misses some
+const QUEUE_SIZE: usize = 16;
+/// gRPC status code for an unknown method
+const STATUS_UNIMPLEMENTED: &str = "12";
+/// gRPC status code for a service that is unavailable (and which the client
+/// may retry)
+const STATUS_UNAVAILABLE: &str = "14";
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Append a length-delimited field (string or message)
+fn put_bytes(out: &mut Vec<u8>, tag: u32, value: &[u8]) {
+    put_varint(out, ((tag << 3) | 2) as u64);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// Append a string field, unless it has the default (empty) value
+fn put_string(out: &mut Vec<u8>, tag: u32, value: &str) {
+    if !value.is_empty() {
+        put_bytes(out, tag, value.as_bytes());
+    }
+}
+
+fn put_double(out: &mut Vec<u8>, tag: u32, value: f64) {
+    put_varint(out, ((tag << 3) | 1) as u64);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Encode an update as an `Instant` message
+fn encode(update: &Update<'_>) -> Vec<u8> {
+    let mut out = vec![];
+    if update.timestamp != 0 {
+        put_varint(&mut out, 1 << 3);
+        put_varint(&mut out, update.timestamp as u64);
+    }
+    put_string(&mut out, 2, &update.serial);
+    let numeric = zip(update.fields.iter(), update.values.iter())
+        .map(|(field, value)| (field, Ok(*value), field.label(*value).unwrap_or_default()));
+    let text = zip(update.text_fields.iter(), update.text.iter())
+        .map(|(field, text)| (field, Err(text.as_str()), ""));
+    for (field, value, label) in numeric.chain(text) {
+        let mut msg = vec![];
+        put_string(&mut msg, 1, field.id);
+        put_string(&mut msg, 2, field.group);
+        put_string(&mut msg, 3, field.name);
+        put_string(&mut msg, 4, field.unit);
+        // Members of a oneof are always sent, even if they are the default
+        match value {
+            Ok(number) => put_double(&mut msg, 5, number),
+            Err(text) => put_bytes(&mut msg, 6, text.as_bytes()),
+        }
+        put_string(&mut msg, 7, label);
+        put_bytes(&mut out, 3, &msg);
+    }
+    out
+}
+
+/// Frame a message with the gRPC length prefix (uncompressed)
+fn frame(message: &[u8]) -> Bytes {
+    let mut out = vec![0];
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+    out.into()
+}
+
+/// Send updates to a client until it goes away or the backend stops. In
+/// the latter case, the response is ended with trailers giving the status.
+async fn stream(mut updates: broadcast::Receiver<Bytes>, mut body_sender: Sender) {
+    loop {
+        match updates.recv().await {
+            Ok(message) => {
+                if body_sender.send_data(message).await.is_err() {
+                    debug!("gRPC client disconnected");
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                debug!("gRPC client is too slow; skipped {n} updates");
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from_static(STATUS_UNAVAILABLE));
+    trailers.insert(
+        "grpc-message",
+        HeaderValue::from_static("Server is shutting down"),
+    );
+    let _ = body_sender.send_trailers(trailers).await;
+}
+
+fn handle(sender: &broadcast::Sender<Bytes>, request: Request<Body>) -> Response<Body> {
+    let builder = Response::builder().header("content-type", "application/grpc");
+    if request.method() != Method::POST || request.uri().path() != SUBSCRIBE_PATH {
+        // A "trailers-only" response
+        return builder
+            .header("grpc-status", STATUS_UNIMPLEMENTED)
+            .header("grpc-message", "Unknown method")
+            .body(Body::empty())
+            .unwrap();
+    }
+    let (body_sender, body) = Body::channel();
+    tokio::spawn(stream(sender.subscribe(), body_sender));
+    builder.body(body).unwrap()
+}
+
+pub struct GrpcReceiver {
+    sender: broadcast::Sender<Bytes>,
+    /// Whether the server has failed
+    status: ServerStatus,
+    /// Stops the server when dropped
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl GrpcReceiver {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sender, _) = broadcast::channel(QUEUE_SIZE);
+        let state = sender.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = handle(&state, request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&config.listen)?
+            .http2_only(true)
+            .serve(make_service);
+        info!("Serving gRPC on {}", server.local_addr());
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            let _ = stop.await;
+        });
+        let status = ServerStatus::default();
+        let server_status = status.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("gRPC server failed: {err}");
+                server_status.fail(err.to_string());
+            }
+        });
+        Ok(Self {
+            sender,
+            status,
+            _shutdown: shutdown,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for GrpcReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            if self.sender.receiver_count() > 0 {
+                // This can only fail if all the clients have just gone
+                let _ = self.sender.send(frame(&encode(&update)));
+            }
+            self.status.record_write();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Address on which to serve gRPC
+    pub listen: SocketAddr,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "Inverter",
+            name: "State",
+            id: "state",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(2, "Normal")],
+            bit: None,
+        }]));
+        let text_fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Text,
+            group: "Inverter",
+            name: "Version",
+            id: "ver",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update =
+            Update::new(300, "12", fields, vec![2.0]).with_text(text_fields, vec!["1".to_owned()]);
+        let mut expected = vec![0x08, 0xac, 0x02, 0x12, 2, b'1', b'2'];
+        expected.extend_from_slice(&[0x1a, 41, 0x0a, 5]);
+        expected.extend_from_slice(b"state\x12\x08Inverter\x1a\x05State\x29");
+        expected.extend_from_slice(&2.0f64.to_le_bytes());
+        expected.extend_from_slice(b"\x3a\x06Normal");
+        expected.extend_from_slice(b"\x1a\x1b\x0a\x03ver\x12\x08Inverter\x1a\x07Version\x32\x011");
+        assert_eq!(encode(&update), expected);
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame(b"abc").as_ref(), b"\x00\x00\x00\x00\x03abc");
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        use hyper::body::HttpBody;
+
+        let (sender, _) = broadcast::channel(QUEUE_SIZE);
+        let (body_sender, mut body) = Body::channel();
+        let task = tokio::spawn(stream(sender.subscribe(), body_sender));
+        sender.send(frame(b"abc")).unwrap();
+        drop(sender);
+        task.await.unwrap();
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(data.as_ref(), b"\x00\x00\x00\x00\x03abc");
+        assert!(body.data().await.is_none());
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], STATUS_UNAVAILABLE);
+        assert_eq!(trailers["grpc-message"], "Server is shutting down");
+    }
+}
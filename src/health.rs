@@ -0,0 +1,550 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Health checks, for container orchestrators. The service is healthy if
+//! updates have been received recently and every backend is running and
+//! able to write. The health is always tracked, but only served over HTTP
+//! with the `health` feature.
+
+#[cfg(feature = "health")]
+use hyper::service::{make_service_fn, service_fn};
+#[cfg(feature = "health")]
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+#[cfg(feature = "health")]
+use log::{error, info};
+#[cfg(feature = "health")]
+use serde::Deserialize;
+use serde::Serialize;
+#[cfg(feature = "health")]
+use std::convert::Infallible;
+use std::future::Future;
+#[cfg(feature = "health")]
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[cfg(feature = "health")]
+fn default_max_age() -> u64 {
+    300
+}
+
+#[cfg(feature = "health")]
+fn default_max_failure() -> u64 {
+    300
+}
+
+/// Structure corresponding to the `[health]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[cfg(feature = "health")]
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Address on which to serve `/healthz`
+    pub listen: SocketAddr,
+    /// Time (in seconds) without updates after which the service is unhealthy
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    /// Time (in seconds) for which a backend can fail to write before it is
+    /// unhealthy
+    #[serde(default = "default_max_failure")]
+    pub max_failure: u64,
+}
+
+struct Backend {
+    /// Type of the backend (the name of its config section)
+    kind: String,
+    running: bool,
+    /// When the backend started failing to write, if its last write failed
+    failing_since: Option<Instant>,
+    last_error: Option<String>,
+}
+
+tokio::task_local! {
+    /// Backend that is being polled, to which writes are attributed. Being
+    /// task-local, it is not inherited by tasks that the backend spawns.
+    static BACKEND: Arc<Mutex<Backend>>;
+}
+
+/// Handle to the backend being polled, for recording the outcome of writes
+/// that are made elsewhere, such as in a thread or a spawned task. It must be
+/// obtained before spawning, since [record_write] in a spawned task is not
+/// attributed to any backend.
+// Only compiled for the backends that record their writes
+#[cfg(any(
+    test,
+    feature = "amqp",
+    feature = "api",
+    feature = "azure_iot",
+    feature = "chat",
+    feature = "clickhouse",
+    feature = "csvfile",
+    feature = "domoticz",
+    feature = "elasticsearch",
+    feature = "email",
+    feature = "emoncms",
+    feature = "grafana_live",
+    feature = "graphite",
+    feature = "grpc",
+    feature = "influxdb1",
+    feature = "influxdb2",
+    feature = "jsonl",
+    feature = "kafka_producer",
+    feature = "mongodb",
+    feature = "mqtt",
+    feature = "mysql",
+    feature = "nats",
+    feature = "otlp",
+    feature = "parquet",
+    feature = "postgres",
+    feature = "prometheus",
+    feature = "pubsub",
+    feature = "push",
+    feature = "pvoutput",
+    feature = "questdb",
+    feature = "redis",
+    feature = "s3",
+    feature = "splunk",
+    feature = "sqlite",
+    feature = "statsd",
+    feature = "telegram",
+    feature = "victoriametrics",
+    feature = "webhook",
+    feature = "websocket",
+    feature = "zabbix"
+))]
+#[derive(Clone)]
+pub(crate) struct WriteRecorder {
+    backend: Option<Arc<Mutex<Backend>>>,
+}
+
+#[cfg(any(
+    test,
+    feature = "amqp",
+    feature = "api",
+    feature = "azure_iot",
+    feature = "chat",
+    feature = "clickhouse",
+    feature = "csvfile",
+    feature = "domoticz",
+    feature = "elasticsearch",
+    feature = "email",
+    feature = "emoncms",
+    feature = "grafana_live",
+    feature = "graphite",
+    feature = "grpc",
+    feature = "influxdb1",
+    feature = "influxdb2",
+    feature = "jsonl",
+    feature = "kafka_producer",
+    feature = "mongodb",
+    feature = "mqtt",
+    feature = "mysql",
+    feature = "nats",
+    feature = "otlp",
+    feature = "parquet",
+    feature = "postgres",
+    feature = "prometheus",
+    feature = "pubsub",
+    feature = "push",
+    feature = "pvoutput",
+    feature = "questdb",
+    feature = "redis",
+    feature = "s3",
+    feature = "splunk",
+    feature = "sqlite",
+    feature = "statsd",
+    feature = "telegram",
+    feature = "victoriametrics",
+    feature = "webhook",
+    feature = "websocket",
+    feature = "zabbix"
+))]
+impl WriteRecorder {
+    /// Get a handle to the backend being polled. Outside of a backend,
+    /// outcomes are ignored.
+    pub(crate) fn current() -> Self {
+        Self {
+            backend: BACKEND.try_with(Arc::clone).ok(),
+        }
+    }
+
+    /// Record the outcome of a write: `None` for success or the error
+    pub(crate) fn record(&self, error: Option<String>) {
+        if let Some(backend) = &self.backend {
+            let mut backend = backend.lock().unwrap();
+            match error {
+                Some(error) => {
+                    backend.failing_since.get_or_insert_with(Instant::now);
+                    backend.last_error = Some(error);
+                }
+                None => backend.failing_since = None,
+            }
+        }
+    }
+}
+
+/// Record the outcome of a write by the current backend (if any):
+/// `None` for success or the error
+#[cfg(any(
+    test,
+    feature = "amqp",
+    feature = "api",
+    feature = "azure_iot",
+    feature = "chat",
+    feature = "clickhouse",
+    feature = "csvfile",
+    feature = "domoticz",
+    feature = "elasticsearch",
+    feature = "email",
+    feature = "emoncms",
+    feature = "grafana_live",
+    feature = "graphite",
+    feature = "grpc",
+    feature = "influxdb1",
+    feature = "influxdb2",
+    feature = "jsonl",
+    feature = "kafka_producer",
+    feature = "mongodb",
+    feature = "mqtt",
+    feature = "mysql",
+    feature = "nats",
+    feature = "otlp",
+    feature = "parquet",
+    feature = "postgres",
+    feature = "prometheus",
+    feature = "pubsub",
+    feature = "push",
+    feature = "pvoutput",
+    feature = "questdb",
+    feature = "redis",
+    feature = "s3",
+    feature = "splunk",
+    feature = "sqlite",
+    feature = "statsd",
+    feature = "telegram",
+    feature = "victoriametrics",
+    feature = "webhook",
+    feature = "websocket",
+    feature = "zabbix"
+))]
+pub(crate) fn record_write(error: Option<String>) {
+    WriteRecorder::current().record(error);
+}
+
+/// Whether the server of a backend (such as an HTTP server that clients
+/// poll) has failed. The backend's writes fail once it has.
+// Only compiled for the backends that run servers
+#[cfg(any(
+    test,
+    feature = "api",
+    feature = "grpc",
+    feature = "prometheus",
+    feature = "websocket"
+))]
+#[derive(Clone, Default)]
+pub(crate) struct ServerStatus {
+    error: Arc<Mutex<Option<String>>>,
+}
+
+#[cfg(any(
+    test,
+    feature = "api",
+    feature = "grpc",
+    feature = "prometheus",
+    feature = "websocket"
+))]
+impl ServerStatus {
+    /// Record that the server has failed
+    pub(crate) fn fail(&self, error: String) {
+        *self.error.lock().unwrap() = Some(error);
+    }
+
+    /// Record a write by the current backend, which succeeds if the server
+    /// has not failed
+    pub(crate) fn record_write(&self) {
+        record_write(self.error.lock().unwrap().clone());
+    }
+}
+
+struct State {
+    started: Instant,
+    last_update: Option<Instant>,
+    backends: Vec<Arc<Mutex<Backend>>>,
+}
+
+// Reports are only used by the server
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+#[derive(Serialize)]
+struct BackendReport {
+    #[serde(rename = "type")]
+    kind: String,
+    healthy: bool,
+    running: bool,
+    /// Seconds for which writes have been failing
+    failing_for: Option<f64>,
+    last_error: Option<String>,
+}
+
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+#[derive(Serialize)]
+struct Report {
+    healthy: bool,
+    /// Seconds since the last update, or since starting if there have been
+    /// none
+    update_age: f64,
+    received: bool,
+    backends: Vec<BackendReport>,
+}
+
+/// Health of the service, which is updated by the pipeline and the backends
+#[derive(Clone)]
+pub struct Health {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                started: Instant::now(),
+                last_update: None,
+                backends: vec![],
+            })),
+        }
+    }
+
+    /// Record that an update has been received from the frontend
+    pub fn received(&self) {
+        self.state.lock().unwrap().last_update = Some(Instant::now());
+    }
+
+    /// Forget the backends that have stopped, once they have been replaced
+    /// after reloading the configuration
+    pub fn forget_stopped(&self) {
+        self.state
+            .lock()
+            .unwrap()
+            .backends
+            .retain(|backend| backend.lock().unwrap().running);
+    }
+
+    /// Run the future of a backend of type `kind`, tracking whether it is
+    /// running and the outcome of its writes
+    pub async fn track<F: Future>(&self, kind: &str, future: F) -> F::Output {
+        let backend = Arc::new(Mutex::new(Backend {
+            kind: kind.to_owned(),
+            running: true,
+            failing_since: None,
+            last_error: None,
+        }));
+        self.state
+            .lock()
+            .unwrap()
+            .backends
+            .push(Arc::clone(&backend));
+        let output = BACKEND.scope(Arc::clone(&backend), future).await;
+        backend.lock().unwrap().running = false;
+        output
+    }
+
+    /// Report the health. The service is unhealthy if there have been no
+    /// updates for `max_age` or a backend has been failing for `max_failure`.
+    #[cfg_attr(not(feature = "health"), allow(dead_code))]
+    fn report(&self, max_age: Duration, max_failure: Duration) -> Report {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let update_age = now - state.last_update.unwrap_or(state.started);
+        let backends: Vec<BackendReport> = state
+            .backends
+            .iter()
+            .map(|backend| {
+                let backend = backend.lock().unwrap();
+                let failing_for = backend.failing_since.map(|since| now - since);
+                BackendReport {
+                    kind: backend.kind.clone(),
+                    healthy: backend.running && failing_for.is_none_or(|f| f <= max_failure),
+                    running: backend.running,
+                    failing_for: failing_for.as_ref().map(Duration::as_secs_f64),
+                    last_error: backend.last_error.clone(),
+                }
+            })
+            .collect();
+        Report {
+            healthy: update_age <= max_age && backends.iter().all(|backend| backend.healthy),
+            update_age: update_age.as_secs_f64(),
+            received: state.last_update.is_some(),
+            backends,
+        }
+    }
+
+    #[cfg(feature = "health")]
+    fn handle(&self, config: &Config, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET || request.uri().path() != "/healthz" {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not found\n"))
+                .unwrap();
+        }
+        let report = self.report(
+            Duration::from_secs(config.max_age),
+            Duration::from_secs(config.max_failure),
+        );
+        let status = match report.healthy {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&report).unwrap()))
+            .unwrap()
+    }
+
+    /// Serve `/healthz` in the background
+    #[cfg(feature = "health")]
+    pub fn serve(&self, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        let health = self.clone();
+        let state = config.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let health = health.clone();
+            let config = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = health.handle(&config, request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&config.listen)?.serve(make_service);
+        info!("Serving health checks on {}", server.local_addr());
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Health check server failed: {err}");
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health() {
+        let check =
+            |health: &Health| health.report(Duration::from_secs(60), Duration::from_secs(30));
+        let health = Health::new();
+        let (sender, receiver) = futures::channel::oneshot::channel::<()>();
+        let backend = health.track("test", async move {
+            record_write(Some("failed".to_owned()));
+            let _ = receiver.await;
+        });
+        tokio::pin!(backend);
+        // Run the backend up to the point where it waits
+        assert!(futures::poll!(&mut backend).is_pending());
+
+        let report = check(&health);
+        assert!(report.healthy);
+        assert!(!report.received);
+        assert_eq!(report.backends[0].kind, "test");
+        assert_eq!(report.backends[0].last_error.as_deref(), Some("failed"));
+
+        // The backend has been failing for too long
+        tokio::time::advance(Duration::from_secs(40)).await;
+        health.received();
+        let report = check(&health);
+        assert!(!report.healthy);
+        assert!(report.received);
+        assert!(!report.backends[0].healthy);
+
+        // A successful write
+        let tracked = Arc::clone(&health.state.lock().unwrap().backends[0]);
+        BACKEND.scope(tracked, async { record_write(None) }).await;
+        assert!(check(&health).healthy);
+
+        // No updates for too long
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!check(&health).healthy);
+        health.received();
+
+        // The backend stops
+        sender.send(()).unwrap();
+        backend.await;
+        let report = check(&health);
+        assert!(!report.healthy);
+        assert!(!report.backends[0].running);
+    }
+
+    #[tokio::test]
+    async fn test_recorders() {
+        let check =
+            |health: &Health| health.report(Duration::from_secs(60), Duration::from_secs(0));
+        let health = Health::new();
+        // A write from another thread
+        health
+            .track("thread", async {
+                let recorder = WriteRecorder::current();
+                std::thread::spawn(move || recorder.record(Some("failed".to_owned())))
+                    .join()
+                    .unwrap();
+            })
+            .await;
+        let report = check(&health);
+        assert_eq!(report.backends[0].last_error.as_deref(), Some("failed"));
+
+        // A write from a spawned task is only attributed to the backend if
+        // the task captures a recorder
+        health.forget_stopped();
+        health
+            .track("task", async {
+                tokio::spawn(async { record_write(Some("lost".to_owned())) })
+                    .await
+                    .unwrap();
+                assert!(check(&health).backends[0].last_error.is_none());
+                let recorder = WriteRecorder::current();
+                tokio::spawn(async move { recorder.record(Some("failed".to_owned())) })
+                    .await
+                    .unwrap();
+            })
+            .await;
+        let report = check(&health);
+        assert_eq!(report.backends[0].kind, "task");
+        assert_eq!(report.backends[0].last_error.as_deref(), Some("failed"));
+
+        // A write by a backend whose server has failed
+        let status = ServerStatus::default();
+        health.forget_stopped();
+        assert!(check(&health).backends.is_empty());
+        health
+            .track("server", async {
+                status.record_write();
+                assert!(check(&health).backends[0].failing_for.is_none());
+                status.fail("closed".to_owned());
+                status.record_write();
+            })
+            .await;
+        let report = check(&health);
+        assert_eq!(report.backends[0].last_error.as_deref(), Some("closed"));
+    }
+}
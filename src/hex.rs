@@ -0,0 +1,127 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend that decodes hex dumps of packets, for debugging
+
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use log::{info, warn};
+use serde::Deserialize;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::fields::FieldConfig;
+use crate::frontend::{FieldWatch, Frontend};
+use crate::packet::{parse_hex, Codec};
+use crate::receiver::{UpdateItem, UpdateStream};
+
+/// Structure corresponding to the `[hex]` section of the configuration file.
+/// It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HexConfig {
+    /// File to read. If not specified, standard input is read.
+    file: Option<PathBuf>,
+    timezone: Tz,
+}
+
+/// Decode packets from text. Each packet is a block of hex digits (the TCP
+/// payload), and blocks are separated by blank lines. The decoded values are
+/// logged.
+fn decode_text(codec: &Codec, text: &str) -> Result<Vec<UpdateItem>, String> {
+    let mut updates = vec![];
+    let mut block = String::new();
+    let mut block_start = 1;
+    for (line_no, line) in text.lines().chain(std::iter::once("")).enumerate() {
+        if !line.trim().is_empty() {
+            if block.is_empty() {
+                block_start = line_no + 1;
+            }
+            block.push_str(line);
+        } else if !block.is_empty() {
+            let payload = parse_hex(&block).map_err(|err| format!("Line {block_start}: {err}"))?;
+            match codec.decode_payload(&payload) {
+                Some(update) => {
+                    for (field, value) in update.fields.iter().zip(update.values.iter()) {
+                        info!("{} = {} {}", field.id, value, field.unit);
+                    }
+                    for (field, text) in update.text_fields.iter().zip(update.text.iter()) {
+                        info!("{} = {:?}", field.id, text);
+                    }
+                    updates.push(update);
+                }
+                None => warn!("Packet at line {block_start} does not match a known layout"),
+            }
+            block.clear();
+        }
+    }
+    Ok(updates)
+}
+
+pub fn create_stream(
+    config: &HexConfig,
+    field_config: &FieldConfig,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    // The input is decoded immediately, so later changes to the fields
+    // have no effect
+    let codec = Codec::new(config.timezone, field_config)?;
+    let text = match &config.file {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        }
+    };
+    let updates = decode_text(&codec, &text)?;
+    Ok(Box::pin(futures::stream::iter(updates)))
+}
+
+#[async_trait(?Send)]
+impl Frontend for HexConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, &field_config.borrow())
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        Codec::new(self.timezone, field_config)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::format_hex;
+    use crate::packet::test::{sample_packet, PAYLOAD_OFFSET};
+
+    #[test]
+    fn test_decode_text() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let hex = format_hex(&sample_packet()[PAYLOAD_OFFSET..]);
+        let (first, second) = hex.split_at(100);
+        // Two packets, one split across lines, plus one that isn't recognised
+        let text = format!("{first}\n{second}\n\n\n{hex}\n\na5 06\n");
+        let updates = decode_text(&codec, &text).unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].serial, "1235687108");
+
+        assert!(decode_text(&codec, "a5 0g").is_err());
+    }
+}
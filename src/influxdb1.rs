@@ -0,0 +1,200 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend for Influxdb 1.x, which uses a database and retention policy
+//! instead of buckets, and username/password authentication instead of
+//! tokens.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use log::{info, warn};
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::batch::{is_fatal_status, run_batched, BatchWriter, Options};
+use super::line_protocol;
+use super::receiver::{Receiver, Update};
+
+pub struct Influxdb1Receiver {
+    client: Client,
+    host: String,
+    database: String,
+    retention_policy: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    batch: Options,
+}
+
+impl Influxdb1Receiver {
+    pub async fn new(config: &Config) -> Self {
+        let receiver = Self::without_ping(config);
+        match receiver.ping().await {
+            Ok(()) => info!(
+                "Successfully connected to Influxdb server at {}",
+                &config.host
+            ),
+            Err(err) => warn!("Could not connect to Influxdb server: {}", err),
+        }
+        receiver
+    }
+
+    fn without_ping(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            host: config.host.trim_end_matches('/').to_owned(),
+            database: config.database.clone(),
+            retention_policy: config.retention_policy.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+
+    async fn ping(&self) -> reqwest::Result<()> {
+        let ping = self.auth(self.client.get(format!("{}/ping", self.host)));
+        ping.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    fn auth(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    fn write_request(&self, lines: Vec<String>) -> RequestBuilder {
+        let mut query = vec![("db", self.database.as_str()), ("precision", "ns")];
+        if let Some(rp) = &self.retention_policy {
+            query.push(("rp", rp.as_str()));
+        }
+        let request = self
+            .client
+            .post(format!("{}/write", self.host))
+            .query(&query)
+            .body(lines.concat());
+        self.auth(request)
+    }
+}
+
+#[async_trait]
+impl BatchWriter for Influxdb1Receiver {
+    type Item = String;
+    type Error = reqwest::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<String> {
+        let lines = line_protocol::encode(update, true);
+        if lines.is_empty() {
+            vec![]
+        } else {
+            vec![lines]
+        }
+    }
+
+    async fn write(&self, lines: Vec<String>) -> reqwest::Result<()> {
+        self.write_request(lines).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    fn is_fatal(&self, err: &reqwest::Error) -> bool {
+        err.status()
+            .is_some_and(|status| is_fatal_status(status.as_u16()))
+    }
+}
+
+#[async_trait]
+impl Receiver for Influxdb1Receiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.ping()
+            .await
+            .map_err(|err| format!("Could not connect to Influxdb server: {err}").into())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    pub database: String,
+    pub retention_policy: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_host() -> String {
+    "http://localhost:8086".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(config: &str) -> Influxdb1Receiver {
+        Influxdb1Receiver::without_ping(&toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn test_config() {
+        let receiver = parse("database = \"solar\"");
+        assert_eq!(receiver.host, "http://localhost:8086");
+        assert!(receiver.retention_policy.is_none());
+        assert!(receiver.username.is_none());
+        let receiver = parse("host = \"http://influx:8086/\"\ndatabase = \"solar\"");
+        assert_eq!(receiver.host, "http://influx:8086");
+        assert!(toml::from_str::<Config>("").is_err());
+        assert!(toml::from_str::<Config>("database = \"solar\"\nbucket = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_write_request() {
+        let receiver = parse(
+            "database = \"solar\"\nretention_policy = \"autogen\"\n\
+             username = \"user\"\npassword = \"secret\"",
+        );
+        let request = receiver
+            .write_request(vec!["a 1\n".to_owned(), "b 2\n".to_owned()])
+            .build()
+            .unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:8086/write?db=solar&precision=ns&rp=autogen"
+        );
+        // base64 of "user:secret"
+        assert_eq!(request.headers()["authorization"], "Basic dXNlcjpzZWNyZXQ=");
+        assert_eq!(request.body().unwrap().as_bytes().unwrap(), b"a 1\nb 2\n");
+
+        let request = parse("database = \"solar\"")
+            .write_request(vec![])
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:8086/write?db=solar&precision=ns"
+        );
+        assert!(request.headers().get("authorization").is_none());
+    }
+}
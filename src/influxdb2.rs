@@ -14,10 +14,9 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use async_std::task;
 use async_trait::async_trait;
 use futures::channel::mpsc::UnboundedReceiver;
-use futures::stream::{self, StreamExt};
+use futures::stream;
 use influxdb2::models::health::Status;
 use influxdb2::models::DataPoint;
 use influxdb2::Client;
@@ -25,91 +24,110 @@ use log::{info, warn};
 use serde::Deserialize;
 use std::iter::zip;
 use std::sync::Arc;
-use std::time::Duration;
 
+use super::batch::{is_fatal_status, run_batched, BatchWriter, Options};
 use super::receiver::{Receiver, Update};
 
 pub struct Influxdb2Receiver {
     client: Client,
     bucket: String,
+    batch: Options,
 }
 
 impl Influxdb2Receiver {
     pub async fn new(config: &Config) -> Self {
-        let client = Client::new(&config.host, &config.org, &config.token);
-        match client.health().await {
-            Ok(health_check) => {
-                if health_check.status == Status::Fail {
-                    match health_check.message {
-                        Some(ref message) => {
-                            warn!("Influxdb server is unhealthy: {}", message);
-                        }
-                        None => {
-                            warn!("Influxdb server is unhealthy");
-                        }
-                    }
-                } else {
-                    info!(
-                        "Successfully connected to Influxdb server at {}",
-                        &config.host
-                    );
-                }
-            }
-            Err(err) => {
-                warn!("Could not connect to Influxdb server: {}", err);
-            }
-        }
-        Self {
-            client,
+        let receiver = Self {
+            client: Client::new(&config.host, &config.org, &config.token),
             bucket: config.bucket.to_owned(),
+            batch: config.batch.clone(),
+        };
+        match receiver.health().await {
+            Ok(()) => info!(
+                "Successfully connected to Influxdb server at {}",
+                &config.host
+            ),
+            Err(err) => warn!("{err}"),
+        }
+        receiver
+    }
+
+    /// Check the health of the server, returning a description of the
+    /// problem if it is unhealthy or cannot be reached
+    async fn health(&self) -> Result<(), String> {
+        match self.client.health().await {
+            Ok(health_check) if health_check.status == Status::Fail => match health_check.message {
+                Some(message) => Err(format!("Influxdb server is unhealthy: {message}")),
+                None => Err("Influxdb server is unhealthy".to_owned()),
+            },
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Could not connect to Influxdb server: {err}")),
         }
     }
 }
 
 #[async_trait]
-impl Receiver for Influxdb2Receiver {
-    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
-        while let Some(update) = receiver.next().await {
-            let mut points = vec![];
-            for (field, value) in zip(update.fields.iter(), update.values.iter()) {
-                let build = DataPoint::builder("inverter")
-                    .timestamp(update.timestamp)
-                    .tag("serial", update.serial.as_str())
-                    .tag("group", field.group)
-                    .tag("name", field.name);
-                let build = if field.unit.is_empty() {
-                    build
-                } else {
-                    build.tag("unit", field.unit)
-                };
-                let build = build.field("value", *value).build();
-                match build {
-                    Ok(value) => {
-                        points.push(value);
-                    }
-                    Err(err) => {
-                        warn!("Error building point: {:?}", err);
-                    }
+impl BatchWriter for Influxdb2Receiver {
+    type Item = DataPoint;
+    type Error = influxdb2::RequestError;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<DataPoint> {
+        let mut points = vec![];
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            let build = DataPoint::builder("inverter")
+                .timestamp(update.timestamp)
+                .tag("serial", update.serial.as_str())
+                .tag("group", field.group)
+                .tag("name", field.name);
+            let build = if field.unit.is_empty() {
+                build
+            } else {
+                build.tag("unit", field.unit)
+            };
+            // Text fields are attached to every point as tags
+            let build = zip(update.text_fields.iter(), update.text.iter())
+                .fold(build, |build, (text_field, text)| {
+                    build.tag(text_field.id, text.as_str())
+                });
+            let build = build.field("value", *value);
+            let build = match field.label(*value) {
+                Some(label) => build.field("label", label),
+                None => build,
+            };
+            let build = build.build();
+            match build {
+                Ok(value) => {
+                    points.push(value);
                 }
-            }
-            if !points.is_empty() {
-                loop {
-                    match self
-                        .client
-                        .write(self.bucket.as_str(), stream::iter(points.clone()))
-                        .await
-                    {
-                        Ok(_) => {
-                            break;
-                        }
-                        Err(err) => {
-                            info!("Error writing to Influxdb; trying again in 5s ({:?})", err);
-                            task::sleep(Duration::from_secs(5)).await;
-                        }
-                    }
+                Err(err) => {
+                    warn!("Error building point: {:?}", err);
                 }
             }
         }
+        points
+    }
+
+    async fn write(&self, points: Vec<DataPoint>) -> Result<(), Self::Error> {
+        self.client
+            .write(self.bucket.as_str(), stream::iter(points))
+            .await
+    }
+
+    fn is_fatal(&self, err: &influxdb2::RequestError) -> bool {
+        match err {
+            influxdb2::RequestError::Http { status, .. } => is_fatal_status(status.as_u16()),
+            _ => false,
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for Influxdb2Receiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.health().await?)
     }
 }
 
@@ -121,6 +139,10 @@ pub struct Config {
     pub org: String,
     pub token: String,
     pub bucket: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
 }
 
 fn default_host() -> String {
@@ -0,0 +1,244 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Settings for individual inverters, identified by serial number
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::fields::{leak_fields, leak_str, Field, FieldType};
+use super::receiver::{Update, UpdateItem};
+use super::transform::{FieldListCache, Transform};
+
+/// Text field holding the name of the inverter
+const NAME_FIELD: Field<'static> = Field {
+    field_type: FieldType::Text,
+    group: "Inverter",
+    name: "Name",
+    id: "inverter_name",
+    scale: 1.0,
+    bias: 0.0,
+    signed: false,
+    unit: "",
+    requires: None,
+    labels: &[],
+    bit: None,
+};
+
+/// Structure corresponding to an entry in the `[inverters]` section of the
+/// configuration file, which is keyed by serial number. It is constructed
+/// from the config file by serde.
+#[derive(Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Human-readable name, reported in the `inverter_name` text field
+    name: Option<String>,
+    /// IDs of fields that are not reported for this inverter
+    #[serde(default)]
+    disabled_fields: Vec<String>,
+    /// New names for fields, keyed by ID
+    #[serde(default)]
+    field_names: HashMap<String, String>,
+    /// Backends that receive updates from this inverter (all if not given)
+    backends: Option<Vec<String>>,
+}
+
+/// Fields to report from a field list, with the indices of the original
+/// fields they are taken from
+struct Selection {
+    fields: &'static [Field<'static>],
+    indices: Vec<usize>,
+}
+
+impl Selection {
+    fn new(
+        fields: &'static [Field<'static>],
+        config: &Config,
+        extra: Option<Field<'static>>,
+    ) -> Self {
+        let mut new_fields = vec![];
+        let mut indices = vec![];
+        for (i, field) in fields.iter().enumerate() {
+            if config.disabled_fields.iter().any(|id| id == field.id) {
+                continue;
+            }
+            let mut field = field.clone();
+            if let Some(name) = config.field_names.get(field.id) {
+                field.name = leak_str(name);
+            }
+            new_fields.push(field);
+            indices.push(i);
+        }
+        new_fields.extend(extra);
+        Self {
+            fields: leak_fields(new_fields),
+            indices,
+        }
+    }
+
+    fn select<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.indices.iter().map(|i| values[*i].clone()).collect()
+    }
+}
+
+/// Per-inverter state of [Inverters]
+struct Inverter {
+    config: Config,
+    numeric: FieldListCache<Selection>,
+    text: FieldListCache<Selection>,
+}
+
+/// Transform that applies the per-inverter settings
+pub struct Inverters {
+    inverters: HashMap<String, Inverter>,
+}
+
+impl Inverters {
+    pub fn new(config: &HashMap<String, Config>) -> Self {
+        let inverters = config
+            .iter()
+            .map(|(serial, config)| {
+                let inverter = Inverter {
+                    config: config.clone(),
+                    numeric: FieldListCache::new(),
+                    text: FieldListCache::new(),
+                };
+                (serial.clone(), inverter)
+            })
+            .collect();
+        Self { inverters }
+    }
+}
+
+impl Transform for Inverters {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        let Some(inverter) = self.inverters.get_mut(&update.serial) else {
+            return Some(update);
+        };
+        let config = &inverter.config;
+        let numeric = inverter
+            .numeric
+            .get(update.fields, |fields| Selection::new(fields, config, None));
+        let name_field = config.name.as_ref().map(|_| NAME_FIELD);
+        let text = inverter.text.get(update.text_fields, |fields| {
+            Selection::new(fields, config, name_field)
+        });
+        let mut text_values = text.select(&update.text);
+        text_values.extend(config.name.iter().cloned());
+        let new_update = Update::new(
+            update.timestamp,
+            update.serial.clone(),
+            numeric.fields,
+            numeric.select(&update.values),
+        )
+        .with_text(text.fields, text_values);
+        Some(Arc::new(new_update))
+    }
+}
+
+/// Which backends receive updates from each inverter
+pub struct Routing {
+    backends: HashMap<String, Vec<String>>,
+}
+
+impl Routing {
+    pub fn new(config: &HashMap<String, Config>) -> Self {
+        let backends = config
+            .iter()
+            .filter_map(|(serial, config)| Some((serial.clone(), config.backends.clone()?)))
+            .collect();
+        Self { backends }
+    }
+
+    /// Check that every backend named in the configuration is one of `known`
+    pub fn check(&self, known: &[&str]) -> Result<(), String> {
+        for (serial, backends) in self.backends.iter() {
+            for backend in backends.iter() {
+                if !known.contains(&backend.as_str()) {
+                    return Err(format!(
+                        "Backend {backend} for inverter {serial} is not configured"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether updates from the inverter `serial` should go to `backend`
+    pub fn sends_to(&self, serial: &str, backend: &str) -> bool {
+        match self.backends.get(serial) {
+            Some(backends) => backends.iter().any(|b| b == backend),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{field, leak};
+
+    fn config() -> HashMap<String, Config> {
+        toml::from_str(
+            r#"
+            [1234]
+            name = "Garage"
+            disabled_fields = ["pv_power"]
+            field_names = { battery_soc = "SOC" }
+            backends = ["influxdb2"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_inverters() {
+        let mut inverters = Inverters::new(&config());
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::StateOfCharge, "battery_soc"),
+        ]);
+        let text_fields = leak([field(FieldType::Text, "version")]);
+        let update = Update::new(0, "1234", fields, vec![1500.0, 80.0])
+            .with_text(text_fields, vec!["1.0".to_owned()]);
+        let update = inverters.apply(Arc::new(update)).unwrap();
+        assert_eq!(update.fields.len(), 1);
+        assert_eq!(update.fields[0].id, "battery_soc");
+        assert_eq!(update.fields[0].name, "SOC");
+        assert_eq!(update.values, vec![80.0]);
+        assert_eq!(update.text_fields[1].id, "inverter_name");
+        assert_eq!(update.text, vec!["1.0", "Garage"]);
+
+        // Other inverters are unaffected
+        let update = Arc::new(Update::new(0, "5678", fields, vec![1500.0, 80.0]));
+        let new_update = inverters.apply(Arc::clone(&update)).unwrap();
+        assert!(Arc::ptr_eq(&update, &new_update));
+    }
+
+    #[test]
+    fn test_routing() {
+        let routing = Routing::new(&config());
+        assert!(routing.sends_to("1234", "influxdb2"));
+        assert!(!routing.sends_to("1234", "mqtt"));
+        assert!(routing.sends_to("5678", "mqtt"));
+        assert!(routing.check(&["influxdb2", "mqtt"]).is_ok());
+        assert_eq!(
+            routing.check(&["mqtt"]).unwrap_err(),
+            "Backend influxdb2 for inverter 1234 is not configured"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Self-describing JSON representation of updates, for passing them between
+//! instances (or to other programs).
+
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+use crate::fields::{leak_fields, leak_str, Field, FieldType};
+use crate::receiver::Update;
+
+/// Description of a field, without the details of how it is decoded
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FieldMeta {
+    pub id: String,
+    pub group: String,
+    pub name: String,
+    pub field_type: FieldType,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub unit: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<(i64, String)>,
+}
+
+impl FieldMeta {
+    pub(crate) fn new(field: &Field<'_>) -> Self {
+        Self {
+            id: field.id.to_owned(),
+            group: field.group.to_owned(),
+            name: field.name.to_owned(),
+            field_type: field.field_type,
+            unit: field.unit.to_owned(),
+            labels: field
+                .labels
+                .iter()
+                .map(|(value, label)| (*value, (*label).to_owned()))
+                .collect(),
+        }
+    }
+
+    fn to_field(&self) -> Field<'static> {
+        let labels: Vec<(i64, &'static str)> = self
+            .labels
+            .iter()
+            .map(|(value, label)| (*value, leak_str(label)))
+            .collect();
+        Field {
+            field_type: self.field_type,
+            group: leak_str(&self.group),
+            name: leak_str(&self.name),
+            id: leak_str(&self.id),
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: leak_str(&self.unit),
+            requires: None,
+            labels: Box::leak(labels.into_boxed_slice()),
+            bit: None,
+        }
+    }
+}
+
+/// Value of a field that may be serialized as `null`. JSON has no
+/// representation for NaN, which is serialized as `null` instead.
+pub trait Nullable: Sized {
+    fn null() -> Self;
+}
+
+impl Nullable for f64 {
+    fn null() -> Self {
+        f64::NAN
+    }
+}
+
+impl Nullable for String {
+    fn null() -> Self {
+        String::new()
+    }
+}
+
+fn deserialize_nullable<'de, D, V>(deserializer: D) -> Result<V, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de> + Nullable,
+{
+    Ok(Option::<V>::deserialize(deserializer)?.unwrap_or_else(V::null))
+}
+
+/// A field together with its value
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(bound(deserialize = "V: Deserialize<'de> + Nullable"))]
+pub struct FieldValue<V> {
+    #[serde(flatten)]
+    pub meta: FieldMeta,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    pub value: V,
+}
+
+/// JSON representation of an [Update]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UpdateRecord {
+    /// Nanoseconds since UNIX epoch
+    pub timestamp: i64,
+    pub serial: String,
+    #[serde(default)]
+    pub fields: Vec<FieldValue<f64>>,
+    #[serde(default)]
+    pub text: Vec<FieldValue<String>>,
+}
+
+impl UpdateRecord {
+    pub fn new(update: &Update<'_>) -> Self {
+        fn values<V: Clone>(fields: &[Field<'_>], values: &[V]) -> Vec<FieldValue<V>> {
+            fields
+                .iter()
+                .zip(values.iter())
+                .map(|(field, value)| FieldValue {
+                    meta: FieldMeta::new(field),
+                    value: value.clone(),
+                })
+                .collect()
+        }
+        Self {
+            timestamp: update.timestamp,
+            serial: update.serial.clone(),
+            fields: values(update.fields, &update.values),
+            text: values(update.text_fields, &update.text),
+        }
+    }
+
+    /// Split the fields into several records, grouped by a key computed from
+    /// each field. The records are in order of first appearance of the key.
+    pub fn split<K: PartialEq>(self, key: impl Fn(&FieldMeta) -> K) -> Vec<(K, UpdateRecord)> {
+        fn find<K: PartialEq>(
+            records: &mut Vec<(K, UpdateRecord)>,
+            empty: &UpdateRecord,
+            key: K,
+        ) -> usize {
+            records
+                .iter()
+                .position(|(k, _)| *k == key)
+                .unwrap_or_else(|| {
+                    records.push((key, empty.clone()));
+                    records.len() - 1
+                })
+        }
+
+        let empty = UpdateRecord {
+            timestamp: self.timestamp,
+            serial: self.serial,
+            fields: vec![],
+            text: vec![],
+        };
+        let mut records = vec![];
+        for field in self.fields.into_iter() {
+            let pos = find(&mut records, &empty, key(&field.meta));
+            records[pos].1.fields.push(field);
+        }
+        for field in self.text.into_iter() {
+            let pos = find(&mut records, &empty, key(&field.meta));
+            records[pos].1.text.push(field);
+        }
+        records
+    }
+}
+
+/// Converts [UpdateRecord]s back into [Update]s. Field descriptions need to
+/// be leaked to give them a static lifetime, so each distinct list of fields
+/// is only leaked once. When the records come from an untrusted source, the
+/// number of lists can be limited so that the leaked memory is bounded.
+#[derive(Default)]
+pub struct Interner {
+    lists: HashMap<Vec<FieldMeta>, &'static [Field<'static>]>,
+    limit: Option<usize>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an interner that holds at most `limit` distinct lists of fields
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            lists: HashMap::new(),
+            limit: Some(limit),
+        }
+    }
+
+    fn intern(&mut self, meta: Vec<FieldMeta>) -> Option<&'static [Field<'static>]> {
+        if !self.lists.contains_key(&meta) && self.limit.is_some_and(|l| self.lists.len() >= l) {
+            return None;
+        }
+        Some(
+            self.lists.entry(meta).or_insert_with_key(|meta| {
+                leak_fields(meta.iter().map(FieldMeta::to_field).collect())
+            }),
+        )
+    }
+
+    /// Decode a record. Returns `None` if it would exceed the limit on the
+    /// number of lists of fields.
+    pub fn try_decode(&mut self, record: UpdateRecord) -> Option<Update<'static>> {
+        let (meta, values) = record
+            .fields
+            .into_iter()
+            .map(|field| (field.meta, field.value))
+            .unzip();
+        let (text_meta, text) = record
+            .text
+            .into_iter()
+            .map(|field| (field.meta, field.value))
+            .unzip();
+        let fields = self.intern(meta)?;
+        let text_fields = self.intern(text_meta)?;
+        Some(
+            Update::new(record.timestamp, record.serial, fields, values)
+                .with_text(text_fields, text),
+        )
+    }
+
+    /// Decode a record, with an interner that has no limit
+    pub fn decode(&mut self, record: UpdateRecord) -> Update<'static> {
+        self.try_decode(record)
+            .expect("decode must only be used without a limit")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "Inverter",
+            name: "Mode",
+            id: "inverter_mode",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(0, "Standby"), (1, "Selling first")],
+            bit: None,
+        }]));
+        let update = Update::new(1234, "5678", fields, vec![1.0]);
+        let json = serde_json::to_string(&UpdateRecord::new(&update)).unwrap();
+        let record: UpdateRecord = serde_json::from_str(&json).unwrap();
+        // NaN is serialized as null
+        let nan = Update::new(1234, "5678", fields, vec![f64::NAN]);
+        let json = serde_json::to_string(&UpdateRecord::new(&nan)).unwrap();
+        let nan: UpdateRecord = serde_json::from_str(&json).unwrap();
+        assert!(nan.fields[0].value.is_nan());
+        let mut interner = Interner::new();
+        let decoded = interner.decode(record.clone());
+        assert_eq!(decoded.timestamp, 1234);
+        assert_eq!(decoded.serial, "5678");
+        assert_eq!(decoded.values, vec![1.0]);
+        assert_eq!(decoded.fields[0].id, "inverter_mode");
+        assert_eq!(decoded.fields[0].label(1.0), Some("Selling first"));
+        // The same list of fields is reused
+        let decoded2 = interner.decode(record.clone());
+        assert!(std::ptr::eq(decoded.fields, decoded2.fields));
+
+        // The text fields and the fields of the record are two lists
+        let mut limited = Interner::with_limit(2);
+        assert!(limited.try_decode(record.clone()).is_some());
+        let mut other = record.clone();
+        other.fields[0].meta.id = "other_mode".to_owned();
+        assert!(limited.try_decode(other).is_none());
+        assert!(limited.try_decode(record).is_some());
+    }
+
+    #[test]
+    fn test_split() {
+        let field = |group, id| Field {
+            field_type: FieldType::Power,
+            group,
+            name: "Power",
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        };
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            field("Grid", "grid_power"),
+            field("Load", "load_power"),
+            field("Grid", "grid_ct_power"),
+        ]));
+        let update = Update::new(1, "1234", fields, vec![1.0, 2.0, 3.0]);
+        let records = UpdateRecord::new(&update).split(|meta| meta.group.clone());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, "Grid");
+        assert_eq!(records[0].1.timestamp, 1);
+        assert_eq!(records[0].1.serial, "1234");
+        let ids: Vec<&str> = records[0]
+            .1
+            .fields
+            .iter()
+            .map(|f| f.meta.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["grid_power", "grid_ct_power"]);
+        assert_eq!(records[1].0, "Load");
+        assert_eq!(records[1].1.fields[0].value, 2.0);
+    }
+}
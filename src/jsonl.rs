@@ -0,0 +1,294 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that appends each update to a JSON Lines file, in the format
+//! described by [UpdateRecord]. Files are rotated in the same way as for the
+//! CSV backend, and can optionally be compressed with gzip or zstd.
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use flate2::write::GzEncoder;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::health::record_write;
+use super::json::UpdateRecord;
+use super::receiver::{Receiver, Update};
+use super::rotate::{utc, Rotation};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "jsonl",
+            Compression::Gzip => "jsonl.gz",
+            Compression::Zstd => "jsonl.zst",
+        }
+    }
+}
+
+enum Writer {
+    Plain(File),
+    /// Each time a file is reopened a new gzip member is started, which
+    /// decompressors treat as a continuation of the same stream.
+    Gzip(GzEncoder<File>),
+    /// Likewise, a new zstd frame is started each time.
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Writer {
+    fn file(&self) -> &File {
+        match self {
+            Writer::Plain(file) => file,
+            Writer::Gzip(encoder) => encoder.get_ref(),
+            Writer::Zstd(encoder) => encoder.get_ref(),
+        }
+    }
+
+    /// Write a line and flush it to the file, so that it is not lost if the
+    /// process is killed
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let writer: &mut dyn Write = match self {
+            Writer::Plain(file) => file,
+            Writer::Gzip(encoder) => encoder,
+            Writer::Zstd(encoder) => encoder,
+        };
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Writer::Plain(_) => {}
+            Writer::Gzip(encoder) => {
+                encoder.finish()?;
+            }
+            Writer::Zstd(encoder) => {
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file currently being written
+struct Output {
+    writer: Writer,
+    /// Day on which the file was started
+    day: NaiveDate,
+}
+
+pub struct JsonlReceiver {
+    rotation: Rotation,
+    compression: Compression,
+    output: Option<Output>,
+}
+
+impl JsonlReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rotation: Rotation {
+                directory: config.directory.clone(),
+                prefix: config.prefix.clone(),
+                extension: config.compression.extension().to_owned(),
+                max_size: config.max_size,
+            },
+            compression: config.compression,
+            output: None,
+        }
+    }
+
+    /// Whether a new file must be started for a record
+    fn must_rotate(&self, time: NaiveDateTime) -> std::io::Result<bool> {
+        let Some(output) = &self.output else {
+            return Ok(true);
+        };
+        let size = output.writer.file().metadata()?.len();
+        Ok(self.rotation.expired(output.day, size, time))
+    }
+
+    /// Finish writing the current file, if any
+    fn close(&mut self) -> std::io::Result<()> {
+        match self.output.take() {
+            Some(output) => output.writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, update: &Update<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        let line = serde_json::to_vec(&UpdateRecord::new(update))?;
+        let start = utc(update.timestamp);
+        if self.must_rotate(start)? {
+            self.close()?;
+            let path = self.rotation.path(start);
+            info!("Writing to {}", path.display());
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let writer = match self.compression {
+                Compression::None => Writer::Plain(file),
+                Compression::Gzip => {
+                    Writer::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+                }
+                Compression::Zstd => Writer::Zstd(zstd::Encoder::new(file, 0)?),
+            };
+            self.output = Some(Output {
+                writer,
+                day: start.date(),
+            });
+        }
+        self.output.as_mut().unwrap().writer.write_line(&line)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for JsonlReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            match self.write(&update) {
+                Ok(()) => record_write(None),
+                Err(err) => {
+                    warn!("Failed to write JSON record: {err}");
+                    // Start a new file next time, in case this one is broken
+                    self.output = None;
+                    record_write(Some(err.to_string()));
+                }
+            }
+        }
+        if let Err(err) = self.close() {
+            warn!("Failed to close JSON Lines file: {err}");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory in which to write the files
+    pub directory: PathBuf,
+    /// Start of each filename
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Size in bytes after which to start a new file
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+fn default_prefix() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+    use crate::json::Interner;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    /// Write two updates with the given compression, each with a separate
+    /// receiver to check that appending works, and return the decompressed
+    /// contents of the file
+    fn write_updates(compression: Compression, decompress: fn(File) -> String) -> String {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let dir = std::env::temp_dir().join(format!(
+            "sunsniff-jsonl-{compression:?}-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            directory: dir.clone(),
+            prefix: "test".to_owned(),
+            max_size: None,
+            compression,
+        };
+        for (timestamp, value) in [(0, 1.0), (500_000_000, -2.5)] {
+            let mut receiver = JsonlReceiver::new(&config);
+            receiver
+                .write(&Update::new(timestamp, "1234", fields, vec![value]))
+                .unwrap();
+            receiver.close().unwrap();
+        }
+        let path = dir.join(format!("test-19700101-000000.{}", compression.extension()));
+        let text = decompress(File::open(&path).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        text
+    }
+
+    fn check_updates(text: &str) {
+        let mut interner = Interner::new();
+        let updates: Vec<Update<'static>> = text
+            .lines()
+            .map(|line| interner.decode(serde_json::from_str(line).unwrap()))
+            .collect();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].timestamp, 0);
+        assert_eq!(updates[0].values, vec![1.0]);
+        assert_eq!(updates[1].timestamp, 500_000_000);
+        assert_eq!(updates[1].values, vec![-2.5]);
+        assert_eq!(updates[1].fields[0].id, "grid_power");
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let text = write_updates(Compression::Gzip, |file| {
+            let mut text = String::new();
+            MultiGzDecoder::new(file).read_to_string(&mut text).unwrap();
+            text
+        });
+        check_updates(&text);
+    }
+
+    #[test]
+    fn test_zstd_round_trip() {
+        let text = write_updates(Compression::Zstd, |file| {
+            let mut text = String::new();
+            zstd::Decoder::new(file)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        });
+        check_updates(&text);
+    }
+}
@@ -0,0 +1,194 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend that consumes packets from a Kafka topic. Each message holds one
+//! packet (the TCP payload), as published by a remote capture agent. This
+//! allows packets from several sites to be decoded centrally.
+
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use futures::channel::mpsc::{self, UnboundedSender};
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use log::{error, warn};
+use serde::Deserialize;
+
+use crate::fields::FieldConfig;
+use crate::frontend::{FieldWatch, Frontend, Reloading};
+use crate::packet::Codec;
+use crate::receiver::{UpdateItem, UpdateStream};
+
+/// Structure corresponding to the `[kafka]` section of the configuration file.
+/// It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// Bootstrap brokers, as host:port
+    brokers: Vec<String>,
+    topic: String,
+    /// Consumer group, used to store the offsets that have been consumed
+    #[serde(default = "default_group")]
+    group: String,
+    timezone: Tz,
+}
+
+fn default_group() -> String {
+    String::from("sunsniff")
+}
+
+/// Decode the values of messages from one partition of a topic (named by
+/// `source` in log messages), sending the updates to `sender`. Returns
+/// `false` if the receiver has shut down.
+fn forward<'a>(
+    codec: &Codec,
+    messages: impl IntoIterator<Item = (i64, &'a [u8])>,
+    source: &str,
+    sender: &UnboundedSender<UpdateItem>,
+) -> bool {
+    for (offset, value) in messages {
+        match codec.decode_payload(value) {
+            Some(update) => {
+                if sender.unbounded_send(update).is_err() {
+                    return false;
+                }
+            }
+            None => warn!("Message at offset {offset} of {source} is not a known packet"),
+        }
+    }
+    true
+}
+
+/// Decode the messages from the consumer, sending the updates to `sender`.
+/// This blocks until the receiver is closed or an error occurs.
+fn consume(
+    mut consumer: Consumer,
+    codec: &mut Reloading<Codec>,
+    sender: &UnboundedSender<UpdateItem>,
+) -> kafka::Result<()> {
+    loop {
+        let message_sets = consumer.poll()?;
+        for message_set in message_sets.iter() {
+            let source = format!("{}:{}", message_set.topic(), message_set.partition());
+            let messages = message_set.messages().iter();
+            if !forward(
+                codec.get(),
+                messages.map(|message| (message.offset, message.value)),
+                &source,
+                sender,
+            ) {
+                return Ok(()); // The receiver has shut down
+            }
+            consumer.consume_messageset(message_set)?;
+        }
+        consumer.commit_consumed()?;
+    }
+}
+
+pub fn create_stream(
+    config: &KafkaConfig,
+    field_config: FieldWatch,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let tz = config.timezone;
+    let mut codec = Reloading::new(field_config, move |field_config| {
+        Codec::new(tz, field_config)
+    })?;
+    let consumer = Consumer::from_hosts(config.brokers.clone())
+        .with_topic(config.topic.clone())
+        .with_group(config.group.clone())
+        .with_fallback_offset(FetchOffset::Latest)
+        .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+        .create()?;
+    let (sender, receiver) = mpsc::unbounded();
+    let topic = config.topic.clone();
+    // The kafka crate is synchronous, so consume on a separate thread.
+    std::thread::spawn(move || {
+        if let Err(err) = consume(consumer, &mut codec, &sender) {
+            error!("Error consuming from {topic}: {err}");
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[async_trait(?Send)]
+impl Frontend for KafkaConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, field_config)
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        Codec::new(self.timezone, field_config)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::test::{sample_packet, PAYLOAD_OFFSET};
+    use futures::StreamExt;
+
+    fn parse(extra: &str) -> Result<KafkaConfig, toml::de::Error> {
+        toml::from_str(&format!(
+            "brokers = [\"localhost:9092\"]\ntopic = \"packets\"\n\
+             timezone = \"Africa/Johannesburg\"\n{extra}"
+        ))
+    }
+
+    #[test]
+    fn test_config() {
+        let config = parse("").unwrap();
+        assert_eq!(config.brokers, vec!["localhost:9092"]);
+        assert_eq!(config.group, "sunsniff");
+        assert_eq!(parse("group = \"site1\"").unwrap().group, "site1");
+        assert!(parse("partition = 1").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let config = parse("").unwrap();
+        assert!(config.check(&FieldConfig::default()).is_ok());
+        let field_config: FieldConfig =
+            toml::from_str("inverter = { layout = \"three_phase\" }").unwrap();
+        assert!(config.check(&field_config).is_err());
+    }
+
+    #[test]
+    fn test_forward() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        let (sender, mut receiver) = mpsc::unbounded();
+        // A packet, then a message that is not a packet, then another packet
+        let messages = [(0, payload), (1, &b"garbage"[..]), (2, payload)];
+        assert!(forward(&codec, messages, "packets:0", &sender));
+        drop(sender);
+        let updates: Vec<UpdateItem> = futures::executor::block_on(receiver.by_ref().collect());
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].serial, updates[1].serial);
+    }
+
+    #[test]
+    fn test_forward_closed() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let packet = sample_packet();
+        let (sender, receiver) = mpsc::unbounded();
+        drop(receiver);
+        let messages = [(0, &packet[PAYLOAD_OFFSET..])];
+        assert!(!forward(&codec, messages, "packets:0", &sender));
+    }
+}
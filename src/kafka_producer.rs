@@ -0,0 +1,311 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that produces each update as a message to a Kafka topic, keyed by
+//! the inverter serial number. Messages are either JSON (see
+//! [UpdateRecord]) or Avro (see `avro/update.avsc`).
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use kafka::producer::{Compression, ProduceConfirm, Producer, Record, RequiredAcks};
+use log::info;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::json::{FieldMeta, FieldValue, UpdateRecord};
+use super::receiver::{Receiver, Update};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Avro,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionConfig {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+}
+
+impl From<CompressionConfig> for Compression {
+    fn from(value: CompressionConfig) -> Self {
+        match value {
+            CompressionConfig::None => Compression::NONE,
+            CompressionConfig::Gzip => Compression::GZIP,
+            CompressionConfig::Snappy => Compression::SNAPPY,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Acks {
+    None,
+    #[default]
+    One,
+    All,
+}
+
+impl From<Acks> for RequiredAcks {
+    fn from(value: Acks) -> Self {
+        match value {
+            Acks::None => RequiredAcks::None,
+            Acks::One => RequiredAcks::One,
+            Acks::All => RequiredAcks::All,
+        }
+    }
+}
+
+/// Minimal encoder for the Avro binary format
+mod avro {
+    pub(super) fn long(out: &mut Vec<u8>, value: i64) {
+        // Zig-zag encoding, then a variable-length integer
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    pub(super) fn string(out: &mut Vec<u8>, value: &str) {
+        long(out, value.len() as i64);
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    pub(super) fn double(out: &mut Vec<u8>, value: f64) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write an array as a single block
+    pub(super) fn array<T>(out: &mut Vec<u8>, items: &[T], mut item: impl FnMut(&mut Vec<u8>, &T)) {
+        if !items.is_empty() {
+            long(out, items.len() as i64);
+            for value in items.iter() {
+                item(out, value);
+            }
+        }
+        long(out, 0);
+    }
+}
+
+fn encode_meta(out: &mut Vec<u8>, meta: &FieldMeta) {
+    avro::string(out, &meta.id);
+    avro::string(out, &meta.group);
+    avro::string(out, &meta.name);
+    let field_type = serde_json::to_value(meta.field_type).unwrap();
+    avro::string(out, field_type.as_str().unwrap());
+    avro::string(out, &meta.unit);
+    avro::array(out, &meta.labels, |out, (value, label)| {
+        avro::long(out, *value);
+        avro::string(out, label);
+    });
+}
+
+/// Encode a record according to `avro/update.avsc`. If `schema_id` is
+/// given, the message is framed as expected by the Confluent schema
+/// registry serializers.
+fn encode_avro(record: &UpdateRecord, schema_id: Option<u32>) -> Vec<u8> {
+    let mut out = vec![];
+    if let Some(schema_id) = schema_id {
+        out.push(0); // Magic byte
+        out.extend_from_slice(&schema_id.to_be_bytes());
+    }
+    avro::long(&mut out, record.timestamp);
+    avro::string(&mut out, &record.serial);
+    avro::array(&mut out, &record.fields, |out, field: &FieldValue<f64>| {
+        encode_meta(out, &field.meta);
+        avro::double(out, field.value);
+    });
+    avro::array(&mut out, &record.text, |out, field: &FieldValue<String>| {
+        encode_meta(out, &field.meta);
+        avro::string(out, &field.value);
+    });
+    out
+}
+
+/// Turn the first per-partition error (if any) into an error
+fn check_confirms(confirms: &[ProduceConfirm]) -> kafka::Result<()> {
+    for confirm in confirms.iter() {
+        for partition in confirm.partition_confirms.iter() {
+            if let Err(code) = partition.offset {
+                return Err(kafka::Error::TopicPartitionError {
+                    topic_name: confirm.topic.clone(),
+                    partition_id: partition.partition,
+                    error_code: code,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A message key (serial number) and value
+type Message = (String, Vec<u8>);
+
+pub struct KafkaProducerReceiver {
+    brokers: Vec<String>,
+    topic: String,
+    format: Format,
+    schema_id: Option<u32>,
+    compression: CompressionConfig,
+    acks: Acks,
+    batch: Options,
+    /// Created on first use, and discarded after an error
+    producer: Arc<Mutex<Option<Producer>>>,
+}
+
+impl KafkaProducerReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            brokers: config.brokers.clone(),
+            topic: config.topic.clone(),
+            format: config.format,
+            schema_id: config.schema_id,
+            compression: config.compression,
+            acks: config.acks,
+            batch: config.batch.clone(),
+            producer: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for KafkaProducerReceiver {
+    type Item = Message;
+    type Error = kafka::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Message> {
+        let record = UpdateRecord::new(update);
+        let value = match self.format {
+            Format::Json => serde_json::to_vec(&record).unwrap(),
+            Format::Avro => encode_avro(&record, self.schema_id),
+        };
+        vec![(update.serial.clone(), value)]
+    }
+
+    async fn write(&self, messages: Vec<Message>) -> kafka::Result<()> {
+        let producer = Arc::clone(&self.producer);
+        let topic = self.topic.clone();
+        let builder = Producer::from_hosts(self.brokers.clone())
+            .with_compression(self.compression.into())
+            .with_required_acks(self.acks.into());
+        // The kafka crate is synchronous, so use a separate thread
+        tokio::task::spawn_blocking(move || {
+            let mut producer = producer.lock().unwrap();
+            if producer.is_none() {
+                // This connects to the brokers
+                *producer = Some(builder.create()?);
+                info!("Connected to Kafka");
+            }
+            let records: Vec<_> = messages
+                .iter()
+                .map(|(key, value)| Record::from_key_value(&topic, key.as_str(), value.as_slice()))
+                .collect();
+            let result = producer
+                .as_mut()
+                .unwrap()
+                .send_all(&records)
+                .and_then(|confirms| check_confirms(&confirms));
+            if result.is_err() {
+                *producer = None; // Reconnect for the next attempt
+            }
+            result
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[async_trait]
+impl Receiver for KafkaProducerReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Bootstrap brokers, as host:port
+    pub brokers: Vec<String>,
+    pub topic: String,
+    #[serde(default)]
+    pub format: Format,
+    /// Schema registry ID of the Avro schema
+    pub schema_id: Option<u32>,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub acks: Acks,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_long() {
+        for (value, expected) in [
+            (0, &[0x00][..]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (-64, &[0x7f]),
+            (64, &[0x80, 0x01]),
+        ] {
+            let mut out = vec![];
+            avro::long(&mut out, value);
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn test_encode_avro() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "G",
+            name: "N",
+            id: "i",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(2, "L")],
+            bit: None,
+        }]));
+        let update = Update::new(3, "s", fields, vec![2.0]);
+        let record = UpdateRecord::new(&update);
+        let mut expected = vec![0, 0, 0, 0, 7];
+        expected.extend_from_slice(b"\x06\x02s\x02\x02i\x02G\x02N\x08Enum\x00");
+        expected.extend_from_slice(b"\x02\x04\x02L\x00");
+        expected.extend_from_slice(&2.0f64.to_le_bytes());
+        expected.extend_from_slice(b"\x00\x00");
+        assert_eq!(encode_avro(&record, Some(7)), expected);
+        assert_eq!(encode_avro(&record, None), expected[5..]);
+    }
+}
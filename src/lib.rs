@@ -15,17 +15,218 @@
  */
 
 #![doc = include_str!("../README.md")]
+#![allow(clippy::doc_lazy_continuation)]
 
-#[cfg(all(not(feature = "pcap"), not(feature = "modbus")))]
+#[cfg(not(any(
+    feature = "hex",
+    feature = "kafka",
+    feature = "modbus",
+    feature = "mqtt",
+    feature = "pcap",
+    feature = "proxy",
+    feature = "rawsock"
+)))]
 compile_error!("At least one frontend feature must be enabled");
 
+pub mod aggregate;
+#[cfg(any(
+    feature = "chat",
+    feature = "email",
+    feature = "push",
+    feature = "telegram"
+))]
+mod alerts;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+#[cfg(feature = "api")]
+pub mod api;
+#[cfg(feature = "aws_iot")]
+pub mod aws_iot;
+#[cfg(feature = "azure_iot")]
+pub mod azure_iot;
+pub mod backend;
+#[cfg(any(
+    feature = "amqp",
+    feature = "azure_iot",
+    feature = "clickhouse",
+    feature = "elasticsearch",
+    feature = "emoncms",
+    feature = "graphite",
+    feature = "influxdb1",
+    feature = "influxdb2",
+    feature = "kafka_producer",
+    feature = "mongodb",
+    feature = "mysql",
+    feature = "nats",
+    feature = "otlp",
+    feature = "postgres",
+    feature = "pubsub",
+    feature = "questdb",
+    feature = "splunk",
+    feature = "sqlite",
+    feature = "victoriametrics",
+    feature = "zabbix"
+))]
+mod batch;
+#[cfg(feature = "chat")]
+pub mod chat;
+#[cfg(feature = "clickhouse")]
+pub mod clickhouse;
+#[cfg(feature = "csvfile")]
+pub mod csvfile;
+pub mod deadband;
+pub mod derived;
+#[cfg(feature = "domoticz")]
+pub mod domoticz;
+#[cfg(feature = "elasticsearch")]
+pub mod elasticsearch;
+#[cfg(feature = "email")]
+pub mod email;
+#[cfg(feature = "emoncms")]
+pub mod emoncms;
 pub mod fields;
+pub mod filter;
+pub mod frontend;
+#[cfg(feature = "grafana_live")]
+pub mod grafana_live;
+#[cfg(feature = "graphite")]
+pub mod graphite;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+#[cfg(feature = "hex")]
+pub mod hex;
+#[cfg(feature = "influxdb1")]
+pub mod influxdb1;
 #[cfg(feature = "influxdb2")]
 pub mod influxdb2;
+pub mod inverters;
+pub mod json;
+#[cfg(feature = "jsonl")]
+pub mod jsonl;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "kafka_producer")]
+pub mod kafka_producer;
+#[cfg(any(
+    feature = "grafana_live",
+    feature = "influxdb1",
+    feature = "questdb",
+    feature = "victoriametrics"
+))]
+mod line_protocol;
 #[cfg(feature = "modbus")]
 pub mod modbus;
+#[cfg(feature = "mongodb")]
+pub mod mongodb;
 #[cfg(feature = "mqtt")]
 pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_ingest;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(any(
+    feature = "hex",
+    feature = "kafka",
+    feature = "pcap",
+    feature = "proxy",
+    feature = "rawsock"
+))]
+pub mod packet;
+#[cfg(feature = "parquet")]
+pub mod parquet;
 #[cfg(feature = "pcap")]
 pub mod pcap;
+pub mod pipeline;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod preview;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "pubsub")]
+pub mod pubsub;
+#[cfg(feature = "push")]
+pub mod push;
+#[cfg(feature = "pvoutput")]
+pub mod pvoutput;
+#[cfg(feature = "questdb")]
+pub mod questdb;
+#[cfg(feature = "rawsock")]
+pub mod rawsock;
 pub mod receiver;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod registry;
+pub mod reload;
+pub mod rename;
+mod rotate;
+#[cfg(feature = "modbus")]
+pub mod rs485;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod shutdown;
+#[cfg(any(
+    feature = "hex",
+    feature = "kafka",
+    feature = "pcap",
+    feature = "proxy",
+    feature = "rawsock"
+))]
+pub mod solarman;
+#[cfg(feature = "splunk")]
+pub mod splunk;
+#[cfg(any(
+    feature = "amqp",
+    feature = "azure_iot",
+    feature = "clickhouse",
+    feature = "elasticsearch",
+    feature = "emoncms",
+    feature = "graphite",
+    feature = "influxdb1",
+    feature = "influxdb2",
+    feature = "kafka_producer",
+    feature = "mongodb",
+    feature = "mysql",
+    feature = "nats",
+    feature = "otlp",
+    feature = "postgres",
+    feature = "pubsub",
+    feature = "questdb",
+    feature = "splunk",
+    feature = "sqlite",
+    feature = "victoriametrics",
+    feature = "zabbix"
+))]
+mod spool;
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+pub mod tags;
+#[cfg(feature = "telegram")]
+pub mod telegram;
+#[cfg(test)]
+mod test_util;
+#[cfg(any(feature = "amqp", feature = "email", feature = "mqtt"))]
+mod tls;
+pub mod transform;
+pub mod units;
+pub mod validate;
+#[cfg(feature = "victoriametrics")]
+pub mod victoriametrics;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+#[cfg(feature = "zabbix")]
+pub mod zabbix;
@@ -0,0 +1,204 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Encoding of updates in the Influxdb line protocol, for backends that
+//! write it directly over HTTP. The schema of [encode] matches the influxdb2
+//! backend.
+
+use std::fmt::Write;
+use std::iter::zip;
+
+use crate::receiver::Update;
+
+/// Escape a tag key, tag value or field key
+fn escape_key(s: &str) -> String {
+    s.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Escape a string field value (without the surrounding quotes)
+#[cfg(any(feature = "influxdb1", feature = "victoriametrics"))]
+fn escape_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Append a tag to a line. Empty tags are not allowed by the protocol, so
+/// they are skipped.
+fn push_tag(line: &mut String, key: &str, value: &str) {
+    if !value.is_empty() {
+        write!(line, ",{}={}", escape_key(key), escape_key(value)).unwrap();
+    }
+}
+
+/// Encode an update as one line per field, each ending with a newline. If
+/// `labels` is false, the labels of enum fields are omitted (for servers that
+/// do not support string fields). Values that are missing are omitted, as
+/// the protocol cannot represent them.
+#[cfg(any(feature = "influxdb1", feature = "victoriametrics"))]
+pub(crate) fn encode(update: &Update<'_>, labels: bool) -> String {
+    let mut out = String::new();
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if !value.is_finite() {
+            continue;
+        }
+        out.push_str("inverter");
+        push_tag(&mut out, "serial", &update.serial);
+        push_tag(&mut out, "group", field.group);
+        push_tag(&mut out, "name", field.name);
+        push_tag(&mut out, "unit", field.unit);
+        // Text fields are attached to every point as tags
+        for (text_field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            push_tag(&mut out, text_field.id, text);
+        }
+        write!(out, " value={value:?}").unwrap();
+        if let Some(label) = field.label(*value).filter(|_| labels) {
+            write!(out, ",label=\"{}\"", escape_string(label)).unwrap();
+        }
+        writeln!(out, " {}", update.timestamp).unwrap();
+    }
+    out
+}
+
+/// Encode an update as a single line, with one field per value (named by
+/// the field ID) and the serial number as the only tag. Values that are
+/// missing are omitted, and an empty string is returned if there are none.
+#[cfg(any(feature = "grafana_live", feature = "questdb"))]
+pub(crate) fn encode_wide(update: &Update<'_>, measurement: &str) -> String {
+    let mut values = String::new();
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if value.is_finite() {
+            let sep = if values.is_empty() { "" } else { "," };
+            write!(values, "{sep}{}={value:?}", escape_key(field.id)).unwrap();
+        }
+    }
+    if values.is_empty() {
+        return values;
+    }
+    let mut out = escape_key(measurement);
+    push_tag(&mut out, "serial", &update.serial);
+    writeln!(out, " {values} {}", update.timestamp).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    #[cfg(any(feature = "influxdb1", feature = "victoriametrics"))]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            Field {
+                field_type: FieldType::Power,
+                group: "Grid",
+                name: "Power, total",
+                id: "grid_power",
+                scale: 1.0,
+                bias: 0.0,
+                signed: true,
+                unit: "W",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+            Field {
+                field_type: FieldType::Enum,
+                group: "Inverter",
+                name: "State",
+                id: "inverter_state",
+                scale: 1.0,
+                bias: 0.0,
+                signed: false,
+                unit: "",
+                requires: None,
+                labels: &[(2, "Normal \"on\"")],
+                bit: None,
+            },
+        ]));
+        let text_fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Text,
+            group: "Inverter",
+            name: "Version",
+            id: "version",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1234, "5678", fields, vec![-100.0, 2.0])
+            .with_text(text_fields, vec!["1.2 a=b".to_owned()]);
+        assert_eq!(
+            encode(&update, true),
+            "inverter,serial=5678,group=Grid,name=Power\\,\\ total,unit=W,version=1.2\\ a\\=b value=-100.0 1234\n\
+             inverter,serial=5678,group=Inverter,name=State,version=1.2\\ a\\=b value=2.0,label=\"Normal \\\"on\\\"\" 1234\n"
+        );
+        assert!(!encode(&update, false).contains("label"));
+        let update = Update::new(1234, "5678", fields, vec![f64::NAN, f64::INFINITY]);
+        assert_eq!(encode(&update, true), "");
+        let update = Update::new(1234, "5678", fields, vec![f64::NAN, 2.0]);
+        assert!(encode(&update, true).starts_with("inverter,serial=5678,group=Inverter,"));
+    }
+
+    #[test]
+    #[cfg(any(feature = "grafana_live", feature = "questdb"))]
+    fn test_encode_wide() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            Field {
+                field_type: FieldType::Power,
+                group: "Grid",
+                name: "Power",
+                id: "grid_power",
+                scale: 1.0,
+                bias: 0.0,
+                signed: true,
+                unit: "W",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+            Field {
+                field_type: FieldType::StateOfCharge,
+                group: "Battery",
+                name: "SOC",
+                id: "battery_soc",
+                scale: 1.0,
+                bias: 0.0,
+                signed: false,
+                unit: "%",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+        ]));
+        let update = Update::new(1234, "5678", fields, vec![-100.0, 55.0]);
+        assert_eq!(
+            encode_wide(&update, "my inverter"),
+            "my\\ inverter,serial=5678 grid_power=-100.0,battery_soc=55.0 1234\n"
+        );
+        let update = Update::new(1234, "5678", fields, vec![f64::NAN, 55.0]);
+        assert_eq!(
+            encode_wide(&update, "inverter"),
+            "inverter,serial=5678 battery_soc=55.0 1234\n"
+        );
+        let update = Update::new(1234, "5678", fields, vec![f64::NAN, f64::NAN]);
+        assert_eq!(encode_wide(&update, "inverter"), "");
+    }
+}
@@ -14,114 +14,659 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use async_trait::async_trait;
 use clap::Parser;
 use futures::channel::mpsc::UnboundedSender;
 use futures::prelude::*;
-use futures::stream::FuturesUnordered;
-use futures::try_join;
+use log::{error, info, warn};
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
-#[cfg(feature = "influxdb2")]
-use sunsniff::influxdb2::Influxdb2Receiver;
+use sunsniff::backend::Options;
+use sunsniff::derived::DerivedFields;
+use sunsniff::fields::{FieldConfig, FieldType};
+use sunsniff::frontend::{FieldWatch, Frontend};
+use sunsniff::health::Health;
+#[cfg(feature = "hex")]
+use sunsniff::hex::HexConfig;
+use sunsniff::inverters::{Inverters, Routing};
+#[cfg(feature = "kafka")]
+use sunsniff::kafka::KafkaConfig;
 #[cfg(feature = "modbus")]
 use sunsniff::modbus::ModbusConfig;
 #[cfg(feature = "mqtt")]
-use sunsniff::mqtt::MqttReceiver;
+use sunsniff::mqtt_ingest::MqttIngestConfig;
 #[cfg(feature = "pcap")]
 use sunsniff::pcap::PcapConfig;
-use sunsniff::receiver::{Receiver, Update, UpdateItem};
+use sunsniff::pipeline;
+use sunsniff::preview::{self, Format};
+#[cfg(feature = "proxy")]
+use sunsniff::proxy::ProxyConfig;
+#[cfg(feature = "rawsock")]
+use sunsniff::rawsock::RawsockConfig;
+use sunsniff::receiver::{Update, UpdateStream};
+use sunsniff::registry::{Instance, Registry};
+use sunsniff::reload::Hangup;
+use sunsniff::rename::Renamer;
+#[cfg(feature = "modbus")]
+use sunsniff::rs485::Rs485Config;
+#[cfg(feature = "script")]
+use sunsniff::script::Script;
+use sunsniff::shutdown::Shutdown;
+use sunsniff::transform::{apply_all, Transform};
+use sunsniff::units::UnitConversion;
+use sunsniff::validate::Validation;
+
+/// Time allowed on shutdown for the backends to write out buffered updates
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Parser)]
 #[clap(author, version)]
 struct Args {
     #[clap()]
     config_file: PathBuf,
+    /// Check the configuration file and exit, with a non-zero status if
+    /// there are errors
+    #[clap(long)]
+    check: bool,
+    /// With --check, also create the backends, which checks connectivity
+    /// for those that connect on startup
+    #[clap(long, requires = "check")]
+    probe: bool,
+    /// Print the decoded updates instead of sending them to the backends
+    #[clap(long)]
+    dry_run: bool,
+    /// Format of the updates printed by --dry-run
+    #[clap(long, value_enum, default_value_t, requires = "dry_run")]
+    format: Format,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum InputConfig {
+    #[cfg(feature = "hex")]
+    Hex(HexConfig),
+    #[cfg(feature = "kafka")]
+    Kafka(KafkaConfig),
     #[cfg(feature = "pcap")]
     Pcap(PcapConfig),
     #[cfg(feature = "modbus")]
     Modbus(ModbusConfig),
+    #[cfg(feature = "modbus")]
+    Rs485(Rs485Config),
+    #[cfg(feature = "mqtt")]
+    MqttIngest(MqttIngestConfig),
+    #[cfg(feature = "proxy")]
+    Proxy(ProxyConfig),
+    #[cfg(feature = "rawsock")]
+    Rawsock(RawsockConfig),
+}
+
+#[async_trait(?Send)]
+impl Frontend for InputConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "hex")]
+            InputConfig::Hex(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "kafka")]
+            InputConfig::Kafka(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "pcap")]
+            InputConfig::Pcap(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "modbus")]
+            InputConfig::Modbus(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "modbus")]
+            InputConfig::Rs485(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "mqtt")]
+            InputConfig::MqttIngest(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "proxy")]
+            InputConfig::Proxy(config) => config.create_stream(field_config).await,
+            #[cfg(feature = "rawsock")]
+            InputConfig::Rawsock(config) => config.create_stream(field_config).await,
+        }
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "hex")]
+            InputConfig::Hex(config) => config.check(field_config),
+            #[cfg(feature = "kafka")]
+            InputConfig::Kafka(config) => config.check(field_config),
+            #[cfg(feature = "pcap")]
+            InputConfig::Pcap(config) => config.check(field_config),
+            #[cfg(feature = "modbus")]
+            InputConfig::Modbus(config) => config.check(field_config),
+            #[cfg(feature = "modbus")]
+            InputConfig::Rs485(config) => config.check(field_config),
+            #[cfg(feature = "mqtt")]
+            InputConfig::MqttIngest(config) => config.check(field_config),
+            #[cfg(feature = "proxy")]
+            InputConfig::Proxy(config) => config.check(field_config),
+            #[cfg(feature = "rawsock")]
+            InputConfig::Rawsock(config) => config.check(field_config),
+        }
+    }
+}
+
+impl InputConfig {
+    /// Name of the section of the configuration file
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "hex")]
+            InputConfig::Hex(_) => "hex",
+            #[cfg(feature = "kafka")]
+            InputConfig::Kafka(_) => "kafka",
+            #[cfg(feature = "pcap")]
+            InputConfig::Pcap(_) => "pcap",
+            #[cfg(feature = "modbus")]
+            InputConfig::Modbus(_) => "modbus",
+            #[cfg(feature = "modbus")]
+            InputConfig::Rs485(_) => "rs485",
+            #[cfg(feature = "mqtt")]
+            InputConfig::MqttIngest(_) => "mqtt_ingest",
+            #[cfg(feature = "proxy")]
+            InputConfig::Proxy(_) => "proxy",
+            #[cfg(feature = "rawsock")]
+            InputConfig::Rawsock(_) => "rawsock",
+        }
+    }
 }
 
 /// Structure corresponding to the configuration file. It is constructured
-/// from the config file by serde.
+/// from the config file by serde. Unknown sections are treated as backends,
+/// and rejected if there is no backend of that type.
 #[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
 struct Config {
     #[serde(flatten)]
     input: InputConfig,
-    #[cfg(feature = "influxdb2")]
+    #[serde(flatten)]
+    field_config: FieldConfig,
     #[serde(default)]
-    influxdb2: Vec<sunsniff::influxdb2::Config>,
-    #[cfg(feature = "mqtt")]
+    derived: Vec<sunsniff::derived::Config>,
+    #[cfg(feature = "script")]
+    script: Option<sunsniff::script::Config>,
+    #[serde(default)]
+    units: HashMap<FieldType, String>,
     #[serde(default)]
-    mqtt: Vec<sunsniff::mqtt::Config>,
+    validation: sunsniff::validate::Config,
+    #[serde(default)]
+    inverters: HashMap<String, sunsniff::inverters::Config>,
+    #[serde(default)]
+    rename: HashMap<String, sunsniff::rename::Rename>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[cfg(feature = "health")]
+    health: Option<sunsniff::health::Config>,
+    /// Sections for the backends, keyed by name
+    #[serde(flatten)]
+    backends: BTreeMap<String, toml::Value>,
+    /// Every section, to find those that change when the file is reloaded
+    #[serde(skip)]
+    sections: toml::Table,
+}
+
+/// Everything downstream of the frontend, which is rebuilt when the
+/// configuration is reloaded (except for backends whose sections are
+/// unchanged)
+struct Outputs {
+    transforms: Vec<Box<dyn Transform>>,
+    routing: Routing,
+    backends: Vec<Running>,
+}
+
+/// Backend running in its own task, which keeps running across reloads of
+/// the configuration as long as its section is unchanged
+struct Running {
+    kind: String,
+    /// Section of the configuration file that it was created from
+    section: toml::Value,
+    options: Options,
+    sender: UnboundedSender<Arc<Update<'static>>>,
+    task: JoinHandle<()>,
 }
 
-/// Top-level execution. Receive updates from a stream and distribute them to
-/// multiple receivers.
-async fn run(
-    stream: &mut (dyn Stream<Item = UpdateItem> + Unpin),
-    sinks: &mut [UnboundedSender<Arc<Update<'static>>>],
-) -> Result<(), Box<dyn std::error::Error>> {
-    while let Some(update) = stream.next().await {
-        for sink in sinks.iter_mut() {
-            sink.unbounded_send(Arc::clone(&update))?;
+impl Running {
+    fn start(instance: Instance, section: toml::Value, health: &Health) -> Self {
+        let (sender, stream) = futures::channel::mpsc::unbounded();
+        let health = health.clone();
+        let kind = instance.kind.clone();
+        let mut receiver = instance.receiver;
+        let task = tokio::spawn(async move { health.track(&kind, receiver.run(stream)).await });
+        Self {
+            kind: instance.kind,
+            section,
+            options: instance.options,
+            sender,
+            task,
         }
     }
-    for sink in sinks.iter_mut() {
-        sink.close().await?; // TODO: do these in parallel?
+
+    /// Close the channel to the backend and wait for it to finish
+    async fn stop(self) {
+        self.sender.close_channel();
+        if let Err(err) = self.task.await {
+            error!("Backend {} failed: {err}", self.kind);
+        }
     }
-    Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    let args = Args::parse();
-    let config = std::fs::read_to_string(args.config_file)?;
-    let config: Config = toml::from_str(&config)?;
+/// Stop the backends and wait for them to finish
+async fn stop_all(backends: Vec<Running>) {
+    future::join_all(backends.into_iter().map(Running::stop)).await;
+}
 
-    let mut receivers: Vec<Box<dyn Receiver>> = vec![];
-    #[cfg(feature = "influxdb2")]
-    {
-        for backend in config.influxdb2.iter() {
-            receivers.push(Box::new(Influxdb2Receiver::new(backend).await));
+/// Create and start the backends for the sections of the configuration file
+/// (see [Registry::sections]). Backends in `previous` that are still
+/// running with unchanged sections are reused, and once everything has been
+/// created the others are stopped before the new ones start, leaving
+/// `previous` empty. If there is an error, `previous` is left unchanged.
+async fn start_all(
+    registry: &Registry,
+    sections: &BTreeMap<String, toml::Value>,
+    previous: &mut Vec<Running>,
+    health: &Health,
+) -> Result<Vec<Running>, Box<dyn std::error::Error>> {
+    // For each section, the index in `previous` of the backend to reuse
+    let mut reused: Vec<Option<usize>> = vec![];
+    let mut created = vec![];
+    for (name, section) in Registry::sections(sections)? {
+        let index = (0..previous.len()).find(|&i| {
+            let backend = &previous[i];
+            backend.kind == name
+                && backend.section == *section
+                && !backend.task.is_finished()
+                && !reused.contains(&Some(i))
+        });
+        if index.is_none() {
+            created.push((registry.create(name, section.clone()).await?, section));
         }
+        reused.push(index);
     }
-    #[cfg(feature = "mqtt")]
-    {
-        for backend in config.mqtt.iter() {
-            receivers.push(Box::new(MqttReceiver::new(backend)?));
+
+    let mut slots: Vec<Option<Running>> = previous.drain(..).map(Some).collect();
+    let kept: Vec<Option<Running>> = reused
+        .into_iter()
+        .map(|index| index.and_then(|i| slots[i].take()))
+        .collect();
+    stop_all(slots.into_iter().flatten().collect()).await;
+    let mut created = created.into_iter();
+    Ok(kept
+        .into_iter()
+        .map(|backend| {
+            backend.unwrap_or_else(|| {
+                let (instance, section) = created.next().unwrap();
+                Running::start(instance, section.clone(), health)
+            })
+        })
+        .collect())
+}
+
+/// Build the transforms to apply to all updates, before they are sent to the
+/// backends. Each error is passed to `error`, together with the header of
+/// the section that caused it, and the transform is left out.
+fn build_transforms(
+    config: &Config,
+    mut error: impl FnMut(&'static str, Box<dyn std::error::Error>),
+) -> Vec<Box<dyn Transform>> {
+    let mut transforms: Vec<Box<dyn Transform>> =
+        vec![Box::new(Validation::new(&config.validation))];
+    match DerivedFields::new(&config.derived) {
+        Ok(derived) => transforms.push(Box::new(derived)),
+        Err(err) => error("[[derived]]", err.into()),
+    }
+    #[cfg(feature = "script")]
+    if let Some(script) = &config.script {
+        match Script::new(script) {
+            Ok(script) => transforms.push(Box::new(script)),
+            Err(err) => error("[script]", err),
         }
     }
+    match UnitConversion::new(&config.units) {
+        Ok(units) => transforms.push(Box::new(units)),
+        Err(err) => error("[units]", err.into()),
+    }
+    transforms.push(Box::new(Inverters::new(&config.inverters)));
+    transforms.push(Box::new(Renamer::new(&config.rename)));
+    transforms
+}
+
+/// Transforms to apply to all updates, before they are sent to the backends
+fn transforms(config: &Config) -> Result<Vec<Box<dyn Transform>>, Box<dyn std::error::Error>> {
+    let mut first = None;
+    let transforms = build_transforms(config, |header, err| {
+        first.get_or_insert_with(|| format!("{header}: {err}"));
+    });
+    match first {
+        Some(err) => Err(err.into()),
+        None => Ok(transforms),
+    }
+}
+
+/// Routing of updates to the backends, checked against the backend sections
+fn routing(config: &Config) -> Result<Routing, Box<dyn std::error::Error>> {
+    let routing = Routing::new(&config.inverters);
+    let kinds: Vec<&str> = config.backends.keys().map(String::as_str).collect();
+    routing.check(&kinds)?;
+    Ok(routing)
+}
+
+impl Outputs {
+    async fn new(config: &Config, health: &Health) -> Result<Self, Box<dyn std::error::Error>> {
+        let transforms = transforms(config)?;
+        let routing = routing(config)?;
+        let backends =
+            start_all(&Registry::builtin(), &config.backends, &mut vec![], health).await?;
+        Ok(Self {
+            transforms,
+            routing,
+            backends,
+        })
+    }
 
-    let mut sinks = vec![];
-    let futures = FuturesUnordered::new();
-    for receiver in receivers.iter_mut() {
-        let (sink, stream) = futures::channel::mpsc::unbounded();
-        futures.push(receiver.run(stream));
-        sinks.push(sink);
+    /// Pass updates from the stream to the backends until either the stream
+    /// ends (returning true) or `stop` completes. The backends keep running.
+    async fn run(
+        &mut self,
+        stream: &mut UpdateStream,
+        tags: &BTreeMap<String, String>,
+        stop: impl Future<Output = ()>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut sinks: Vec<pipeline::Sink> = self
+            .backends
+            .iter()
+            .map(|backend| pipeline::Sink {
+                backend: &backend.kind,
+                transforms: backend.options.transforms(tags),
+                // Closing a clone leaves the channel open
+                sender: backend.sender.clone(),
+            })
+            .collect();
+        pipeline::run_until(
+            stream,
+            &mut self.transforms,
+            &self.routing,
+            &mut sinks,
+            stop,
+        )
+        .await
     }
+}
+
+fn load_config(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut config: Config = toml::from_str(&text)?;
+    config.sections = toml::from_str(&text)?;
+    Ok(config)
+}
+
+/// Load the configuration file again and replace `outputs` with outputs
+/// built from it, returning the new configuration. Backends whose sections
+/// are unchanged keep running, and everything else is built or checked
+/// before the old backends are stopped, so that if there is an error they
+/// keep running. The frontend is not replaced, but the new field
+/// configuration must apply to it.
+async fn reload(
+    path: &Path,
+    config: &Config,
+    outputs: &mut Outputs,
+    health: &Health,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    let new_config = load_config(path)?;
+    let name = config.input.name();
+    config
+        .input
+        .check(&new_config.field_config)
+        .map_err(|err| format!("[{name}]: {err}"))?;
+    let transforms = transforms(&new_config)?;
+    let routing = routing(&new_config)?;
+    let registry = Registry::builtin();
+    registry.check_all(&new_config.backends)?;
+    let mut previous = std::mem::take(&mut outputs.backends);
+    let backends = match start_all(&registry, &new_config.backends, &mut previous, health).await {
+        Ok(backends) => backends,
+        Err(err) => {
+            // The old backends may hold resources that the new ones need
+            // (such as listening ports), so stop those that are being
+            // replaced and try again. Servers are stopped by tasks which are
+            // woken when the backends finish, so let them run.
+            warn!("Could not create the new backends alongside the old ones ({err})");
+            let (mut kept, replaced) = previous.into_iter().partition(|backend| {
+                match new_config.backends.get(&backend.kind) {
+                    Some(toml::Value::Array(sections)) => sections.contains(&backend.section),
+                    _ => false,
+                }
+            });
+            stop_all(replaced).await;
+            tokio::task::yield_now().await;
+            match start_all(&registry, &new_config.backends, &mut kept, health).await {
+                Ok(backends) => backends,
+                Err(err) => {
+                    outputs.backends =
+                        match start_all(&registry, &config.backends, &mut kept, health).await {
+                            Ok(backends) => backends,
+                            Err(old_err) => {
+                                error!("Could not recreate the previous backends: {old_err}");
+                                stop_all(kept).await;
+                                vec![]
+                            }
+                        };
+                    health.forget_stopped();
+                    return Err(err);
+                }
+            }
+        }
+    };
+    health.forget_stopped();
+    *outputs = Outputs {
+        transforms,
+        routing,
+        backends,
+    };
+    Ok(new_config)
+}
 
-    // TODO: better handling of errors from receivers
-    let mut stream = match &config.input {
-        #[cfg(feature = "pcap")]
-        InputConfig::Pcap(pcap_config) => sunsniff::pcap::create_stream(pcap_config)?,
-        #[cfg(feature = "modbus")]
-        InputConfig::Modbus(modbus_config) => {
-            sunsniff::modbus::create_stream(modbus_config).await?
+/// Warn about changes to the sections that only take effect after a
+/// restart, compared to the `started` sections (those of the configuration
+/// that the process started with)
+fn warn_not_reloaded(started: &toml::Table, input: &InputConfig, new_config: &Config) {
+    let mut names = vec![input.name(), new_config.input.name(), "health"];
+    names.dedup();
+    for name in names {
+        if started.get(name) != new_config.sections.get(name) {
+            warn!("Changes to [{name}] take effect after a restart");
         }
+    }
+}
+
+/// Wait for a request to reload the configuration or (setting `stopping`)
+/// to shut down
+async fn wait_for_signal(hangup: &mut Hangup, shutdown: &mut Shutdown, stopping: &mut bool) {
+    tokio::select! {
+        _ = hangup.recv() => {}
+        _ = shutdown.recv() => {
+            info!("Shutting down");
+            *stopping = true;
+        }
+    }
+}
+
+/// Line number (1-based) of the `index`th section with the given header
+/// (such as `[name]` or `[[name]]`) in the configuration file, if it can be
+/// found. The tables nested in a `[name]` section (such as `[name.key]`)
+/// also count.
+fn section_line(text: &str, header: &str, index: usize) -> Option<usize> {
+    let name = header.trim_matches(|c| c == '[' || c == ']');
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            line.strip_prefix("[[")
+                .and_then(|line| line.strip_suffix("]]"))
+                .or_else(|| line.strip_prefix('[')?.strip_suffix(']'))
+                .is_some_and(|found| {
+                    let found = found.trim();
+                    found == name
+                        || found
+                            .strip_prefix(name)
+                            .is_some_and(|rest| rest.starts_with('.'))
+                })
+        })
+        .nth(index)
+        .map(|(i, _)| i + 1)
+}
+
+/// Check the configuration file, building everything that is built on
+/// startup, and return all the errors found. Only the first error is found
+/// when the file cannot be parsed.
+async fn check_config(path: &Path, probe: bool) -> Vec<String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => return vec![format!("{path:?}: {err}")],
+    };
+    let config: Config = match toml::from_str(&text) {
+        Ok(config) => config,
+        Err(err) => return vec![err.to_string().trim_end().to_owned()],
+    };
+    let mut errors = vec![];
+    // Report an error in the `index`th section with the given header
+    let mut report = |header: &str, index: usize, err: &dyn std::fmt::Display| {
+        errors.push(match section_line(&text, header, index) {
+            Some(line) => format!("line {line}: {err}"),
+            None => err.to_string(),
+        });
     };
-    try_join!(
-        run(&mut stream, &mut sinks),
-        futures.collect::<Vec<_>>().map(Ok)
-    )?;
+    let header = format!("[{}]", config.input.name());
+    if let Err(err) = config.input.check(&config.field_config) {
+        report(&header, 0, &format!("{header}: {err}"));
+    }
+    build_transforms(&config, |header, err| {
+        report(header, 0, &format!("{header}: {err}"));
+    });
+    if let Err(err) = routing(&config) {
+        report("[inverters]", 0, &format!("[inverters]: {err}"));
+    }
+
+    let registry = Registry::builtin();
+    for (name, value) in config.backends.iter() {
+        let toml::Value::Array(sections) = value else {
+            report(
+                name,
+                0,
+                &format!("`{name}` must be an array of tables ([[{name}]])"),
+            );
+            continue;
+        };
+        for (index, section) in sections.iter().enumerate() {
+            let result = if probe {
+                match registry.create(name, section.clone()).await {
+                    Ok(mut instance) => instance
+                        .receiver
+                        .probe()
+                        .await
+                        .map_err(|err| format!("[[{name}]]: {err}").into()),
+                    Err(err) => Err(err),
+                }
+            } else {
+                registry.check(name, section.clone())
+            };
+            if let Err(err) = result {
+                report(name, index, &err);
+            }
+        }
+    }
+    errors
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let args = Args::parse();
+    if args.check {
+        let errors = check_config(&args.config_file, args.probe).await;
+        if errors.is_empty() {
+            println!("{:?}: configuration is valid", args.config_file);
+            return Ok(());
+        }
+        for err in errors.iter() {
+            eprintln!("error: {err}");
+        }
+        eprintln!("{:?}: {} error(s) found", args.config_file, errors.len());
+        std::process::exit(1);
+    }
+    let mut config = load_config(&args.config_file)?;
+    if args.dry_run {
+        let mut transforms = transforms(&config)?;
+        let (_, field_config) = watch::channel(Arc::new(config.field_config.clone()));
+        let mut stream = config.input.create_stream(field_config).await?;
+        while let Some(update) = stream.next().await {
+            if let Some(update) = apply_all(&mut transforms, update) {
+                print!("{}", preview::format(&update, args.format));
+            }
+        }
+        return Ok(());
+    }
+    let mut hangup = Hangup::install()?;
+    let mut shutdown = Shutdown::install()?;
+    let health = Health::new();
+    #[cfg(feature = "health")]
+    if let Some(health_config) = &config.health {
+        health.serve(health_config)?;
+    }
+    let mut outputs = Outputs::new(&config, &health).await?;
+    // Changes to the fields are sent to the running frontend
+    let (field_sender, field_config) = watch::channel(Arc::new(config.field_config.clone()));
+    let stream = config.input.create_stream(field_config).await?;
+    let received = health.clone();
+    let mut stream: UpdateStream = Box::pin(stream.inspect(move |_| received.received()));
+    let started = config.sections.clone();
+    let mut stopping = false;
+    while !outputs
+        .run(
+            &mut stream,
+            &config.tags,
+            wait_for_signal(&mut hangup, &mut shutdown, &mut stopping),
+        )
+        .await?
+        && !stopping
+    {
+        info!("Reloading configuration from {:?}", args.config_file);
+        match reload(&args.config_file, &config, &mut outputs, &health).await {
+            Ok(new_config) => {
+                warn_not_reloaded(&started, &config.input, &new_config);
+                field_sender.send_replace(Arc::new(new_config.field_config.clone()));
+                // The frontend is kept, with its original configuration
+                config = Config {
+                    input: config.input,
+                    ..new_config
+                };
+                info!("Configuration reloaded");
+            }
+            Err(err) => {
+                error!("Could not apply the new configuration: {err}");
+                warn!("Keeping the previous configuration");
+            }
+        }
+    }
+    // A backend that cannot reach its server may keep retrying, so after a
+    // shutdown request the wait is bounded, and another request (or the
+    // first, if the input ended) exits immediately.
+    tokio::select! {
+        _ = stop_all(outputs.backends) => {}
+        _ = tokio::time::sleep(SHUTDOWN_TIMEOUT), if stopping => {
+            error!("Backends did not stop within {SHUTDOWN_TIMEOUT:?}, exiting anyway");
+            std::process::exit(1);
+        }
+        _ = shutdown.recv() => {
+            error!("Shutdown requested, exiting without waiting for the backends");
+            std::process::exit(1);
+        }
+    }
     Ok(())
 }
@@ -14,11 +14,13 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use async_trait::async_trait;
 use futures::channel::mpsc;
 use futures::prelude::*;
-use log::{error, info};
+use log::{error, info, warn};
 use serde::Deserialize;
 use serde_with::serde_as;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::MissedTickBehavior;
@@ -26,8 +28,12 @@ use tokio_modbus::client::Context;
 use tokio_modbus::prelude::Reader;
 use tokio_modbus::slave::Slave;
 
+use crate::fields::{merge_fields, FieldConfig, FieldSet, Layout};
+use crate::frontend::{FieldWatch, Frontend, Reloading};
 use crate::receiver::{Update, UpdateStream};
 
+pub(crate) const REG_SERIAL: u16 = 3;
+pub(crate) const SERIAL_WORDS: u16 = 5;
 const REG_CLOCK: u16 = 22;
 const NUM_PROGRAMS: usize = 6;
 
@@ -43,20 +49,180 @@ pub struct ModbusConfig {
     baud: u32,
     #[serde(default = "default_modbus_id")]
     modbus_id: u8,
+    #[serde(default)]
+    parity: Parity,
+    #[serde(default = "default_stop_bits")]
+    stop_bits: u8,
 }
 
-fn default_baud() -> u32 {
+/// Parity setting for the serial port
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+impl From<Parity> for tokio_serial::Parity {
+    fn from(parity: Parity) -> Self {
+        match parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Odd => tokio_serial::Parity::Odd,
+            Parity::Even => tokio_serial::Parity::Even,
+        }
+    }
+}
+
+pub(crate) fn default_baud() -> u32 {
     9600
 }
 
-fn default_modbus_id() -> u8 {
+pub(crate) fn default_modbus_id() -> u8 {
     1
 }
 
-async fn read_values(ctx: &mut Context) -> Result<Vec<f64>, std::io::Error> {
-    let mut values = Vec::with_capacity(FIELDS.len());
+pub(crate) fn default_stop_bits() -> u8 {
+    1
+}
+
+/// Indices of the fields used to compute derived fields. These are
+/// looked up by ID because the set of fields depends on the configuration.
+/// A derived field is left as zero if it or any of its inputs is missing.
+struct DerivedIndices {
+    /// Start time of each program
+    times: Option<[usize; NUM_PROGRAMS]>,
+    /// Power of each program
+    powers: Option<[usize; NUM_PROGRAMS]>,
+    /// Battery SOC of each program
+    socs: Option<[usize; NUM_PROGRAMS]>,
+    power: Option<usize>,
+    soc: Option<usize>,
+    state: Option<usize>,
+    grid_connected: Option<usize>,
+    work_mode: Option<usize>,
+    mode: Option<usize>,
+}
+
+impl DerivedIndices {
+    fn new(fields: &[Field<'_>]) -> Self {
+        let index = |id: &str| fields.iter().position(|f| f.id == id);
+        let programs = |prefix: &str| {
+            let indices: Option<Vec<usize>> = (1..=NUM_PROGRAMS)
+                .map(|i| index(&format!("{prefix}_{i}")))
+                .collect();
+            indices.map(|indices| indices.try_into().unwrap())
+        };
+        let derived = Self {
+            times: programs("inverter_program_time"),
+            powers: programs("inverter_program_power"),
+            socs: programs("inverter_program_soc"),
+            power: index("inverter_program_power"),
+            soc: index("inverter_program_soc"),
+            state: index("inverter_state"),
+            grid_connected: index("grid_connected"),
+            work_mode: index("inverter_work_mode"),
+            mode: index("inverter_mode"),
+        };
+        if derived.power.is_some() && derived.power_sources().is_none() {
+            warn!(
+                "inverter_program_power cannot be computed because the program fields are missing"
+            );
+        }
+        if derived.soc.is_some() && derived.soc_sources().is_none() {
+            warn!("inverter_program_soc cannot be computed because the program fields are missing");
+        }
+        if derived.mode.is_some() && derived.mode_sources().is_none() {
+            warn!("inverter_mode cannot be computed because the inverter state fields are missing");
+        }
+        derived
+    }
+
+    /// Indices to compute `inverter_program_power` from, if it is present
+    fn power_sources(&self) -> Option<(usize, [usize; NUM_PROGRAMS], [usize; NUM_PROGRAMS])> {
+        Some((self.power?, self.times?, self.powers?))
+    }
+
+    /// Indices to compute `inverter_program_soc` from, if it is present
+    fn soc_sources(&self) -> Option<(usize, [usize; NUM_PROGRAMS], [usize; NUM_PROGRAMS])> {
+        Some((self.soc?, self.times?, self.socs?))
+    }
+
+    /// Indices to compute `inverter_mode` from, if it is present
+    fn mode_sources(&self) -> Option<(usize, usize, usize, usize)> {
+        Some((
+            self.mode?,
+            self.state?,
+            self.grid_connected?,
+            self.work_mode?,
+        ))
+    }
+
+    /// Whether the inverter clock is needed to compute the fields
+    fn needs_clock(&self) -> bool {
+        self.power_sources().is_some() || self.soc_sources().is_some()
+    }
+
+    /// Fill in the derived fields of `values`, given the inverter time as
+    /// seconds since midnight (only used if [DerivedIndices::needs_clock]).
+    fn compute(&self, values: &mut [f64], now: f64) {
+        // Which program is current
+        let program = |values: &[f64], times: &[usize; NUM_PROGRAMS]| {
+            (0..(NUM_PROGRAMS - 1))
+                .find(|&i| now >= values[times[i]] && now < values[times[i + 1]])
+                .unwrap_or(NUM_PROGRAMS - 1)
+        };
+        if let Some((power, times, powers)) = self.power_sources() {
+            values[power] = values[powers[program(values, &times)]];
+        }
+        if let Some((soc, times, socs)) = self.soc_sources() {
+            values[soc] = values[socs[program(values, &times)]];
+        }
+        if let Some((mode, state, grid_connected, work_mode)) = self.mode_sources() {
+            values[mode] = inverter_mode(values[state], values[grid_connected], values[work_mode]);
+        }
+    }
+}
+
+/// Compute the value of the `inverter_mode` field. See fields.csv for the
+/// meanings of the values.
+fn inverter_mode(state: f64, grid_connected: f64, work_mode: f64) -> f64 {
+    if state == 4.0 {
+        5.0 // Fault
+    } else if state != 2.0 && state != 3.0 {
+        0.0 // Standby
+    } else if grid_connected == 0.0 {
+        4.0 // Off-grid
+    } else {
+        1.0 + work_mode
+    }
+}
+
+/// Read the text fields. The registers for each field are consecutive, so
+/// each field is read with a single request.
+async fn read_text(
+    ctx: &mut Context,
+    fields: &FieldSet<Vec<u16>>,
+) -> Result<Vec<String>, std::io::Error> {
+    let mut text = Vec::with_capacity(fields.text_fields.len());
+    for (field, regs) in fields.text_fields.iter().zip(fields.text_addresses.iter()) {
+        let parts = ctx
+            .read_holding_registers(regs[0], regs.len() as u16)
+            .await?;
+        text.push(field.text_from_u16s(parts));
+    }
+    Ok(text)
+}
+
+async fn read_values(
+    ctx: &mut Context,
+    fields: &FieldSet<Vec<u16>>,
+    derived: &DerivedIndices,
+) -> Result<Vec<f64>, std::io::Error> {
+    let mut values = Vec::with_capacity(fields.fields.len());
     let mut parts = [0u16; 2];
-    for (field, regs) in FIELDS.iter().zip(REGISTERS.iter()) {
+    for (field, regs) in fields.fields.iter().zip(fields.addresses.iter()) {
         let value;
         if !regs.is_empty() {
             for (i, reg) in regs.iter().enumerate() {
@@ -70,59 +236,176 @@ async fn read_values(ctx: &mut Context) -> Result<Vec<f64>, std::io::Error> {
         values.push(value);
     }
     // Get the inverter time, since that'll determine which program is current
-    let time_regs = ctx.read_holding_registers(REG_CLOCK, 3).await?;
-    let hour = time_regs[1] & 0xff;
-    let minute = time_regs[2] >> 8;
-    let second = time_regs[2] & 0xff;
-    let now = (hour as f64) * 3600.0 + (minute as f64) * 60.0 + (second as f64);
-    let mut prog = NUM_PROGRAMS - 1;
-    for i in 0..(NUM_PROGRAMS - 1) {
-        let start = values[field_idx::INVERTER_PROGRAM_TIME_1 + i];
-        let stop = values[field_idx::INVERTER_PROGRAM_TIME_2 + i];
-        if now >= start && now < stop {
-            prog = i;
-            break;
-        }
+    let mut now = 0.0;
+    if derived.needs_clock() {
+        let time_regs = ctx.read_holding_registers(REG_CLOCK, 3).await?;
+        let hour = time_regs[1] & 0xff;
+        let minute = time_regs[2] >> 8;
+        let second = time_regs[2] & 0xff;
+        now = (hour as f64) * 3600.0 + (minute as f64) * 60.0 + (second as f64);
     }
-    values[field_idx::INVERTER_PROGRAM_POWER] = values[field_idx::INVERTER_PROGRAM_POWER_1 + prog];
-    values[field_idx::INVERTER_PROGRAM_SOC] = values[field_idx::INVERTER_PROGRAM_SOC_1 + prog];
+    derived.compute(&mut values, now);
 
     Ok(values)
 }
 
+/// Determine whether the device is a host:port address for Modbus over TCP
+/// (rather than a serial device). This only checks the syntax, so that a
+/// hostname that fails to resolve is reported rather than opened as a
+/// serial device.
+fn is_tcp(device: &str) -> bool {
+    match device.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty() && !host.contains('/') && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Resolve a host:port address for Modbus over TCP
+async fn resolve_tcp(device: &str) -> Result<SocketAddr, std::io::Error> {
+    tokio::net::lookup_host(device)
+        .await
+        .map_err(|err| {
+            std::io::Error::new(err.kind(), format!("Could not resolve {device}: {err}"))
+        })?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Could not resolve {device}: no addresses found"),
+            )
+        })
+}
+
+/// Connector for Modbus over TCP that resolves the address again on each
+/// reconnection, in case it has changed.
+#[derive(Debug)]
+struct TcpConnector {
+    device: String,
+}
+
+#[async_trait]
+impl modbus_robust::Connector for TcpConnector {
+    type Output = Context;
+
+    async fn connect(&mut self, slave: Slave) -> Result<Context, std::io::Error> {
+        let socket_addr = resolve_tcp(&self.device).await?;
+        tokio_modbus::client::tcp::connect_slave(socket_addr, slave).await
+    }
+}
+
+/// Describe a serial port with the given settings
+pub(crate) fn serial_port(
+    device: &str,
+    baud: u32,
+    parity: Parity,
+    stop_bits: u8,
+) -> Result<tokio_serial::SerialPortBuilder, String> {
+    let stop_bits = match stop_bits {
+        1 => tokio_serial::StopBits::One,
+        2 => tokio_serial::StopBits::Two,
+        n => return Err(format!("Invalid number of stop bits {n} (must be 1 or 2)")),
+    };
+    Ok(tokio_serial::new(device, baud)
+        .parity(parity.into())
+        .stop_bits(stop_bits))
+}
+
+/// Create a context for Modbus RTU over a serial port. This is similar to
+/// [modbus_robust::new_rtu_slave], but allows the parity and stop bits to be
+/// set.
+fn new_rtu_slave(config: &ModbusConfig, slave: Slave) -> Result<Context, String> {
+    let builder = serial_port(&config.device, config.baud, config.parity, config.stop_bits)?;
+    Ok(modbus_robust::new_sync(
+        move |slave| {
+            let serial_stream = tokio_serial::SerialStream::open(&builder)?;
+            Ok(tokio_modbus::client::rtu::attach_slave(
+                serial_stream,
+                slave,
+            ))
+        },
+        slave,
+    ))
+}
+
+/// Determine the fields to read and their registers. Computed fields have
+/// no registers. Three-phase inverters have their own register map.
+pub(crate) fn modbus_fields(field_config: &FieldConfig) -> Result<FieldSet<Vec<u16>>, String> {
+    let (fields, registers) = match field_config.inverter.layout {
+        Layout::ThreePhase => (three_phase::FIELDS, three_phase::REGISTERS),
+        Layout::SinglePhase | Layout::SplitPhase => (FIELDS, REGISTERS),
+    };
+    let registers: Vec<Vec<u16>> = registers.iter().map(|r| r.to_vec()).collect();
+    let field_map = field_config.load_field_map()?;
+    merge_fields(
+        fields,
+        &registers,
+        field_config,
+        field_map.as_deref(),
+        |f| {
+            if f.computed {
+                Some(vec![])
+            } else {
+                f.addresses(f.reg, f.reg2, 1)
+            }
+        },
+    )
+}
+
+/// Decode the inverter serial number from registers 3-7
+pub(crate) fn parse_serial(words: &[u16]) -> Result<String, std::str::Utf8Error> {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    Ok(std::str::from_utf8(&bytes)?.to_owned())
+}
+
 pub async fn create_stream(
     config: &ModbusConfig,
+    field_config: FieldWatch,
 ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let mut fields = Reloading::new(field_config, |field_config| {
+        let fields = modbus_fields(field_config)?;
+        let derived = DerivedIndices::new(fields.fields);
+        Ok((fields, derived))
+    })?;
     let modbus_id = config.modbus_id;
     let interval = config.interval;
     let (mut sender, receiver) = mpsc::channel(1);
     let slave = Slave(modbus_id);
-    let mut ctx = match config.device.parse() {
-        Ok(socket_addr) => modbus_robust::new_tcp_slave(socket_addr, slave),
-        Err(_) => modbus_robust::new_rtu_slave(&config.device, config.baud, slave),
+    let mut ctx = if is_tcp(&config.device) {
+        let connector = TcpConnector {
+            device: config.device.clone(),
+        };
+        modbus_robust::RobustClient::new_context(connector, slave)
+    } else {
+        new_rtu_slave(config, slave)?
     };
-    let serial_words = ctx.read_holding_registers(3, 5).await?;
-    let mut serial_bytes = [0u8; 10];
-    for i in 0..5 {
-        let bytes = serial_words[i].to_be_bytes();
-        serial_bytes[2 * i] = bytes[0];
-        serial_bytes[2 * i + 1] = bytes[1];
-    }
-    let serial = std::str::from_utf8(&serial_bytes)?.to_owned();
+    let serial_words = ctx.read_holding_registers(REG_SERIAL, SERIAL_WORDS).await?;
+    let serial = parse_serial(&serial_words)?;
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(interval);
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         loop {
             interval.tick().await;
-            match read_values(&mut ctx).await {
+            let (fields, derived) = fields.get();
+            let result = match read_values(&mut ctx, fields, derived).await {
+                Ok(values) => read_text(&mut ctx, fields).await.map(|text| (values, text)),
+                Err(err) => Err(err),
+            };
+            match result {
                 Err(err) => {
                     error!("Failed to read values from modbus: {err:?}");
                 }
-                Ok(values) => {
+                Ok((values, text)) => {
                     info!("Received a set of values from modbus");
                     let now = chrono::Utc::now();
-                    let update =
-                        Update::new(now.timestamp_nanos_opt().unwrap(), &serial, FIELDS, values);
+                    let update = Update::new(
+                        now.timestamp_nanos_opt().unwrap(),
+                        &serial,
+                        fields.fields,
+                        values,
+                    )
+                    .with_text(fields.text_fields, text);
                     // TODO: Handle error from send
                     sender.send(Arc::new(update)).await.unwrap();
                 }
@@ -132,4 +415,115 @@ pub async fn create_stream(
     Ok(Box::pin(receiver))
 }
 
+#[async_trait(?Send)]
+impl Frontend for ModbusConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, field_config).await
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        modbus_fields(field_config)?;
+        serial_port(&self.device, self.baud, self.parity, self.stop_bits)?;
+        Ok(())
+    }
+}
+
 include!(concat!(env!("OUT_DIR"), "/modbus_fields.rs"));
+
+mod three_phase {
+    include!(concat!(env!("OUT_DIR"), "/modbus_fields_three_phase.rs"));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_tcp() {
+        assert!(is_tcp("192.168.1.10:502"));
+        assert!(is_tcp("inverter.local:8899"));
+        assert!(is_tcp("[::1]:502"));
+        assert!(!is_tcp("/dev/ttyUSB0"));
+        assert!(!is_tcp("COM3"));
+        assert!(!is_tcp("inverter.local"));
+        assert!(!is_tcp("inverter.local:modbus"));
+        assert!(!is_tcp(":502"));
+    }
+
+    #[test]
+    fn test_inverter_mode() {
+        assert_eq!(inverter_mode(0.0, 1.0, 2.0), 0.0);
+        assert_eq!(inverter_mode(2.0, 1.0, 0.0), 1.0);
+        assert_eq!(inverter_mode(2.0, 1.0, 2.0), 3.0);
+        assert_eq!(inverter_mode(3.0, 0.0, 2.0), 4.0);
+        assert_eq!(inverter_mode(4.0, 1.0, 0.0), 5.0);
+    }
+
+    #[test]
+    fn test_derived() {
+        let fields = modbus_fields(&FieldConfig::default()).unwrap();
+        let index = |id: &str| fields.fields.iter().position(|f| f.id == id).unwrap();
+        let derived = DerivedIndices::new(fields.fields);
+        assert!(derived.needs_clock());
+        let mut values = vec![0.0; fields.fields.len()];
+        for i in 0..NUM_PROGRAMS {
+            values[index(&format!("inverter_program_time_{}", i + 1))] = (i * 3600) as f64;
+            values[index(&format!("inverter_program_power_{}", i + 1))] = (i * 100) as f64;
+            values[index(&format!("inverter_program_soc_{}", i + 1))] = (i * 10) as f64;
+        }
+        values[index("inverter_state")] = 2.0;
+        values[index("grid_connected")] = 1.0;
+        values[index("inverter_work_mode")] = 2.0;
+        derived.compute(&mut values, 7200.0);
+        assert_eq!(values[index("inverter_program_power")], 200.0);
+        assert_eq!(values[index("inverter_program_soc")], 20.0);
+        assert_eq!(values[index("inverter_mode")], 3.0);
+        derived.compute(&mut values, 80000.0);
+        assert_eq!(values[index("inverter_program_power")], 500.0);
+    }
+
+    #[test]
+    fn test_three_phase() {
+        let config: FieldConfig =
+            toml::from_str("inverter = { layout = \"three_phase\" }").unwrap();
+        let fields = modbus_fields(&config).unwrap();
+        let regs = |id: &str| {
+            let index = fields.fields.iter().position(|f| f.id == id).unwrap();
+            fields.addresses[index].clone()
+        };
+        assert_eq!(regs("grid_voltage"), vec![598]);
+        assert_eq!(regs("grid_voltage_l3"), vec![600]);
+        assert_eq!(regs("load_power_l2"), vec![651]);
+        assert_eq!(regs("battery_charge_total"), vec![516, 517]);
+        // Fields of the other layouts are not read
+        assert!(!fields.fields.iter().any(|f| f.id == "load_current_l2"));
+        let derived = DerivedIndices::new(fields.fields);
+        assert!(!derived.needs_clock());
+
+        // Without the three-phase layout, L3 is not reported
+        let fields = modbus_fields(&FieldConfig::default()).unwrap();
+        assert!(!fields.fields.iter().any(|f| f.id == "grid_voltage_l3"));
+    }
+
+    #[test]
+    fn test_derived_missing() {
+        let fields = modbus_fields(&FieldConfig::default()).unwrap();
+        // Drop a program time and the grid connection
+        let fields: Vec<Field<'_>> = fields
+            .fields
+            .iter()
+            .filter(|f| f.id != "inverter_program_time_3" && f.id != "grid_connected")
+            .cloned()
+            .collect();
+        let derived = DerivedIndices::new(&fields);
+        assert!(derived.times.is_none());
+        assert!(derived.powers.is_some());
+        assert!(!derived.needs_clock());
+        let mut values = vec![1.0; fields.len()];
+        derived.compute(&mut values, 0.0);
+        assert!(values.iter().all(|&v| v == 1.0));
+    }
+}
@@ -0,0 +1,232 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that inserts a document per update into a MongoDB time-series
+//! collection.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::{ErrorKind, RETRYABLE_WRITE_ERROR};
+use mongodb::options::{CreateCollectionOptions, TimeseriesGranularity, TimeseriesOptions};
+use mongodb::{Client, Database};
+use serde::Deserialize;
+use std::iter::zip;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+
+/// Options for creating the collection
+fn create_options(config: &Config) -> CreateCollectionOptions {
+    let timeseries = TimeseriesOptions::builder()
+        .time_field("time".to_owned())
+        .meta_field(Some("serial".to_owned()))
+        .granularity(Some(TimeseriesGranularity::Seconds))
+        .build();
+    CreateCollectionOptions::builder()
+        .timeseries(timeseries)
+        .expire_after_seconds(config.expire_after.map(Duration::from_secs))
+        .build()
+}
+
+/// Document for an update, with the time, serial number and a property per
+/// field
+fn encode(update: &Update<'_>) -> Document {
+    let mut doc = doc! {
+        "time": DateTime::from_millis(update.timestamp.div_euclid(1_000_000)),
+        "serial": &update.serial,
+    };
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if value.is_finite() {
+            doc.insert(field.id, *value);
+        }
+    }
+    for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+        doc.insert(field.id, text);
+    }
+    doc
+}
+
+pub struct MongodbReceiver {
+    url: String,
+    collection: String,
+    create_options: CreateCollectionOptions,
+    database: tokio::sync::Mutex<Option<Database>>,
+    /// Whether the collection is known to exist
+    created: AtomicBool,
+    batch: Options,
+}
+
+impl MongodbReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            url: config.url.clone(),
+            collection: config.collection.clone(),
+            create_options: create_options(config),
+            database: tokio::sync::Mutex::new(None),
+            created: AtomicBool::new(false),
+            batch: config.batch.clone(),
+        }
+    }
+
+    /// Get a handle to the database. The client connects lazily, so this
+    /// only fails if the connection string is invalid.
+    async fn database(&self) -> mongodb::error::Result<Database> {
+        let mut database = self.database.lock().await;
+        if database.is_none() {
+            let client = Client::with_uri_str(&self.url).await?;
+            // Use the same default as the MongoDB shell
+            *database = Some(
+                client
+                    .default_database()
+                    .unwrap_or_else(|| client.database("test")),
+            );
+        }
+        Ok(database.clone().unwrap())
+    }
+}
+
+#[async_trait]
+impl BatchWriter for MongodbReceiver {
+    type Item = Document;
+    type Error = mongodb::error::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Document> {
+        vec![encode(update)]
+    }
+
+    async fn write(&self, docs: Vec<Document>) -> mongodb::error::Result<()> {
+        let database = self.database().await?;
+        if !self.created.load(Ordering::Relaxed) {
+            let names = database
+                .list_collection_names(doc! { "name": &self.collection })
+                .await?;
+            if names.is_empty() {
+                database
+                    .create_collection(&self.collection, self.create_options.clone())
+                    .await?;
+            }
+            self.created.store(true, Ordering::Relaxed);
+        }
+        database
+            .collection::<Document>(&self.collection)
+            .insert_many(docs, None)
+            .await?;
+        Ok(())
+    }
+
+    fn is_fatal(&self, err: &mongodb::error::Error) -> bool {
+        // Connection problems may clear up by themselves, as may errors that
+        // the server marks as retryable
+        let transient = matches!(
+            *err.kind,
+            ErrorKind::Io(_)
+                | ErrorKind::ServerSelection { .. }
+                | ErrorKind::ConnectionPoolCleared { .. }
+                | ErrorKind::DnsResolve { .. }
+        );
+        !(transient || err.contains_label(RETRYABLE_WRITE_ERROR))
+    }
+}
+
+#[async_trait]
+impl Receiver for MongodbReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.database()
+            .await?
+            .run_command(doc! { "ping": 1 }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Connection string, including the database
+    pub url: String,
+    #[serde(default = "default_collection")]
+    pub collection: String,
+    /// Delete documents after this many seconds (when creating the
+    /// collection)
+    pub expire_after: Option<u64>,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_collection() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_create_options() {
+        let config: Config =
+            toml::from_str("url = \"mongodb://localhost/solar\"\nexpire_after = 86400").unwrap();
+        let options = create_options(&config);
+        let timeseries = options.timeseries.unwrap();
+        assert_eq!(timeseries.time_field, "time");
+        assert_eq!(timeseries.meta_field.as_deref(), Some("serial"));
+        assert_eq!(
+            options.expire_after_seconds,
+            Some(Duration::from_secs(86400))
+        );
+        assert!(
+            toml::from_str::<Config>("url = \"mongodb://localhost\"\nmongosh = \"x\"").is_err()
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1_700_000_000_123_456_789, "1234", fields, vec![1500.0]);
+        assert_eq!(
+            encode(&update),
+            doc! {
+                "time": DateTime::from_millis(1_700_000_000_123),
+                "serial": "1234",
+                "pv_power": 1500.0,
+            }
+        );
+        let nan = Update::new(0, "1234", fields, vec![f64::NAN]);
+        assert!(!encode(&nan).contains_key("pv_power"));
+    }
+}
@@ -17,34 +17,124 @@
 use async_trait::async_trait;
 use futures::channel::mpsc::UnboundedReceiver;
 use futures::stream::StreamExt;
-use log::warn;
-use mqtt_async_client::client::{Client, Publish, QoS};
+use log::{info, warn};
+use rumqttc::{
+    AsyncClient, ClientError, ConnectionError, Event, EventLoop, Incoming, LastWill, MqttOptions,
+    Outgoing, QoS, TlsConfiguration, Transport,
+};
 use serde::{self, Deserialize, Serialize};
 use serde_json;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::iter::zip;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 
 use super::fields::{Field, FieldType};
+use super::health::{record_write, WriteRecorder};
+use super::json::UpdateRecord;
 use super::receiver::{Receiver, Update};
+pub use super::tls::TlsConfig;
+
+/// Number of messages that can be queued while the broker is unreachable
+const QUEUE_CAPACITY: usize = 100;
+/// Largest packet that can be sent to or received from the broker
+const MAX_PACKET_SIZE: usize = 1 << 20;
+/// Delay before reconnecting after the connection to the broker fails
+pub(crate) const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Time to wait on shutdown for queued messages to be sent
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time without updates after which an inverter's sensors are unavailable
+const EXPIRE_AFTER: Duration = Duration::from_secs(600);
+/// Interval between checks for inverters that have stopped sending updates
+const EXPIRE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Expand the `{serial}`, and (if a field is given) the `{group}` and `{id}`
+/// placeholders in a topic template
+pub(crate) fn expand_topic(template: &str, serial: &str, field: Option<&Field<'_>>) -> String {
+    let topic = template.replace("{serial}", serial);
+    match field {
+        Some(field) => topic
+            .replace("{group}", field.group)
+            .replace("{id}", field.id),
+        None => topic,
+    }
+}
+
+/// Check that a topic template only uses the given placeholders
+pub(crate) fn check_template(template: &str, placeholders: &[&str]) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let len = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated placeholder in topic {template}"))?;
+        let name = &rest[start + 1..start + len];
+        if !placeholders.contains(&name) {
+            return Err(format!(
+                "Unknown placeholder {{{name}}} in topic {template}"
+            ));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    Ok(())
+}
+
+fn qos_from_level(level: u8) -> Result<QoS, String> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => Err(format!(
+            "Invalid MQTT QoS level {level} (must be 0, 1 or 2)"
+        )),
+    }
+}
+
+/// Topic on which the availability of sunsniff itself is published. The
+/// broker publishes `offline` to it (as the last will) if sunsniff dies.
+const STATUS_TOPIC: &str = "sunsniff/availability";
+
+/// Topic on which the availability of an inverter's sensors is published
+fn availability_topic(serial: &str) -> String {
+    format!("sunsniff/{serial}/availability")
+}
+
+/// Payload for an availability topic
+fn availability_payload(available: bool) -> &'static str {
+    if available {
+        "online"
+    } else {
+        "offline"
+    }
+}
 
 struct ClassInfo<'a> {
     device_class: Option<&'a str>,
-    state_class: &'a str,
+    state_class: Option<&'a str>,
 }
 
 impl<'a> ClassInfo<'a> {
     const fn new(device_class: &'a str, state_class: &'a str) -> Self {
         ClassInfo {
             device_class: Some(device_class),
-            state_class,
+            state_class: Some(state_class),
         }
     }
 
     const fn new_no_device(state_class: &'a str) -> Self {
         ClassInfo {
             device_class: None,
-            state_class,
+            state_class: Some(state_class),
+        }
+    }
+
+    /// Class for a sensor without a state class (such as text or codes)
+    const fn new_stateless() -> Self {
+        ClassInfo {
+            device_class: None,
+            state_class: None,
         }
     }
 }
@@ -53,9 +143,12 @@ impl From<FieldType> for ClassInfo<'static> {
     fn from(ft: FieldType) -> Self {
         match ft {
             FieldType::Charge | FieldType::Unitless => ClassInfo::new_no_device("measurement"),
+            FieldType::Enum | FieldType::Flags | FieldType::Text | FieldType::Version => {
+                ClassInfo::new_stateless()
+            }
             FieldType::Current => ClassInfo::new("current", "measurement"),
             FieldType::Energy => ClassInfo::new("energy", "total_increasing"),
-            FieldType::Frequency => ClassInfo::new_no_device("measurement"),
+            FieldType::Frequency => ClassInfo::new("frequency", "measurement"),
             FieldType::Power => ClassInfo::new("power", "measurement"),
             FieldType::StateOfCharge => ClassInfo::new("battery", "measurement"),
             FieldType::Temperature => ClassInfo::new("temperature", "measurement"),
@@ -68,26 +161,53 @@ impl From<FieldType> for ClassInfo<'static> {
 #[derive(Serialize)]
 struct Device<'a> {
     identifiers: (&'a str,),
+    manufacturer: &'a str,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Availability {
+    topic: String,
 }
 
 #[derive(Serialize)]
 struct Sensor<'a> {
+    /// Both sunsniff and the inverter must be available
+    availability: [Availability; 2],
+    availability_mode: &'a str,
     device: Device<'a>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     device_class: Option<&'a str>,
-    expire_after: i32,
+    expire_after: u64,
     name: &'a str,
     object_id: &'a str,
-    state_class: &'a str,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<Vec<&'a str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'a str>,
     state_topic: &'a str,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suggested_display_precision: Option<u32>,
     unique_id: &'a str,
-    unit_of_measurement: &'a str,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+}
+
+/// Number of decimal places needed to show a value with the given scale
+fn display_precision(scale: f64) -> u32 {
+    if scale > 0.0 && scale < 1.0 {
+        (-scale.log10() - 1e-6).ceil() as u32
+    } else {
+        0
+    }
 }
 
 /// Field associated with a specific device
 struct DeviceField<'a> {
     field: &'a Field<'a>,
     serial: &'a str,
+    /// Whether this is the text sensor holding the label of an enum field
+    label: bool,
     unique_id: String,
     state_topic: String,
     config_topic: String,
@@ -95,91 +215,423 @@ struct DeviceField<'a> {
 
 impl<'a> DeviceField<'a> {
     fn new(field: &'a Field<'a>, serial: &'a str) -> Self {
-        let unique_id = format!("sunsniff_{}_{}", serial, field.id);
+        Self::with_id(
+            field,
+            serial,
+            false,
+            format!("sunsniff_{}_{}", serial, field.id),
+        )
+    }
+
+    fn new_label(field: &'a Field<'a>, serial: &'a str) -> Self {
+        Self::with_id(
+            field,
+            serial,
+            true,
+            format!("sunsniff_{}_{}_label", serial, field.id),
+        )
+    }
+
+    fn with_id(field: &'a Field<'a>, serial: &'a str, label: bool, unique_id: String) -> Self {
         let state_topic = format!("homeassistant/sensor/{unique_id}/state");
         let config_topic = format!("homeassistant/sensor/{unique_id}/config");
         Self {
             field,
             serial,
+            label,
             unique_id,
             state_topic,
             config_topic,
         }
     }
+
+    /// Home Assistant discovery information for the sensor
+    fn discovery_payload(&self) -> Vec<u8> {
+        let full_name = if self.label {
+            format!("{} {} label", self.field.group, self.field.name)
+        } else {
+            format!("{} {}", self.field.group, self.field.name)
+        };
+        let field_type = self.field.field_type;
+        let class_info: ClassInfo = if self.label {
+            // Labels are restricted to a known set, so Home Assistant can
+            // treat them as an enum.
+            ClassInfo {
+                device_class: Some("enum"),
+                state_class: None,
+            }
+        } else {
+            field_type.into()
+        };
+        let options = self.label.then(|| {
+            let mut options: Vec<&str> =
+                self.field.labels.iter().map(|(_, label)| *label).collect();
+            options.push("Unknown");
+            options
+        });
+        let numeric = !self.label
+            && !matches!(
+                field_type,
+                FieldType::Enum | FieldType::Flags | FieldType::Text | FieldType::Version
+            );
+        let unit = self.field.unit;
+        let sensor = Sensor {
+            availability: [
+                Availability {
+                    topic: STATUS_TOPIC.to_owned(),
+                },
+                Availability {
+                    topic: availability_topic(self.serial),
+                },
+            ],
+            availability_mode: "all",
+            device: Device {
+                identifiers: (self.serial,),
+                manufacturer: "Sunsynk/Deye",
+                name: format!("Inverter {}", self.serial),
+            },
+            device_class: class_info.device_class,
+            expire_after: EXPIRE_AFTER.as_secs(),
+            name: &full_name,
+            object_id: &self.unique_id,
+            options,
+            state_class: class_info.state_class,
+            state_topic: &self.state_topic,
+            suggested_display_precision: numeric.then(|| display_precision(self.field.scale)),
+            unique_id: &self.unique_id,
+            unit_of_measurement: if unit.is_empty() || self.label {
+                None
+            } else {
+                Some(unit)
+            },
+        };
+        // TODO: more graceful error handling on to_vec
+        serde_json::to_vec(&sensor).unwrap()
+    }
 }
 
 pub struct MqttReceiver {
-    client: Client,
+    client: AsyncClient,
+    /// Connection to the broker, until [Receiver::run] starts polling it
+    event_loop: Option<EventLoop>,
+    /// Topic template for publishing whole updates as JSON
+    topic: Option<String>,
+    /// Whether to publish each field to its own topic
+    field_topics: bool,
+    /// Topic template used with `field_topics`
+    field_topic: String,
+    /// QoS for publishing values
+    qos: QoS,
+    /// Whether to retain published values
+    retain: bool,
+    /// Whether to publish Home Assistant sensors
+    home_assistant: bool,
     registered: HashSet<String>,
+    /// Last value published for each text sensor, by unique ID
+    text: HashMap<String, String>,
+    /// Time of the last update for each inverter that is currently available
+    last_seen: HashMap<String, Instant>,
 }
 
 impl MqttReceiver {
-    pub fn new(config: &Config) -> mqtt_async_client::Result<Self> {
-        let client = Client::builder()
-            .set_url_string(&config.url)?
-            .set_username(config.username.clone())
-            .set_password(config.password.as_ref().map(|s| s.as_bytes().to_vec()))
-            .build()?;
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut options = options(
+            &config.url,
+            &config.username,
+            &config.password,
+            config.tls.as_ref(),
+            client_id(),
+        )?;
+        options.set_last_will(LastWill::new(
+            STATUS_TOPIC,
+            availability_payload(false),
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, event_loop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        if let Some(topic) = &config.topic {
+            check_template(topic, &["serial"])?;
+        }
+        check_template(&config.field_topic, &["serial", "group", "id"])?;
         Ok(MqttReceiver {
             client,
+            event_loop: Some(event_loop),
+            topic: config.topic.clone(),
+            field_topics: config.field_topics,
+            field_topic: config.field_topic.clone(),
+            qos: qos_from_level(config.qos)?,
+            retain: config.retain,
+            home_assistant: config.home_assistant,
             registered: HashSet::new(),
+            text: HashMap::new(),
+            last_seen: HashMap::new(),
         })
     }
 
-    async fn register_field<'a>(
-        &mut self,
-        field: &DeviceField<'a>,
-    ) -> mqtt_async_client::Result<()> {
+    fn register_field(&mut self, field: &DeviceField<'_>) -> Result<(), ClientError> {
         if !self.registered.contains(&field.unique_id) {
-            let full_name = format!("{} {}", field.field.group, field.field.name);
-            let class_info: ClassInfo = field.field.field_type.into();
-            let sensor = Sensor {
-                device: Device {
-                    identifiers: (field.serial,),
-                },
-                device_class: class_info.device_class,
-                expire_after: 600,
-                name: &full_name,
-                object_id: &field.unique_id,
-                state_class: class_info.state_class,
-                state_topic: &field.state_topic,
-                unique_id: &field.unique_id,
-                unit_of_measurement: field.field.unit,
-            };
-            // TODO: more graceful error handling on to_vec
-            let mut msg = Publish::new(
-                field.config_topic.to_owned(),
-                serde_json::to_vec(&sensor).unwrap(),
-            );
-            let msg = msg.set_retain(true).set_qos(QoS::AtLeastOnce);
-            self.client.publish(msg).await?;
+            self.client.try_publish(
+                &field.config_topic,
+                QoS::AtLeastOnce,
+                true,
+                field.discovery_payload(),
+            )?;
             self.registered.insert(field.unique_id.to_owned());
         }
         Ok(())
     }
+
+    /// Publish whether the sensors for an inverter are available
+    fn publish_availability(&mut self, serial: &str, available: bool) {
+        let payload = availability_payload(available);
+        let result =
+            self.client
+                .try_publish(availability_topic(serial), QoS::AtLeastOnce, true, payload);
+        if let Err(e) = result {
+            warn!("Sending availability for {} failed: {}", serial, e);
+            record_write(Some(e.to_string()));
+        }
+    }
+
+    /// Mark inverters that have not sent updates recently as unavailable
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .last_seen
+            .iter()
+            .filter(|(_, last)| now.duration_since(**last) >= EXPIRE_AFTER)
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for serial in expired {
+            info!("No updates from {serial} for {EXPIRE_AFTER:?}; marking it unavailable");
+            self.last_seen.remove(&serial);
+            self.publish_availability(&serial, false);
+        }
+    }
+
+    /// Publish each field to its own topic, with just the value as payload
+    fn publish_field_topics(&mut self, update: &Update<'_>) {
+        let values = update.values.iter().map(|value| value.to_string());
+        let text = update.text.iter().cloned();
+        let fields = update.fields.iter().chain(update.text_fields.iter());
+        for (field, payload) in zip(fields, values.chain(text)) {
+            let topic = expand_topic(&self.field_topic, &update.serial, Some(field));
+            let result = self
+                .client
+                .try_publish(topic, self.qos, self.retain, payload);
+            if let Err(e) = result {
+                warn!("Sending update for {} failed: {}", field.id, e);
+                record_write(Some(e.to_string()));
+            }
+        }
+    }
+
+    /// Publish the update as Home Assistant sensors
+    fn publish_home_assistant(&mut self, update: &Update<'_>) {
+        if self
+            .last_seen
+            .insert(update.serial.clone(), Instant::now())
+            .is_none()
+        {
+            self.publish_availability(&update.serial, true);
+        }
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            let device_field = DeviceField::new(field, &update.serial);
+            self.publish(&device_field, value.to_string(), false);
+            if !field.labels.is_empty() {
+                let label_field = DeviceField::new_label(field, &update.serial);
+                let label = field.label(*value).unwrap_or("Unknown");
+                self.publish(&label_field, label.to_owned(), false);
+            }
+        }
+        // Text rarely changes, so it is only published (and retained)
+        // when it changes.
+        for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            let device_field = DeviceField::new(field, &update.serial);
+            if self.text.get(&device_field.unique_id) != Some(text) {
+                self.publish(&device_field, text.clone(), true);
+                self.text
+                    .insert(device_field.unique_id.clone(), text.clone());
+            }
+        }
+    }
+
+    /// Register the field if necessary, then publish a value
+    fn publish(&mut self, field: &DeviceField<'_>, payload: String, retain: bool) {
+        let id = field.field.id;
+        self.register_field(field)
+            .unwrap_or_else(|e| warn!("Registering {} failed: {}", id, e));
+        let result =
+            self.client
+                .try_publish(&field.state_topic, self.qos, retain || self.retain, payload);
+        if let Err(e) = result {
+            warn!("Sending update for {} failed: {}", id, e);
+            record_write(Some(e.to_string()));
+        }
+    }
 }
 
 #[async_trait]
 impl Receiver for MqttReceiver {
     async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
-        self.client
-            .connect()
-            .await
-            .unwrap_or_else(|e| warn!("Couldn't connect to MQTT broker (will keep trying): {}", e));
-        while let Some(update) = receiver.next().await {
-            for (field, value) in zip(update.fields.iter(), update.values.iter()) {
-                let device_field = DeviceField::new(field, &update.serial);
-                self.register_field(&device_field)
-                    .await
-                    .unwrap_or_else(|e| warn!("Registering {} failed: {}", field.id, e));
-                let payload = value.to_string().as_bytes().to_vec();
-                let msg = Publish::new(device_field.state_topic, payload);
-                self.client
-                    .publish(&msg)
-                    .await
-                    .unwrap_or_else(|e| warn!("Sending update for {} failed: {}", field.id, e));
+        let event_loop = self.event_loop.take().expect("receiver is only run once");
+        // The last will replaces the status if the connection is lost, so
+        // it is published again on every connection.
+        let client = self.client.clone();
+        let driver = spawn_event_loop(event_loop, move || publish_status(&client, true));
+        let mut expire_check = tokio::time::interval(EXPIRE_CHECK_INTERVAL);
+        loop {
+            let update = tokio::select! {
+                update = receiver.next() => match update {
+                    Some(update) => update,
+                    None => break,
+                },
+                _ = expire_check.tick() => {
+                    self.expire();
+                    continue;
+                }
+            };
+            if let Some(topic) = &self.topic {
+                let topic = expand_topic(topic, &update.serial, None);
+                let record = UpdateRecord::new(&update);
+                let payload = serde_json::to_vec(&record).unwrap();
+                let result = self
+                    .client
+                    .try_publish(&topic, self.qos, self.retain, payload);
+                if let Err(e) = result {
+                    warn!("Sending update to {} failed: {}", topic, e);
+                    record_write(Some(e.to_string()));
+                }
+            }
+            if self.field_topics {
+                self.publish_field_topics(&update);
+            }
+            if self.home_assistant {
+                self.publish_home_assistant(&update);
+            }
+        }
+        // The input has finished, so no more updates will arrive
+        let serials: Vec<String> = self.last_seen.drain().map(|(serial, _)| serial).collect();
+        for serial in serials {
+            self.publish_availability(&serial, false);
+        }
+        // A clean disconnect does not trigger the last will
+        publish_status(&self.client, false);
+        disconnect(&self.client, driver).await;
+    }
+}
+
+/// Publish whether sunsniff itself is available
+fn publish_status(client: &AsyncClient, available: bool) {
+    let payload = availability_payload(available);
+    if let Err(e) = client.try_publish(STATUS_TOPIC, QoS::AtLeastOnce, true, payload) {
+        warn!("Sending availability failed: {}", e);
+        record_write(Some(e.to_string()));
+    }
+}
+
+/// Generate a client ID that is unique within the process
+pub(crate) fn client_id() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("sunsniff-{}-{index}", std::process::id())
+}
+
+/// Options for connecting to a broker given by an `mqtt://host[:port]` or
+/// `mqtts://host[:port]` URL. TLS is used if the URL has the `mqtts` scheme
+/// or if TLS settings are given.
+pub(crate) fn options(
+    url: &str,
+    username: &Option<String>,
+    password: &Option<String>,
+    tls: Option<&TlsConfig>,
+    client_id: String,
+) -> Result<MqttOptions, Box<dyn std::error::Error>> {
+    let (scheme, address) = url
+        .split_once("://")
+        .ok_or_else(|| format!("Invalid MQTT URL {url}"))?;
+    let default_port = match scheme {
+        "mqtt" => 1883,
+        "mqtts" => 8883,
+        _ => return Err(format!("Unsupported scheme in MQTT URL {url}").into()),
+    };
+    let address = address.trim_end_matches('/');
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("Invalid port in MQTT URL {url}"))?,
+        ),
+        None => (address, default_port),
+    };
+    if host.is_empty() {
+        return Err(format!("Missing host in MQTT URL {url}").into());
+    }
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+    if let Some(username) = username {
+        options.set_credentials(username, password.as_deref().unwrap_or(""));
+    }
+    if tls.is_some() || scheme == "mqtts" {
+        let config = tls.unwrap_or(&TlsConfig::default()).client_config()?;
+        options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+            Arc::new(config),
+        )));
+    }
+    Ok(options)
+}
+
+/// Create a client for the broker. Nothing is sent until the event loop is
+/// polled, for instance by [spawn_event_loop].
+pub(crate) fn client(
+    url: &str,
+    username: &Option<String>,
+    password: &Option<String>,
+    tls: Option<&TlsConfig>,
+) -> Result<(AsyncClient, EventLoop), Box<dyn std::error::Error>> {
+    let options = options(url, username, password, tls, client_id())?;
+    Ok(AsyncClient::new(options, QUEUE_CAPACITY))
+}
+
+/// Drive the connection to the broker until the client disconnects or is
+/// dropped, calling `on_connect` each time the connection is established.
+/// Sending a message counts as a successful write by the current backend,
+/// and failing to connect counts as a failed one.
+pub(crate) fn spawn_event_loop(
+    mut event_loop: EventLoop,
+    on_connect: impl Fn() + Send + 'static,
+) -> JoinHandle<()> {
+    let recorder = WriteRecorder::current();
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => on_connect(),
+                Ok(Event::Outgoing(Outgoing::Publish(_))) => recorder.record(None),
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(ConnectionError::RequestsDone) => break,
+                Err(e) => {
+                    warn!("Connection to MQTT broker failed (will keep trying): {}", e);
+                    recorder.record(Some(e.to_string()));
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
             }
         }
+    })
+}
+
+/// Disconnect from the broker once the queued messages have been sent,
+/// giving up after [DISCONNECT_TIMEOUT] if the broker is unreachable
+pub(crate) async fn disconnect(client: &AsyncClient, mut driver: JoinHandle<()>) {
+    let sent = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+        if client.disconnect().await.is_ok() {
+            let _ = (&mut driver).await;
+        }
+    })
+    .await;
+    if sent.is_err() {
+        warn!("Timed out sending queued messages to the MQTT broker");
+        driver.abort();
     }
 }
 
@@ -189,4 +641,143 @@ pub struct Config {
     pub url: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// Publish each update as a single JSON message to this topic (for the
+    /// `mqtt_ingest` frontend)
+    pub topic: Option<String>,
+    /// Publish each field to its own topic, with the plain value as payload
+    #[serde(default)]
+    pub field_topics: bool,
+    /// Template for the topics used by `field_topics`
+    #[serde(default = "default_field_topic")]
+    pub field_topic: String,
+    /// QoS level (0, 1 or 2) for publishing values
+    #[serde(default)]
+    pub qos: u8,
+    /// Whether the broker should retain published values
+    #[serde(default)]
+    pub retain: bool,
+    /// Publish sensors with Home Assistant discovery information
+    #[serde(default = "default_home_assistant")]
+    pub home_assistant: bool,
+}
+
+fn default_home_assistant() -> bool {
+    true
+}
+
+fn default_field_topic() -> String {
+    String::from("sunsniff/{serial}/{id}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::field;
+    use serde_json::{json, Value};
+
+    fn payload(field: &DeviceField) -> Value {
+        serde_json::from_slice(&field.discovery_payload()).unwrap()
+    }
+
+    #[test]
+    fn test_options() {
+        let parse = |url: &str, tls: Option<&TlsConfig>| {
+            options(url, &Some("user".to_owned()), &None, tls, "id".to_owned())
+        };
+        let options = parse("mqtt://broker", None).unwrap();
+        assert_eq!(options.broker_address(), ("broker".to_owned(), 1883));
+        assert_eq!(options.credentials().unwrap().username, "user");
+        assert!(matches!(options.transport(), Transport::Tcp));
+        let options = parse("mqtts://broker/", None).unwrap();
+        assert_eq!(options.broker_address(), ("broker".to_owned(), 8883));
+        assert!(matches!(options.transport(), Transport::Tls(_)));
+        let options = parse("mqtt://broker:1884", Some(&TlsConfig::default())).unwrap();
+        assert_eq!(options.broker_address(), ("broker".to_owned(), 1884));
+        assert!(matches!(options.transport(), Transport::Tls(_)));
+        assert!(parse("http://broker", None).is_err());
+        assert!(parse("broker:1883", None).is_err());
+        assert!(parse("mqtt://broker:port", None).is_err());
+        assert!(parse("mqtt://:1883", None).is_err());
+        assert_ne!(client_id(), client_id());
+    }
+
+    #[test]
+    fn test_expand_topic() {
+        let field = field(FieldType::Energy, "battery_test");
+        assert_eq!(
+            expand_topic(&default_field_topic(), "1234", Some(&field)),
+            "sunsniff/1234/battery_test"
+        );
+        assert_eq!(
+            expand_topic("inverters/{serial}/{group}/{id}", "1234", Some(&field)),
+            "inverters/1234/Test/battery_test"
+        );
+        assert_eq!(
+            expand_topic("updates/{serial}", "1234", None),
+            "updates/1234"
+        );
+    }
+
+    #[test]
+    fn test_check_template() {
+        let all = ["serial", "group", "id"];
+        assert!(check_template("sunsniff/{serial}/{id}", &all).is_ok());
+        assert!(check_template("sunsniff", &all).is_ok());
+        assert!(check_template("sunsniff/{id}", &["serial"]).is_err());
+        assert!(check_template("sunsniff/{serial", &all).is_err());
+    }
+
+    #[test]
+    fn test_display_precision() {
+        assert_eq!(display_precision(1.0), 0);
+        assert_eq!(display_precision(60.0), 0);
+        assert_eq!(display_precision(0.1), 1);
+        assert_eq!(display_precision(0.01), 2);
+        assert_eq!(display_precision(0.05), 2);
+    }
+
+    #[test]
+    fn test_discovery_energy() {
+        let field = Field {
+            scale: 0.1,
+            ..field(FieldType::Energy, "battery_test")
+        };
+        let payload = payload(&DeviceField::new(&field, "1234"));
+        assert_eq!(payload["device_class"], "energy");
+        assert_eq!(payload["state_class"], "total_increasing");
+        assert_eq!(payload["suggested_display_precision"], 1);
+        assert_eq!(payload["unit_of_measurement"], "kWh");
+        assert_eq!(payload["unique_id"], "sunsniff_1234_battery_test");
+        assert_eq!(
+            payload["availability"],
+            json!([
+                {"topic": "sunsniff/availability"},
+                {"topic": "sunsniff/1234/availability"},
+            ])
+        );
+        assert_eq!(payload["availability_mode"], "all");
+        assert_eq!(
+            payload["device"],
+            json!({
+                "identifiers": ["1234"],
+                "manufacturer": "Sunsynk/Deye",
+                "name": "Inverter 1234",
+            })
+        );
+    }
+
+    #[test]
+    fn test_discovery_label() {
+        let field = Field {
+            labels: &[(0, "Off"), (1, "On")],
+            ..field(FieldType::Enum, "battery_test")
+        };
+        let payload = payload(&DeviceField::new_label(&field, "1234"));
+        assert_eq!(payload["device_class"], "enum");
+        assert_eq!(payload["options"], json!(["Off", "On", "Unknown"]));
+        assert!(payload.get("state_class").is_none());
+        assert!(payload.get("suggested_display_precision").is_none());
+        assert!(payload.get("unit_of_measurement").is_none());
+    }
 }
@@ -0,0 +1,233 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend that subscribes to updates published over MQTT by another
+//! instance (see [crate::mqtt::Config::topic]). This allows a lightweight
+//! instance near the inverter to do the capture, with a central instance
+//! feeding the backends.
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use log::{error, warn};
+use rumqttc::{AsyncClient, Event, Packet, QoS, SubAck, SubscribeReasonCode};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::fields::FieldConfig;
+use crate::frontend::{FieldWatch, Frontend};
+use crate::json::{Interner, UpdateRecord};
+use crate::mqtt::{client, TlsConfig, RETRY_DELAY};
+use crate::receiver::{Update, UpdateStream};
+
+fn default_max_field_lists() -> usize {
+    100
+}
+
+/// Structure corresponding to the `[mqtt_ingest]` section of the
+/// configuration file. It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttIngestConfig {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    tls: Option<TlsConfig>,
+    topic: String,
+    /// Maximum number of distinct lists of fields to accept. The field
+    /// descriptions are kept for the lifetime of the process, so this bounds
+    /// the memory used if a publisher sends arbitrary fields.
+    #[serde(default = "default_max_field_lists")]
+    max_field_lists: usize,
+}
+
+/// Decode a message published by another instance on `topic`, logging why
+/// if it cannot be decoded
+fn decode(
+    interner: &mut Interner,
+    max_field_lists: usize,
+    topic: &str,
+    payload: &[u8],
+) -> Option<Update<'static>> {
+    match serde_json::from_slice::<UpdateRecord>(payload) {
+        Ok(record) => {
+            let update = interner.try_decode(record);
+            if update.is_none() {
+                warn!(
+                    "Dropping update received on {topic}: more than {max_field_lists} distinct sets of fields"
+                );
+            }
+            update
+        }
+        Err(err) => {
+            warn!("Invalid update received on {topic}: {err}");
+            None
+        }
+    }
+}
+
+/// Check whether the broker accepted the subscription to `topic`
+fn check_subscription(ack: &SubAck, topic: &str) -> Result<(), String> {
+    if ack.return_codes.contains(&SubscribeReasonCode::Failure) {
+        Err(format!("MQTT broker refused subscription to {topic}"))
+    } else {
+        Ok(())
+    }
+}
+
+fn subscribe(client: &AsyncClient, topic: &str) -> Result<(), rumqttc::ClientError> {
+    client.try_subscribe(topic, QoS::AtLeastOnce)
+}
+
+pub async fn create_stream(
+    config: &MqttIngestConfig,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let (client, mut event_loop) = client(
+        &config.url,
+        &config.username,
+        &config.password,
+        config.tls.as_ref(),
+    )?;
+    let topic = config.topic.clone();
+    // Connect and subscribe before returning, so that problems are reported
+    // at startup
+    loop {
+        match event_loop.poll().await? {
+            Event::Incoming(Packet::ConnAck(_)) => subscribe(&client, &topic)?,
+            Event::Incoming(Packet::SubAck(ack)) => {
+                check_subscription(&ack, &topic)?;
+                break;
+            }
+            _ => {}
+        }
+    }
+    let max_field_lists = config.max_field_lists;
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        let mut interner = Interner::with_limit(max_field_lists);
+        loop {
+            let message = match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(message))) => message,
+                // The session is not persistent, so the subscription has to
+                // be renewed after reconnecting
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    if let Err(err) = subscribe(&client, &topic) {
+                        error!("Error subscribing to {topic}: {err}");
+                    }
+                    continue;
+                }
+                Ok(Event::Incoming(Packet::SubAck(ack))) => {
+                    if let Err(err) = check_subscription(&ack, &topic) {
+                        error!("{err}");
+                    }
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(err) => {
+                    error!("Error reading from MQTT broker: {err}");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let update = decode(
+                &mut interner,
+                max_field_lists,
+                &message.topic,
+                &message.payload,
+            );
+            if let Some(update) = update {
+                if sender.unbounded_send(Arc::new(update)).is_err() {
+                    break; // The receiver has shut down
+                }
+            }
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[async_trait(?Send)]
+impl Frontend for MqttIngestConfig {
+    async fn create_stream(
+        &self,
+        _field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self).await
+    }
+
+    fn check(&self, _field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        client(&self.url, &self.username, &self.password, self.tls.as_ref())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    fn parse(extra: &str) -> Result<MqttIngestConfig, toml::de::Error> {
+        toml::from_str(&format!(
+            "url = \"mqtt://localhost\"\ntopic = \"sunsniff/updates\"\n{extra}"
+        ))
+    }
+
+    fn payload(id: &'static str, value: f64) -> Vec<u8> {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "Battery",
+            name: "Power",
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1234, "5678", fields, vec![value]);
+        serde_json::to_vec(&UpdateRecord::new(&update)).unwrap()
+    }
+
+    #[test]
+    fn test_config() {
+        let config = parse("").unwrap();
+        assert_eq!(config.max_field_lists, default_max_field_lists());
+        assert!(config.check(&FieldConfig::default()).is_ok());
+        assert_eq!(parse("max_field_lists = 3").unwrap().max_field_lists, 3);
+        assert!(parse("qos = 1").is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut interner = Interner::with_limit(4);
+        let update = decode(&mut interner, 4, "t", &payload("battery_power", -500.0)).unwrap();
+        assert_eq!(update.timestamp, 1234);
+        assert_eq!(update.serial, "5678");
+        assert_eq!(update.fields[0].id, "battery_power");
+        assert_eq!(update.values, vec![-500.0]);
+        assert!(decode(&mut interner, 4, "t", b"{not json").is_none());
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        // Each list of fields (and the empty list of text fields) counts
+        // against the limit
+        let mut interner = Interner::with_limit(2);
+        assert!(decode(&mut interner, 2, "t", &payload("a", 1.0)).is_some());
+        assert!(decode(&mut interner, 2, "t", &payload("a", 2.0)).is_some());
+        assert!(decode(&mut interner, 2, "t", &payload("b", 1.0)).is_none());
+    }
+}
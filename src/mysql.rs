@@ -0,0 +1,292 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that inserts updates into a MySQL or MariaDB table, using a pool
+//! of connections and prepared statements.
+
+use async_trait::async_trait;
+use chrono::{Datelike, Timelike};
+use futures::channel::mpsc::UnboundedReceiver;
+use mysql_async::prelude::Queryable;
+use mysql_async::{OptsBuilder, Params, Pool, PoolConstraints, PoolOpts, TxOpts, Value};
+use serde::Deserialize;
+use std::iter::zip;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+use super::rotate::utc;
+
+/// Server errors that may clear up by themselves (too many connections,
+/// lock wait timeout and deadlock), so that the write is retried rather
+/// than the batch being discarded
+const TRANSIENT_ERRORS: [u16; 3] = [1040, 1205, 1213];
+/// Writes are made one batch at a time, so more connections would not help
+const MAX_CONNECTIONS: usize = 2;
+
+fn quote_ident(ident: &str) -> String {
+    format!("`{}`", ident.replace('`', "``"))
+}
+
+/// Statement to create the table if necessary
+fn create_query(table: &str) -> String {
+    let index = quote_ident(&format!("{table}_time"));
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+         (time DATETIME(6) NOT NULL, serial VARCHAR(64) NOT NULL, id VARCHAR(64) NOT NULL, \
+         value DOUBLE, text TEXT, INDEX {index} (time))",
+        quote_ident(table)
+    )
+}
+
+fn insert_query(table: &str) -> String {
+    format!(
+        "INSERT INTO {} (time, serial, id, value, text) VALUES (?, ?, ?, ?, ?)",
+        quote_ident(table)
+    )
+}
+
+/// Convert a timestamp in nanoseconds since the UNIX epoch to a `DATETIME`
+/// value in UTC, with microsecond precision
+fn datetime(timestamp: i64) -> Value {
+    let time = utc(timestamp);
+    Value::Date(
+        time.year() as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+        time.nanosecond() / 1000,
+    )
+}
+
+/// A row of the table
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    time: Value,
+    serial: String,
+    id: String,
+    value: Option<f64>,
+    /// Label of an enum field, or the value of a text field
+    text: Option<String>,
+}
+
+impl From<Row> for Params {
+    fn from(row: Row) -> Self {
+        Params::Positional(vec![
+            row.time,
+            row.serial.into(),
+            row.id.into(),
+            row.value.into(),
+            row.text.into(),
+        ])
+    }
+}
+
+pub struct MysqlReceiver {
+    pool: Pool,
+    table: String,
+    insert: String,
+    /// Whether the table is known to exist
+    created: AtomicBool,
+    batch: Options,
+}
+
+impl MysqlReceiver {
+    pub fn new(config: &Config) -> Self {
+        let constraints = PoolConstraints::new(0, MAX_CONNECTIONS).unwrap();
+        let opts = OptsBuilder::default()
+            .ip_or_hostname(config.host.clone())
+            .tcp_port(config.port)
+            .user(config.user.clone())
+            .pass(config.password.clone())
+            .db_name(Some(config.database.clone()))
+            .pool_opts(PoolOpts::default().with_constraints(constraints));
+        Self {
+            pool: Pool::new(opts),
+            table: config.table.clone(),
+            insert: insert_query(&config.table),
+            created: AtomicBool::new(false),
+            batch: config.batch.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for MysqlReceiver {
+    type Item = Row;
+    type Error = mysql_async::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Row> {
+        let time = datetime(update.timestamp);
+        let row = |id: &str, value, text| Row {
+            time: time.clone(),
+            serial: update.serial.clone(),
+            id: id.to_owned(),
+            value,
+            text,
+        };
+        let numeric = zip(update.fields.iter(), update.values.iter()).map(|(field, value)| {
+            row(
+                field.id,
+                Some(*value),
+                field.label(*value).map(str::to_owned),
+            )
+        });
+        let text = zip(update.text_fields.iter(), update.text.iter())
+            .map(|(field, text)| row(field.id, None, Some(text.clone())));
+        numeric.chain(text).collect()
+    }
+
+    async fn write(&self, rows: Vec<Row>) -> mysql_async::Result<()> {
+        let mut conn = self.pool.get_conn().await?;
+        if !self.created.load(Ordering::Relaxed) {
+            conn.query_drop(create_query(&self.table)).await?;
+            self.created.store(true, Ordering::Relaxed);
+        }
+        // A batch is written completely or not at all, so that retrying it
+        // does not duplicate rows
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        tx.exec_batch(self.insert.as_str(), rows).await?;
+        tx.commit().await
+    }
+
+    fn is_fatal(&self, err: &mysql_async::Error) -> bool {
+        matches!(err, mysql_async::Error::Server(err) if !TRANSIENT_ERRORS.contains(&err.code))
+    }
+}
+
+#[async_trait]
+impl Receiver for MysqlReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+        let _ = self.pool.clone().disconnect().await;
+    }
+
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get_conn().await?;
+        conn.ping().await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub database: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    3306
+}
+
+fn default_table() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_queries() {
+        let sql = create_query("my`table");
+        assert!(
+            sql.starts_with("CREATE TABLE IF NOT EXISTS `my``table` (time DATETIME(6) NOT NULL")
+        );
+        assert!(sql.ends_with("INDEX `my``table_time` (time))"));
+        assert_eq!(
+            insert_query("sunsniff"),
+            "INSERT INTO `sunsniff` (time, serial, id, value, text) VALUES (?, ?, ?, ?, ?)"
+        );
+    }
+
+    #[test]
+    fn test_datetime() {
+        assert_eq!(
+            datetime(1_700_000_000_123_456_789),
+            Value::Date(2023, 11, 14, 22, 13, 20, 123_456)
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let config: Config = toml::from_str("database = \"solar\"").unwrap();
+        assert_eq!((config.host.as_str(), config.port), ("localhost", 3306));
+        let receiver = MysqlReceiver::new(&config);
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "Inverter",
+            name: "State",
+            id: "inverter_state",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(2, "Normal")],
+            bit: None,
+        }]));
+        let text_fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Text,
+            group: "Inverter",
+            name: "Version",
+            id: "version",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(0, "1234", fields, vec![2.0])
+            .with_text(text_fields, vec!["1.0".to_owned()]);
+        let rows = receiver.encode(&update);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            Params::from(rows[0].clone()),
+            Params::Positional(vec![
+                Value::Date(1970, 1, 1, 0, 0, 0, 0),
+                Value::from("1234"),
+                Value::from("inverter_state"),
+                Value::Double(2.0),
+                Value::from("Normal"),
+            ])
+        );
+        assert_eq!(rows[1].value, None);
+        assert_eq!(rows[1].text.as_deref(), Some("1.0"));
+    }
+}
@@ -0,0 +1,496 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that publishes updates to NATS, optionally waiting for JetStream
+//! to acknowledge them.
+//!
+//! The NATS client protocol is a simple text protocol, so it is spoken
+//! directly.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::json::UpdateRecord;
+use super::receiver::{Receiver, Update};
+
+/// Time to wait for the server to respond, unless overridden by the
+/// `timeout` in the retry configuration
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Message from the server (other than `PING`, which is handled internally)
+#[derive(Debug, PartialEq)]
+enum ServerMessage {
+    Info(Value),
+    Msg { subject: String, payload: Vec<u8> },
+    Ok,
+    Pong,
+}
+
+/// Read a message from the server. A `-ERR` is returned as an error.
+async fn read_message<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<ServerMessage>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    let line = line.trim_end();
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid message: {line}"));
+    let (op, args) = line.split_once(' ').unwrap_or((line, ""));
+    match op.to_ascii_uppercase().as_str() {
+        "INFO" => Ok(Some(ServerMessage::Info(
+            serde_json::from_str(args).map_err(|_| invalid())?,
+        ))),
+        "MSG" => {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            if parts.len() < 3 {
+                return Err(invalid());
+            }
+            let len: usize = parts[parts.len() - 1].parse().map_err(|_| invalid())?;
+            let mut payload = vec![0u8; len + 2];
+            reader.read_exact(&mut payload).await?;
+            payload.truncate(len);
+            Ok(Some(ServerMessage::Msg {
+                subject: parts[0].to_owned(),
+                payload,
+            }))
+        }
+        "+OK" => Ok(Some(ServerMessage::Ok)),
+        "PING" => Ok(None),
+        "PONG" => Ok(Some(ServerMessage::Pong)),
+        "-ERR" => Err(Error::other(format!(
+            "NATS error: {}",
+            args.trim_matches('\'')
+        ))),
+        _ => Err(invalid()),
+    }
+}
+
+/// Make a string safe to use as a single token of a subject
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c == '.' || c == '*' || c == '>' || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Fill in the placeholders in a subject template
+fn render(template: &str, serial: &str, group: &str, id: &str) -> String {
+    template
+        .replace("{serial}", &sanitize(serial))
+        .replace("{group}", &sanitize(group))
+        .replace("{id}", &sanitize(id))
+}
+
+/// Subject filter matching every subject produced from a template
+fn wildcard(template: &str) -> String {
+    template
+        .split('.')
+        .map(|token| if token.contains('{') { "*" } else { token })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Subject and payload of a message to publish
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Message {
+    subject: String,
+    payload: Vec<u8>,
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    /// Prefix for the subjects of replies sent to us
+    inbox: String,
+}
+
+impl Connection {
+    /// Read the next message, answering any pings along the way
+    async fn next_message(&mut self) -> std::io::Result<ServerMessage> {
+        loop {
+            match read_message(&mut self.reader).await? {
+                Some(message) => return Ok(message),
+                None => self.writer.write_all(b"PONG\r\n").await?,
+            }
+        }
+    }
+
+    /// Publish a message and wait for the reply
+    async fn request(&mut self, subject: &str, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        let reply = format!("{}.request", self.inbox);
+        let mut data = format!("PUB {subject} {reply} {}\r\n", payload.len()).into_bytes();
+        data.extend_from_slice(payload);
+        data.extend_from_slice(b"\r\n");
+        self.writer.write_all(&data).await?;
+        loop {
+            if let ServerMessage::Msg { subject, payload } = self.next_message().await? {
+                if subject == reply {
+                    return Ok(payload);
+                }
+            }
+        }
+    }
+
+    /// Publish messages. If `jetstream` is true, wait for each to be
+    /// acknowledged, otherwise just wait for the server to process them.
+    async fn publish(&mut self, messages: &[Message], jetstream: bool) -> std::io::Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let mut data = vec![];
+        let mut pending = HashSet::new();
+        for (i, message) in messages.iter().enumerate() {
+            let len = message.payload.len();
+            if jetstream {
+                let reply = format!("{}.{i}", self.inbox);
+                data.extend(format!("PUB {} {reply} {len}\r\n", message.subject).bytes());
+                pending.insert(reply);
+            } else {
+                data.extend(format!("PUB {} {len}\r\n", message.subject).bytes());
+            }
+            data.extend_from_slice(&message.payload);
+            data.extend_from_slice(b"\r\n");
+        }
+        if !jetstream {
+            data.extend_from_slice(b"PING\r\n");
+        }
+        self.writer.write_all(&data).await?;
+        loop {
+            match self.next_message().await? {
+                ServerMessage::Pong if !jetstream => return Ok(()),
+                ServerMessage::Msg { subject, payload } if pending.remove(&subject) => {
+                    check_ack(&payload)?;
+                    if pending.is_empty() {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Turn a JetStream API error response into an error
+fn check_ack(payload: &[u8]) -> std::io::Result<()> {
+    let response: Value = serde_json::from_slice(payload)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid JetStream response"))?;
+    match response.get("error") {
+        Some(error) => Err(Error::other(format!(
+            "JetStream error: {}",
+            error["description"].as_str().unwrap_or("unknown")
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub struct NatsReceiver {
+    address: String,
+    user: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+    subject: String,
+    jetstream: bool,
+    stream: Option<String>,
+    batch: Options,
+    /// Created on first use, and discarded after an error
+    connection: Mutex<Option<Connection>>,
+}
+
+impl NatsReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            address: format!("{}:{}", config.host, config.port),
+            user: config.user.clone(),
+            password: config.password.clone(),
+            token: config.token.clone(),
+            subject: config.subject.clone(),
+            jetstream: config.jetstream || config.stream.is_some(),
+            stream: config.stream.clone(),
+            batch: config.batch.clone(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<Connection> {
+        let (reader, writer) = TcpStream::connect(&self.address).await?.into_split();
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut connection = Connection {
+            reader: BufReader::new(reader),
+            writer,
+            inbox: format!("_INBOX.sunsniff.{}.{nanos}", std::process::id()),
+        };
+        match connection.next_message().await? {
+            ServerMessage::Info(info) => {
+                if info["tls_required"] == json!(true) {
+                    return Err(Error::new(ErrorKind::Unsupported, "TLS is not supported"));
+                }
+            }
+            other => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expected INFO, received {other:?}"),
+                ))
+            }
+        }
+        let options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "name": "sunsniff",
+            "user": self.user,
+            "pass": self.password,
+            "auth_token": self.token,
+        });
+        let data = format!(
+            "CONNECT {options}\r\nSUB {}.* 1\r\nPING\r\n",
+            connection.inbox
+        );
+        connection.writer.write_all(data.as_bytes()).await?;
+        while connection.next_message().await? != ServerMessage::Pong {}
+        info!("Connected to NATS at {}", self.address);
+
+        if let Some(stream) = &self.stream {
+            let config = json!({"name": stream, "subjects": [wildcard(&self.subject)]});
+            let response = connection
+                .request(
+                    &format!("$JS.API.STREAM.CREATE.{stream}"),
+                    config.to_string().as_bytes(),
+                )
+                .await?;
+            // If the stream already exists with a different configuration,
+            // it is used as is.
+            if let Err(err) = check_ack(&response) {
+                warn!("Could not create stream {stream}: {err}");
+            }
+        }
+        Ok(connection)
+    }
+
+    async fn write_inner(
+        &self,
+        connection: &mut Option<Connection>,
+        messages: &[Message],
+    ) -> std::io::Result<()> {
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        connection
+            .as_mut()
+            .unwrap()
+            .publish(messages, self.jetstream)
+            .await
+    }
+}
+
+#[async_trait]
+impl BatchWriter for NatsReceiver {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Message> {
+        UpdateRecord::new(update)
+            .split(|meta| render(&self.subject, &update.serial, &meta.group, &meta.id))
+            .into_iter()
+            .map(|(subject, record)| Message {
+                subject,
+                payload: serde_json::to_vec(&record).unwrap(),
+            })
+            .collect()
+    }
+
+    async fn write(&self, messages: Vec<Message>) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().await;
+        let timeout = self
+            .batch
+            .retry
+            .timeout
+            .map_or(TIMEOUT, Duration::from_secs);
+        let result = tokio::time::timeout(timeout, self.write_inner(&mut connection, &messages))
+            .await
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "timed out")));
+        if result.is_err() {
+            *connection = None; // Reconnect for the next attempt
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Receiver for NatsReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    /// Subject, with optional `{serial}`, `{group}` and `{id}` placeholders
+    #[serde(default = "default_subject")]
+    pub subject: String,
+    /// Wait for JetStream to acknowledge each message
+    #[serde(default)]
+    pub jetstream: bool,
+    /// JetStream stream to create (implies `jetstream`)
+    pub stream: Option<String>,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    4222
+}
+
+fn default_subject() -> String {
+    "sunsniff.{serial}".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    fn receiver(subject: &str) -> NatsReceiver {
+        NatsReceiver::new(&Config {
+            host: default_host(),
+            port: default_port(),
+            user: None,
+            password: None,
+            token: None,
+            subject: subject.to_owned(),
+            jetstream: false,
+            stream: None,
+            batch: Options::default(),
+        })
+    }
+
+    #[test]
+    fn test_subject() {
+        assert_eq!(
+            render("solar.{serial}.{group}.{id}", "12.34", "PV 1", "pv_power"),
+            "solar.12_34.PV_1.pv_power"
+        );
+        assert_eq!(wildcard("solar.{serial}.{group}"), "solar.*.*");
+        assert_eq!(wildcard("solar.inv-{serial}"), "solar.*");
+    }
+
+    #[test]
+    fn test_encode() {
+        let field = |group, id| Field {
+            field_type: FieldType::Power,
+            group,
+            name: "Power",
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        };
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            field("Grid", "grid_power"),
+            field("Load", "load_power"),
+            field("Grid", "grid_ct_power"),
+        ]));
+        let update = Update::new(1, "1234", fields, vec![1.0, 2.0, 3.0]);
+
+        let messages = receiver("sunsniff.{serial}").encode(&update);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].subject, "sunsniff.1234");
+
+        let messages = receiver("sunsniff.{serial}.{group}").encode(&update);
+        let subjects: Vec<&str> = messages.iter().map(|m| m.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["sunsniff.1234.Grid", "sunsniff.1234.Load"]);
+        let record: UpdateRecord = serde_json::from_slice(&messages[0].payload).unwrap();
+        let ids: Vec<&str> = record.fields.iter().map(|f| f.meta.id.as_str()).collect();
+        assert_eq!(ids, vec!["grid_power", "grid_ct_power"]);
+        assert_eq!(record.timestamp, 1);
+        assert_eq!(record.serial, "1234");
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let data: &[u8] = b"INFO {\"server_id\":\"x\"}\r\nPING\r\nMSG a.b 1 c 3\r\nabc\r\n\
+                            +OK\r\npong\r\n-ERR 'Authorization Violation'\r\n";
+        let mut reader = BufReader::new(data);
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(ServerMessage::Info(json!({"server_id": "x"})))
+        );
+        assert_eq!(read_message(&mut reader).await.unwrap(), None);
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(ServerMessage::Msg {
+                subject: "a.b".to_owned(),
+                payload: b"abc".to_vec()
+            })
+        );
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(ServerMessage::Ok)
+        );
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(ServerMessage::Pong)
+        );
+        let err = read_message(&mut reader).await.unwrap_err();
+        assert_eq!(err.to_string(), "NATS error: Authorization Violation");
+    }
+
+    #[test]
+    fn test_check_ack() {
+        assert!(check_ack(br#"{"stream":"S","seq":1}"#).is_ok());
+        assert!(check_ack(br#"{"error":{"code":503,"description":"no responders"}}"#).is_err());
+    }
+}
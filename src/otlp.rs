@@ -0,0 +1,242 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that exports values as OpenTelemetry metrics, using OTLP over HTTP
+//! with the JSON encoding.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::iter::zip;
+use std::sync::Arc;
+
+use super::batch::{is_fatal_status, run_batched, BatchWriter, Options};
+use super::fields::FieldType;
+use super::receiver::{Receiver, Update};
+
+/// Value of AGGREGATION_TEMPORALITY_CUMULATIVE
+const CUMULATIVE: i32 = 2;
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+/// Convert an update to a list of OTLP `Metric` objects. Energy fields are
+/// counters (monotonic sums); everything else is a gauge.
+fn encode(update: &Update<'_>) -> Vec<Value> {
+    let mut metrics = vec![];
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if !value.is_finite() {
+            continue; // JSON cannot represent them
+        }
+        let point = json!({
+            "attributes": [
+                string_attribute("serial", &update.serial),
+                string_attribute("group", field.group),
+            ],
+            // 64-bit integers are encoded as strings in JSON
+            "timeUnixNano": update.timestamp.to_string(),
+            "asDouble": value,
+        });
+        let mut metric = json!({
+            "name": format!("sunsniff.{}", field.id),
+            "description": field.name,
+            "unit": field.unit,
+        });
+        if field.field_type == FieldType::Energy {
+            metric["sum"] = json!({
+                "dataPoints": [point],
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+            });
+        } else {
+            metric["gauge"] = json!({"dataPoints": [point]});
+        }
+        metrics.push(metric);
+    }
+    metrics
+}
+
+pub struct OtlpReceiver {
+    client: Client,
+    url: String,
+    headers: HashMap<String, String>,
+    service_name: String,
+    batch: Options,
+}
+
+impl OtlpReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            url: config.url.clone(),
+            headers: config.headers.clone(),
+            service_name: config.service_name.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+
+    /// Wrap metrics in an `ExportMetricsServiceRequest`
+    fn request(&self, metrics: Vec<Value>) -> Value {
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [string_attribute("service.name", &self.service_name)],
+                },
+                "scopeMetrics": [{
+                    "scope": {"name": "sunsniff", "version": env!("CARGO_PKG_VERSION")},
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+}
+
+#[async_trait]
+impl BatchWriter for OtlpReceiver {
+    type Item = Value;
+    type Error = reqwest::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Value> {
+        encode(update)
+    }
+
+    async fn write(&self, metrics: Vec<Value>) -> reqwest::Result<()> {
+        let body = serde_json::to_vec(&self.request(metrics)).unwrap();
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        for (key, value) in self.headers.iter() {
+            request = request.header(key, value);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    fn is_fatal(&self, err: &reqwest::Error) -> bool {
+        err.status()
+            .is_some_and(|status| is_fatal_status(status.as_u16()))
+    }
+}
+
+#[async_trait]
+impl Receiver for OtlpReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// URL of the OTLP/HTTP metrics endpoint
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// Extra HTTP headers, such as for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Value of the `service.name` resource attribute
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_url() -> String {
+    "http://localhost:4318/v1/metrics".to_string()
+}
+
+fn default_service_name() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::Field;
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            Field {
+                field_type: FieldType::Power,
+                group: "Grid",
+                name: "Power",
+                id: "grid_power",
+                scale: 1.0,
+                bias: 0.0,
+                signed: true,
+                unit: "W",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+            Field {
+                field_type: FieldType::Energy,
+                group: "Grid",
+                name: "Total import",
+                id: "grid_import_total",
+                scale: 0.1,
+                bias: 0.0,
+                signed: false,
+                unit: "kWh",
+                requires: None,
+                labels: &[],
+                bit: None,
+            },
+        ]));
+        let update = Update::new(1_700_000_000_000_000_000, "1234", fields, vec![-2.5, 100.0]);
+        let metrics = encode(&update);
+        let point = |value: f64| {
+            json!({
+                "attributes": [
+                    {"key": "serial", "value": {"stringValue": "1234"}},
+                    {"key": "group", "value": {"stringValue": "Grid"}},
+                ],
+                "timeUnixNano": "1700000000000000000",
+                "asDouble": value,
+            })
+        };
+        assert_eq!(
+            metrics,
+            vec![
+                json!({
+                    "name": "sunsniff.grid_power",
+                    "description": "Power",
+                    "unit": "W",
+                    "gauge": {"dataPoints": [point(-2.5)]},
+                }),
+                json!({
+                    "name": "sunsniff.grid_import_total",
+                    "description": "Total import",
+                    "unit": "kWh",
+                    "sum": {
+                        "dataPoints": [point(100.0)],
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    },
+                }),
+            ]
+        );
+    }
+}
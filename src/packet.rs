@@ -0,0 +1,602 @@
+/* Copyright 2022-2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Decoding of the packets sent by the dongle to the remote server. This is
+//! shared by the frontends that see those packets.
+
+use chrono::{DateTime, LocalResult, NaiveDate};
+use chrono_tz::Tz;
+#[cfg(any(feature = "pcap", feature = "rawsock"))]
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use log::info;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::fields::{merge_fields, FieldConfig, FieldSet, Layout, PacketLayoutConfig};
+use crate::receiver::{Update, UpdateItem};
+use crate::solarman;
+
+/// Description of a packet layout emitted by a particular dongle firmware.
+/// A packet is matched to a layout by its length and its first bytes.
+#[derive(Clone)]
+struct PacketLayout {
+    /// Name used in log messages
+    name: Cow<'static, str>,
+    /// Expected length of the packet (TCP payload)
+    length: usize,
+    /// Expected first bytes of the packet
+    header: Cow<'static, [u8]>,
+    /// Offsets containing the inverter serial number
+    serial_range: Range<usize>,
+    /// Offset at which the timestamp is located
+    datetime_offset: usize,
+    /// Fields in the packet
+    fields: &'static [Field<'static>],
+    /// Offsets of the words for each field
+    offsets: &'static [&'static [usize]],
+    /// Amount added to each of `offsets` (and to the offsets of user-defined
+    /// fields)
+    shift: isize,
+}
+
+impl PacketLayout {
+    /// Create a layout from a `[[packet_layouts]]` section of the
+    /// configuration, based on the built-in layout
+    fn from_config(config: &PacketLayoutConfig) -> Result<Self, String> {
+        let header = parse_hex(&config.header)
+            .map_err(|err| format!("Invalid header for layout {}: {err}", config.name))?;
+        if header.is_empty() {
+            return Err(format!("Layout {} must have a header", config.name));
+        }
+        let serial_range = config.serial_offset..config.serial_offset + 10;
+        if serial_range.end > config.length || config.datetime_offset + 6 > config.length {
+            return Err(format!(
+                "Serial number or timestamp is out of range for layout {}",
+                config.name
+            ));
+        }
+        Ok(Self {
+            name: Cow::Owned(config.name.clone()),
+            length: config.length,
+            header: Cow::Owned(header),
+            serial_range,
+            datetime_offset: config.datetime_offset,
+            shift: config.shift,
+            ..LAYOUTS[0].clone()
+        })
+    }
+
+    fn matches(&self, payload: &[u8]) -> bool {
+        payload.len() == self.length && payload.starts_with(&self.header)
+    }
+
+    /// Apply the shift to an offset, returning `None` if the result is
+    /// negative
+    fn shifted(&self, offset: usize) -> Option<usize> {
+        offset.checked_add_signed(self.shift)
+    }
+
+    /// Whether a packet with this layout could start at the start of `data`
+    /// (which may be shorter than the header).
+    fn could_start(&self, data: &[u8]) -> bool {
+        let n = data.len().min(self.header.len());
+        data[..n] == self.header[..n]
+    }
+}
+
+/// Built-in packet layouts. Layouts from the configuration are tried first,
+/// then these, and the first one that matches a packet is used.
+const LAYOUTS: &[PacketLayout] = &[PacketLayout {
+    name: Cow::Borrowed("solarman-292"),
+    length: 292,
+    header: Cow::Borrowed(&[0xa5]),
+    serial_range: 11..21,
+    datetime_offset: 37,
+    fields: FIELDS,
+    offsets: OFFSETS,
+    shift: 0,
+}];
+
+/// Decoder for packets sent by the dongle
+#[derive(Clone)]
+pub struct Codec {
+    tz: Tz,
+    /// Known layouts, each with the fields to decode and the offsets of their words
+    layouts: Vec<(PacketLayout, FieldSet<Vec<usize>>)>,
+}
+
+/// Extract the timestamp from the packet.
+///
+/// The timestamp consists of YY-MM-DD HH:MM:SS in 6 one-byte fields, with
+/// the year relative to 2000. It is in local time, so needs to be combined
+/// with the timestamp.
+///
+/// If the timestamp is an invalid time, or is invalid or ambiguous for the
+/// time zone, returns `None`.
+fn parse_timestamp(payload: &[u8], offset: usize, tz: Tz) -> Option<DateTime<Tz>> {
+    let dt = NaiveDate::from_ymd_opt(
+        payload[offset] as i32 + 2000,
+        payload[offset + 1] as u32,
+        payload[offset + 2] as u32,
+    )?
+    .and_hms_opt(
+        payload[offset + 3] as u32,
+        payload[offset + 4] as u32,
+        payload[offset + 5] as u32,
+    )?
+    .and_local_timezone(tz);
+    match dt {
+        LocalResult::Single(x) => Some(x),
+        _ => None, // TODO: what to do with ambiguous times - try to guess based on history?
+    }
+}
+
+impl Codec {
+    pub fn new(tz: Tz, field_config: &FieldConfig) -> Result<Self, String> {
+        if field_config.inverter.layout == Layout::ThreePhase && field_config.field_map.is_none() {
+            return Err(
+                "The packets of three-phase inverters can only be decoded with a field_map".into(),
+            );
+        }
+        let configured = field_config
+            .packet_layouts
+            .iter()
+            .map(PacketLayout::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        let field_map = field_config.load_field_map()?;
+        let mut layouts = Vec::with_capacity(configured.len() + LAYOUTS.len());
+        for layout in configured.into_iter().chain(LAYOUTS.iter().cloned()) {
+            let offsets: Vec<Vec<usize>> = layout.offsets.iter().map(|o| o.to_vec()).collect();
+            let mut fields = merge_fields(
+                layout.fields,
+                &offsets,
+                field_config,
+                field_map.as_deref(),
+                |f| f.addresses(f.offset, f.offset2, 2),
+            )?;
+            let all_fields = fields.fields.iter().chain(fields.text_fields.iter());
+            let all_offsets = fields
+                .addresses
+                .iter_mut()
+                .chain(fields.text_addresses.iter_mut());
+            for (field, field_offsets) in all_fields.zip(all_offsets) {
+                for offset in field_offsets.iter_mut() {
+                    match layout.shifted(*offset) {
+                        Some(shifted) if shifted + 2 <= layout.length => *offset = shifted,
+                        _ => {
+                            return Err(format!(
+                                "Offset for field {} is out of range for layout {}",
+                                field.id, layout.name
+                            ))
+                        }
+                    }
+                }
+            }
+            layouts.push((layout, fields));
+        }
+        Ok(Codec { tz, layouts })
+    }
+
+    /// Decode a packet (the TCP payload). Returns `None` if it doesn't match
+    /// any known layout.
+    pub fn decode_payload(&self, payload: &[u8]) -> Option<Arc<Update<'static>>> {
+        let (layout, fields) = self
+            .layouts
+            .iter()
+            .find(|(layout, _)| layout.matches(payload))?;
+        let dt = match parse_timestamp(payload, layout.datetime_offset, self.tz) {
+            Some(x) => x,
+            None => {
+                return None; // Parse error means it's probably not the packet we expected
+            }
+        };
+        let serial =
+            std::str::from_utf8(&payload[layout.serial_range.clone()]).unwrap_or("unknown");
+        info!(
+            "Received {} packet with timestamp {:?} for inverter {}",
+            layout.name, dt, serial
+        );
+        let word = |offset: usize| {
+            let bytes = &payload[offset..offset + 2];
+            let bytes = <&[u8; 2]>::try_from(bytes).unwrap();
+            u16::from_be_bytes(*bytes)
+        };
+        let mut values = Vec::with_capacity(fields.fields.len());
+        for (offsets, field) in fields.addresses.iter().zip(fields.fields.iter()) {
+            let value = field.from_u16s(offsets.iter().cloned().map(word));
+            values.push(value);
+        }
+        let mut text = Vec::with_capacity(fields.text_fields.len());
+        for (offsets, field) in fields.text_addresses.iter().zip(fields.text_fields.iter()) {
+            text.push(field.text_from_u16s(offsets.iter().cloned().map(word)));
+        }
+        /* unwrapping timestamp_nanos_opt is safe because the encoding
+         * only supports up to 2127 (or 2255 if the year is interpreted
+         * as unsigned), which DateTime supports up to 2262 for
+         * nanosecond timestamps.
+         */
+        let update = Update::new(
+            dt.timestamp_nanos_opt().unwrap(),
+            serial,
+            fields.fields,
+            values,
+        )
+        .with_text(fields.text_fields, text);
+        Some(Arc::new(update))
+    }
+
+    /// Split a buffer of data received over a stream into messages,
+    /// decoding those that match a known layout and removing the data that
+    /// has been consumed. A message is either a Solarman V5 frame (such as
+    /// a heartbeat) or a packet that matches a layout. Data that does not
+    /// start with a known header is skipped, so that the stream can
+    /// resynchronise after lost data. An incomplete message at the end of
+    /// the buffer is left there to be completed by future data.
+    pub fn decode_messages(&self, buffer: &mut Vec<u8>) -> Vec<Message> {
+        let mut messages = vec![];
+        let mut pos = 0;
+        while pos < buffer.len() {
+            let rest = &buffer[pos..];
+            // A frame with a valid checksum is taken whole, even if it does
+            // not match a layout, so that the packets after it are found
+            if let Some((_, length)) = solarman::Frame::parse(rest) {
+                let data = rest[..length].to_vec();
+                let update = self.decode_payload(&data);
+                messages.push(Message { data, update });
+                pos += length;
+                continue;
+            }
+            if !self
+                .layouts
+                .iter()
+                .any(|(layout, _)| layout.could_start(rest))
+                && solarman::frame_length(rest).is_none()
+            {
+                pos += 1;
+                continue;
+            }
+            let complete: Vec<usize> = self
+                .layouts
+                .iter()
+                .filter(|(layout, _)| {
+                    rest.len() >= layout.length && layout.matches(&rest[..layout.length])
+                })
+                .map(|(layout, _)| layout.length)
+                .collect();
+            // If several layouts match, prefer one that is followed by the
+            // end of the data or the start of another packet
+            let boundary = |length: usize| {
+                let next = &rest[length..];
+                next.is_empty()
+                    || self
+                        .layouts
+                        .iter()
+                        .any(|(layout, _)| layout.could_start(next))
+            };
+            let complete = complete
+                .iter()
+                .find(|&&length| boundary(length))
+                .or(complete.first())
+                .copied();
+            if let Some(length) = complete {
+                if let Some(update) = self.decode_payload(&rest[..length]) {
+                    messages.push(Message {
+                        data: rest[..length].to_vec(),
+                        update: Some(update),
+                    });
+                    pos += length;
+                    continue;
+                }
+            } else if self
+                .layouts
+                .iter()
+                .any(|(layout, _)| rest.len() < layout.length)
+                || solarman::frame_length(rest).is_some_and(|length| rest.len() < length)
+            {
+                break; // Wait for more data
+            }
+            pos += 1;
+        }
+        buffer.drain(..pos);
+        messages
+    }
+
+    /// Decode all the complete packets in a buffer of data received over a
+    /// stream, removing the data that has been consumed. See
+    /// [Codec::decode_messages].
+    pub fn decode_stream(&self, buffer: &mut Vec<u8>) -> Vec<UpdateItem> {
+        self.decode_messages(buffer)
+            .into_iter()
+            .filter_map(|message| message.update)
+            .collect()
+    }
+}
+
+/// A message received from the dongle over a stream
+pub struct Message {
+    /// The bytes of the message
+    pub data: Vec<u8>,
+    /// The decoded packet, if the message matches a known layout
+    pub update: Option<UpdateItem>,
+}
+
+/// Identifies one direction of a TCP connection, by source and destination
+/// address and port.
+pub type Flow = (IpAddr, u16, IpAddr, u16);
+
+/// Maximum number of flows tracked by [Reassembler]. There is no reliable way
+/// to tell when a flow has ended (the FIN may not be captured), so when
+/// there are too many, they are all forgotten.
+const MAX_FLOWS: usize = 1024;
+
+/// Reassembles packets that are split across TCP segments, or that share a
+/// segment. Retransmitted data is ignored, and if data is missing the
+/// partial packet is discarded.
+pub struct Reassembler<K> {
+    /// Sequence number of the next expected byte, and data not yet decoded
+    flows: HashMap<K, (u32, Vec<u8>)>,
+}
+
+impl<K: Hash + Eq> Reassembler<K> {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Add a segment with sequence number `seq` to a flow and decode any
+    /// packets that it completes.
+    pub fn push(&mut self, codec: &Codec, flow: K, seq: u32, data: &[u8]) -> Vec<UpdateItem> {
+        if data.is_empty() {
+            return vec![];
+        }
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&flow) {
+            self.flows.clear();
+        }
+        let (next, buffer) = self.flows.entry(flow).or_insert_with(|| (seq, Vec::new()));
+        // Number of bytes in this segment that have already been seen
+        let seen = next.wrapping_sub(seq) as i32;
+        let seen = if seen < 0 {
+            buffer.clear(); // Some data was lost
+            0
+        } else if seen as usize >= data.len() {
+            return vec![]; // Retransmission
+        } else {
+            seen as usize
+        };
+        buffer.extend_from_slice(&data[seen..]);
+        *next = seq.wrapping_add(data.len() as u32);
+        codec.decode_stream(buffer)
+    }
+}
+
+impl<K: Hash + Eq> Default for Reassembler<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Extract the flow, sequence number and payload from a TCP segment
+#[cfg(any(feature = "pcap", feature = "rawsock"))]
+pub(crate) fn tcp_segment<'a>(sliced: &SlicedPacket<'a>) -> Option<(Flow, u32, &'a [u8])> {
+    let (src, dst) = match &sliced.ip {
+        Some(InternetSlice::Ipv4(header, _)) => (
+            IpAddr::from(header.source_addr()),
+            IpAddr::from(header.destination_addr()),
+        ),
+        Some(InternetSlice::Ipv6(header, _)) => (
+            IpAddr::from(header.source_addr()),
+            IpAddr::from(header.destination_addr()),
+        ),
+        None => return None,
+    };
+    match &sliced.transport {
+        Some(TransportSlice::Tcp(tcp)) => Some((
+            (src, tcp.source_port(), dst, tcp.destination_port()),
+            tcp.sequence_number(),
+            sliced.payload,
+        )),
+        _ => None,
+    }
+}
+
+/// Parse a string of hexadecimal digits into bytes. Whitespace is ignored.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or_else(|| format!("Invalid hex digit {c:?}"))
+        })
+        .collect::<Result<_, _>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return Err("Odd number of hex digits".to_owned());
+    }
+    Ok(digits.chunks(2).map(|d| (d[0] << 4) | d[1]).collect())
+}
+
+/// Format bytes as hexadecimal digits
+pub fn format_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+include!(concat!(env!("OUT_DIR"), "/pcap_fields.rs"));
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::solarman::test::HEARTBEAT_FRAME;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(
+            parse_hex("a5 06\n01ff").unwrap(),
+            vec![0xa5, 0x06, 0x01, 0xff]
+        );
+        assert_eq!(format_hex(&[0xa5, 0x06, 0x01, 0xff]), "a50601ff");
+        assert!(parse_hex("a5 0").is_err());
+        assert!(parse_hex("a5 0g").is_err());
+    }
+
+    fn codec() -> Codec {
+        Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let codec = codec();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        // Garbage, then two packets in one segment, then half a packet
+        let mut buffer = vec![0x01, 0xa5, 0x02];
+        buffer.extend_from_slice(payload);
+        buffer.extend_from_slice(payload);
+        buffer.extend_from_slice(&payload[..100]);
+        let updates = codec.decode_stream(&mut buffer);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(buffer, &payload[..100]);
+        buffer.extend_from_slice(&payload[100..]);
+        assert_eq!(codec.decode_stream(&mut buffer).len(), 1);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_messages() {
+        let codec = codec();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        // A heartbeat, a packet, and half a heartbeat
+        let mut buffer = HEARTBEAT_FRAME.to_vec();
+        buffer.extend_from_slice(payload);
+        buffer.extend_from_slice(&HEARTBEAT_FRAME[..5]);
+        let messages = codec.decode_messages(&mut buffer);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].data, HEARTBEAT_FRAME);
+        assert!(messages[0].update.is_none());
+        assert_eq!(messages[1].data, payload);
+        assert!(messages[1].update.is_some());
+        assert_eq!(buffer, &HEARTBEAT_FRAME[..5]);
+        buffer.extend_from_slice(&HEARTBEAT_FRAME[5..]);
+        assert_eq!(codec.decode_messages(&mut buffer).len(), 1);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_packet_layouts() {
+        let field_config: FieldConfig = toml::from_str(
+            r#"
+            [[packet_layouts]]
+            name = "padded"
+            length = 296
+            header = "a5"
+            serial_offset = 11
+            datetime_offset = 37
+            shift = 4
+            "#,
+        )
+        .unwrap();
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &field_config).unwrap();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        // The same packet with padding after the timestamp
+        let mut padded = payload[..43].to_vec();
+        padded.extend_from_slice(&[0; 4]);
+        padded.extend_from_slice(&payload[43..]);
+
+        let mut buffer = payload.to_vec();
+        buffer.extend_from_slice(&padded);
+        buffer.extend_from_slice(payload);
+        let updates = codec.decode_stream(&mut buffer);
+        assert!(buffer.is_empty());
+        assert_eq!(updates.len(), 3);
+        for update in updates.iter() {
+            assert_eq!(update.serial, updates[0].serial);
+            assert_eq!(update.timestamp, updates[0].timestamp);
+            assert_eq!(update.values, updates[0].values);
+        }
+
+        // Fields that would fall outside the packet
+        let mut field_config = field_config;
+        field_config.packet_layouts[0].shift = -50;
+        assert!(Codec::new(chrono_tz::Africa::Johannesburg, &field_config).is_err());
+        field_config.packet_layouts[0].shift = 0;
+        field_config.packet_layouts[0].datetime_offset = 291;
+        assert!(Codec::new(chrono_tz::Africa::Johannesburg, &field_config).is_err());
+    }
+
+    #[test]
+    fn test_reassembler() {
+        let codec = codec();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        let mut reassembler = Reassembler::new();
+        let seq = u32::MAX - 10; // Check wraparound
+        assert!(reassembler.push(&codec, 1, seq, &payload[..100]).is_empty());
+        // Retransmission of the first segment
+        assert!(reassembler.push(&codec, 1, seq, &payload[..100]).is_empty());
+        // Other flows are independent
+        assert!(reassembler.push(&codec, 2, 0, &payload[..50]).is_empty());
+        let seq2 = seq.wrapping_add(100);
+        assert_eq!(reassembler.push(&codec, 1, seq2, &payload[100..]).len(), 1);
+        // Retransmission of the second segment
+        assert!(reassembler
+            .push(&codec, 1, seq2, &payload[100..])
+            .is_empty());
+        // Lost data: the partial packet is discarded
+        assert!(reassembler.push(&codec, 2, 100, &payload[100..]).is_empty());
+        assert_eq!(reassembler.push(&codec, 2, 1000, payload).len(), 1);
+    }
+
+    /// Offset of the TCP payload within the sample packet
+    pub(crate) const PAYLOAD_OFFSET: usize = 54;
+
+    /// Sample data from a real packet, but with the serial number altered for privacy
+    pub(crate) fn sample_packet() -> Vec<u8> {
+        vec![
+            0x04, 0x42, 0x1a, 0x78, 0xac, 0xd0, 0x60, 0x55, 0xf9, 0xb0, 0x92, 0x14, 0x08, 0x00,
+            0x45, 0x00, 0x01, 0x4c, 0x04, 0xf5, 0x00, 0x00, 0xff, 0x06, 0x80, 0x75, 0xc0, 0xa8,
+            0x00, 0xca, 0x2f, 0xf2, 0x43, 0xdd, 0xc5, 0x9a, 0xc7, 0x9c, 0x67, 0x56, 0xe9, 0xb1,
+            0x8d, 0xea, 0x57, 0xed, 0x50, 0x18, 0x15, 0xb6, 0xd3, 0x84, 0x00, 0x00, 0xa5, 0x06,
+            0x01, 0x09, 0x02, 0xce, 0x00, 0x00, 0xfa, 0x01, 0x19, 0x31, 0x32, 0x33, 0x35, 0x36,
+            0x38, 0x37, 0x31, 0x30, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x16, 0x0b, 0x05, 0x08, 0x20, 0x2e, 0x01,
+            0x00, 0x02, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x09, 0x7a, 0x00, 0x00, 0x01, 0x29,
+            0x01, 0x13, 0x00, 0xc8, 0x0d, 0x1d, 0x00, 0x00, 0x00, 0x03, 0x00, 0x08, 0x08, 0x4a,
+            0x00, 0x00, 0x05, 0x52, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x02, 0xe7, 0x13, 0x7a,
+            0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x0c, 0x5f, 0x00, 0x00,
+            0x0a, 0xe1, 0x00, 0x00, 0x00, 0x00, 0x06, 0x30, 0x05, 0x9f, 0x00, 0x00, 0x00, 0x01,
+            0x07, 0xd0, 0x00, 0x00, 0x0d, 0xfa, 0x00, 0x00, 0x08, 0x3e, 0x00, 0x00, 0x0a, 0x01,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x64,
+            0x00, 0x07, 0x06, 0x65, 0x00, 0x39, 0x00, 0x4c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x9e, 0x00, 0x01, 0xa2, 0x00, 0x01,
+            0xcf, 0x5e, 0x21, 0xc1, 0x00, 0x2b, 0x09, 0x1d, 0x00, 0x00, 0x09, 0x1d, 0x00, 0x00,
+            0x09, 0x1d, 0x00, 0x00, 0x09, 0x1d, 0x09, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x84,
+            0x00, 0x00, 0x01, 0x4d, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0xff, 0xb8,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe6, 0x00, 0x00,
+            0x00, 0xe6, 0x00, 0xe6, 0x00, 0x00, 0x00, 0xe6, 0x00, 0x9e, 0x00, 0x00, 0x00, 0x7e,
+            0x04, 0xba, 0x14, 0xdf, 0x00, 0x36, 0x00, 0x9e, 0x03, 0xa2, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xfd, 0x81, 0xfb, 0x54, 0x13, 0x7a, 0x13, 0x7a, 0x00, 0x01, 0x00, 0x10,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0xea, 0x00, 0x00, 0x00, 0x64,
+            0x00, 0x69, 0x00, 0x36, 0x14, 0xda, 0x00, 0x0a, 0x04, 0xba,
+        ]
+    }
+}
@@ -0,0 +1,723 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that writes Apache Parquet files, with one file per hour or day.
+//!
+//! Only a small subset of Parquet is needed: a fixed, flat schema with one
+//! row per field (like the narrow SQL tables), PLAIN-encoded data pages and
+//! gzip compression. That is simple enough to write directly, with a minimal
+//! encoder for the Thrift compact protocol used by the metadata.
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Timelike};
+use flate2::write::GzEncoder;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::iter::zip;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::health::record_write;
+use super::receiver::{Receiver, Update};
+use super::rotate::{utc, Rotation};
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Constants from the Parquet format specification
+const TYPE_INT64: i32 = 2;
+const TYPE_DOUBLE: i32 = 5;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const REPETITION_REQUIRED: i32 = 0;
+const REPETITION_OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const CONVERTED_TIMESTAMP_MICROS: i32 = 10;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const CODEC_GZIP: i32 = 2;
+const PAGE_DATA: i32 = 0;
+
+/// Value in the Thrift compact protocol (only the types that are needed)
+enum Thrift {
+    I32(i32),
+    I64(i64),
+    Binary(Vec<u8>),
+    List(Vec<Thrift>),
+    Struct(Vec<(i16, Thrift)>),
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+impl Thrift {
+    fn string(s: &str) -> Self {
+        Thrift::Binary(s.as_bytes().to_vec())
+    }
+
+    fn type_id(&self) -> u8 {
+        match self {
+            Thrift::I32(_) => 5,
+            Thrift::I64(_) => 6,
+            Thrift::Binary(_) => 8,
+            Thrift::List(_) => 9,
+            Thrift::Struct(_) => 12,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Thrift::I32(value) => varint(out, zigzag(*value as i64)),
+            Thrift::I64(value) => varint(out, zigzag(*value)),
+            Thrift::Binary(value) => {
+                varint(out, value.len() as u64);
+                out.extend_from_slice(value);
+            }
+            Thrift::List(items) => {
+                // Empty lists are never written, so the element type is arbitrary
+                let elem_type = items.first().map_or(12, Thrift::type_id);
+                if items.len() < 15 {
+                    out.push(((items.len() as u8) << 4) | elem_type);
+                } else {
+                    out.push(0xf0 | elem_type);
+                    varint(out, items.len() as u64);
+                }
+                for item in items.iter() {
+                    item.encode(out);
+                }
+            }
+            Thrift::Struct(fields) => {
+                let mut last = 0;
+                for (id, value) in fields.iter() {
+                    let delta = id - last;
+                    if (1..=15).contains(&delta) {
+                        out.push(((delta as u8) << 4) | value.type_id());
+                    } else {
+                        out.push(value.type_id());
+                        varint(out, zigzag(*id as i64));
+                    }
+                    value.encode(out);
+                    last = *id;
+                }
+                out.push(0); // stop field
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// Description of a column in the schema
+struct Column {
+    name: &'static str,
+    physical_type: i32,
+    converted_type: i32,
+    optional: bool,
+}
+
+const SCHEMA: [Column; 5] = [
+    Column {
+        name: "time",
+        physical_type: TYPE_INT64,
+        converted_type: CONVERTED_TIMESTAMP_MICROS,
+        optional: false,
+    },
+    Column {
+        name: "serial",
+        physical_type: TYPE_BYTE_ARRAY,
+        converted_type: CONVERTED_UTF8,
+        optional: false,
+    },
+    Column {
+        name: "id",
+        physical_type: TYPE_BYTE_ARRAY,
+        converted_type: CONVERTED_UTF8,
+        optional: false,
+    },
+    Column {
+        name: "value",
+        physical_type: TYPE_DOUBLE,
+        converted_type: -1,
+        optional: true,
+    },
+    Column {
+        name: "text",
+        physical_type: TYPE_BYTE_ARRAY,
+        converted_type: CONVERTED_UTF8,
+        optional: true,
+    },
+];
+
+fn schema() -> Thrift {
+    let mut elements = vec![Thrift::Struct(vec![
+        (4, Thrift::string("schema")),
+        (5, Thrift::I32(SCHEMA.len() as i32)),
+    ])];
+    for column in SCHEMA.iter() {
+        let repetition = if column.optional {
+            REPETITION_OPTIONAL
+        } else {
+            REPETITION_REQUIRED
+        };
+        let mut element = vec![
+            (1, Thrift::I32(column.physical_type)),
+            (3, Thrift::I32(repetition)),
+            (4, Thrift::string(column.name)),
+        ];
+        if column.converted_type >= 0 {
+            element.push((6, Thrift::I32(column.converted_type)));
+        }
+        elements.push(Thrift::Struct(element));
+    }
+    Thrift::List(elements)
+}
+
+fn plain_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Encode the definition levels of an optional column, using bit-packing
+/// with a bit width of 1. The final group is padded with zeros.
+fn definition_levels(out: &mut Vec<u8>, present: &[bool]) {
+    let mut levels = vec![];
+    varint(&mut levels, ((present.len().div_ceil(8) as u64) << 1) | 1);
+    for chunk in present.chunks(8) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u8, |acc, (i, &p)| acc | ((p as u8) << i));
+        levels.push(bits);
+    }
+    out.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+    out.extend_from_slice(&levels);
+}
+
+/// Rows buffered for the current row group, stored by column
+#[derive(Default)]
+struct Rows {
+    /// Microseconds since the UNIX epoch
+    time: Vec<i64>,
+    serial: Vec<String>,
+    id: Vec<String>,
+    value: Vec<Option<f64>>,
+    /// Label of an enum field, or the value of a text field
+    text: Vec<Option<String>>,
+}
+
+impl Rows {
+    fn len(&self) -> usize {
+        self.time.len()
+    }
+
+    fn push(&mut self, update: &Update<'_>) {
+        let time = update.timestamp.div_euclid(1000);
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            self.time.push(time);
+            self.serial.push(update.serial.clone());
+            self.id.push(field.id.to_owned());
+            self.value.push(Some(*value));
+            self.text.push(field.label(*value).map(str::to_owned));
+        }
+        for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            self.time.push(time);
+            self.serial.push(update.serial.clone());
+            self.id.push(field.id.to_owned());
+            self.value.push(None);
+            self.text.push(Some(text.clone()));
+        }
+    }
+
+    /// Uncompressed contents of the data page for a column of [SCHEMA]
+    fn page(&self, column: usize) -> Vec<u8> {
+        let mut out = vec![];
+        match column {
+            0 => {
+                for time in self.time.iter() {
+                    out.extend_from_slice(&time.to_le_bytes());
+                }
+            }
+            1 => self.serial.iter().for_each(|s| plain_string(&mut out, s)),
+            2 => self.id.iter().for_each(|s| plain_string(&mut out, s)),
+            3 => {
+                let present: Vec<bool> = self.value.iter().map(Option::is_some).collect();
+                definition_levels(&mut out, &present);
+                for value in self.value.iter().flatten() {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            4 => {
+                let present: Vec<bool> = self.text.iter().map(Option::is_some).collect();
+                definition_levels(&mut out, &present);
+                self.text
+                    .iter()
+                    .flatten()
+                    .for_each(|s| plain_string(&mut out, s));
+            }
+            _ => unreachable!(),
+        }
+        out
+    }
+}
+
+/// A Parquet file being written. It is written under a temporary name and
+/// renamed once the footer is written, so that incomplete files are not
+/// picked up by queries.
+struct ParquetFile {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    /// Number of bytes written so far
+    offset: u64,
+    row_groups: Vec<Thrift>,
+    num_rows: i64,
+}
+
+impl ParquetFile {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
+        let tmp_path = path.with_extension("parquet.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        Ok(Self {
+            writer,
+            path,
+            tmp_path,
+            offset: MAGIC.len() as u64,
+            row_groups: vec![],
+            num_rows: 0,
+        })
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write the rows as a row group, with a single page per column
+    fn write_row_group(&mut self, rows: &Rows) -> std::io::Result<()> {
+        let num_rows = rows.len() as i64;
+        let mut chunks = vec![];
+        let mut total_size = 0;
+        for (index, column) in SCHEMA.iter().enumerate() {
+            let page = rows.page(index);
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(&page)?;
+            let compressed = encoder.finish()?;
+            let header = Thrift::Struct(vec![
+                (1, Thrift::I32(PAGE_DATA)),
+                (2, Thrift::I32(page.len() as i32)),
+                (3, Thrift::I32(compressed.len() as i32)),
+                (
+                    5,
+                    Thrift::Struct(vec![
+                        (1, Thrift::I32(num_rows as i32)),
+                        (2, Thrift::I32(ENCODING_PLAIN)),
+                        (3, Thrift::I32(ENCODING_RLE)),
+                        (4, Thrift::I32(ENCODING_RLE)),
+                    ]),
+                ),
+            ])
+            .to_bytes();
+            let start = self.offset as i64;
+            self.write_bytes(&header)?;
+            self.write_bytes(&compressed)?;
+            let uncompressed_size = (header.len() + page.len()) as i64;
+            let compressed_size = (header.len() + compressed.len()) as i64;
+            total_size += uncompressed_size;
+            let meta = Thrift::Struct(vec![
+                (1, Thrift::I32(column.physical_type)),
+                (
+                    2,
+                    Thrift::List(vec![Thrift::I32(ENCODING_PLAIN), Thrift::I32(ENCODING_RLE)]),
+                ),
+                (3, Thrift::List(vec![Thrift::string(column.name)])),
+                (4, Thrift::I32(CODEC_GZIP)),
+                (5, Thrift::I64(num_rows)),
+                (6, Thrift::I64(uncompressed_size)),
+                (7, Thrift::I64(compressed_size)),
+                (9, Thrift::I64(start)),
+            ]);
+            chunks.push(Thrift::Struct(vec![(2, Thrift::I64(start)), (3, meta)]));
+        }
+        self.row_groups.push(Thrift::Struct(vec![
+            (1, Thrift::List(chunks)),
+            (2, Thrift::I64(total_size)),
+            (3, Thrift::I64(num_rows)),
+        ]));
+        self.num_rows += num_rows;
+        Ok(())
+    }
+
+    /// Pass the data written so far to the operating system
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Write the footer and move the file into place
+    fn finish(mut self) -> std::io::Result<()> {
+        let metadata = Thrift::Struct(vec![
+            (1, Thrift::I32(1)),
+            (2, schema()),
+            (3, Thrift::I64(self.num_rows)),
+            (4, Thrift::List(std::mem::take(&mut self.row_groups))),
+            (
+                6,
+                Thrift::string(concat!("sunsniff version ", env!("CARGO_PKG_VERSION"))),
+            ),
+        ])
+        .to_bytes();
+        self.write_bytes(&metadata)?;
+        self.write_bytes(&(metadata.len() as u32).to_le_bytes())?;
+        self.write_bytes(MAGIC)?;
+        self.flush()?;
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        info!("Finished writing {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hour,
+    #[default]
+    Day,
+}
+
+impl Period {
+    /// Start of the period containing `time`
+    fn start(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let hour = match self {
+            Period::Hour => time.hour(),
+            Period::Day => 0,
+        };
+        time.date().and_hms_opt(hour, 0, 0).unwrap()
+    }
+}
+
+/// The file currently being written
+struct Output {
+    file: ParquetFile,
+    /// Start of the period covered by the file
+    start: NaiveDateTime,
+}
+
+pub struct ParquetReceiver {
+    rotation: Rotation,
+    period: Period,
+    row_group_size: usize,
+    flush_interval: Duration,
+    rows: Rows,
+    /// When the buffered rows must be written, if there are any
+    flush_deadline: Option<Instant>,
+    output: Option<Output>,
+}
+
+impl ParquetReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rotation: Rotation {
+                directory: config.directory.clone(),
+                prefix: config.prefix.clone(),
+                extension: "parquet".to_owned(),
+                max_size: None,
+            },
+            period: config.period,
+            row_group_size: config.row_group_size,
+            flush_interval: Duration::from_secs(config.flush_interval),
+            rows: Rows::default(),
+            flush_deadline: None,
+            output: None,
+        }
+    }
+
+    /// Write the buffered rows to the current file
+    fn flush(&mut self) -> std::io::Result<()> {
+        let rows = std::mem::take(&mut self.rows);
+        self.flush_deadline = None;
+        if let Some(output) = &mut self.output {
+            if rows.len() > 0 {
+                output.file.write_row_group(&rows)?;
+                output.file.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard the current file, whose structure may be inconsistent after
+    /// an error
+    fn abandon(&mut self, err: std::io::Error) {
+        warn!("Failed to write Parquet file: {err}");
+        self.output = None;
+        self.rows = Rows::default();
+        self.flush_deadline = None;
+        record_write(Some(err.to_string()));
+    }
+
+    /// Write the buffered rows and complete the current file, if any
+    fn close(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        match self.output.take() {
+            Some(output) => output.file.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, update: &Update<'_>) -> std::io::Result<()> {
+        let start = self.period.start(utc(update.timestamp));
+        if self.output.as_ref().is_some_and(|o| o.start != start) {
+            self.close()?;
+        }
+        if self.output.is_none() {
+            let path = self.rotation.path(start);
+            info!("Writing to {}", path.display());
+            self.output = Some(Output {
+                file: ParquetFile::create(path)?,
+                start,
+            });
+        }
+        self.rows.push(update);
+        self.flush_deadline
+            .get_or_insert_with(|| Instant::now() + self.flush_interval);
+        if self.rows.len() >= self.row_group_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for ParquetReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        loop {
+            let deadline = self.flush_deadline;
+            let result = tokio::select! {
+                update = receiver.next() => match update {
+                    Some(update) => self.write(&update),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.flush()
+                }
+            };
+            match result {
+                Ok(()) => record_write(None),
+                Err(err) => self.abandon(err),
+            }
+        }
+        if let Err(err) = self.close() {
+            warn!("Failed to write Parquet file: {err}");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory in which to write the files
+    pub directory: PathBuf,
+    /// Start of each filename
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Time covered by each file
+    #[serde(default)]
+    pub period: Period,
+    /// Maximum number of rows to buffer in memory before writing them
+    #[serde(default = "default_row_group_size")]
+    pub row_group_size: usize,
+    /// Seconds after which buffered rows are written, even if there are
+    /// fewer than `row_group_size`
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+}
+
+fn default_prefix() -> String {
+    "sunsniff".to_string()
+}
+
+fn default_row_group_size() -> usize {
+    100000
+}
+
+fn default_flush_interval() -> u64 {
+    300
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field as Value;
+
+    #[test]
+    fn test_thrift() {
+        let value = Thrift::Struct(vec![
+            (1, Thrift::I32(-1)),
+            (3, Thrift::I64(300)),
+            (20, Thrift::List(vec![Thrift::string("ab")])),
+        ]);
+        assert_eq!(
+            value.to_bytes(),
+            vec![0x15, 0x01, 0x26, 0xd8, 0x04, 0x09, 0x28, 0x18, 0x02, b'a', b'b', 0x00]
+        );
+    }
+
+    #[test]
+    fn test_definition_levels() {
+        let mut out = vec![];
+        let present = [true, false, true, true, false, false, false, false, true];
+        definition_levels(&mut out, &present);
+        assert_eq!(out, vec![3, 0, 0, 0, 0x05, 0x0d, 0x01]);
+    }
+
+    #[test]
+    fn test_period() {
+        let time = utc(1_700_000_000_000_000_000);
+        assert_eq!(Period::Hour.start(time).to_string(), "2023-11-14 22:00:00");
+        assert_eq!(Period::Day.start(time).to_string(), "2023-11-14 00:00:00");
+    }
+
+    fn receiver(name: &str, row_group_size: usize) -> (ParquetReceiver, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("sunsniff-parquet-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let receiver = ParquetReceiver::new(&Config {
+            directory: dir.clone(),
+            prefix: "test".to_owned(),
+            period: Period::Day,
+            row_group_size,
+            flush_interval: 60,
+        });
+        (receiver, dir)
+    }
+
+    fn update(value: f64, text: &str) -> Update<'static> {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "Inverter",
+            name: "State",
+            id: "inverter_state",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(2, "Normal")],
+            bit: None,
+        }]));
+        let text_fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Text,
+            group: "Inverter",
+            name: "Version",
+            id: "version",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        Update::new(1_500_000, "1234", fields, vec![value])
+            .with_text(text_fields, vec![text.to_owned()])
+    }
+
+    #[test]
+    fn test_file() {
+        let (mut receiver, dir) = receiver("file", 3);
+        for (value, text) in [(2.0, "1.0"), (3.0, "1.1"), (2.0, "1.1")] {
+            receiver.write(&update(value, text)).unwrap();
+        }
+        receiver.close().unwrap();
+        let file = File::open(dir.join("test-19700101-000000.parquet")).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        // The first row group is written when it is full, and the second on
+        // closing
+        assert_eq!(reader.num_row_groups(), 2);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 6);
+        let rows: Vec<Vec<Value>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(_, value)| value.clone())
+                    .collect()
+            })
+            .collect();
+        let row = |id: &str, value, text: Option<&str>| {
+            vec![
+                Value::TimestampMicros(1500),
+                Value::Str("1234".to_owned()),
+                Value::Str(id.to_owned()),
+                value,
+                text.map_or(Value::Null, |text| Value::Str(text.to_owned())),
+            ]
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row("inverter_state", Value::Double(2.0), Some("Normal")),
+                row("version", Value::Null, Some("1.0")),
+                row("inverter_state", Value::Double(3.0), None),
+                row("version", Value::Null, Some("1.1")),
+                row("inverter_state", Value::Double(2.0), Some("Normal")),
+                row("version", Value::Null, Some("1.1")),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_interval() {
+        let (mut receiver, dir) = receiver("flush", 100);
+        let tmp_path = dir.join("test-19700101-000000.parquet.tmp");
+        let (sender, stream) = futures::channel::mpsc::unbounded();
+        sender.unbounded_send(Arc::new(update(2.0, "1.0"))).unwrap();
+        let check = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            assert_eq!(std::fs::metadata(&tmp_path).unwrap().len(), 0);
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            assert!(std::fs::metadata(&tmp_path).unwrap().len() > MAGIC.len() as u64);
+            drop(sender);
+        };
+        tokio::join!(receiver.run(stream), check);
+        assert!(!tmp_path.exists());
+        let file = File::open(dir.join("test-19700101-000000.parquet")).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        assert_eq!(reader.num_row_groups(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
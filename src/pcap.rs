@@ -14,110 +14,212 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use chrono::{DateTime, LocalResult, NaiveDate};
+use async_trait::async_trait;
 use chrono_tz::Tz;
 use etherparse::SlicedPacket;
+use futures::channel::mpsc::{self, UnboundedSender};
 use futures::prelude::*;
-use log::{error, info};
-use pcap::{Capture, Device, Packet, PacketCodec};
+use log::{error, info, warn};
+use pcap::{Active, Capture, Device, Linktype, Packet, PacketCodec, PacketStream};
 use serde::Deserialize;
-use std::ops::Range;
+use std::collections::{HashSet, VecDeque};
+use std::os::fd::IntoRawFd;
+use std::process::{Command, Stdio};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
-use crate::receiver::{Update, UpdateStream};
+use crate::fields::FieldConfig;
+use crate::frontend::{FieldWatch, Frontend, Reloading};
+use crate::packet::{tcp_segment, Codec, Flow, Reassembler};
+use crate::receiver::{Update, UpdateItem, UpdateStream};
 
-/// Expected length of the packet (TCP payload)
-const MAGIC_LENGTH: usize = 292;
-/// Expected first byte of the packet
-const MAGIC_HEADER: u8 = 0xa5;
-/// Offsets containing the inverter serial number
-const SERIAL_RANGE: Range<usize> = 11..21;
-/// Offset at which the timestamp is located
-const DATETIME_OFFSET: usize = 37;
+/// Initial delay before trying to reopen a device after a failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between attempts to reopen a device
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 /// Structure corresponding to the `[pcap]` section of the configuration file.
 /// It is constructed from the config file by serde.
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PcapConfig {
+    #[serde(default)]
     device: String,
+    /// Devices to capture on simultaneously (used instead of `device`)
+    #[serde(default)]
+    devices: Vec<String>,
     #[serde(default)]
     file: bool,
     filter: Option<String>,
+    /// Maximum number of bytes to capture from each packet
+    snaplen: Option<i32>,
+    /// Command whose standard output is a pcap stream (used instead of
+    /// `device`)
+    command: Option<Vec<String>>,
     timezone: Tz,
+    /// Speed at which to replay a file, relative to real time. If not
+    /// specified, the file is replayed as fast as possible.
+    replay_speed: Option<f64>,
+    /// Replace timestamps of replayed packets with the replay time
+    #[serde(default)]
+    rebase_timestamps: bool,
 }
 
-struct Codec {
-    pub tz: Tz,
-}
-
-/// Extract the timestamp from the packet.
-///
-/// The timestamp consists of YY-MM-DD HH:MM:SS in 6 one-byte fields, with
-/// the year relative to 2000. It is in local time, so needs to be combined
-/// with the timestamp.
-///
-/// If the timestamp is an invalid time, or is invalid or ambiguous for the
-/// time zone, returns `None`.
-fn parse_timestamp(payload: &[u8], tz: Tz) -> Option<DateTime<Tz>> {
-    let dt = NaiveDate::from_ymd_opt(
-        payload[DATETIME_OFFSET] as i32 + 2000,
-        payload[DATETIME_OFFSET + 1] as u32,
-        payload[DATETIME_OFFSET + 2] as u32,
-    )?
-    .and_hms_opt(
-        payload[DATETIME_OFFSET + 3] as u32,
-        payload[DATETIME_OFFSET + 4] as u32,
-        payload[DATETIME_OFFSET + 5] as u32,
-    )?
-    .and_local_timezone(tz);
-    match dt {
-        LocalResult::Single(x) => Some(x),
-        _ => None, // TODO: what to do with ambiguous times - try to guess based on history?
-    }
-}
-
-impl Codec {
-    fn decode_data(&self, packet_data: &[u8]) -> Option<Arc<Update<'static>>> {
-        if let Ok(sliced) = SlicedPacket::from_ethernet(packet_data) {
-            if sliced.payload.len() == MAGIC_LENGTH && sliced.payload[0] == MAGIC_HEADER {
-                let dt = match parse_timestamp(sliced.payload, self.tz) {
-                    Some(x) => x,
-                    None => {
-                        return None; // Parse error means it's probably not the packet we expected
-                    }
-                };
-                let serial =
-                    std::str::from_utf8(&sliced.payload[SERIAL_RANGE]).unwrap_or("unknown");
-                info!(
-                    "Received packet with timestamp {:?} for inverter {}",
-                    dt, serial
-                );
-                let mut values = Vec::with_capacity(FIELDS.len());
-                for (&offsets, field) in OFFSETS.iter().zip(FIELDS.iter()) {
-                    let parts = offsets.iter().cloned().map(|offset| {
-                        let bytes = &sliced.payload[offset..offset + 2];
-                        let bytes = <&[u8; 2]>::try_from(bytes).unwrap();
-                        u16::from_be_bytes(*bytes)
-                    });
-                    let value = field.from_u16s(parts);
-                    values.push(value);
-                }
-                /* unwrapping timestamp_nanos_opt is safe because the encoding
-                 * only supports up to 2127 (or 2255 if the year is interpreted
-                 * as unsigned), which DateTime supports up to 2262 for
-                 * nanosecond timestamps.
-                 */
-                let update = Update::new(dt.timestamp_nanos_opt().unwrap(), serial, FIELDS, values);
-                return Some(Arc::new(update));
+/// Controls the timing and timestamps of updates replayed from a file
+struct Pacer {
+    speed: Option<f64>,
+    rebase: bool,
+    /// Timestamp of the first update, the instant at which it was replayed,
+    /// and the corresponding UNIX time in nanoseconds
+    start: Option<(i64, Instant, i64)>,
+}
+
+impl Pacer {
+    fn new(speed: Option<f64>, rebase: bool) -> Self {
+        Self {
+            speed,
+            rebase,
+            start: None,
+        }
+    }
+
+    /// Determine when to emit an update (`None` for immediately), and
+    /// rebase its timestamp if requested.
+    fn pace(&mut self, update: Arc<Update<'static>>) -> (Option<Instant>, Arc<Update<'static>>) {
+        let now = Instant::now();
+        let now_ns = chrono::Utc::now().timestamp_nanos_opt().unwrap();
+        let (first, start, start_ns) = *self.start.get_or_insert((update.timestamp, now, now_ns));
+        let (deadline, timestamp) = match self.speed {
+            Some(speed) => {
+                // Packets may be out of order, in which case they're emitted immediately
+                let offset = ((update.timestamp - first) as f64 / speed).max(0.0) as u64;
+                (
+                    Some(start + Duration::from_nanos(offset)),
+                    start_ns + offset as i64,
+                )
+            }
+            None => (None, now_ns),
+        };
+        if self.rebase {
+            let rebased = Update::new(
+                timestamp,
+                update.serial.clone(),
+                update.fields,
+                update.values.clone(),
+            )
+            .with_text(update.text_fields, update.text.clone());
+            (deadline, Arc::new(rebased))
+        } else {
+            (deadline, update)
+        }
+    }
+}
+
+/// Number of recent updates remembered by [Dedup]
+const DEDUP_WINDOW: usize = 64;
+
+/// Suppresses duplicate updates when capturing on several devices, in case
+/// the same packet is seen on more than one of them (e.g. on a bridge and
+/// one of its ports). A window of recent updates is remembered, so that a
+/// copy that arrives late or out of order is also suppressed.
+#[derive(Default)]
+struct Dedup {
+    /// Serial number and timestamp of recent updates
+    seen: HashSet<(String, i64)>,
+    /// The keys of `seen`, oldest first
+    order: VecDeque<(String, i64)>,
+}
+
+impl Dedup {
+    /// Returns whether the update has not been seen before
+    fn check(&mut self, update: &Update<'_>) -> bool {
+        let key = (update.serial.clone(), update.timestamp);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > DEDUP_WINDOW {
+            let oldest = self.order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+}
+
+/// Link-layer framing of captured packets
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Framing {
+    Ethernet,
+    /// Linux cooked capture (e.g. from capturing on the `any` device)
+    LinuxSll,
+    /// Linux cooked capture, version 2
+    LinuxSll2,
+    /// Raw IP packets
+    Raw,
+}
+
+impl Framing {
+    fn from_linktype(linktype: Linktype) -> Option<Self> {
+        match linktype {
+            Linktype::ETHERNET => Some(Framing::Ethernet),
+            Linktype::LINUX_SLL => Some(Framing::LinuxSll),
+            Linktype::LINUX_SLL2 => Some(Framing::LinuxSll2),
+            Linktype::RAW | Linktype::IPV4 => Some(Framing::Raw),
+            _ => None,
+        }
+    }
+
+    /// Split a captured packet into its headers and payload
+    fn slice(self, data: &[u8]) -> Option<SlicedPacket<'_>> {
+        let ether_type = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        match self {
+            Framing::Ethernet => SlicedPacket::from_ethernet(data).ok(),
+            Framing::LinuxSll if data.len() >= 16 => {
+                SlicedPacket::from_ether_type(ether_type(14), &data[16..]).ok()
+            }
+            Framing::LinuxSll2 if data.len() >= 20 => {
+                SlicedPacket::from_ether_type(ether_type(0), &data[20..]).ok()
+            }
+            Framing::Raw => SlicedPacket::from_ip(data).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Decoder for captured packets
+struct PcapCodec {
+    codec: Reloading<Codec>,
+    framing: Framing,
+    reassembler: Reassembler<Flow>,
+}
+
+impl PcapCodec {
+    fn with_codec(codec: Reloading<Codec>) -> Self {
+        Self {
+            codec,
+            framing: Framing::Ethernet,
+            reassembler: Reassembler::new(),
+        }
+    }
+
+    /// Decode a captured packet, returning the packets from the dongle that
+    /// it completes (there may be none, or more than one).
+    fn decode_data(&mut self, packet_data: &[u8]) -> Vec<UpdateItem> {
+        let Some(sliced) = self.framing.slice(packet_data) else {
+            return vec![];
+        };
+        match tcp_segment(&sliced) {
+            Some((flow, seq, payload)) => {
+                self.reassembler.push(self.codec.get(), flow, seq, payload)
             }
+            None => vec![],
         }
-        None
     }
 }
 
-impl PacketCodec for Codec {
-    type Item = Option<Arc<Update<'static>>>;
+impl PacketCodec for PcapCodec {
+    type Item = Vec<UpdateItem>;
 
     /// Decode a single packet
     fn decode(&mut self, packet: Packet<'_>) -> Self::Item {
@@ -125,101 +227,572 @@ impl PacketCodec for Codec {
     }
 }
 
-async fn filter_fn(
-    item: Result<Option<Arc<Update<'static>>>, pcap::Error>,
-) -> Option<Arc<Update<'static>>> {
-    match item {
-        Ok(value) => value,
+fn filter_fn(item: Result<Vec<UpdateItem>, pcap::Error>) -> impl Stream<Item = UpdateItem> {
+    let updates = match item {
+        Ok(updates) => updates,
         Err(err) => {
             error!("Error from pcap: {err:?}");
-            None
+            vec![]
+        }
+    };
+    futures::stream::iter(updates)
+}
+
+/// Decode a pcap stream from a file descriptor, sending the updates to
+/// `sender`. This blocks until the stream ends.
+fn read_stream(
+    stream: impl IntoRawFd,
+    filter: &str,
+    mut codec: PcapCodec,
+    sender: UnboundedSender<Arc<Update<'static>>>,
+) -> Result<(), pcap::Error> {
+    // Safety: ownership of the file descriptor is transferred to the capture
+    let mut cap = unsafe { Capture::from_raw_fd(stream.into_raw_fd()) }?;
+    cap.filter(filter, true)?;
+    let linktype = cap.get_datalink();
+    codec.framing = Framing::from_linktype(linktype)
+        .ok_or_else(|| pcap::Error::PcapError(format!("Unsupported link type {linktype:?}")))?;
+    for item in cap.iter(codec) {
+        for update in item? {
+            if sender.unbounded_send(update).is_err() {
+                return Ok(()); // The receiver has shut down
+            }
         }
     }
+    Ok(())
 }
 
-pub fn create_stream(config: &PcapConfig) -> Result<UpdateStream, Box<dyn std::error::Error>> {
-    let base_filter = "tcp";
-    let filter = match &config.filter {
-        Some(expr) => format!("({}) and ({})", base_filter, expr),
-        None => String::from(base_filter),
-    };
+/// Run a command that writes a pcap stream to its standard output (such as
+/// `tcpdump -w -` on a remote host) and decode the packets. The stream is
+/// read on a separate thread, because reading it blocks.
+fn capture_command(
+    command: &[String],
+    filter: &str,
+    codec: PcapCodec,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let (program, args) = command.split_first().ok_or("command must not be empty")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let filter = filter.to_owned();
+    let program = program.clone();
+    let (sender, receiver) = mpsc::unbounded();
+    std::thread::spawn(move || {
+        if let Err(err) = read_stream(stdout, &filter, codec, sender) {
+            error!("Error reading from {program}: {err}");
+        }
+        match child.wait() {
+            Ok(status) => info!("{program} exited with {status}"),
+            Err(err) => error!("Error waiting for {program}: {err}"),
+        }
+    });
+    Ok(Box::pin(receiver))
+}
 
-    let codec = Codec {
-        tz: config.timezone,
-    };
+/// Build the pcap filter expression. Frames may carry an 802.1Q VLAN tag
+/// (e.g. on a mirrored trunk port), which changes the offsets that the
+/// filter must use. The `vlan` keyword shifts the offsets for the remainder
+/// of the expression, so the untagged case must come first.
+fn capture_filter(user_filter: Option<&str>) -> String {
+    match user_filter {
+        Some(expr) => format!("(tcp and ({expr})) or (vlan and tcp and ({expr}))"),
+        None => String::from("tcp or (vlan and tcp)"),
+    }
+}
+
+/// Open a live capture on a device
+fn open_device(
+    device: &str,
+    snaplen: Option<i32>,
+    filter: &str,
+    mut codec: PcapCodec,
+) -> Result<PacketStream<Active, PcapCodec>, pcap::Error> {
+    let mut cap = Capture::from_device(Device::from(device))?.immediate_mode(true);
+    if let Some(snaplen) = snaplen {
+        cap = cap.snaplen(snaplen);
+    }
+    let cap = cap.open()?;
+    let mut cap = cap.setnonblock()?;
+    // Prefer Ethernet framing, but some devices (such as `any`) do not
+    // offer it, in which case their default is kept.
+    if cap.list_datalinks()?.contains(&Linktype::ETHERNET) {
+        cap.set_datalink(Linktype::ETHERNET)?;
+    }
+    let linktype = cap.get_datalink();
+    codec.framing = Framing::from_linktype(linktype).ok_or_else(|| {
+        pcap::Error::PcapError(format!("Unsupported link type {linktype:?} on {device}"))
+    })?;
+    cap.filter(filter, true)?;
+    cap.stream(codec)
+}
+
+/// Capture live from a single device. If the capture fails (for example,
+/// because a USB network adapter was unplugged or a bridge was restarted),
+/// the device is reopened, retrying with exponential backoff.
+fn capture_device(
+    device: &str,
+    config: &PcapConfig,
+    filter: &str,
+    codec: &Reloading<Codec>,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let codec = codec.clone();
+    let snaplen = config.snaplen;
+    // Open the device once up front so that configuration errors are
+    // reported immediately.
+    let mut stream = open_device(
+        device,
+        snaplen,
+        filter,
+        PcapCodec::with_codec(codec.clone()),
+    )?;
+    let device = device.to_owned();
+    let filter = filter.to_owned();
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        loop {
+            while let Some(item) = stream.next().await {
+                match item {
+                    Ok(updates) => {
+                        for update in updates {
+                            if sender.unbounded_send(update).is_err() {
+                                return; // The receiver has shut down
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("Capture on {device} failed: {err}");
+                        break;
+                    }
+                }
+            }
+            let mut delay = MIN_RETRY_DELAY;
+            stream = loop {
+                info!("Reopening {device} in {delay:?}");
+                tokio::time::sleep(delay).await;
+                match open_device(
+                    &device,
+                    snaplen,
+                    &filter,
+                    PcapCodec::with_codec(codec.clone()),
+                ) {
+                    Ok(stream) => {
+                        info!("Reopened {device}");
+                        break stream;
+                    }
+                    Err(err) => {
+                        warn!("Could not reopen {device}: {err}");
+                        delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    }
+                }
+            };
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+/// Check the configuration and build the fields, without capturing
+fn check(
+    config: &PcapConfig,
+    field_config: &FieldConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Codec::new(config.timezone, field_config)?;
+    if config.replay_speed.is_some_and(|speed| speed <= 0.0) {
+        return Err("replay_speed must be positive".into());
+    }
+    if config.command.is_none() {
+        if !config.devices.is_empty() && (!config.device.is_empty() || config.file) {
+            return Err("devices cannot be used together with device or file".into());
+        }
+        if config.devices.is_empty() && config.device.is_empty() {
+            return Err("either device, devices or command must be specified".into());
+        }
+    }
+    Ok(())
+}
+
+pub fn create_stream(
+    config: &PcapConfig,
+    field_config: FieldWatch,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let filter = capture_filter(config.filter.as_deref());
+
+    check(config, &field_config.borrow())?;
+    let tz = config.timezone;
+    let reloading = Reloading::new(field_config, move |field_config| {
+        Codec::new(tz, field_config)
+    })?;
+    let mut codec = PcapCodec::with_codec(reloading.clone());
+    if let Some(command) = &config.command {
+        return capture_command(command, &filter, codec);
+    }
+    if !config.devices.is_empty() {
+        let streams = config
+            .devices
+            .iter()
+            .map(|device| capture_device(device, config, &filter, &reloading))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut dedup = Dedup::default();
+        return Ok(Box::pin(
+            futures::stream::select_all(streams)
+                .filter(move |update| future::ready(dedup.check(update))),
+        ));
+    }
     if config.file {
+        // libpcap also reads pcapng files, provided that all the
+        // interfaces have the same link type.
         let mut cap = Capture::from_file(&config.device)?;
         cap.filter(filter.as_str(), true)?;
-        cap.set_datalink(pcap::Linktype::ETHERNET)?;
-        /* cap.stream doesn't work on files. This is a somewhat hacky
-         * workaround: it's probably going to load all the packets into
-         * the sinks at once before giving them a chance to run.
+        let linktype = cap.get_datalink();
+        codec.framing = Framing::from_linktype(linktype)
+            .ok_or_else(|| format!("Unsupported link type {linktype:?} in {}", config.device))?;
+        /* cap.stream doesn't work on files, so iterate synchronously.
+         * Wait (or at least yield) after each update so that the receivers
+         * get a chance to run, rather than queuing up every packet in the
+         * file.
          */
+        let device = config.device.clone();
+        let end = futures::stream::once(async move {
+            info!("Finished reading {device}");
+            None
+        });
+        let mut pacer = Pacer::new(config.replay_speed, config.rebase_timestamps);
         Ok(Box::pin(
-            futures::stream::iter(cap.iter(codec)).filter_map(filter_fn),
+            futures::stream::iter(cap.iter(codec))
+                .flat_map(filter_fn)
+                .map(move |update| pacer.pace(update))
+                .then(|(deadline, update)| async move {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => tokio::task::yield_now().await,
+                    }
+                    Some(update)
+                })
+                .chain(end)
+                .filter_map(future::ready),
         ))
     } else {
-        let device = Device::from(config.device.as_str());
-        let cap = Capture::from_device(device)?.immediate_mode(true).open()?;
-        let mut cap = cap.setnonblock()?;
-        cap.filter(filter.as_str(), true)?;
-        cap.set_datalink(pcap::Linktype::ETHERNET)?;
-        Ok(Box::pin(cap.stream(codec)?.filter_map(filter_fn)))
+        capture_device(&config.device, config, &filter, &reloading)
     }
 }
 
-include!(concat!(env!("OUT_DIR"), "/pcap_fields.rs"));
+#[async_trait(?Send)]
+impl Frontend for PcapConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, field_config)
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        check(self, field_config)
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::packet::test::{sample_packet, PAYLOAD_OFFSET};
+    use assert_approx_eq::assert_approx_eq;
     use std::collections::HashMap;
 
-    #[test]
-    fn test_decode_packet() {
-        // Sample data from a real packet, but with the serial number altered for privacy
-        let packet_data = [
-            0x04, 0x42, 0x1a, 0x78, 0xac, 0xd0, 0x60, 0x55, 0xf9, 0xb0, 0x92, 0x14, 0x08, 0x00,
-            0x45, 0x00, 0x01, 0x4c, 0x04, 0xf5, 0x00, 0x00, 0xff, 0x06, 0x80, 0x75, 0xc0, 0xa8,
-            0x00, 0xca, 0x2f, 0xf2, 0x43, 0xdd, 0xc5, 0x9a, 0xc7, 0x9c, 0x67, 0x56, 0xe9, 0xb1,
-            0x8d, 0xea, 0x57, 0xed, 0x50, 0x18, 0x15, 0xb6, 0xd3, 0x84, 0x00, 0x00, 0xa5, 0x06,
-            0x01, 0x09, 0x02, 0xce, 0x00, 0x00, 0xfa, 0x01, 0x19, 0x31, 0x32, 0x33, 0x35, 0x36,
-            0x38, 0x37, 0x31, 0x30, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x16, 0x0b, 0x05, 0x08, 0x20, 0x2e, 0x01,
-            0x00, 0x02, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x09, 0x7a, 0x00, 0x00, 0x01, 0x29,
-            0x01, 0x13, 0x00, 0xc8, 0x0d, 0x1d, 0x00, 0x00, 0x00, 0x03, 0x00, 0x08, 0x08, 0x4a,
-            0x00, 0x00, 0x05, 0x52, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x02, 0xe7, 0x13, 0x7a,
-            0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x0c, 0x5f, 0x00, 0x00,
-            0x0a, 0xe1, 0x00, 0x00, 0x00, 0x00, 0x06, 0x30, 0x05, 0x9f, 0x00, 0x00, 0x00, 0x01,
-            0x07, 0xd0, 0x00, 0x00, 0x0d, 0xfa, 0x00, 0x00, 0x08, 0x3e, 0x00, 0x00, 0x0a, 0x01,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x64,
-            0x00, 0x07, 0x06, 0x65, 0x00, 0x39, 0x00, 0x4c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x9e, 0x00, 0x01, 0xa2, 0x00, 0x01,
-            0xcf, 0x5e, 0x21, 0xc1, 0x00, 0x2b, 0x09, 0x1d, 0x00, 0x00, 0x09, 0x1d, 0x00, 0x00,
-            0x09, 0x1d, 0x00, 0x00, 0x09, 0x1d, 0x09, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x84,
-            0x00, 0x00, 0x01, 0x4d, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0xff, 0xb8,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe6, 0x00, 0x00,
-            0x00, 0xe6, 0x00, 0xe6, 0x00, 0x00, 0x00, 0xe6, 0x00, 0x9e, 0x00, 0x00, 0x00, 0x7e,
-            0x04, 0xba, 0x14, 0xdf, 0x00, 0x36, 0x00, 0x9e, 0x03, 0xa2, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0xfd, 0x81, 0xfb, 0x54, 0x13, 0x7a, 0x13, 0x7a, 0x00, 0x01, 0x00, 0x10,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0xea, 0x00, 0x00, 0x00, 0x64,
-            0x00, 0x69, 0x00, 0x36, 0x14, 0xda, 0x00, 0x0a, 0x04, 0xba,
-        ];
+    /// Create a codec, with a field configuration given as TOML
+    fn codec(field_config: &str) -> PcapCodec {
+        let field_config: FieldConfig = toml::from_str(field_config).unwrap();
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &field_config).unwrap();
+        PcapCodec::with_codec(Reloading::fixed(codec))
+    }
 
-        let c = Codec {
-            tz: chrono_tz::Africa::Johannesburg,
-        };
-        let update = c.decode_data(&packet_data).unwrap();
-        assert_eq!(update.serial, "1235687108");
-        assert_eq!(update.timestamp, 1667629966000000000);
-        let mut values = HashMap::<&str, f64>::new();
+    /// Decode a single captured packet, bypassing reassembly
+    fn decode_data(c: &mut PcapCodec, packet_data: &[u8]) -> Option<UpdateItem> {
+        let payload = c.framing.slice(packet_data)?.payload;
+        c.codec.get().decode_payload(payload)
+    }
+
+    fn decode_values(c: &mut PcapCodec, packet_data: &[u8]) -> HashMap<&'static str, f64> {
+        values_by_id(&decode_data(c, packet_data).unwrap())
+    }
+
+    fn values_by_id(update: &Update<'static>) -> HashMap<&'static str, f64> {
+        let mut values = HashMap::new();
         for (field, value) in update.fields.iter().zip(update.values.iter()) {
             values.insert(field.id, *value);
         }
+        values
+    }
+
+    #[test]
+    fn test_decode_packet() {
+        let packet_data = sample_packet();
+        let mut c = codec("");
+        let update = decode_data(&mut c, &packet_data).unwrap();
+        assert_eq!(update.serial, "1235687108");
+        assert_eq!(update.timestamp, 1667629966000000000);
+        let values = values_by_id(&update);
         // Just a smattering of values for sanity checking. This is not
         // intended to verify all the offsets.
         assert_eq!(values["grid_voltage"], 233.3);
         assert_eq!(values["battery_temperature"], 21.0);
         assert_eq!(values["battery_soc"], 54.0);
+        assert_approx_eq!(values["battery_voltage"], 53.43);
+        assert_eq!(values["bms_soc"], 54.0);
+        assert_eq!(values["pv_production_total"], 357.8);
+        assert_eq!(values["battery_power"], -639.0);
+        assert_eq!(values["grid_power_l1"], -72.0);
+        assert_eq!(values["load_consumption_today"], 1.5);
+        assert_approx_eq!(values["pv_production_today"], 0.7);
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        let frame = |seq: u32, data: &[u8]| {
+            let builder = etherparse::PacketBuilder::ethernet2([1; 6], [2; 6])
+                .ipv4([192, 168, 0, 2], [192, 168, 0, 1], 64)
+                .tcp(1234, 10000, seq, 1024);
+            let mut frame = vec![];
+            builder.write(&mut frame, data).unwrap();
+            frame
+        };
+        let mut c = codec("");
+        assert!(c.decode_data(&frame(1000, &payload[..200])).is_empty());
+        let updates = c.decode_data(&frame(1200, &payload[200..]));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].serial, "1235687108");
+        // Retransmission
+        assert!(c.decode_data(&frame(1200, &payload[200..])).is_empty());
+    }
+
+    #[test]
+    fn test_vlan_ipv6() {
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        let mut frame = vec![];
+        etherparse::PacketBuilder::ethernet2([1; 6], [2; 6])
+            .single_vlan(10)
+            .ipv6([1; 16], [2; 16], 64)
+            .tcp(1234, 10000, 1000, 1024)
+            .write(&mut frame, payload)
+            .unwrap();
+        let mut c = codec("");
+        let updates = c.decode_data(&frame);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].serial, "1235687108");
+    }
+
+    #[test]
+    fn test_check() {
+        let check = |text: &str, field_config: &str| {
+            let config: PcapConfig = toml::from_str(text).unwrap();
+            let field_config: FieldConfig = toml::from_str(field_config).unwrap();
+            Frontend::check(&config, &field_config)
+        };
+        let device = "device = \"eth0\"\ntimezone = \"UTC\"";
+        assert!(check(device, "").is_ok());
+        assert!(check("timezone = \"UTC\"", "").is_err());
+        assert!(check("devices = [\"eth0\"]\nfile = true\ntimezone = \"UTC\"", "").is_err());
+        assert!(check(&format!("{device}\nreplay_speed = 0.0"), "").is_err());
+        // The fields are built
+        assert!(check(device, "field_map = \"/nonexistent\"").is_err());
+    }
+
+    #[test]
+    fn test_capture_filter() {
+        assert_eq!(capture_filter(None), "tcp or (vlan and tcp)");
+        assert_eq!(
+            capture_filter(Some("host 192.168.0.21")),
+            "(tcp and (host 192.168.0.21)) or (vlan and tcp and (host 192.168.0.21))"
+        );
+    }
+
+    #[test]
+    fn test_unknown_layout() {
+        let mut c = codec("");
+        let mut packet_data = sample_packet();
+        packet_data[PAYLOAD_OFFSET] = 0x5a;
+        assert!(decode_data(&mut c, &packet_data).is_none());
+        let mut packet_data = sample_packet();
+        packet_data.push(0);
+        assert!(decode_data(&mut c, &packet_data).is_none());
+    }
+
+    #[test]
+    fn test_decode_energy_total_two_words() {
+        // Patch pv_production_total to 0x0001_9c40 (105536) to check that
+        // the low word is not sign-extended and the high word is included.
+        let mut packet_data = sample_packet();
+        let low = PAYLOAD_OFFSET + 118;
+        let high = PAYLOAD_OFFSET + 120;
+        packet_data[low..low + 2].copy_from_slice(&[0x9c, 0x40]);
+        packet_data[high..high + 2].copy_from_slice(&[0x00, 0x01]);
+        let mut c = codec("");
+        let values = decode_values(&mut c, &packet_data);
+        assert_eq!(values["pv_production_total"], 10553.6);
+    }
+
+    #[test]
+    fn test_linux_sll() {
+        let packet = sample_packet();
+        let mut sll = vec![
+            0, 0, 0, 1, 0, 6, 0x04, 0x42, 0x1a, 0x78, 0xac, 0xd0, 0, 0, 0x08, 0x00,
+        ];
+        sll.extend_from_slice(&packet[14..]);
+        let mut c = codec("");
+        assert!(decode_data(&mut c, &sll).is_none());
+        c.framing = Framing::LinuxSll;
+        let update = decode_data(&mut c, &sll).unwrap();
+        assert_eq!(update.serial, "1235687108");
+        assert!(decode_data(&mut c, &sll[..10]).is_none());
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut dedup = Dedup::default();
+        let update = |timestamp, serial| Update::new(timestamp, serial, &[], vec![]);
+        assert!(dedup.check(&update(1, "1234")));
+        assert!(!dedup.check(&update(1, "1234")));
+        assert!(dedup.check(&update(1, "5678")));
+        assert!(dedup.check(&update(2, "1234")));
+        // A late copy of an older update is still suppressed
+        assert!(!dedup.check(&update(1, "1234")));
+        for timestamp in 3..3 + DEDUP_WINDOW as i64 {
+            assert!(dedup.check(&update(timestamp, "1234")));
+        }
+        // Until it falls out of the window
+        assert!(dedup.check(&update(1, "1234")));
+    }
+
+    #[test]
+    fn test_pacer() {
+        let mut c = codec("");
+        let update = decode_data(&mut c, &sample_packet()).unwrap();
+        let later = Arc::new(Update::new(
+            update.timestamp + 10_000_000_000,
+            update.serial.clone(),
+            update.fields,
+            update.values.clone(),
+        ));
+
+        let mut pacer = Pacer::new(None, false);
+        let (deadline, paced) = pacer.pace(Arc::clone(&update));
+        assert!(deadline.is_none());
+        assert!(Arc::ptr_eq(&paced, &update));
+
+        let mut pacer = Pacer::new(Some(2.0), true);
+        let (first_deadline, first) = pacer.pace(Arc::clone(&update));
+        let (second_deadline, second) = pacer.pace(later);
+        assert_eq!(
+            second_deadline.unwrap() - first_deadline.unwrap(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(second.timestamp - first.timestamp, 5_000_000_000);
+        assert!(first.timestamp > update.timestamp);
+        assert_eq!(first.values, update.values);
+    }
+
+    #[test]
+    fn test_pv_strings() {
+        let packet_data = sample_packet();
+        let mut c = codec("");
+        let values = decode_values(&mut c, &packet_data);
+        assert_eq!(values["pv_power_2"], 0.0);
+        assert!(!values.contains_key("pv_power_3"));
+
+        let mut c = codec("inverter = { pv_strings = 3 }");
+        let values = decode_values(&mut c, &packet_data);
+        assert_eq!(values["pv_power"], 930.0);
+        assert_eq!(values["pv_power_3"], 0.0);
+
+        let mut c = codec("inverter = { pv_strings = 1 }");
+        let values = decode_values(&mut c, &packet_data);
+        assert!(!values.contains_key("pv_voltage_2"));
+    }
+
+    #[test]
+    fn test_decode_pv_strings() {
+        // Patch the second and third strings, which are idle in the capture
+        let mut packet_data = sample_packet();
+        for (offset, value) in [(150, 35), (152, 3125), (154, 42), (250, 810), (252, 1312)] {
+            let offset = PAYLOAD_OFFSET + offset;
+            packet_data[offset..offset + 2].copy_from_slice(&u16::to_be_bytes(value));
+        }
+        let mut c = codec("inverter = { pv_strings = 3 }");
+        let values = decode_values(&mut c, &packet_data);
+        assert_eq!(values["pv_current_2"], 3.5);
+        assert_eq!(values["pv_voltage_3"], 312.5);
+        assert_eq!(values["pv_current_3"], 4.2);
+        assert_eq!(values["pv_power_2"], 810.0);
+        assert_eq!(values["pv_power_3"], 1312.0);
+    }
+
+    #[test]
+    fn test_split_phase() {
+        let packet_data = sample_packet();
+        let mut c = codec("inverter = { layout = \"split_phase\" }");
+        let values = decode_values(&mut c, &packet_data);
+        assert_eq!(values["grid_voltage_l1_l2"], 233.3);
+        assert_eq!(values["load_power_l1"], 230.0);
+        assert_eq!(values["load_power_l2"], 0.0);
+    }
+
+    #[test]
+    fn test_three_phase() {
+        // The packet layout of three-phase inverters is not known
+        let field_config: FieldConfig =
+            toml::from_str("inverter = { layout = \"three_phase\" }").unwrap();
+        assert!(Codec::new(chrono_tz::Africa::Johannesburg, &field_config).is_err());
+    }
+
+    #[test]
+    fn test_extra_fields() {
+        let mut c = codec(
+            r#"
+            [[fields]]
+            field_type = "Power"
+            group = "Test"
+            name = "Battery power copy"
+            id = "test_battery_power"
+            offset = 256
+            "#,
+        );
+        let values = decode_values(&mut c, &sample_packet());
+        assert_eq!(values["test_battery_power"], -639.0);
+        assert_eq!(values["battery_soc"], 54.0);
+    }
+
+    #[test]
+    fn test_text_fields() {
+        let mut c = codec(
+            r#"
+            [[fields]]
+            field_type = "Text"
+            group = "Inverter"
+            name = "Serial copy"
+            id = "test_serial"
+            offset = 11
+            length = 5
+            "#,
+        );
+        let update = decode_data(&mut c, &sample_packet()).unwrap();
+        assert_eq!(update.text_fields.len(), 1);
+        assert_eq!(update.text_fields[0].id, "test_serial");
+        assert_eq!(update.text, vec!["1235687108"]);
+    }
+
+    #[test]
+    fn test_field_overrides() {
+        let mut c = codec(
+            r#"
+            [field_overrides]
+            battery_power = { scale = -1.0, name = "Charge power" }
+            grid_voltage = { disabled = true }
+            "#,
+        );
+        let values = decode_values(&mut c, &sample_packet());
+        assert_eq!(values["battery_power"], 639.0);
+        assert!(!values.contains_key("grid_voltage"));
     }
 }
@@ -0,0 +1,83 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Distribution of updates from a frontend to the backends
+
+use futures::channel::mpsc::UnboundedSender;
+use futures::prelude::*;
+use std::sync::Arc;
+
+use super::inverters::Routing;
+use super::receiver::{Update, UpdateItem};
+use super::transform::{apply_all, Transform};
+
+/// Channel to a receiver, with the transforms specific to the receiver
+pub struct Sink<'a> {
+    /// Type of the backend (the name of its config section)
+    pub backend: &'a str,
+    pub transforms: Vec<Box<dyn Transform>>,
+    pub sender: UnboundedSender<Arc<Update<'static>>>,
+}
+
+/// Receive updates from a stream, transform them, and distribute them to
+/// multiple receivers. Once the stream ends, the channels to the receivers
+/// are closed.
+pub async fn run(
+    stream: &mut (dyn Stream<Item = UpdateItem> + Unpin),
+    transforms: &mut [Box<dyn Transform>],
+    routing: &Routing,
+    sinks: &mut [Sink<'_>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_until(stream, transforms, routing, sinks, future::pending()).await?;
+    Ok(())
+}
+
+/// Like [run], but also stops (closing the channels to the receivers) when
+/// `stop` completes, leaving the rest of the stream to be handled later.
+/// Returns true if the stream ended.
+pub async fn run_until(
+    stream: &mut (dyn Stream<Item = UpdateItem> + Unpin),
+    transforms: &mut [Box<dyn Transform>],
+    routing: &Routing,
+    sinks: &mut [Sink<'_>],
+    stop: impl Future<Output = ()>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    tokio::pin!(stop);
+    let ended = loop {
+        let update = tokio::select! {
+            update = stream.next() => update,
+            () = &mut stop => break false,
+        };
+        let Some(update) = update else {
+            break true;
+        };
+        let Some(update) = apply_all(transforms, update) else {
+            continue;
+        };
+        for sink in sinks.iter_mut() {
+            if !routing.sends_to(&update.serial, sink.backend) {
+                continue;
+            }
+            if let Some(update) = apply_all(&mut sink.transforms, Arc::clone(&update)) {
+                sink.sender.unbounded_send(update)?;
+            }
+        }
+    };
+    for sink in sinks.iter_mut() {
+        sink.sender.close().await?; // TODO: do these in parallel?
+    }
+    Ok(ended)
+}
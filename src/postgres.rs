@@ -0,0 +1,325 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that inserts updates into a PostgreSQL (optionally TimescaleDB)
+//! table.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::error::Error as _;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls, Transaction};
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::health::WriteRecorder;
+use super::receiver::{Receiver, Update};
+use super::rotate::utc;
+use super::sql::{quote_ident, quote_literal, Record, Value};
+
+/// Server errors that may clear up by themselves, so that the write is
+/// retried rather than the batch being discarded
+const TRANSIENT_ERRORS: [SqlState; 5] = [
+    SqlState::T_R_SERIALIZATION_FAILURE,
+    SqlState::T_R_DEADLOCK_DETECTED,
+    SqlState::TOO_MANY_CONNECTIONS,
+    SqlState::ADMIN_SHUTDOWN,
+    SqlState::CANNOT_CONNECT_NOW,
+];
+
+/// Statements to create the table if necessary
+fn setup(config: &Config) -> String {
+    let table = quote_ident(&config.table);
+    let mut sql = String::from("SET client_min_messages = warning;\n");
+    if config.wide {
+        writeln!(
+            sql,
+            "CREATE TABLE IF NOT EXISTS {table} \
+             (time TIMESTAMPTZ NOT NULL, serial TEXT NOT NULL);"
+        )
+        .unwrap();
+    } else {
+        writeln!(
+            sql,
+            "CREATE TABLE IF NOT EXISTS {table} \
+             (time TIMESTAMPTZ NOT NULL, serial TEXT NOT NULL, id TEXT NOT NULL, \
+             value DOUBLE PRECISION, text TEXT);"
+        )
+        .unwrap();
+    }
+    if config.hypertable {
+        sql.push_str("CREATE EXTENSION IF NOT EXISTS timescaledb;\n");
+        writeln!(
+            sql,
+            "SELECT create_hypertable({}, 'time', if_not_exists => TRUE);",
+            quote_literal(&config.table)
+        )
+        .unwrap();
+    }
+    sql
+}
+
+fn insert_narrow(table: &str) -> String {
+    format!(
+        "INSERT INTO {} (time, serial, id, value, text) VALUES ($1, $2, $3, $4, $5)",
+        quote_ident(table)
+    )
+}
+
+/// Statements to insert a single row with a column per field. The first
+/// statements add columns for fields that are not in `columns` (which are
+/// added to `new_columns`), and the last is the insert.
+fn encode_wide(
+    table: &str,
+    columns: &HashSet<String>,
+    new_columns: &mut HashSet<String>,
+    record: &Record,
+) -> Vec<String> {
+    let table = quote_ident(table);
+    let mut statements = vec![];
+    let mut names = vec!["time".to_owned(), "serial".to_owned()];
+    for (id, value) in record.values.iter() {
+        let column = quote_ident(id);
+        if !columns.contains(id) && new_columns.insert(id.clone()) {
+            let sql_type = match value {
+                Value::Number(..) => "DOUBLE PRECISION",
+                Value::Text(_) => "TEXT",
+            };
+            statements.push(format!(
+                "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} {sql_type}"
+            ));
+        }
+        names.push(column);
+    }
+    let params: Vec<String> = (1..=names.len()).map(|i| format!("${i}")).collect();
+    statements.push(format!(
+        "INSERT INTO {table} ({}) VALUES ({})",
+        names.join(", "),
+        params.join(", ")
+    ));
+    statements
+}
+
+pub struct PostgresReceiver {
+    url: String,
+    setup: String,
+    table: String,
+    wide: bool,
+    /// Connection to the server, made when it is first needed, and again
+    /// after it is lost
+    client: tokio::sync::Mutex<Option<Client>>,
+    /// Columns known to exist in wide mode
+    columns: Mutex<HashSet<String>>,
+    batch: Options,
+}
+
+impl PostgresReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            url: config.url.clone(),
+            setup: setup(config),
+            table: config.table.clone(),
+            wide: config.wide,
+            client: tokio::sync::Mutex::new(None),
+            columns: Mutex::new(HashSet::new()),
+            batch: config.batch.clone(),
+        }
+    }
+
+    async fn connect(&self) -> Result<Client, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::connect(&self.url, NoTls).await?;
+        let recorder = WriteRecorder::current();
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                warn!("PostgreSQL connection failed: {err}");
+                recorder.record(Some(err.to_string()));
+            }
+        });
+        Ok(client)
+    }
+
+    async fn write_narrow(
+        &self,
+        tx: &Transaction<'_>,
+        records: &[Record],
+    ) -> Result<(), tokio_postgres::Error> {
+        let insert = tx.prepare(&insert_narrow(&self.table)).await?;
+        for record in records.iter() {
+            let time = utc(record.timestamp).and_utc();
+            for (id, value) in record.values.iter() {
+                let (value, text) = value.columns();
+                tx.execute(&insert, &[&time, &record.serial, id, &value, &text])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_wide(
+        &self,
+        tx: &Transaction<'_>,
+        records: &[Record],
+        new_columns: &mut HashSet<String>,
+    ) -> Result<(), tokio_postgres::Error> {
+        let columns = self.columns.lock().unwrap().clone();
+        for record in records.iter() {
+            let statements = encode_wide(&self.table, &columns, new_columns, record);
+            let (insert, alters) = statements.split_last().unwrap();
+            for alter in alters {
+                tx.batch_execute(alter).await?;
+            }
+            let time = utc(record.timestamp).and_utc();
+            let mut params: Vec<Box<dyn ToSql + Sync + Send>> =
+                vec![Box::new(time), Box::new(record.serial.clone())];
+            for (_, value) in record.values.iter() {
+                params.push(match value {
+                    Value::Number(..) => Box::new(value.columns().0),
+                    Value::Text(text) => Box::new(text.clone()),
+                });
+            }
+            let params: Vec<&(dyn ToSql + Sync)> = params
+                .iter()
+                .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+                .collect();
+            tx.execute(insert.as_str(), &params).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BatchWriter for PostgresReceiver {
+    type Item = Record;
+    type Error = tokio_postgres::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Record> {
+        vec![Record::new(update)]
+    }
+
+    async fn write(&self, records: Vec<Record>) -> Result<(), tokio_postgres::Error> {
+        let mut client = self.client.lock().await;
+        if client.as_ref().is_none_or(Client::is_closed) {
+            let new_client = self.connect().await?;
+            new_client.batch_execute(&self.setup).await?;
+            *client = Some(new_client);
+        }
+        // A batch is written completely or not at all, so that retrying it
+        // does not duplicate rows
+        let tx = client.as_mut().unwrap().transaction().await?;
+        let mut new_columns = HashSet::new();
+        if self.wide {
+            self.write_wide(&tx, &records, &mut new_columns).await?;
+        } else {
+            self.write_narrow(&tx, &records).await?;
+        }
+        tx.commit().await?;
+        self.columns.lock().unwrap().extend(new_columns);
+        Ok(())
+    }
+
+    fn is_fatal(&self, err: &tokio_postgres::Error) -> bool {
+        match err.code() {
+            Some(code) => !TRANSIENT_ERRORS.contains(code),
+            // Errors without a code that are not connection problems come
+            // from converting the values, and would fail again
+            None => !(err.is_closed() || err.source().is_some_and(|e| e.is::<io::Error>())),
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for PostgresReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.connect().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Connection string or URI for the database
+    pub url: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Use one column per field instead of one row per field
+    #[serde(default)]
+    pub wide: bool,
+    /// Turn the table into a TimescaleDB hypertable
+    #[serde(default)]
+    pub hypertable: bool,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_table() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sql::test::update;
+
+    #[test]
+    fn test_setup() {
+        let config: Config =
+            toml::from_str("url = \"postgresql:///solar\"\nhypertable = true").unwrap();
+        let sql = setup(&config);
+        assert!(sql.contains("CREATE TABLE IF NOT EXISTS \"sunsniff\" (time TIMESTAMPTZ"));
+        assert!(
+            sql.ends_with("SELECT create_hypertable('sunsniff', 'time', if_not_exists => TRUE);\n")
+        );
+        assert!(toml::from_str::<Config>("url = \"x\"\npsql = \"psql\"").is_err());
+    }
+
+    #[test]
+    fn test_encode_wide() {
+        let record = Record::new(&update());
+        let mut columns = HashSet::new();
+        let insert = "INSERT INTO \"sunsniff\" (time, serial, \"inverter_state\", \"version\") \
+                      VALUES ($1, $2, $3, $4)";
+        assert_eq!(
+            encode_wide("sunsniff", &columns, &mut columns.clone(), &record),
+            vec![
+                "ALTER TABLE \"sunsniff\" ADD COLUMN IF NOT EXISTS \"inverter_state\" \
+                 DOUBLE PRECISION",
+                "ALTER TABLE \"sunsniff\" ADD COLUMN IF NOT EXISTS \"version\" TEXT",
+                insert,
+            ]
+        );
+        // Columns are only added once
+        let mut new_columns = HashSet::new();
+        encode_wide("sunsniff", &columns, &mut new_columns, &record);
+        columns.extend(new_columns);
+        assert_eq!(
+            encode_wide("sunsniff", &columns, &mut HashSet::new(), &record),
+            vec![insert]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Formatting of updates for people to read, for trying out a configuration
+//! without publishing anything
+
+use clap::ValueEnum;
+use std::fmt::Write;
+
+use super::fields::Field;
+use super::json::UpdateRecord;
+use super::receiver::Update;
+use super::rotate::utc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum Format {
+    /// A table of fields, one per line
+    #[default]
+    Table,
+    /// One line of JSON, as written by the JSON Lines backend
+    Json,
+}
+
+/// Text of a value for the table, including its label if it has one
+fn value_text(field: &Field<'_>, value: f64) -> String {
+    if value.is_nan() {
+        return "-".to_owned();
+    }
+    match field.label(value) {
+        Some(label) => format!("{value} ({label})"),
+        None => format!("{value}"),
+    }
+}
+
+/// Format an update. The result ends with a newline.
+pub fn format(update: &Update<'_>, format: Format) -> String {
+    match format {
+        Format::Table => table(update),
+        Format::Json => {
+            // Serializing an UpdateRecord cannot fail
+            let mut text = serde_json::to_string(&UpdateRecord::new(update)).unwrap();
+            text.push('\n');
+            text
+        }
+    }
+}
+
+fn table(update: &Update<'_>) -> String {
+    let rows: Vec<[String; 4]> = update
+        .fields
+        .iter()
+        .zip(update.values.iter())
+        .map(|(field, value)| (field, value_text(field, *value)))
+        .chain(
+            update
+                .text_fields
+                .iter()
+                .zip(update.text.iter())
+                .map(|(field, text)| (field, format!("{text:?}"))),
+        )
+        .map(|(field, value)| {
+            [
+                field.group.to_owned(),
+                field.name.to_owned(),
+                field.id.to_owned(),
+                format!("{value} {}", field.unit).trim_end().to_owned(),
+            ]
+        })
+        .collect();
+    let mut widths = [0; 3];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let time = utc(update.timestamp).format("%Y-%m-%d %H:%M:%S%.3f UTC");
+    let mut text = format!("{time}  {}\n", update.serial);
+    for [group, name, id, value] in rows.iter() {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            text,
+            "  {group:w0$}  {name:w1$}  {id:w2$}  {value}",
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_util::{grid_power_field, leak, state_field};
+
+    #[test]
+    fn test_format() {
+        let fields = leak([grid_power_field(), state_field()]);
+        let update = Update::new(1_500_000_000, "1234", fields, vec![-150.0, 2.0]);
+        assert_eq!(
+            format(&update, Format::Table),
+            "1970-01-01 00:00:01.500 UTC  1234\n  \
+             Grid      Power  grid_power      -150 W\n  \
+             Inverter  State  inverter_state  2 (Normal)\n"
+        );
+        let json = format(&update, Format::Json);
+        assert!(json.starts_with("{\"timestamp\":1500000000,\"serial\":\"1234\","));
+        assert!(json.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,230 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that serves the latest value of each field over HTTP, for
+//! scraping by Prometheus.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::oneshot;
+use futures::stream::StreamExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::iter::zip;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use super::fields::Field;
+use super::health::ServerStatus;
+use super::receiver::{Receiver, Update};
+
+/// Samples for a single metric, keyed by the serial number and the rendered
+/// labels
+struct Family {
+    help: String,
+    samples: BTreeMap<(String, String), f64>,
+}
+
+/// Latest values of all the fields seen so far
+#[derive(Default)]
+struct Metrics {
+    /// Families keyed by metric name
+    families: BTreeMap<String, Family>,
+}
+
+/// Convert a field ID into a valid metric name
+fn metric_name(id: &str, suffix: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("sunsniff_{name}{suffix}")
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    fn set(&mut self, field: &Field<'_>, suffix: &str, serial: &str, labels: String, value: f64) {
+        let name = metric_name(field.id, suffix);
+        let family = self.families.entry(name).or_insert_with(|| {
+            let mut help = format!("{} {}", field.group, field.name);
+            if !field.unit.is_empty() {
+                write!(help, " ({})", field.unit).unwrap();
+            }
+            Family {
+                help,
+                samples: BTreeMap::new(),
+            }
+        });
+        family.samples.insert((serial.to_owned(), labels), value);
+    }
+
+    fn update(&mut self, update: &Update<'_>) {
+        let labels = |field: &Field<'_>| {
+            format!(
+                "serial=\"{}\",group=\"{}\"",
+                escape_label(&update.serial),
+                escape_label(field.group)
+            )
+        };
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            self.set(field, "", &update.serial, labels(field), *value);
+        }
+        // Text is exposed in the style of an info metric, with the text as a
+        // label. Only the latest text is kept.
+        for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            let name = metric_name(field.id, "_info");
+            if let Some(family) = self.families.get_mut(&name) {
+                family
+                    .samples
+                    .retain(|(serial, _), _| serial != &update.serial);
+            }
+            let labels = format!("{},value=\"{}\"", labels(field), escape_label(text));
+            self.set(field, "_info", &update.serial, labels, 1.0);
+        }
+    }
+
+    /// Render in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, family) in self.families.iter() {
+            writeln!(out, "# HELP {name} {}", family.help.replace('\n', " ")).unwrap();
+            writeln!(out, "# TYPE {name} gauge").unwrap();
+            for ((_, labels), value) in family.samples.iter() {
+                writeln!(out, "{name}{{{labels}}} {value}").unwrap();
+            }
+        }
+        out
+    }
+}
+
+fn handle(metrics: &Mutex<Metrics>, request: Request<Body>) -> Response<Body> {
+    if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        let body = metrics.lock().unwrap().render();
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap()
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found\n"))
+            .unwrap()
+    }
+}
+
+pub struct PrometheusReceiver {
+    metrics: Arc<Mutex<Metrics>>,
+    /// Whether the server has failed
+    status: ServerStatus,
+    /// Stops the server when dropped
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl PrometheusReceiver {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let state = Arc::clone(&metrics);
+        let make_service = make_service_fn(move |_conn| {
+            let state = Arc::clone(&state);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = handle(&state, request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&config.listen)?.serve(make_service);
+        info!("Serving Prometheus metrics on {}", server.local_addr());
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            let _ = stop.await;
+        });
+        let status = ServerStatus::default();
+        let server_status = status.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Prometheus server failed: {err}");
+                server_status.fail(err.to_string());
+            }
+        });
+        Ok(Self {
+            metrics,
+            status,
+            _shutdown: shutdown,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for PrometheusReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            self.metrics.lock().unwrap().update(&update);
+            self.status.record_write();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Address on which to serve `/metrics`
+    pub listen: SocketAddr,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::FieldType;
+    use crate::test_util::{field, leak};
+
+    #[test]
+    fn test_render() {
+        let fields = leak([field(FieldType::Power, "battery_power")]);
+        let text_fields = leak([field(FieldType::Text, "battery_version")]);
+        let mut metrics = Metrics::default();
+        metrics.update(
+            &Update::new(1, "1234", fields, vec![-50.0])
+                .with_text(text_fields, vec!["v\"1\"".to_owned()]),
+        );
+        metrics.update(
+            &Update::new(2, "1234", fields, vec![25.5])
+                .with_text(text_fields, vec!["v2".to_owned()]),
+        );
+        metrics.update(&Update::new(2, "5678", fields, vec![10.0]));
+        assert_eq!(
+            metrics.render(),
+            "# HELP sunsniff_battery_power Test battery_power (W)\n\
+             # TYPE sunsniff_battery_power gauge\n\
+             sunsniff_battery_power{serial=\"1234\",group=\"Test\"} 25.5\n\
+             sunsniff_battery_power{serial=\"5678\",group=\"Test\"} 10\n\
+             # HELP sunsniff_battery_version_info Test battery_version\n\
+             # TYPE sunsniff_battery_version_info gauge\n\
+             sunsniff_battery_version_info{serial=\"1234\",group=\"Test\",value=\"v2\"} 1\n"
+        );
+    }
+}
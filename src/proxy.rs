@@ -0,0 +1,541 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend that sits between the dongle and the remote server as a TCP
+//! proxy, decoding the packets that pass through it. If no remote server is
+//! configured, the packets are absorbed instead of being forwarded.
+
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::try_join;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::fields::FieldConfig;
+use crate::frontend::{FieldWatch, Frontend, Reloading};
+use crate::packet::{format_hex, parse_hex, Codec};
+use crate::receiver::{UpdateItem, UpdateStream};
+use crate::solarman::{Ack, Frame};
+
+/// Maximum size of a single read from the dongle
+const BUFFER_SIZE: usize = 4096;
+
+fn default_acknowledge() -> bool {
+    true
+}
+
+/// Transport protocol on which to listen
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// Structure corresponding to the `[proxy]` section of the configuration file.
+/// It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    listen: String,
+    #[serde(default)]
+    protocol: Protocol,
+    /// Remote server. If not specified, data is not forwarded.
+    upstream: Option<String>,
+    /// Reply to send to the dongle for each packet (hex-encoded), when not
+    /// forwarding
+    reply: Option<String>,
+    /// Replies to specific messages from the dongle, when not forwarding
+    #[serde(default)]
+    replies: Vec<ReplyConfig>,
+    /// Whether to acknowledge Solarman V5 messages that no entry in
+    /// `replies` matches, when not forwarding
+    #[serde(default = "default_acknowledge")]
+    acknowledge: bool,
+    timezone: Tz,
+}
+
+/// A reply to messages from the dongle that start with a given prefix
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplyConfig {
+    /// Prefix of the message (hex-encoded)
+    prefix: String,
+    /// Reply to send (hex-encoded)
+    reply: String,
+}
+
+/// Current time as a Unix timestamp, for acknowledgements
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as u32)
+}
+
+/// Replies to send to the dongle, when not forwarding
+struct Replies {
+    /// Prefixes and the corresponding replies, in order of priority
+    rules: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Whether to acknowledge Solarman V5 messages that no rule matches
+    acknowledge: bool,
+    /// Source of the time reported in acknowledgements
+    clock: fn() -> u32,
+    /// Reply to any other message
+    default: Vec<u8>,
+}
+
+impl Replies {
+    fn new(config: &ProxyConfig) -> Result<Self, String> {
+        let mut rules = vec![];
+        for rule in config.replies.iter() {
+            rules.push((parse_hex(&rule.prefix)?, parse_hex(&rule.reply)?));
+        }
+        let default = match &config.reply {
+            Some(reply) => parse_hex(reply)?,
+            None => vec![],
+        };
+        Ok(Self {
+            rules,
+            acknowledge: config.acknowledge,
+            clock: unix_time,
+            default,
+        })
+    }
+
+    /// Get the reply to a message (which may be empty)
+    fn get(&self, message: &[u8]) -> Cow<'_, [u8]> {
+        if let Some((_, reply)) = self
+            .rules
+            .iter()
+            .find(|(prefix, _)| message.starts_with(prefix))
+        {
+            return Cow::Borrowed(reply);
+        }
+        if self.acknowledge {
+            let ack = Frame::parse(message)
+                .filter(|(_, length)| *length == message.len())
+                .and_then(|(frame, _)| Ack::new((self.clock)()).build(&frame));
+            if let Some(ack) = ack {
+                return Cow::Owned(ack);
+            }
+        }
+        Cow::Borrowed(&self.default)
+    }
+}
+
+/// Forward data from the dongle to the server, decoding it along the way.
+async fn upload(
+    mut dongle: impl AsyncReadExt + Unpin,
+    mut server: impl AsyncWriteExt + Unpin,
+    codec: &mut Reloading<Codec>,
+    sender: &UnboundedSender<UpdateItem>,
+) -> Result<(), std::io::Error> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut pending = vec![];
+    loop {
+        let n = dongle.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        pending.extend_from_slice(&buffer[..n]);
+        for update in codec.get().decode_stream(&mut pending) {
+            // The only error is if the receiver is closed, in which case
+            // we're shutting down.
+            let _ = sender.unbounded_send(update);
+        }
+        server.write_all(&buffer[..n]).await?;
+    }
+    server.shutdown().await
+}
+
+/// Forward data from the server back to the dongle. The data is logged to
+/// help with configuring [ProxyConfig::reply].
+async fn download(
+    mut server: impl AsyncReadExt + Unpin,
+    mut dongle: impl AsyncWriteExt + Unpin,
+) -> Result<(), std::io::Error> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = server.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        debug!("Received from server: {}", format_hex(&buffer[..n]));
+        dongle.write_all(&buffer[..n]).await?;
+    }
+    dongle.shutdown().await
+}
+
+/// Absorb data from the dongle without forwarding it, decoding it and
+/// sending a reply (if non-empty) to each message.
+async fn absorb(
+    mut dongle: impl AsyncReadExt + AsyncWriteExt + Unpin,
+    replies: &Replies,
+    codec: &mut Reloading<Codec>,
+    sender: &UnboundedSender<UpdateItem>,
+) -> Result<(), std::io::Error> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut pending = vec![];
+    loop {
+        let n = dongle.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        pending.extend_from_slice(&buffer[..n]);
+        for message in codec.get().decode_messages(&mut pending) {
+            if let Some(update) = message.update {
+                let _ = sender.unbounded_send(update);
+            }
+            let reply = replies.get(&message.data);
+            if reply.is_empty() {
+                debug!("No reply to message: {}", format_hex(&message.data));
+            } else {
+                dongle.write_all(&reply).await?;
+            }
+        }
+    }
+    dongle.shutdown().await
+}
+
+/// Handle a single connection from a dongle
+async fn handle_connection(
+    dongle: TcpStream,
+    peer: SocketAddr,
+    upstream: Option<&str>,
+    replies: &Replies,
+    mut codec: Reloading<Codec>,
+    sender: &UnboundedSender<UpdateItem>,
+) {
+    let Some(upstream) = upstream else {
+        match absorb(dongle, replies, &mut codec, sender).await {
+            Ok(_) => info!("Connection from {peer} closed"),
+            Err(err) => warn!("Connection from {peer} failed: {err}"),
+        }
+        return;
+    };
+    let server = match TcpStream::connect(upstream).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("Could not connect to {upstream}: {err}");
+            return;
+        }
+    };
+    let (dongle_read, dongle_write) = dongle.into_split();
+    let (server_read, server_write) = server.into_split();
+    match try_join!(
+        upload(dongle_read, server_write, &mut codec, sender),
+        download(server_read, dongle_write)
+    ) {
+        Ok(_) => info!("Connection from {peer} closed"),
+        Err(err) => warn!("Connection from {peer} failed: {err}"),
+    }
+}
+
+/// Receive datagrams, decoding each one and sending a reply (if non-empty)
+/// back to the sender.
+async fn receive_udp(
+    socket: UdpSocket,
+    replies: &Replies,
+    codec: &mut Reloading<Codec>,
+    sender: &UnboundedSender<UpdateItem>,
+) {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buffer).await {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Failed to receive datagram: {err}");
+                continue;
+            }
+        };
+        if let Some(update) = codec.get().decode_payload(&buffer[..n]) {
+            let _ = sender.unbounded_send(update);
+        }
+        let reply = replies.get(&buffer[..n]);
+        if !reply.is_empty() {
+            if let Err(err) = socket.send_to(&reply, peer).await {
+                warn!("Failed to send reply to {peer}: {err}");
+            }
+        }
+    }
+}
+
+/// Check the configuration, build the fields and create the replies,
+/// without listening
+fn check(
+    config: &ProxyConfig,
+    field_config: &FieldConfig,
+) -> Result<Replies, Box<dyn std::error::Error>> {
+    Codec::new(config.timezone, field_config)?;
+    if config.upstream.is_some() && (config.reply.is_some() || !config.replies.is_empty()) {
+        return Err("reply and replies cannot be used together with upstream".into());
+    }
+    if config.upstream.is_none()
+        && !config.acknowledge
+        && config.reply.is_none()
+        && config.replies.is_empty()
+    {
+        return Err("acknowledge cannot be disabled without upstream, reply or replies".into());
+    }
+    if config.protocol == Protocol::Udp && config.upstream.is_some() {
+        return Err("upstream cannot be used with UDP".into());
+    }
+    Ok(Replies::new(config)?)
+}
+
+pub async fn create_stream(
+    config: &ProxyConfig,
+    field_config: FieldWatch,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let replies = Arc::new(check(config, &field_config.borrow())?);
+    let tz = config.timezone;
+    let mut codec = Reloading::new(field_config, move |field_config| {
+        Codec::new(tz, field_config)
+    })?;
+    let upstream = Arc::new(config.upstream.clone());
+    let (sender, receiver) = mpsc::unbounded();
+    if config.protocol == Protocol::Udp {
+        let socket = UdpSocket::bind(&config.listen).await?;
+        tokio::spawn(async move {
+            receive_udp(socket, &replies, &mut codec, &sender).await;
+        });
+        return Ok(Box::pin(receiver));
+    }
+    let listener = TcpListener::bind(&config.listen).await?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((dongle, peer)) => {
+                    info!("Accepted connection from {peer}");
+                    let upstream = Arc::clone(&upstream);
+                    let replies = Arc::clone(&replies);
+                    let codec = codec.clone();
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        handle_connection(
+                            dongle,
+                            peer,
+                            upstream.as_deref(),
+                            &replies,
+                            codec,
+                            &sender,
+                        )
+                        .await;
+                    });
+                }
+                Err(err) => {
+                    error!("Failed to accept connection: {err}");
+                }
+            }
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[async_trait(?Send)]
+impl Frontend for ProxyConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, field_config).await
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        check(self, field_config)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::test::{sample_packet, PAYLOAD_OFFSET};
+    use futures::StreamExt;
+
+    /// Messages from a dongle (with its serial number altered for privacy)
+    const HANDSHAKE: &str =
+        "a5 0d 00 10 41 3a 02 2a 6f 2b 9a 02 4f 15 00 00 3c 00 00 00 01 00 00 00 9b 15";
+    const DATA: &str =
+        "a5 0d 00 10 42 3b 02 2a 6f 2b 9a 01 00 00 00 00 3c 00 00 00 01 00 00 00 38 15";
+    const HEARTBEAT: &str = "a5 01 00 10 47 3c 02 2a 6f 2b 9a 00 f4 15";
+    /// Acknowledgements of the messages, at the time given by [test_clock]
+    const HANDSHAKE_ACK: &str =
+        "a5 0a 00 10 11 3a 02 2a 6f 2b 9a 02 01 00 f1 53 65 00 00 00 00 71 15";
+    const DATA_ACK: &str = "a5 0a 00 10 12 3b 02 2a 6f 2b 9a 01 01 00 f1 53 65 00 00 00 00 72 15";
+    const HEARTBEAT_ACK: &str =
+        "a5 0a 00 10 17 3c 02 2a 6f 2b 9a 00 01 00 f1 53 65 00 00 00 00 77 15";
+
+    fn test_clock() -> u32 {
+        1_700_000_000
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let mut codec = Reloading::fixed(codec);
+        let (sender, mut receiver) = mpsc::unbounded();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        let mut forwarded = vec![];
+        upload(payload, &mut forwarded, &mut codec, &sender)
+            .await
+            .unwrap();
+        assert_eq!(forwarded, payload);
+        drop(sender);
+        let update = receiver.next().await.unwrap();
+        assert_eq!(update.serial, "1235687108");
+        assert!(receiver.next().await.is_none());
+    }
+
+    #[test]
+    fn test_replies() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+            listen = "0.0.0.0:10000"
+            reply = "a5 00"
+            timezone = "Africa/Johannesburg"
+            replies = [
+                { prefix = "a5 10 41", reply = "a5 01" },
+                { prefix = "a5 10", reply = "a5 02" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let mut replies = Replies::new(&config).unwrap();
+        replies.clock = test_clock;
+        assert_eq!(*replies.get(&[0xa5, 0x10, 0x41, 0x00]), [0xa5, 0x01]);
+        assert_eq!(*replies.get(&[0xa5, 0x10, 0x47]), [0xa5, 0x02]);
+        assert_eq!(*replies.get(&[0xa5, 0x06]), [0xa5, 0x00]);
+        let ack = parse_hex(HEARTBEAT_ACK).unwrap();
+        assert_eq!(*replies.get(&parse_hex(HEARTBEAT).unwrap()), ack);
+        replies.acknowledge = false;
+        assert_eq!(*replies.get(&parse_hex(HEARTBEAT).unwrap()), [0xa5, 0x00]);
+    }
+
+    #[test]
+    fn test_config() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+            listen = "127.0.0.1:0"
+            acknowledge = false
+            timezone = "Africa/Johannesburg"
+            "#,
+        )
+        .unwrap();
+        let err = check(&config, &FieldConfig::default()).err().unwrap();
+        assert!(err.to_string().contains("acknowledge"));
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let mut codec = Reloading::fixed(codec);
+        let (sender, mut receiver) = mpsc::unbounded();
+        let packet = sample_packet();
+        let (mut dongle, proxy) = tokio::io::duplex(BUFFER_SIZE);
+        let absorbing = tokio::spawn(async move {
+            let replies = Replies {
+                rules: vec![],
+                acknowledge: true,
+                clock: test_clock,
+                default: vec![],
+            };
+            absorb(proxy, &replies, &mut codec, &sender).await.unwrap();
+        });
+        // Several messages in one write: only the V5 frames are acknowledged
+        let mut messages = parse_hex(HANDSHAKE).unwrap();
+        messages.extend_from_slice(&packet[PAYLOAD_OFFSET..]);
+        messages.extend(parse_hex(DATA).unwrap());
+        messages.extend(parse_hex(HEARTBEAT).unwrap());
+        dongle.write_all(&messages).await.unwrap();
+        dongle.shutdown().await.unwrap();
+        let mut replies = vec![];
+        dongle.read_to_end(&mut replies).await.unwrap();
+        let mut expected = parse_hex(HANDSHAKE_ACK).unwrap();
+        expected.extend(parse_hex(DATA_ACK).unwrap());
+        expected.extend(parse_hex(HEARTBEAT_ACK).unwrap());
+        assert_eq!(replies, expected);
+        absorbing.await.unwrap();
+        let update = receiver.next().await.unwrap();
+        assert_eq!(update.serial, "1235687108");
+        assert!(receiver.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_absorb() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let mut codec = Reloading::fixed(codec);
+        let (sender, mut receiver) = mpsc::unbounded();
+        let packet = sample_packet();
+        let payload = &packet[PAYLOAD_OFFSET..];
+        let (mut dongle, proxy) = tokio::io::duplex(BUFFER_SIZE);
+        let absorbing = tokio::spawn(async move {
+            let replies = Replies {
+                rules: vec![(vec![0xa5, 0x06], vec![0xa5, 0x01])],
+                acknowledge: true,
+                clock: test_clock,
+                default: vec![],
+            };
+            absorb(proxy, &replies, &mut codec, &sender).await.unwrap();
+        });
+        dongle.write_all(payload).await.unwrap();
+        let mut reply = [0u8; 2];
+        dongle.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0xa5, 0x01]);
+        drop(dongle);
+        absorbing.await.unwrap();
+        let update = receiver.next().await.unwrap();
+        assert_eq!(update.serial, "1235687108");
+    }
+
+    #[tokio::test]
+    async fn test_receive_udp() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let mut codec = Reloading::fixed(codec);
+        let (sender, mut receiver) = mpsc::unbounded();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let replies = Replies {
+                rules: vec![],
+                acknowledge: true,
+                clock: test_clock,
+                default: vec![0xa5, 0x01],
+            };
+            receive_udp(socket, &replies, &mut codec, &sender).await;
+        });
+        let packet = sample_packet();
+        let dongle = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        dongle
+            .send_to(&packet[PAYLOAD_OFFSET..], addr)
+            .await
+            .unwrap();
+        let mut reply = [0u8; 16];
+        let (n, _) = dongle.recv_from(&mut reply).await.unwrap();
+        assert_eq!(&reply[..n], &[0xa5, 0x01]);
+        let update = receiver.next().await.unwrap();
+        assert_eq!(update.serial, "1235687108");
+    }
+}
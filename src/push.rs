@@ -0,0 +1,271 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends alerts (see [super::alerts]) as phone push
+//! notifications, through [Pushover](https://pushover.net) or
+//! [ntfy](https://ntfy.sh). Notifications for alerts that fire have high
+//! priority.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use super::alerts::AlertConfig;
+use super::alerts::{default_min_interval, run_alerts, Alerts, Level, Notification, Notifier};
+use super::receiver::{Receiver, Update};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Service {
+    Pushover,
+    Ntfy,
+}
+
+enum Target {
+    Pushover {
+        token: String,
+        user: String,
+        device: Option<String>,
+    },
+    Ntfy {
+        server: String,
+        topic: String,
+        token: Option<String>,
+    },
+}
+
+struct Push {
+    client: Client,
+    target: Target,
+}
+
+impl Push {
+    fn request(&self, notification: &Notification) -> RequestBuilder {
+        match &self.target {
+            Target::Pushover {
+                token,
+                user,
+                device,
+            } => {
+                let priority = match notification.level {
+                    Level::Fired => "1",
+                    Level::Cleared => "0",
+                    Level::Info => "-1",
+                };
+                let mut form = vec![
+                    ("token", token.as_str()),
+                    ("user", user.as_str()),
+                    ("title", notification.subject.as_str()),
+                    ("message", notification.body.as_str()),
+                    ("priority", priority),
+                ];
+                if let Some(device) = device {
+                    form.push(("device", device));
+                }
+                self.client.post(PUSHOVER_URL).form(&form)
+            }
+            Target::Ntfy {
+                server,
+                topic,
+                token,
+            } => {
+                let (priority, tag) = match notification.level {
+                    Level::Fired => (4, "warning"),
+                    Level::Cleared => (3, "white_check_mark"),
+                    Level::Info => (2, "information_source"),
+                };
+                // JSON publishing avoids having to encode the title in a header
+                let body = json!({
+                    "topic": topic,
+                    "title": notification.subject,
+                    "message": notification.body,
+                    "priority": priority,
+                    "tags": [tag],
+                });
+                let mut request = self
+                    .client
+                    .post(server)
+                    .header("Content-Type", "application/json")
+                    .body(body.to_string());
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                request
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Push {
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        self.request(notification)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("push: {err}"))?;
+        Ok(())
+    }
+}
+
+pub struct PushReceiver {
+    push: Push,
+    alerts: Alerts,
+}
+
+impl PushReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let target = match config.service {
+            Service::Pushover => {
+                let (Some(token), Some(user), None) = (&config.token, &config.user, &config.topic)
+                else {
+                    return Err("Pushover needs token and user (and no topic)".to_owned());
+                };
+                Target::Pushover {
+                    token: token.clone(),
+                    user: user.clone(),
+                    device: config.device.clone(),
+                }
+            }
+            Service::Ntfy => {
+                let (Some(topic), None, None) = (&config.topic, &config.user, &config.device)
+                else {
+                    return Err("ntfy needs a topic (and no user or device)".to_owned());
+                };
+                Target::Ntfy {
+                    server: config.server.clone(),
+                    topic: topic.clone(),
+                    token: config.token.clone(),
+                }
+            }
+        };
+        Ok(Self {
+            push: Push {
+                client: Client::builder()
+                    .timeout(TIMEOUT)
+                    .build()
+                    .map_err(|err| err.to_string())?,
+                target,
+            },
+            alerts: Alerts::new(&config.alerts, config.min_interval)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for PushReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_alerts(&mut self.push, &mut self.alerts, receiver).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub service: Service,
+    /// Pushover application token, or ntfy access token (optional)
+    pub token: Option<String>,
+    /// Pushover user or group key
+    pub user: Option<String>,
+    /// Pushover device to send to (defaults to all of the user's devices)
+    pub device: Option<String>,
+    /// ntfy server
+    #[serde(default = "default_server")]
+    pub server: String,
+    /// ntfy topic
+    pub topic: Option<String>,
+    /// Minimum number of seconds between notifications for the same alert
+    /// and inverter
+    #[serde(default = "default_min_interval")]
+    pub min_interval: u64,
+    pub alerts: Vec<AlertConfig>,
+}
+
+fn default_server() -> String {
+    "https://ntfy.sh".to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_receiver(toml: &str) -> Result<PushReceiver, String> {
+        let config: Config = toml::from_str(&format!("alerts = []\n{toml}")).unwrap();
+        PushReceiver::new(&config)
+    }
+
+    fn notification(level: Level) -> Notification {
+        Notification {
+            level,
+            subject: "Grid lost on 1234".to_owned(),
+            body: "Inverter: 1234\n".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_pushover() {
+        let receiver = make_receiver(
+            "service = \"pushover\"\ntoken = \"app\"\nuser = \"me\"\ndevice = \"phone\"",
+        )
+        .unwrap();
+        let request = receiver
+            .push
+            .request(&notification(Level::Fired))
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), PUSHOVER_URL);
+        assert_eq!(
+            request.body().unwrap().as_bytes().unwrap(),
+            b"token=app&user=me&title=Grid+lost+on+1234&message=Inverter%3A+1234%0A\
+              &priority=1&device=phone"
+        );
+        assert!(make_receiver("service = \"pushover\"\ntoken = \"app\"").is_err());
+    }
+
+    #[test]
+    fn test_ntfy() {
+        let receiver =
+            make_receiver("service = \"ntfy\"\ntopic = \"inverter\"\ntoken = \"tk_abc\"").unwrap();
+        let request = receiver
+            .push
+            .request(&notification(Level::Cleared))
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), "https://ntfy.sh/");
+        assert_eq!(request.headers()["Authorization"], "Bearer tk_abc");
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "topic": "inverter",
+                "title": "Grid lost on 1234",
+                "message": "Inverter: 1234\n",
+                "priority": 3,
+                "tags": ["white_check_mark"],
+            })
+        );
+        assert!(make_receiver("service = \"ntfy\"").is_err());
+        assert!(make_receiver("service = \"ntfy\"\ntopic = \"inverter\"\nuser = \"me\"").is_err());
+    }
+}
@@ -0,0 +1,428 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that uploads status to [PVOutput](https://pvoutput.org). Values
+//! are aggregated over each status interval: energy is the latest value and
+//! everything else is averaged.
+
+use async_trait::async_trait;
+use chrono::{Duration as ChronoDuration, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::iter::zip;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::fields::FieldType;
+use super::health::record_write;
+use super::receiver::{Receiver, Update};
+use super::units::to_default_unit;
+
+/// Number of `v` parameters (v1 to v6 are standard, v7 to v12 are extended)
+const NUM_VALUES: usize = 12;
+/// Number of `v` parameters available without donation mode
+const NUM_STANDARD: usize = 6;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Values accumulated over one status interval
+#[derive(Debug)]
+struct Interval {
+    /// Start of the interval, in local time
+    start: NaiveDateTime,
+    sums: [f64; NUM_VALUES],
+    counts: [u32; NUM_VALUES],
+}
+
+impl Interval {
+    fn new(start: NaiveDateTime) -> Self {
+        Self {
+            start,
+            sums: [0.0; NUM_VALUES],
+            counts: [0; NUM_VALUES],
+        }
+    }
+
+    /// Add a value. For energy, only the latest value is kept.
+    fn add(&mut self, index: usize, value: f64, energy: bool) {
+        if energy {
+            self.sums[index] = value;
+            self.counts[index] = 1;
+        } else {
+            self.sums[index] += value;
+            self.counts[index] += 1;
+        }
+    }
+
+    /// Finish the interval, labelling it with its end time
+    fn status(&self, length: ChronoDuration) -> Status {
+        let mut time = self.start + length;
+        if time.date() != self.start.date() {
+            // Daily energy is reset at midnight, so keep it in the same day
+            time = self
+                .start
+                .date()
+                .and_time(NaiveTime::from_hms_opt(23, 59, 0).unwrap());
+        }
+        let mut values = [None; NUM_VALUES];
+        for (i, value) in values.iter_mut().enumerate() {
+            if self.counts[i] > 0 {
+                *value = Some(self.sums[i] / self.counts[i] as f64);
+            }
+        }
+        Status { time, values }
+    }
+}
+
+/// A status to upload
+#[derive(Debug, PartialEq)]
+struct Status {
+    /// Local time
+    time: NaiveDateTime,
+    values: [Option<f64>; NUM_VALUES],
+}
+
+impl Status {
+    /// Parameters for `addstatus.jsp`
+    fn params(&self) -> Vec<(String, String)> {
+        let mut params = vec![
+            ("d".to_owned(), self.time.format("%Y%m%d").to_string()),
+            ("t".to_owned(), self.time.format("%H:%M").to_string()),
+        ];
+        for (i, value) in self.values.iter().enumerate() {
+            if let Some(value) = value {
+                // Energy and power are integers (Wh and W)
+                let value = if i < 4 {
+                    format!("{value:.0}")
+                } else {
+                    format!("{value:.2}")
+                };
+                params.push((format!("v{}", i + 1), value));
+            }
+        }
+        params
+    }
+}
+
+pub struct PvoutputReceiver {
+    client: Client,
+    url: String,
+    api_key: String,
+    system_id: String,
+    timezone: Tz,
+    interval: ChronoDuration,
+    serial: Option<String>,
+    /// Field ID for each `v` parameter
+    fields: [Option<String>; NUM_VALUES],
+    /// Minimum time between requests
+    spacing: Duration,
+    max_buffer: usize,
+}
+
+impl PvoutputReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        if !(1..=60).contains(&config.interval) {
+            return Err("PVOutput interval must be between 1 and 60 minutes".to_owned());
+        }
+        let mut fields: [Option<String>; NUM_VALUES] = Default::default();
+        for (i, id) in DEFAULT_FIELDS.iter().enumerate() {
+            fields[i] = Some(id.to_string());
+        }
+        for (key, id) in config.fields.iter() {
+            let index = key
+                .strip_prefix('v')
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|n| (1..=NUM_VALUES).contains(n))
+                .ok_or_else(|| format!("Invalid PVOutput parameter {key:?}"))?;
+            if index > NUM_STANDARD && !config.donation {
+                return Err(format!("PVOutput parameter {key} requires donation mode"));
+            }
+            fields[index - 1] = Some(id.clone()).filter(|id| !id.is_empty());
+        }
+        // Rate limits are per hour
+        let requests_per_hour = if config.donation { 300 } else { 60 };
+        Ok(Self {
+            client: Client::new(),
+            url: config.url.clone(),
+            api_key: config.api_key.clone(),
+            system_id: config.system_id.to_string(),
+            timezone: config.timezone,
+            interval: ChronoDuration::minutes(config.interval),
+            serial: config.serial.clone(),
+            fields,
+            spacing: Duration::from_secs(3600 / requests_per_hour),
+            max_buffer: config.max_buffer,
+        })
+    }
+
+    /// Add an update to the current interval. If the update starts a new
+    /// interval, the status for the previous interval is returned.
+    fn add(&self, current: &mut Option<Interval>, update: &Update<'_>) -> Option<Status> {
+        if self
+            .serial
+            .as_ref()
+            .is_some_and(|serial| *serial != update.serial)
+        {
+            return None;
+        }
+        let local = self
+            .timezone
+            .timestamp_nanos(update.timestamp)
+            .naive_local();
+        let length = self.interval.num_seconds();
+        let offset = local.and_utc().timestamp().rem_euclid(length);
+        let start = local
+            - ChronoDuration::seconds(offset)
+            - ChronoDuration::nanoseconds(local.and_utc().timestamp_subsec_nanos() as i64);
+        let mut status = None;
+        if current
+            .as_ref()
+            .is_some_and(|interval| interval.start != start)
+        {
+            status = current
+                .take()
+                .map(|interval| interval.status(self.interval));
+        }
+        let interval = current.get_or_insert_with(|| Interval::new(start));
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            if !value.is_finite() {
+                continue;
+            }
+            for (i, id) in self.fields.iter().enumerate() {
+                if id.as_deref() == Some(field.id) {
+                    let energy = field.field_type == FieldType::Energy;
+                    let mut value = to_default_unit(field, *value);
+                    if energy {
+                        value *= 1000.0; // kWh to Wh
+                    }
+                    interval.add(i, value, energy);
+                }
+            }
+        }
+        status
+    }
+
+    /// Upload a status. Returns true if it was accepted or should be
+    /// discarded, or false if it should be retried.
+    async fn send(&self, status: &Status) -> bool {
+        let result = self
+            .client
+            .post(&self.url)
+            .header("X-Pvoutput-Apikey", &self.api_key)
+            .header("X-Pvoutput-SystemId", &self.system_id)
+            .form(&status.params())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                record_write(None);
+                true
+            }
+            Ok(response) if response.status() == StatusCode::BAD_REQUEST => {
+                // The status is invalid (e.g. too old), so retrying won't help
+                let message = response.text().await.unwrap_or_default();
+                warn!("PVOutput rejected status for {}: {message}", status.time);
+                record_write(Some(message));
+                true
+            }
+            Ok(response) => {
+                let code = response.status();
+                let message = response.text().await.unwrap_or_default();
+                info!("Error uploading to PVOutput ({code}: {message})");
+                record_write(Some(format!("{code}: {message}")));
+                false
+            }
+            Err(err) => {
+                info!("Error uploading to PVOutput ({err})");
+                record_write(Some(err.to_string()));
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for PvoutputReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        let mut current = None;
+        let mut queue = VecDeque::new();
+        let mut closed = false;
+        let mut next_request = Instant::now();
+        let mut delay = MIN_RETRY_DELAY;
+        while !(closed && queue.is_empty()) {
+            tokio::select! {
+                update = receiver.next(), if !closed => {
+                    let status = match &update {
+                        Some(update) => self.add(&mut current, update),
+                        None => {
+                            closed = true;
+                            current.take().map(|interval| interval.status(self.interval))
+                        }
+                    };
+                    if let Some(status) = status {
+                        if queue.len() >= self.max_buffer {
+                            warn!("PVOutput buffer is full; discarding the oldest status");
+                            queue.pop_front();
+                        }
+                        queue.push_back(status);
+                    }
+                }
+                _ = tokio::time::sleep_until(next_request), if !queue.is_empty() => {
+                    if self.send(&queue[0]).await {
+                        queue.pop_front();
+                        next_request = Instant::now() + self.spacing;
+                        delay = MIN_RETRY_DELAY;
+                    } else {
+                        next_request = Instant::now() + delay;
+                        delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Default field IDs for v1 to v6
+const DEFAULT_FIELDS: [&str; NUM_STANDARD] = [
+    "pv_production_today",
+    "pv_power",
+    "load_consumption_today",
+    "load_power",
+    "inverter_temperature_dc",
+    "grid_voltage",
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub api_key: String,
+    pub system_id: u32,
+    /// Time zone of the PVOutput system
+    pub timezone: Tz,
+    /// Status interval in minutes (must match the system settings)
+    #[serde(default = "default_interval")]
+    pub interval: i64,
+    /// Only upload values from the inverter with this serial number
+    pub serial: Option<String>,
+    /// Field IDs to use for `v1` to `v12`, overriding the defaults
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Whether the account has donation mode (higher rate limit and extended
+    /// parameters)
+    #[serde(default)]
+    pub donation: bool,
+    /// Maximum number of statuses to hold while PVOutput is unreachable
+    #[serde(default = "default_max_buffer")]
+    pub max_buffer: usize,
+    #[serde(default = "default_url")]
+    pub url: String,
+}
+
+fn default_interval() -> i64 {
+    5
+}
+
+fn default_max_buffer() -> usize {
+    1000
+}
+
+fn default_url() -> String {
+    "https://pvoutput.org/service/r2/addstatus.jsp".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::Field;
+    use crate::test_util::{field, leak};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!(
+            "api_key = \"key\"\nsystem_id = 1\ntimezone = \"Africa/Johannesburg\"\n{toml}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fields() {
+        assert!(PvoutputReceiver::new(&config("[fields]\nv7 = \"battery_soc\"")).is_err());
+        assert!(PvoutputReceiver::new(&config("[fields]\nv13 = \"battery_soc\"")).is_err());
+        let receiver = PvoutputReceiver::new(&config(
+            "donation = true\n[fields]\nv6 = \"\"\nv7 = \"battery_soc\"",
+        ))
+        .unwrap();
+        assert_eq!(receiver.fields[0].as_deref(), Some("pv_production_today"));
+        assert_eq!(receiver.fields[5], None);
+        assert_eq!(receiver.fields[6].as_deref(), Some("battery_soc"));
+        assert_eq!(receiver.spacing, Duration::from_secs(12));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let receiver = PvoutputReceiver::new(&config("serial = \"1234\"")).unwrap();
+        let fields = leak([
+            field(FieldType::Energy, "pv_production_today"),
+            Field {
+                unit: "kW",
+                ..field(FieldType::Power, "pv_power")
+            },
+            field(FieldType::Temperature, "battery_temperature"),
+        ]);
+        // 2023-11-14 23:53:20 SAST
+        let minute = 60_000_000_000;
+        let base = 1_700_000_000_000_000_000 - 20 * minute;
+        let mut current = None;
+        let mut statuses = vec![];
+        for (timestamp, serial, values) in [
+            (base, "1234", vec![10.0, 1.0, 20.0]),
+            (base + minute, "1234", vec![10.1, 2.0, 20.0]),
+            (base + minute, "5678", vec![50.0, 9.0, 20.0]),
+            (base + 2 * minute, "1234", vec![10.2, 1.5, 20.0]),
+            (base + 7 * minute, "1234", vec![0.0, 0.5, 20.0]),
+        ] {
+            let update = Update::new(timestamp, serial, fields, values);
+            statuses.extend(receiver.add(&mut current, &update));
+        }
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(
+            statuses[0].params(),
+            vec![
+                ("d".to_owned(), "20231114".to_owned()),
+                ("t".to_owned(), "23:55".to_owned()),
+                ("v1".to_owned(), "10100".to_owned()),
+                ("v2".to_owned(), "1500".to_owned()),
+            ]
+        );
+        // The interval crossing midnight is kept in the same day
+        assert_eq!(
+            statuses[1].params()[1],
+            ("t".to_owned(), "23:59".to_owned())
+        );
+        assert_eq!(
+            statuses[1].params()[2],
+            ("v1".to_owned(), "10200".to_owned())
+        );
+        let status = current.unwrap().status(receiver.interval);
+        assert_eq!(status.params()[0], ("d".to_owned(), "20231115".to_owned()));
+        assert_eq!(status.params()[1], ("t".to_owned(), "00:05".to_owned()));
+    }
+}
@@ -0,0 +1,138 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends updates to [QuestDB](https://questdb.io) using the
+//! InfluxDB line protocol over TCP. Each update becomes a row with a column
+//! per field, and the serial number as a symbol column.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::line_protocol;
+use super::receiver::{Receiver, Update};
+
+pub struct QuestdbReceiver {
+    address: String,
+    table: String,
+    batch: Options,
+}
+
+impl QuestdbReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            address: format!("{}:{}", config.host, config.port),
+            table: config.table.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for QuestdbReceiver {
+    type Item = String;
+    type Error = std::io::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<String> {
+        let line = line_protocol::encode_wide(update, &self.table);
+        if line.is_empty() {
+            vec![]
+        } else {
+            vec![line]
+        }
+    }
+
+    async fn write(&self, lines: Vec<String>) -> std::io::Result<()> {
+        // The TCP protocol has no acknowledgements: QuestDB logs and
+        // disconnects on errors, but this cannot be detected here.
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(lines.concat().as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+#[async_trait]
+impl Receiver for QuestdbReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Port of the ILP TCP receiver
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Table name (created automatically by QuestDB)
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    9009
+}
+
+fn default_table() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let config: Config = toml::from_str("host = \"questdb\"").unwrap();
+        let receiver = QuestdbReceiver::new(&config);
+        assert_eq!(receiver.address, "questdb:9009");
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1234, "5678", fields, vec![1500.0]);
+        assert_eq!(
+            receiver.encode(&update),
+            vec!["sunsniff,serial=5678 pv_power=1500.0 1234\n"]
+        );
+        let update = Update::new(1234, "5678", fields, vec![f64::NAN]);
+        assert!(receiver.encode(&update).is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend that captures packets with a Linux `AF_PACKET` socket. It is a
+//! lighter-weight alternative to the pcap frontend that does not depend on
+//! libpcap, for use on minimal systems such as OpenWrt routers.
+
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use etherparse::SlicedPacket;
+use futures::channel::mpsc;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+
+use crate::fields::FieldConfig;
+use crate::frontend::{FieldWatch, Frontend, Reloading};
+use crate::packet::{tcp_segment, Codec, Flow, Reassembler};
+use crate::receiver::{UpdateItem, UpdateStream};
+
+/// Large enough for any Ethernet frame
+const BUFFER_SIZE: usize = 65536;
+/// Initial delay before trying to reopen a device after a failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between attempts to reopen a device
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Structure corresponding to the `[rawsock]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawsockConfig {
+    device: String,
+    timezone: Tz,
+}
+
+/// Convert a libc return value to a [Result]
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Open a non-blocking socket that receives all Ethernet frames on a device
+fn open_socket(device: &str) -> io::Result<OwnedFd> {
+    let name =
+        CString::new(device).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // Safety: name is a valid NUL-terminated string
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    // Safety: the arguments are valid, and on success ownership of the
+    // returned descriptor is taken immediately.
+    let fd = unsafe {
+        OwnedFd::from_raw_fd(check(libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            protocol as libc::c_int,
+        ))?)
+    };
+    // Safety: sockaddr_ll is plain old data, so zero is a valid value
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = ifindex as i32;
+    // Safety: addr is a valid sockaddr_ll and the length matches
+    check(unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    })?;
+    Ok(fd)
+}
+
+/// Decode an Ethernet frame, returning the packets from the dongle that it
+/// completes.
+fn decode_frame(
+    codec: &Codec,
+    reassembler: &mut Reassembler<Flow>,
+    frame: &[u8],
+) -> Vec<UpdateItem> {
+    let Ok(sliced) = SlicedPacket::from_ethernet(frame) else {
+        return vec![];
+    };
+    match tcp_segment(&sliced) {
+        Some((flow, seq, payload)) => reassembler.push(codec, flow, seq, payload),
+        None => vec![],
+    }
+}
+
+/// Receive frames from the socket and decode them, until the receiver of
+/// `sender` is dropped.
+async fn receive(
+    socket: AsyncFd<OwnedFd>,
+    codec: &mut Reloading<Codec>,
+    sender: &mpsc::UnboundedSender<UpdateItem>,
+) -> io::Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut reassembler = Reassembler::new();
+    loop {
+        let mut guard = socket.readable().await?;
+        let result = guard.try_io(|socket| {
+            // Safety: buffer is valid for writes of its length
+            let n = unsafe {
+                libc::recv(
+                    socket.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        });
+        let n = match result {
+            Ok(result) => result?,
+            Err(_would_block) => continue,
+        };
+        for update in decode_frame(codec.get(), &mut reassembler, &buffer[..n]) {
+            if sender.unbounded_send(update).is_err() {
+                return Ok(()); // The receiver has shut down
+            }
+        }
+    }
+}
+
+pub fn create_stream(
+    config: &RawsockConfig,
+    field_config: FieldWatch,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let tz = config.timezone;
+    let mut codec = Reloading::new(field_config, move |field_config| {
+        Codec::new(tz, field_config)
+    })?;
+    let mut socket = open_socket(&config.device)
+        .map_err(|err| format!("Could not open {}: {err}", config.device))?;
+    let (sender, receiver) = mpsc::unbounded();
+    let device = config.device.clone();
+    // If capturing fails (e.g. because the device was removed), reopen the
+    // device, retrying with exponential backoff.
+    tokio::spawn(async move {
+        loop {
+            let result = match AsyncFd::new(socket) {
+                Ok(socket) => receive(socket, &mut codec, &sender).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => return, // The receiver has shut down
+                Err(err) => error!("Capture on {device} failed: {err}"),
+            }
+            let mut delay = MIN_RETRY_DELAY;
+            socket = loop {
+                info!("Reopening {device} in {delay:?}");
+                tokio::time::sleep(delay).await;
+                match open_socket(&device) {
+                    Ok(socket) => {
+                        info!("Reopened {device}");
+                        break socket;
+                    }
+                    Err(err) => {
+                        warn!("Could not reopen {device}: {err}");
+                        delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    }
+                }
+            };
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[async_trait(?Send)]
+impl Frontend for RawsockConfig {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, field_config)
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        Codec::new(self.timezone, field_config)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::test::sample_packet;
+
+    #[test]
+    fn test_decode_frame() {
+        let codec = Codec::new(chrono_tz::Africa::Johannesburg, &FieldConfig::default()).unwrap();
+        let mut reassembler = Reassembler::new();
+        let packet = sample_packet();
+        assert!(decode_frame(&codec, &mut reassembler, &packet[..60]).is_empty());
+        let updates = decode_frame(&codec, &mut reassembler, &packet);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].serial, "1235687108");
+    }
+}
@@ -35,13 +35,23 @@ pub struct Update<'a> {
     pub fields: &'a [Field<'a>],
     /// Values for the fields in `fields` (with the same length)
     pub values: Vec<f64>,
+    /// Text fields contained in the update
+    pub text_fields: &'a [Field<'a>],
+    /// Values for the fields in `text_fields` (with the same length)
+    pub text: Vec<String>,
 }
 
 /// Trait to be implemented by receiver plugins
 #[async_trait]
-pub trait Receiver {
+pub trait Receiver: Send {
     /// Run forever, receiving a stream of updates
     async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>);
+
+    /// Check that the server (if any) can be reached, returning the error if
+    /// not. Receivers that have no way to check succeed.
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
 }
 
 impl<'a> Update<'a> {
@@ -56,8 +66,17 @@ impl<'a> Update<'a> {
             serial: serial.into(),
             fields,
             values,
+            text_fields: &[],
+            text: vec![],
         }
     }
+
+    /// Add text fields to the update
+    pub fn with_text(mut self, text_fields: &'a [Field<'a>], text: Vec<String>) -> Self {
+        self.text_fields = text_fields;
+        self.text = text;
+        self
+    }
 }
 
 pub type UpdateItem = Arc<Update<'static>>;
@@ -0,0 +1,370 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that stores the latest values in Redis hashes, and optionally
+//! appends them to a Redis stream and to RedisTimeSeries keys.
+//!
+//! The Redis protocol (RESP) is simple enough that it is spoken directly.
+//! Commands for each update are pipelined.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use std::io::{Error, ErrorKind};
+use std::iter::zip;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use super::health::record_write;
+use super::receiver::{Receiver, Update};
+
+/// Encode a command as an array of bulk strings
+fn command<S: AsRef<[u8]>>(args: &[S]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args.iter() {
+        let arg = arg.as_ref();
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Read a reply and discard it. Returns the message if it is an error reply.
+async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut error = None;
+    // Number of values still to read (including nested array elements)
+    let mut remaining = 1;
+    while remaining > 0 {
+        remaining -= 1;
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(line.len().min(1));
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid reply: {line}"));
+        match kind {
+            "+" | ":" => {}
+            "-" => {
+                error.get_or_insert_with(|| rest.to_owned());
+            }
+            "$" => {
+                let len: i64 = rest.parse().map_err(|_| invalid())?;
+                if len >= 0 {
+                    // Skip the data and the trailing CRLF
+                    let mut data = vec![0u8; len as usize + 2];
+                    reader.read_exact(&mut data).await?;
+                }
+            }
+            "*" => {
+                let len: i64 = rest.parse().map_err(|_| invalid())?;
+                remaining += len.max(0) as usize;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(error)
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    /// Send commands and wait for their replies. If any command fails, the
+    /// first error is returned as a string.
+    async fn pipeline(&mut self, commands: &[Vec<u8>]) -> std::io::Result<Option<String>> {
+        self.writer.write_all(&commands.concat()).await?;
+        let mut error = None;
+        for _ in commands.iter() {
+            let reply = read_reply(&mut self.reader).await?;
+            error = error.or(reply);
+        }
+        Ok(error)
+    }
+}
+
+pub struct RedisReceiver {
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
+    database: u32,
+    prefix: String,
+    stream: bool,
+    stream_max_len: u64,
+    timeseries: bool,
+    connection: Option<Connection>,
+}
+
+impl RedisReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            address: format!("{}:{}", config.host, config.port),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            database: config.database,
+            prefix: config.prefix.clone(),
+            stream: config.stream,
+            stream_max_len: config.stream_max_len,
+            timeseries: config.timeseries,
+            connection: None,
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<Connection> {
+        let (reader, writer) = TcpStream::connect(&self.address).await?.into_split();
+        let mut connection = Connection {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let mut commands = vec![];
+        if let Some(password) = &self.password {
+            match &self.username {
+                Some(username) => commands.push(command(&["AUTH", username, password])),
+                None => commands.push(command(&["AUTH", password])),
+            }
+        }
+        if self.database != 0 {
+            commands.push(command(&["SELECT".to_owned(), self.database.to_string()]));
+        }
+        if let Some(err) = connection.pipeline(&commands).await? {
+            return Err(Error::new(ErrorKind::PermissionDenied, err));
+        }
+        info!("Connected to Redis at {}", self.address);
+        Ok(connection)
+    }
+
+    /// Commands to store an update
+    fn encode(&self, update: &Update<'_>) -> Vec<Vec<u8>> {
+        let key = format!("{}:{}", self.prefix, update.serial);
+        let mut entries = vec![("timestamp".to_owned(), update.timestamp.to_string())];
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            entries.push((field.id.to_owned(), value.to_string()));
+        }
+        for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            entries.push((field.id.to_owned(), text.clone()));
+        }
+        let flat: Vec<&str> = entries
+            .iter()
+            .flat_map(|(k, v)| [k.as_str(), v.as_str()])
+            .collect();
+
+        let mut commands = vec![];
+        let mut hset = vec!["HSET", &key];
+        hset.extend_from_slice(&flat);
+        commands.push(command(&hset));
+        if self.stream {
+            let stream_key = format!("{key}:stream");
+            let max_len = self.stream_max_len.to_string();
+            // Redis stream IDs are in milliseconds
+            let id = format!("{}-*", update.timestamp.div_euclid(1_000_000));
+            let mut xadd = vec!["XADD", &stream_key, "MAXLEN", "~", &max_len, &id];
+            xadd.extend_from_slice(&flat);
+            commands.push(command(&xadd));
+        }
+        if self.timeseries {
+            let timestamp = update.timestamp.div_euclid(1_000_000).to_string();
+            for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+                commands.push(command(&[
+                    "TS.ADD",
+                    &format!("{key}:{}", field.id),
+                    &timestamp,
+                    &value.to_string(),
+                    "ON_DUPLICATE",
+                    "LAST",
+                    "LABELS",
+                    "serial",
+                    &update.serial,
+                    "id",
+                    field.id,
+                    "group",
+                    field.group,
+                ]));
+            }
+        }
+        commands
+    }
+
+    async fn write(&mut self, update: &Update<'_>) -> std::io::Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect().await?);
+        }
+        let commands = self.encode(update);
+        let connection = self.connection.as_mut().unwrap();
+        if let Some(err) = connection.pipeline(&commands).await? {
+            warn!("Redis command failed: {err}");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for RedisReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            match self.write(&update).await {
+                Ok(()) => record_write(None),
+                Err(err) => {
+                    warn!("Failed to write to Redis at {}: {err}", self.address);
+                    // Reconnect for the next update
+                    self.connection = None;
+                    record_write(Some(err.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Database number
+    #[serde(default)]
+    pub database: u32,
+    /// Start of each key
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Append each update to a stream
+    #[serde(default)]
+    pub stream: bool,
+    /// Approximate number of updates to keep in each stream
+    #[serde(default = "default_stream_max_len")]
+    pub stream_max_len: u64,
+    /// Add each value to a RedisTimeSeries key
+    #[serde(default)]
+    pub timeseries: bool,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    6379
+}
+
+fn default_prefix() -> String {
+    "sunsniff".to_string()
+}
+
+fn default_stream_max_len() -> u64 {
+    10000
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let receiver = RedisReceiver::new(&Config {
+            host: default_host(),
+            port: default_port(),
+            username: None,
+            password: None,
+            database: 0,
+            prefix: "solar".to_owned(),
+            stream: true,
+            stream_max_len: 100,
+            timeseries: true,
+        });
+        let update = Update::new(1_500_000_000, "1234", fields, vec![-2.5]);
+        let commands = receiver.encode(&update);
+        let expected: Vec<Vec<u8>> = vec![
+            command(&[
+                "HSET",
+                "solar:1234",
+                "timestamp",
+                "1500000000",
+                "grid_power",
+                "-2.5",
+            ]),
+            command(&[
+                "XADD",
+                "solar:1234:stream",
+                "MAXLEN",
+                "~",
+                "100",
+                "1500-*",
+                "timestamp",
+                "1500000000",
+                "grid_power",
+                "-2.5",
+            ]),
+            command(&[
+                "TS.ADD",
+                "solar:1234:grid_power",
+                "1500",
+                "-2.5",
+                "ON_DUPLICATE",
+                "LAST",
+                "LABELS",
+                "serial",
+                "1234",
+                "id",
+                "grid_power",
+                "group",
+                "Grid",
+            ]),
+        ];
+        assert_eq!(commands, expected);
+        assert_eq!(command(&["GET", "a"]), b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_reply() {
+        let data: &[u8] = b"+OK\r\n:5\r\n$3\r\nabc\r\n*2\r\n$-1\r\n-ERR bad\r\n-ERR worse\r\n";
+        let mut reader = BufReader::new(data);
+        assert_eq!(read_reply(&mut reader).await.unwrap(), None);
+        assert_eq!(read_reply(&mut reader).await.unwrap(), None);
+        assert_eq!(read_reply(&mut reader).await.unwrap(), None);
+        assert_eq!(
+            read_reply(&mut reader).await.unwrap(),
+            Some("ERR bad".to_owned())
+        );
+        assert_eq!(
+            read_reply(&mut reader).await.unwrap(),
+            Some("ERR worse".to_owned())
+        );
+        assert!(read_reply(&mut reader).await.is_err());
+    }
+}
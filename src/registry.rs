@@ -0,0 +1,455 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Registry of backend types, which creates backends from their sections of
+//! the configuration file. Each type is registered under a name, together
+//! with a factory that creates a [Receiver] from its configuration. A section
+//! gives the name of its type with a `type` key, or else by its own name.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+
+use super::backend::{Backend, Options};
+use super::receiver::Receiver;
+
+type Created = Result<Box<dyn Receiver>, Box<dyn Error>>;
+type Factory = Box<
+    dyn Fn(toml::Value) -> Result<(Options, LocalBoxFuture<'static, Created>), toml::de::Error>,
+>;
+
+/// Backend created from the configuration file
+pub struct Instance {
+    /// Name of the config section (which is also the type of the backend,
+    /// unless the section has a `type` key)
+    pub kind: String,
+    pub options: Options,
+    pub receiver: Box<dyn Receiver>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    factories: BTreeMap<String, Factory>,
+}
+
+impl Registry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry containing the backends that are built in (those
+    /// enabled by cargo features)
+    pub fn builtin() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = Self::new();
+        #[cfg(feature = "amqp")]
+        registry.register("amqp", |config: crate::amqp::Config| async move {
+            crate::amqp::AmqpReceiver::new(&config)
+        });
+        #[cfg(feature = "api")]
+        registry.register("api", |config: crate::api::Config| async move {
+            crate::api::ApiReceiver::new(&config)
+        });
+        #[cfg(feature = "aws_iot")]
+        registry.register("aws_iot", |config: crate::aws_iot::Config| async move {
+            crate::aws_iot::AwsIotReceiver::new(&config)
+        });
+        #[cfg(feature = "azure_iot")]
+        registry.register("azure_iot", |config: crate::azure_iot::Config| async move {
+            Ok(crate::azure_iot::AzureIotReceiver::new(&config)?)
+        });
+        #[cfg(feature = "chat")]
+        registry.register("chat", |config: crate::chat::Config| async move {
+            Ok(crate::chat::ChatReceiver::new(&config)?)
+        });
+        #[cfg(feature = "clickhouse")]
+        registry.register(
+            "clickhouse",
+            |config: crate::clickhouse::Config| async move {
+                Ok(crate::clickhouse::ClickhouseReceiver::new(&config))
+            },
+        );
+        #[cfg(feature = "csvfile")]
+        registry.register("csvfile", |config: crate::csvfile::Config| async move {
+            Ok(crate::csvfile::CsvReceiver::new(&config))
+        });
+        #[cfg(feature = "domoticz")]
+        registry.register("domoticz", |config: crate::domoticz::Config| async move {
+            Ok(crate::domoticz::DomoticzReceiver::new(&config)?)
+        });
+        #[cfg(feature = "elasticsearch")]
+        registry.register(
+            "elasticsearch",
+            |config: crate::elasticsearch::Config| async move {
+                Ok(crate::elasticsearch::ElasticsearchReceiver::new(&config))
+            },
+        );
+        #[cfg(feature = "email")]
+        registry.register("email", |config: crate::email::Config| async move {
+            Ok(crate::email::EmailReceiver::new(&config)?)
+        });
+        #[cfg(feature = "emoncms")]
+        registry.register("emoncms", |config: crate::emoncms::Config| async move {
+            Ok(crate::emoncms::EmoncmsReceiver::new(&config))
+        });
+        #[cfg(feature = "grafana_live")]
+        registry.register(
+            "grafana_live",
+            |config: crate::grafana_live::Config| async move {
+                Ok(crate::grafana_live::GrafanaLiveReceiver::new(&config)?)
+            },
+        );
+        #[cfg(feature = "graphite")]
+        registry.register("graphite", |config: crate::graphite::Config| async move {
+            Ok(crate::graphite::GraphiteReceiver::new(&config))
+        });
+        #[cfg(feature = "grpc")]
+        registry.register("grpc", |config: crate::grpc::Config| async move {
+            crate::grpc::GrpcReceiver::new(&config)
+        });
+        #[cfg(feature = "influxdb1")]
+        registry.register("influxdb1", |config: crate::influxdb1::Config| async move {
+            Ok(crate::influxdb1::Influxdb1Receiver::new(&config).await)
+        });
+        #[cfg(feature = "influxdb2")]
+        registry.register("influxdb2", |config: crate::influxdb2::Config| async move {
+            Ok(crate::influxdb2::Influxdb2Receiver::new(&config).await)
+        });
+        #[cfg(feature = "jsonl")]
+        registry.register("jsonl", |config: crate::jsonl::Config| async move {
+            Ok(crate::jsonl::JsonlReceiver::new(&config))
+        });
+        #[cfg(feature = "kafka_producer")]
+        registry.register(
+            "kafka_producer",
+            |config: crate::kafka_producer::Config| async move {
+                Ok(crate::kafka_producer::KafkaProducerReceiver::new(&config))
+            },
+        );
+        #[cfg(feature = "mongodb")]
+        registry.register("mongodb", |config: crate::mongodb::Config| async move {
+            Ok(crate::mongodb::MongodbReceiver::new(&config))
+        });
+        #[cfg(feature = "mqtt")]
+        registry.register("mqtt", |config: crate::mqtt::Config| async move {
+            crate::mqtt::MqttReceiver::new(&config)
+        });
+        #[cfg(feature = "mysql")]
+        registry.register("mysql", |config: crate::mysql::Config| async move {
+            Ok(crate::mysql::MysqlReceiver::new(&config))
+        });
+        #[cfg(feature = "nats")]
+        registry.register("nats", |config: crate::nats::Config| async move {
+            Ok(crate::nats::NatsReceiver::new(&config))
+        });
+        #[cfg(feature = "otlp")]
+        registry.register("otlp", |config: crate::otlp::Config| async move {
+            Ok(crate::otlp::OtlpReceiver::new(&config))
+        });
+        #[cfg(feature = "parquet")]
+        registry.register("parquet", |config: crate::parquet::Config| async move {
+            Ok(crate::parquet::ParquetReceiver::new(&config))
+        });
+        #[cfg(feature = "postgres")]
+        registry.register("postgres", |config: crate::postgres::Config| async move {
+            Ok(crate::postgres::PostgresReceiver::new(&config))
+        });
+        #[cfg(feature = "prometheus")]
+        registry.register(
+            "prometheus",
+            |config: crate::prometheus::Config| async move {
+                crate::prometheus::PrometheusReceiver::new(&config)
+            },
+        );
+        #[cfg(feature = "pubsub")]
+        registry.register("pubsub", |config: crate::pubsub::Config| async move {
+            Ok(crate::pubsub::PubsubReceiver::new(&config)?)
+        });
+        #[cfg(feature = "push")]
+        registry.register("push", |config: crate::push::Config| async move {
+            Ok(crate::push::PushReceiver::new(&config)?)
+        });
+        #[cfg(feature = "pvoutput")]
+        registry.register("pvoutput", |config: crate::pvoutput::Config| async move {
+            Ok(crate::pvoutput::PvoutputReceiver::new(&config)?)
+        });
+        #[cfg(feature = "questdb")]
+        registry.register("questdb", |config: crate::questdb::Config| async move {
+            Ok(crate::questdb::QuestdbReceiver::new(&config))
+        });
+        #[cfg(feature = "redis")]
+        registry.register("redis", |config: crate::redis::Config| async move {
+            Ok(crate::redis::RedisReceiver::new(&config))
+        });
+        #[cfg(feature = "s3")]
+        registry.register("s3", |config: crate::s3::Config| async move {
+            Ok(crate::s3::S3Receiver::new(&config)?)
+        });
+        #[cfg(feature = "splunk")]
+        registry.register("splunk", |config: crate::splunk::Config| async move {
+            Ok(crate::splunk::SplunkReceiver::new(&config))
+        });
+        #[cfg(feature = "sqlite")]
+        registry.register("sqlite", |config: crate::sqlite::Config| async move {
+            Ok(crate::sqlite::SqliteReceiver::new(&config))
+        });
+        #[cfg(feature = "statsd")]
+        registry.register("statsd", |config: crate::statsd::Config| async move {
+            Ok(crate::statsd::StatsdReceiver::new(&config))
+        });
+        #[cfg(feature = "telegram")]
+        registry.register("telegram", |config: crate::telegram::Config| async move {
+            Ok(crate::telegram::TelegramReceiver::new(&config)?)
+        });
+        #[cfg(feature = "victoriametrics")]
+        registry.register(
+            "victoriametrics",
+            |config: crate::victoriametrics::Config| async move {
+                Ok(crate::victoriametrics::VictoriaMetricsReceiver::new(
+                    &config,
+                ))
+            },
+        );
+        #[cfg(feature = "webhook")]
+        registry.register("webhook", |config: crate::webhook::Config| async move {
+            Ok(crate::webhook::WebhookReceiver::new(&config)?)
+        });
+        #[cfg(feature = "websocket")]
+        registry.register("websocket", |config: crate::websocket::Config| async move {
+            crate::websocket::WebSocketReceiver::new(&config)
+        });
+        #[cfg(feature = "zabbix")]
+        registry.register("zabbix", |config: crate::zabbix::Config| async move {
+            Ok(crate::zabbix::ZabbixReceiver::new(&config))
+        });
+        registry
+    }
+
+    /// Register a backend type. The `factory` creates the backend from its
+    /// configuration `C`. Options common to all backends are handled by the
+    /// registry, so `C` should only contain the backend-specific options.
+    /// An existing type with the same name is replaced.
+    pub fn register<C, R, F, Fut>(&mut self, name: &str, factory: F)
+    where
+        C: DeserializeOwned + 'static,
+        R: Receiver + 'static,
+        F: Fn(C) -> Fut + 'static,
+        Fut: Future<Output = Result<R, Box<dyn Error>>> + 'static,
+    {
+        let factory = move |value| {
+            let backend = Backend::<C>::deserialize(value)?;
+            let receiver = factory(backend.config)
+                .map(|result| result.map(|receiver| Box::new(receiver) as Box<dyn Receiver>));
+            Ok((backend.options, receiver.boxed_local()))
+        };
+        self.factories.insert(name.to_owned(), Box::new(factory));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    /// Names of the registered backend types
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Deserialize the section of the configuration file called `name`,
+    /// returning its options and a future that creates the backend. The type
+    /// is taken from the `type` key of the section if there is one, and is
+    /// otherwise `name`.
+    fn parse(
+        &self,
+        name: &str,
+        mut section: toml::Value,
+    ) -> Result<(Options, LocalBoxFuture<'static, Created>), Box<dyn Error>> {
+        let kind = match section
+            .as_table_mut()
+            .and_then(|table| table.remove("type"))
+        {
+            Some(toml::Value::String(kind)) => kind,
+            Some(_) => return Err(format!("[[{name}]]: `type` must be a string").into()),
+            None => name.to_owned(),
+        };
+        let factory = self
+            .factories
+            .get(&kind)
+            .ok_or_else(|| format!("Unknown backend type `{kind}`"))?;
+        Ok(
+            factory(section)
+                .map_err(|err| format!("[[{name}]]: {}", err.to_string().trim_end()))?,
+        )
+    }
+
+    /// Check the section of the configuration file called `name`, without
+    /// creating the backend. Its type is given by its `type` key, or else by
+    /// `name`.
+    pub fn check(&self, name: &str, section: toml::Value) -> Result<(), Box<dyn Error>> {
+        // The future is dropped without being polled, so nothing is created
+        let (_options, _receiver) = self.parse(name, section)?;
+        Ok(())
+    }
+
+    /// Create a backend from the section of the configuration file called
+    /// `name`. Its type is given by its `type` key, or else by `name`.
+    pub async fn create(
+        &self,
+        name: &str,
+        section: toml::Value,
+    ) -> Result<Instance, Box<dyn Error>> {
+        let (options, receiver) = self.parse(name, section)?;
+        Ok(Instance {
+            kind: name.to_owned(),
+            options,
+            receiver: receiver.await?,
+        })
+    }
+
+    /// Split the sections of the configuration file that are not otherwise
+    /// used into the section for each backend, with its name. Each name may
+    /// have any number of sections (an array of tables), and they are listed
+    /// in order of name, then section.
+    pub fn sections(
+        sections: &BTreeMap<String, toml::Value>,
+    ) -> Result<Vec<(&str, &toml::Value)>, Box<dyn Error>> {
+        let mut split = vec![];
+        for (name, value) in sections.iter() {
+            let toml::Value::Array(sections) = value else {
+                return Err(format!("`{name}` must be an array of tables ([[{name}]])").into());
+            };
+            split.extend(sections.iter().map(|section| (name.as_str(), section)));
+        }
+        Ok(split)
+    }
+
+    /// Check that each backend section of the configuration file (see
+    /// [Registry::sections]) has a registered type and valid options for
+    /// that type. The backends are not created, so this does not check that
+    /// they can connect to anything.
+    pub fn check_all(
+        &self,
+        sections: &BTreeMap<String, toml::Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        for (name, section) in Self::sections(sections)? {
+            self.check(name, section.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Create the backends for the sections of the configuration file that
+    /// are not otherwise used (see [Registry::sections]), in order
+    pub async fn create_all(
+        &self,
+        sections: &BTreeMap<String, toml::Value>,
+    ) -> Result<Vec<Instance>, Box<dyn Error>> {
+        let mut instances = vec![];
+        for (name, section) in Self::sections(sections)? {
+            instances.push(self.create(name, section.clone()).await?);
+        }
+        Ok(instances)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use std::sync::Arc;
+
+    use crate::receiver::Update;
+
+    struct TestReceiver;
+
+    #[async_trait]
+    impl Receiver for TestReceiver {
+        async fn run<'a>(&mut self, _receiver: UnboundedReceiver<Arc<Update<'a>>>) {}
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Config {
+        fail: bool,
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::new();
+        registry.register("test", |config: Config| async move {
+            match config.fail {
+                false => Ok(TestReceiver),
+                true => Err("failed".into()),
+            }
+        });
+        assert!(registry.contains("test"));
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["test"]);
+        let create_all = |text: &str| {
+            let sections = toml::from_str(text).unwrap();
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            rt.block_on(registry.create_all(&sections))
+        };
+
+        let instances =
+            create_all("[[test]]\nfail = false\ninclude = [\"pv_*\"]\n[[test]]\nfail = false")
+                .unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].kind, "test");
+        assert_eq!(instances[0].options.include, vec!["pv_*"]);
+        assert!(instances[1].options.include.is_empty());
+
+        assert!(create_all("[[test]]\nfail = true").is_err());
+        assert!(create_all("[[test]]\nfail = false\nother = 1").is_err());
+        assert!(create_all("[[other]]\nfail = false").is_err());
+        assert!(create_all("[test]\nfail = false").is_err());
+
+        // The `type` key selects the type instead of the section name
+        let instances = create_all("[[mine]]\ntype = \"test\"\nfail = false").unwrap();
+        assert_eq!(instances[0].kind, "mine");
+        assert!(create_all("[[test]]\ntype = \"other\"\nfail = false").is_err());
+        assert!(create_all("[[mine]]\ntype = 1\nfail = false").is_err());
+
+        // Checking does not create the backend
+        let section = |text: &str| toml::from_str::<toml::Value>(text).unwrap();
+        assert!(registry.check("test", section("fail = true")).is_ok());
+        let err = registry.check("test", section("fail = 1")).unwrap_err();
+        assert!(err.to_string().starts_with("[[test]]: "));
+        assert!(registry.check("other", section("fail = true")).is_err());
+        let sections = |text: &str| toml::from_str(text).unwrap();
+        assert!(registry
+            .check_all(&sections("[[test]]\nfail = true"))
+            .is_ok());
+        assert!(registry
+            .check_all(&sections("[[test]]\nfail = true\n[[test]]\nfail = 1"))
+            .is_err());
+        assert!(registry
+            .check_all(&sections("[test]\nfail = true"))
+            .is_err());
+
+        let split = sections("[[test]]\nfail = true\n[[other]]\n[[test]]\nfail = false");
+        let kinds: Vec<&str> = Registry::sections(&split)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(kinds, vec!["other", "test", "test"]);
+    }
+}
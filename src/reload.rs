@@ -0,0 +1,65 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Notification of requests to reload the configuration, which are made by
+//! sending SIGHUP to the process
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Handle to the SIGHUP handler. Once installed, SIGHUP no longer terminates
+/// the process.
+pub struct Hangup {
+    #[cfg(unix)]
+    hangup: Signal,
+}
+
+impl Hangup {
+    /// Install the signal handler. On platforms without SIGHUP, no request
+    /// is ever received.
+    pub fn install() -> std::io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Wait for the next request. Requests made while not waiting are not
+    /// lost, but several of them may be combined.
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.hangup.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_hangup() {
+        let mut hangup = Hangup::install().unwrap();
+        // SAFETY: raising a signal in the current process is harmless now
+        // that it is handled.
+        assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+        tokio::time::timeout(Duration::from_secs(5), hangup.recv())
+            .await
+            .unwrap();
+    }
+}
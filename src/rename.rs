@@ -0,0 +1,145 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Renaming of fields on output, for compatibility with other tools
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::fields::{leak_fields, leak_str, Field};
+use super::receiver::{Update, UpdateItem};
+use super::transform::{FieldListCache, Transform};
+
+/// New ID and/or name for a field
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldRename {
+    id: Option<String>,
+    name: Option<String>,
+}
+
+/// Entry in the `[rename]` section of the configuration file. A plain string
+/// gives a new ID.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Rename {
+    Id(String),
+    Full(FieldRename),
+}
+
+impl Rename {
+    fn apply(&self, field: &mut Field<'static>) {
+        match self {
+            Rename::Id(id) => field.id = leak_str(id),
+            Rename::Full(rename) => {
+                if let Some(id) = &rename.id {
+                    field.id = leak_str(id);
+                }
+                if let Some(name) = &rename.name {
+                    field.name = leak_str(name);
+                }
+            }
+        }
+    }
+}
+
+/// Transform that renames fields
+pub struct Renamer {
+    renames: HashMap<String, Rename>,
+    cache: FieldListCache<&'static [Field<'static>]>,
+}
+
+impl Renamer {
+    pub fn new(renames: &HashMap<String, Rename>) -> Self {
+        Self {
+            renames: renames.clone(),
+            cache: FieldListCache::new(),
+        }
+    }
+
+    fn rename(
+        renames: &HashMap<String, Rename>,
+        fields: &'static [Field<'static>],
+    ) -> &'static [Field<'static>] {
+        let mut fields = fields.to_vec();
+        for field in fields.iter_mut() {
+            if let Some(rename) = renames.get(field.id) {
+                rename.apply(field);
+            }
+        }
+        leak_fields(fields)
+    }
+}
+
+impl Transform for Renamer {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        if self.renames.is_empty() {
+            return Some(update);
+        }
+        let renames = &self.renames;
+        let fields = *self
+            .cache
+            .get(update.fields, |fields| Self::rename(renames, fields));
+        let text_fields = *self
+            .cache
+            .get(update.text_fields, |fields| Self::rename(renames, fields));
+        let new_update = Update::new(
+            update.timestamp,
+            update.serial.clone(),
+            fields,
+            update.values.clone(),
+        )
+        .with_text(text_fields, update.text.clone());
+        Some(Arc::new(new_update))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::FieldType;
+    use crate::test_util::{field, leak};
+
+    #[test]
+    fn test_rename() {
+        let renames = toml::from_str(
+            r#"
+            battery_soc = "ess_soc"
+            pv_power = { id = "solar_power", name = "Solar power" }
+            version = { name = "Firmware" }
+            "#,
+        )
+        .unwrap();
+        let mut renamer = Renamer::new(&renames);
+        let fields = leak([
+            field(FieldType::StateOfCharge, "battery_soc"),
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Power, "grid_power"),
+        ]);
+        let text_fields = leak([field(FieldType::Text, "version")]);
+        let update = Update::new(0, "1234", fields, vec![80.0, 1500.0, 200.0])
+            .with_text(text_fields, vec!["1.0".to_owned()]);
+        let update = renamer.apply(Arc::new(update)).unwrap();
+        let ids: Vec<&str> = update.fields.iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec!["ess_soc", "solar_power", "grid_power"]);
+        assert_eq!(update.fields[0].name, "battery_soc");
+        assert_eq!(update.fields[1].name, "Solar power");
+        assert_eq!(update.values, vec![80.0, 1500.0, 200.0]);
+        assert_eq!(update.text_fields[0].id, "version");
+        assert_eq!(update.text_fields[0].name, "Firmware");
+    }
+}
@@ -0,0 +1,96 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Naming and rotation of the files written by file backends. A new file is
+//! started each day (UTC) and optionally when it reaches a maximum size.
+//! Files are named after the time of their first record.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use std::path::PathBuf;
+
+/// Convert a timestamp in nanoseconds since the UNIX epoch to UTC
+pub(crate) fn utc(timestamp: i64) -> NaiveDateTime {
+    let secs = timestamp.div_euclid(1_000_000_000);
+    let nsecs = timestamp.rem_euclid(1_000_000_000) as u32;
+    DateTime::from_timestamp(secs, nsecs)
+        .unwrap_or_default()
+        .naive_utc()
+}
+
+// Some backends only use [utc]
+#[cfg_attr(
+    not(any(feature = "csvfile", feature = "jsonl", feature = "parquet")),
+    allow(dead_code)
+)]
+pub(crate) struct Rotation {
+    pub directory: PathBuf,
+    pub prefix: String,
+    /// Filename extension (without the dot)
+    pub extension: String,
+    /// Size in bytes after which to start a new file
+    // Only used with [Rotation::expired]
+    #[cfg_attr(not(any(feature = "csvfile", feature = "jsonl")), allow(dead_code))]
+    pub max_size: Option<u64>,
+}
+
+#[cfg_attr(
+    not(any(feature = "csvfile", feature = "jsonl", feature = "parquet")),
+    allow(dead_code)
+)]
+impl Rotation {
+    /// Path for a file whose first record is at `start`
+    pub fn path(&self, start: NaiveDateTime) -> PathBuf {
+        let filename = format!(
+            "{}-{}.{}",
+            self.prefix,
+            start.format("%Y%m%d-%H%M%S"),
+            self.extension
+        );
+        self.directory.join(filename)
+    }
+
+    /// Whether a file started on `day`, currently `size` bytes long, should
+    /// be replaced before writing a record for `time`
+    // The parquet backend starts new files by period instead
+    #[cfg_attr(not(any(feature = "csvfile", feature = "jsonl")), allow(dead_code))]
+    pub fn expired(&self, day: NaiveDate, size: u64, time: NaiveDateTime) -> bool {
+        day != time.date() || self.max_size.is_some_and(|max_size| size >= max_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let rotation = Rotation {
+            directory: PathBuf::from("/data"),
+            prefix: "test".to_owned(),
+            extension: "csv".to_owned(),
+            max_size: Some(1000),
+        };
+        let start = utc(1_700_000_000_000_000_000);
+        assert_eq!(
+            rotation.path(start),
+            PathBuf::from("/data/test-20231114-221320.csv")
+        );
+        let day = start.date();
+        assert!(!rotation.expired(day, 999, start));
+        assert!(rotation.expired(day, 1000, start));
+        assert!(rotation.expired(day, 0, utc(1_700_100_000_000_000_000)));
+    }
+}
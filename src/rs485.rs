@@ -0,0 +1,442 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Frontend that passively monitors an RS485 bus shared between the inverter
+//! and another Modbus master (such as SolarAssistant), decoding the
+//! responses that it observes. It never transmits anything.
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_with::serde_as;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::time::MissedTickBehavior;
+
+use crate::fields::{Field, FieldConfig, FieldSet};
+use crate::frontend::{FieldWatch, Frontend, Reloading};
+use crate::modbus::{
+    default_baud, default_modbus_id, default_stop_bits, modbus_fields, parse_serial, serial_port,
+    Parity, REG_SERIAL, SERIAL_WORDS,
+};
+use crate::receiver::{Update, UpdateStream};
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// Structure corresponding to the `[rs485]` section of the configuration file.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rs485Config {
+    device: String,
+    /// Interval at which to report the most recently observed values
+    #[serde_as(as = "serde_with::DurationSecondsWithFrac<f64>")]
+    interval: Duration,
+    #[serde(default = "default_baud")]
+    baud: u32,
+    #[serde(default = "default_modbus_id")]
+    modbus_id: u8,
+    #[serde(default)]
+    parity: Parity,
+    #[serde(default = "default_stop_bits")]
+    stop_bits: u8,
+    /// Inverter serial number, if the other master does not read it
+    serial: Option<String>,
+}
+
+/// A Modbus RTU frame observed on the bus
+#[derive(Debug, PartialEq)]
+enum Frame {
+    /// Request to read holding registers
+    Request { slave: u8, start: u16, count: u16 },
+    /// Response containing holding registers
+    Response { slave: u8, values: Vec<u16> },
+    /// Any other frame
+    Other,
+}
+
+/// Result of trying to parse a frame from the start of a buffer
+#[derive(Debug, PartialEq)]
+enum Parse {
+    /// A frame, and its length in bytes
+    Frame(Frame, usize),
+    /// More data is needed
+    Incomplete,
+    /// The buffer does not start with a valid frame
+    Invalid,
+}
+
+/// Compute the Modbus CRC-16 of some data
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Check the CRC at the end of a frame
+fn check_crc(frame: &[u8]) -> bool {
+    let (data, crc) = frame.split_at(frame.len() - 2);
+    crc16(data) == u16::from_le_bytes([crc[0], crc[1]])
+}
+
+/// Try to parse a frame from the start of `data`. RTU frames are delimited
+/// by silence on the bus, which can't be reliably observed through the
+/// serial driver, so instead the possible lengths of the frame are inferred
+/// from the function code and validated with the CRC.
+fn parse_frame(data: &[u8]) -> Parse {
+    if data.len() < 2 {
+        return Parse::Incomplete;
+    }
+    let (slave, function) = (data[0], data[1]);
+    let mut lengths = vec![];
+    let mut incomplete = false;
+    match function {
+        READ_HOLDING_REGISTERS => {
+            lengths.push(8); // Request
+            match data.get(2) {
+                Some(&n) => lengths.push(5 + n as usize), // Response
+                None => incomplete = true,
+            }
+        }
+        WRITE_SINGLE_REGISTER => lengths.push(8),
+        WRITE_MULTIPLE_REGISTERS => {
+            lengths.push(8); // Response
+            match data.get(6) {
+                Some(&n) => lengths.push(9 + n as usize), // Request
+                None => incomplete = true,
+            }
+        }
+        f if f & 0x80 != 0 => lengths.push(5), // Exception
+        _ => return Parse::Invalid,
+    }
+    for &length in lengths.iter() {
+        if length > data.len() {
+            incomplete = true;
+        } else if check_crc(&data[..length]) {
+            let word = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+            let frame = match function {
+                READ_HOLDING_REGISTERS if length == 8 => Frame::Request {
+                    slave,
+                    start: word(2),
+                    count: word(4),
+                },
+                READ_HOLDING_REGISTERS => Frame::Response {
+                    slave,
+                    values: (3..length - 2).step_by(2).map(word).collect(),
+                },
+                _ => Frame::Other,
+            };
+            return Parse::Frame(frame, length);
+        }
+    }
+    if incomplete {
+        Parse::Incomplete
+    } else {
+        Parse::Invalid
+    }
+}
+
+/// Tracks the register values observed on the bus
+struct Monitor {
+    slave: u8,
+    /// Most recent request for registers (start and count)
+    pending: Option<(u16, u16)>,
+    registers: HashMap<u16, u16>,
+}
+
+impl Monitor {
+    fn new(slave: u8) -> Self {
+        Self {
+            slave,
+            pending: None,
+            registers: HashMap::new(),
+        }
+    }
+
+    fn observe(&mut self, frame: Frame) {
+        match frame {
+            Frame::Request {
+                slave,
+                start,
+                count,
+            } if slave == self.slave => {
+                self.pending = Some((start, count));
+            }
+            Frame::Response { slave, values } if slave == self.slave => {
+                if let Some((start, count)) = self.pending.take() {
+                    if values.len() == count as usize {
+                        for (i, value) in values.into_iter().enumerate() {
+                            self.registers.insert(start.wrapping_add(i as u16), value);
+                        }
+                    }
+                }
+            }
+            _ => self.pending = None,
+        }
+    }
+
+    /// Look up the values of some registers, if they have all been observed
+    fn get(&self, regs: &[u16]) -> Option<Vec<u16>> {
+        regs.iter()
+            .map(|reg| self.registers.get(reg).copied())
+            .collect()
+    }
+}
+
+/// Fields for which values are available, and their values
+type Snapshot = (
+    Vec<Field<'static>>,
+    Vec<f64>,
+    Vec<Field<'static>>,
+    Vec<String>,
+);
+
+/// Collect the values of the fields whose registers have all been observed.
+/// Computed fields are not available.
+fn snapshot(monitor: &Monitor, fields: &FieldSet<Vec<u16>>) -> Snapshot {
+    let mut result: Snapshot = Default::default();
+    for (field, regs) in fields.fields.iter().zip(fields.addresses.iter()) {
+        if regs.is_empty() {
+            continue;
+        }
+        if let Some(parts) = monitor.get(regs) {
+            result.0.push(field.clone());
+            result.1.push(field.from_u16s(parts));
+        }
+    }
+    for (field, regs) in fields.text_fields.iter().zip(fields.text_addresses.iter()) {
+        if let Some(parts) = monitor.get(regs) {
+            result.2.push(field.clone());
+            result.3.push(field.text_from_u16s(parts));
+        }
+    }
+    result
+}
+
+/// Leaked field lists, keyed by field IDs. The set of observed registers
+/// settles down quickly, so only a few lists are leaked.
+type FieldLists = HashMap<Vec<&'static str>, &'static [Field<'static>]>;
+
+fn intern(lists: &mut FieldLists, fields: Vec<Field<'static>>) -> &'static [Field<'static>] {
+    let key = fields.iter().map(|f| f.id).collect();
+    lists
+        .entry(key)
+        .or_insert_with(|| Box::leak(fields.into_boxed_slice()))
+}
+
+pub fn create_stream(
+    config: &Rs485Config,
+    field_config: FieldWatch,
+) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+    let mut fields = Reloading::new(field_config, modbus_fields)?;
+    let builder = serial_port(&config.device, config.baud, config.parity, config.stop_bits)?;
+    let mut port = tokio_serial::SerialStream::open(&builder)?;
+    let mut monitor = Monitor::new(config.modbus_id);
+    let fixed_serial = config.serial.clone();
+    let device = config.device.clone();
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        let mut buffer = vec![];
+        let mut chunk = [0u8; 256];
+        let mut lists = FieldLists::new();
+        let mut warned = false;
+        loop {
+            tokio::select! {
+                result = port.read(&mut chunk) => {
+                    let n = match result {
+                        Ok(0) => {
+                            info!("{device} closed");
+                            break;
+                        }
+                        Ok(n) => n,
+                        Err(err) => {
+                            error!("Error reading from {device}: {err}");
+                            break;
+                        }
+                    };
+                    buffer.extend_from_slice(&chunk[..n]);
+                    let mut pos = 0;
+                    loop {
+                        match parse_frame(&buffer[pos..]) {
+                            Parse::Frame(frame, length) => {
+                                monitor.observe(frame);
+                                pos += length;
+                            }
+                            Parse::Incomplete => break,
+                            Parse::Invalid => pos += 1,
+                        }
+                    }
+                    buffer.drain(..pos);
+                }
+                _ = interval.tick() => {
+                    let serial_regs: Vec<u16> = (REG_SERIAL..REG_SERIAL + SERIAL_WORDS).collect();
+                    let serial = match &fixed_serial {
+                        Some(serial) => serial.clone(),
+                        None => match monitor.get(&serial_regs).map(|words| parse_serial(&words)) {
+                            Some(Ok(serial)) => serial,
+                            _ => {
+                                if !warned {
+                                    warn!("Serial number not seen on the bus yet; set serial in the config");
+                                    warned = true;
+                                }
+                                continue;
+                            }
+                        },
+                    };
+                    let (values_fields, values, text_fields, text) = snapshot(&monitor, fields.get());
+                    if values.is_empty() && text.is_empty() {
+                        continue;
+                    }
+                    let update = Update::new(
+                        chrono::Utc::now().timestamp_nanos_opt().unwrap(),
+                        serial,
+                        intern(&mut lists, values_fields),
+                        values,
+                    )
+                    .with_text(intern(&mut lists, text_fields), text);
+                    if sender.unbounded_send(Arc::new(update)).is_err() {
+                        break; // The receiver has shut down
+                    }
+                }
+            }
+        }
+    });
+    Ok(Box::pin(receiver))
+}
+
+#[async_trait(?Send)]
+impl Frontend for Rs485Config {
+    async fn create_stream(
+        &self,
+        field_config: FieldWatch,
+    ) -> Result<UpdateStream, Box<dyn std::error::Error>> {
+        create_stream(self, field_config)
+    }
+
+    fn check(&self, field_config: &FieldConfig) -> Result<(), Box<dyn std::error::Error>> {
+        modbus_fields(field_config)?;
+        serial_port(&self.device, self.baud, self.parity, self.stop_bits)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Append the CRC to a frame
+    fn with_crc(mut data: Vec<u8>) -> Vec<u8> {
+        let crc = crc16(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_crc() {
+        // Example from the Modbus specification
+        assert_eq!(crc16(&[0x02, 0x07]), 0x1241);
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let request = with_crc(vec![1, 3, 0, 3, 0, 2]);
+        let response = with_crc(vec![1, 3, 4, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(
+            parse_frame(&request),
+            Parse::Frame(
+                Frame::Request {
+                    slave: 1,
+                    start: 3,
+                    count: 2
+                },
+                8
+            )
+        );
+        assert_eq!(
+            parse_frame(&response),
+            Parse::Frame(
+                Frame::Response {
+                    slave: 1,
+                    values: vec![0x1234, 0x5678]
+                },
+                9
+            )
+        );
+        assert_eq!(parse_frame(&response[..6]), Parse::Incomplete);
+        let mut corrupt = response.clone();
+        corrupt[4] ^= 1;
+        assert_eq!(parse_frame(&corrupt), Parse::Invalid);
+        let write = with_crc(vec![1, 0x10, 0, 100, 0, 1, 2, 0, 5]);
+        assert_eq!(parse_frame(&write), Parse::Frame(Frame::Other, 11));
+    }
+
+    #[test]
+    fn test_monitor() {
+        let mut monitor = Monitor::new(1);
+        monitor.observe(Frame::Request {
+            slave: 1,
+            start: 10,
+            count: 2,
+        });
+        monitor.observe(Frame::Response {
+            slave: 1,
+            values: vec![5, 6],
+        });
+        // Response from another slave, with the request not seen
+        monitor.observe(Frame::Response {
+            slave: 2,
+            values: vec![7],
+        });
+        assert_eq!(monitor.get(&[10, 11]), Some(vec![5, 6]));
+        assert_eq!(monitor.get(&[11, 12]), None);
+
+        let fields = modbus_fields(&FieldConfig::default()).unwrap();
+        let (reg, field) = fields
+            .addresses
+            .iter()
+            .zip(fields.fields.iter())
+            .find(|(regs, _)| regs.len() == 1)
+            .unwrap();
+        monitor.observe(Frame::Request {
+            slave: 1,
+            start: reg[0],
+            count: 1,
+        });
+        monitor.observe(Frame::Response {
+            slave: 1,
+            values: vec![42],
+        });
+        let (values_fields, values, _, _) = snapshot(&monitor, &fields);
+        let pos = values_fields.iter().position(|f| f.id == field.id).unwrap();
+        assert_eq!(values[pos], field.from_u16s([42]));
+    }
+}
@@ -0,0 +1,248 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Transformation of updates by a user-supplied [Rhai](https://rhai.rs)
+//! script, for site-specific logic that cannot be expressed with derived
+//! fields.
+//!
+//! The script must define a function `transform(update)`, which is called
+//! for each update. The update is passed as an object map in the form of an
+//! [UpdateRecord] (as written by the JSON Lines backend). The function must
+//! return the update (with any fields modified, removed or added), or `()` to
+//! drop it. The script runs in the process, with a limit on the number of
+//! operations for each update, so that it cannot hold up the other stages.
+
+use log::error;
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::json::{Interner, UpdateRecord};
+use super::receiver::UpdateItem;
+use super::transform::Transform;
+
+/// Name of the function that the script must define
+const FUNCTION: &str = "transform";
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+fn default_max_field_lists() -> usize {
+    100
+}
+
+/// Structure corresponding to the `[script]` section of the configuration
+/// file. It is constructed from the config file by serde.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// File containing the script
+    path: PathBuf,
+    /// Maximum number of operations that the script may perform for each
+    /// update
+    #[serde(default = "default_max_operations")]
+    max_operations: u64,
+    /// Maximum number of distinct lists of fields that the script may
+    /// produce. The field descriptions are kept for the lifetime of the
+    /// process, so this bounds the memory used if the script makes up fields.
+    #[serde(default = "default_max_field_lists")]
+    max_field_lists: usize,
+}
+
+/// Transform that passes updates through a script. If the script fails, the
+/// update is passed on unchanged.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    interner: Interner,
+    max_field_lists: usize,
+}
+
+impl Script {
+    /// Compile the script
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        let ast = engine
+            .compile_file(config.path.clone())
+            .map_err(|err| format!("{:?}: {err}", config.path))?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == FUNCTION && function.params.len() == 1)
+        {
+            return Err(format!("{:?} does not define {FUNCTION}(update)", config.path).into());
+        }
+        Ok(Self {
+            engine,
+            ast,
+            interner: Interner::with_limit(config.max_field_lists),
+            max_field_lists: config.max_field_lists,
+        })
+    }
+
+    /// Pass a record to the script, returning `None` if it drops the update
+    fn call(&self, record: &UpdateRecord) -> Result<Option<UpdateRecord>, String> {
+        let update = rhai::serde::to_dynamic(record).map_err(|err| err.to_string())?;
+        let reply: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, FUNCTION, (update,))
+            .map_err(|err| err.to_string())?;
+        if reply.is_unit() {
+            return Ok(None);
+        }
+        rhai::serde::from_dynamic(&reply)
+            .map(Some)
+            .map_err(|err| format!("invalid update returned by the script: {err}"))
+    }
+}
+
+impl Transform for Script {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        let record = match self.call(&UpdateRecord::new(&update)) {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(err) => {
+                error!("Script failed; passing the update on unchanged ({err})");
+                return Some(update);
+            }
+        };
+        match self.interner.try_decode(record) {
+            Some(new_update) => Some(Arc::new(new_update)),
+            None => {
+                error!(
+                    "Script produced more than {} distinct sets of fields; \
+                     passing the update on unchanged",
+                    self.max_field_lists
+                );
+                Some(update)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::receiver::Update;
+    use crate::test_util::{grid_power_field, leak};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Gives each script file a unique name, as the tests run in parallel
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// Compile `source` as a script
+    fn script(source: &str, max_field_lists: usize) -> Result<Script, Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("sunsniff-script-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.rhai", COUNTER.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&path, source).unwrap();
+        let config: Config = toml::from_str(&format!(
+            "path = {:?}\nmax_operations = 1000\nmax_field_lists = {max_field_lists}",
+            path.to_str().unwrap()
+        ))
+        .unwrap();
+        let script = Script::new(&config);
+        std::fs::remove_file(&path).unwrap();
+        script
+    }
+
+    fn apply(source: &str, update: &UpdateItem) -> Option<UpdateItem> {
+        script(source, 10).unwrap().apply(Arc::clone(update))
+    }
+
+    #[test]
+    fn test_script() {
+        let fields = leak([grid_power_field()]);
+        let update = Arc::new(Update::new(1234, "5678", fields, vec![-150.0]));
+
+        let renamed = apply(
+            r#"
+            fn transform(update) {
+                update.serial = "cabin";
+                update.fields[0].id = "grid_watts";
+                update.fields[0].value *= 2;
+                update
+            }"#,
+            &update,
+        )
+        .unwrap();
+        assert_eq!(renamed.timestamp, 1234);
+        assert_eq!(renamed.serial, "cabin");
+        assert_eq!(renamed.fields[0].id, "grid_watts");
+        assert_eq!(renamed.fields[0].unit, "W");
+        assert_eq!(renamed.values, vec![-300.0]);
+
+        let added = apply(
+            r#"
+            fn transform(update) {
+                update.text.push(#{
+                    id: "site", group: "Site", name: "Name", field_type: "Text",
+                    value: "cabin"
+                });
+                update
+            }"#,
+            &update,
+        )
+        .unwrap();
+        assert_eq!(added.text_fields[0].id, "site");
+        assert_eq!(added.text, vec!["cabin"]);
+
+        let missing = Arc::new(Update::new(1234, "5678", fields, vec![f64::NAN]));
+        let unchanged = apply("fn transform(update) { update }", &missing).unwrap();
+        assert!(unchanged.values[0].is_nan());
+
+        let dropped = apply("fn transform(update) { () }", &update);
+        assert!(dropped.is_none());
+
+        // A script that fails passes the update through
+        for source in [
+            "fn transform(update) { update.missing.value }",
+            "fn transform(update) { loop {} }",
+            "fn transform(update) { 42 }",
+        ] {
+            let failed = apply(source, &update).unwrap();
+            assert!(Arc::ptr_eq(&failed, &update), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_max_field_lists() {
+        let fields = leak([grid_power_field()]);
+        let mut script = script(
+            "fn transform(update) { update.fields[0].id += update.timestamp; update }",
+            // The (empty) list of text fields is one of them
+            3,
+        )
+        .unwrap();
+        let mut apply = |timestamp| {
+            let update = Arc::new(Update::new(timestamp, "1234", fields, vec![1.0]));
+            script.apply(update).unwrap().fields[0].id
+        };
+        assert_eq!(apply(1), "grid_power1");
+        assert_eq!(apply(2), "grid_power2");
+        assert_eq!(apply(3), "grid_power");
+        assert_eq!(apply(1), "grid_power1");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(script("fn transform(update) {", 10).is_err());
+        assert!(script("fn other(update) { update }", 10).is_err());
+    }
+}
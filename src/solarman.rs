@@ -0,0 +1,188 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Solarman V5 framing, which the dongle uses for its messages to the
+//! server (such as the handshake and heartbeats), and which the server uses
+//! to acknowledge them.
+//!
+//! Each frame consists of a start byte, the length of the payload (2 bytes),
+//! a control code (2 bytes), a sequence number (2 bytes), the serial number
+//! of the dongle (4 bytes), the payload, a checksum and an end byte. All
+//! integers are little-endian.
+
+/// First byte of each frame
+pub const START: u8 = 0xa5;
+/// Last byte of each frame
+pub const END: u8 = 0x15;
+/// Length of the part of the frame before the payload
+const HEADER_LENGTH: usize = 11;
+/// Length of the part of the frame after the payload
+const TRAILER_LENGTH: usize = 2;
+
+/// Control code of the handshake sent by the dongle when it connects
+pub const HANDSHAKE: u16 = 0x4110;
+/// Control code of data sent by the dongle
+pub const DATA: u16 = 0x4210;
+/// Control code of information about the dongle (such as its Wi-Fi signal)
+pub const INFO: u16 = 0x4310;
+/// Control code of the heartbeats sent by the dongle
+pub const HEARTBEAT: u16 = 0x4710;
+/// Control code of reports sent by the dongle
+pub const REPORT: u16 = 0x4810;
+/// Difference between the control code of a message from the dongle and
+/// the control code of the reply
+const REPLY_OFFSET: u16 = 0x3000;
+
+/// A frame, referencing the payload of the data it was parsed from
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Frame<'a> {
+    pub control: u16,
+    /// Sequence number, which replies echo
+    pub sequence: [u8; 2],
+    /// Serial number of the dongle
+    pub logger: u32,
+    pub payload: &'a [u8],
+}
+
+/// Checksum of the bytes between the start byte and the checksum
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Length of the frame that starts at the start of `data`, according to
+/// its header, or `None` if `data` does not start with a frame header. The
+/// frame may be longer than `data`.
+pub fn frame_length(data: &[u8]) -> Option<usize> {
+    match data {
+        [START, low, high, ..] => {
+            Some(HEADER_LENGTH + u16::from_le_bytes([*low, *high]) as usize + TRAILER_LENGTH)
+        }
+        _ => None,
+    }
+}
+
+impl<'a> Frame<'a> {
+    /// Parse the frame at the start of `data`, returning it and its length.
+    /// Returns `None` if `data` does not start with a complete frame with a
+    /// valid checksum.
+    pub fn parse(data: &'a [u8]) -> Option<(Self, usize)> {
+        let length = frame_length(data)?;
+        let frame = data.get(..length)?;
+        if frame[length - 1] != END || frame[length - 2] != checksum(&frame[1..length - 2]) {
+            return None;
+        }
+        let frame = Frame {
+            control: u16::from_le_bytes([frame[3], frame[4]]),
+            sequence: [frame[5], frame[6]],
+            logger: u32::from_le_bytes([frame[7], frame[8], frame[9], frame[10]]),
+            payload: &frame[HEADER_LENGTH..length - TRAILER_LENGTH],
+        };
+        Some((frame, length))
+    }
+
+    /// Serialize the frame
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LENGTH + self.payload.len() + TRAILER_LENGTH);
+        data.push(START);
+        data.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        data.extend_from_slice(&self.control.to_le_bytes());
+        data.extend_from_slice(&self.sequence);
+        data.extend_from_slice(&self.logger.to_le_bytes());
+        data.extend_from_slice(self.payload);
+        data.push(checksum(&data[1..]));
+        data.push(END);
+        data
+    }
+}
+
+/// Builder for the acknowledgements that the server sends in reply to the
+/// dongle's handshake, heartbeats, data, information and reports. The
+/// payload of an acknowledgement is the frame type (the first byte of the
+/// message's payload), a status of 1, the time and 4 zero bytes.
+pub struct Ack {
+    /// Time to report to the dongle, as a Unix timestamp
+    time: u32,
+}
+
+impl Ack {
+    /// Create a builder that reports `time` (a Unix timestamp) to the dongle
+    pub fn new(time: u32) -> Self {
+        Self { time }
+    }
+
+    /// Build the acknowledgement of a message from the dongle. Returns
+    /// `None` if the message is not one that the server acknowledges.
+    pub fn build(&self, message: &Frame<'_>) -> Option<Vec<u8>> {
+        if ![HANDSHAKE, DATA, INFO, HEARTBEAT, REPORT].contains(&message.control) {
+            return None;
+        }
+        let mut payload = vec![message.payload.first().copied().unwrap_or(0), 0x01];
+        payload.extend_from_slice(&self.time.to_le_bytes());
+        payload.extend_from_slice(&[0; 4]);
+        let reply = Frame {
+            control: message.control - REPLY_OFFSET,
+            sequence: message.sequence,
+            logger: message.logger,
+            payload: &payload,
+        };
+        Some(reply.encode())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Heartbeat captured from a dongle
+    pub(crate) const HEARTBEAT_FRAME: [u8; 14] = [
+        0xa5, 0x01, 0x00, 0x10, 0x47, 0x3c, 0x02, 0x2a, 0x6f, 0x2b, 0x9a, 0x00, 0xf4, 0x15,
+    ];
+
+    #[test]
+    fn test_parse() {
+        let mut data = HEARTBEAT_FRAME.to_vec();
+        data.push(START);
+        let (frame, length) = Frame::parse(&data).unwrap();
+        assert_eq!(length, HEARTBEAT_FRAME.len());
+        assert_eq!(frame.control, HEARTBEAT);
+        assert_eq!(frame.sequence, [0x3c, 0x02]);
+        assert_eq!(frame.logger, 0x9a2b6f2a);
+        assert_eq!(frame.payload, &[0x00]);
+        assert_eq!(frame.encode(), HEARTBEAT_FRAME);
+        // Incomplete, or a bad checksum
+        assert_eq!(frame_length(&HEARTBEAT_FRAME[..3]), Some(14));
+        assert!(Frame::parse(&HEARTBEAT_FRAME[..13]).is_none());
+        let mut corrupt = HEARTBEAT_FRAME;
+        corrupt[11] = 0x01;
+        assert!(Frame::parse(&corrupt).is_none());
+    }
+
+    #[test]
+    fn test_ack() {
+        let (frame, _) = Frame::parse(&HEARTBEAT_FRAME).unwrap();
+        let ack = Ack::new(1_700_000_000).build(&frame).unwrap();
+        assert_eq!(
+            ack,
+            [
+                0xa5, 0x0a, 0x00, 0x10, 0x17, 0x3c, 0x02, 0x2a, 0x6f, 0x2b, 0x9a, 0x00, 0x01, 0x00,
+                0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x77, 0x15
+            ]
+        );
+        // Replies are not acknowledged
+        let (reply, _) = Frame::parse(&ack).unwrap();
+        assert!(Ack::new(0).build(&reply).is_none());
+    }
+}
@@ -0,0 +1,194 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends an event per update to a Splunk [HTTP Event
+//! Collector](https://docs.splunk.com/Documentation/Splunk/latest/Data/UsetheHTTPEventCollector).
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::iter::zip;
+use std::sync::Arc;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+
+pub struct SplunkReceiver {
+    client: Client,
+    url: String,
+    token: String,
+    sourcetype: String,
+    source: String,
+    index: Option<String>,
+    host: Option<String>,
+    batch: Options,
+}
+
+impl SplunkReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            url: format!(
+                "{}/services/collector/event",
+                config.url.trim_end_matches('/')
+            ),
+            token: config.token.clone(),
+            sourcetype: config.sourcetype.clone(),
+            source: config.source.clone(),
+            index: config.index.clone(),
+            host: config.host.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for SplunkReceiver {
+    type Item = Value;
+    type Error = String;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Value> {
+        let mut event = Map::new();
+        event.insert("serial".to_owned(), json!(update.serial));
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            if value.is_finite() {
+                event.insert(field.id.to_owned(), json!(value));
+            }
+        }
+        for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+            event.insert(field.id.to_owned(), json!(text));
+        }
+        // HEC takes seconds, with up to millisecond precision
+        let time = update.timestamp.div_euclid(1_000_000) as f64 / 1000.0;
+        let mut metadata = json!({
+            "time": time,
+            "sourcetype": self.sourcetype,
+            "source": self.source,
+            "event": event,
+        });
+        if let Some(index) = &self.index {
+            metadata["index"] = json!(index);
+        }
+        if let Some(host) = &self.host {
+            metadata["host"] = json!(host);
+        }
+        vec![metadata]
+    }
+
+    async fn write(&self, events: Vec<Value>) -> Result<(), String> {
+        // Several events may be sent in one request by concatenating them
+        let body: String = events.iter().map(|event| event.to_string()).collect();
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Authorization", format!("Splunk {}", self.token))
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            // Errors have a JSON body with a description in "text"
+            let body = response.text().await.unwrap_or_default();
+            let message = match serde_json::from_str::<Value>(&body) {
+                Ok(value) => value["text"].as_str().unwrap_or(&body).to_owned(),
+                Err(_) => body,
+            };
+            Err(format!("{status}: {message}"))
+        }
+    }
+}
+
+#[async_trait]
+impl Receiver for SplunkReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Base URL of the collector, such as `https://splunk:8088`
+    pub url: String,
+    /// HEC token
+    pub token: String,
+    #[serde(default = "default_sourcetype")]
+    pub sourcetype: String,
+    #[serde(default = "default_source")]
+    pub source: String,
+    /// Index (if not set, the default index for the token is used)
+    pub index: Option<String>,
+    /// Host to report (if not set, Splunk uses the address of the sender)
+    pub host: Option<String>,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_sourcetype() -> String {
+    "sunsniff".to_owned()
+}
+
+fn default_source() -> String {
+    "sunsniff".to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let config: Config = toml::from_str(
+            "url = \"https://splunk:8088/\"\ntoken = \"abc\"\n\
+             sourcetype = \"solar:inverter\"\nindex = \"solar\"",
+        )
+        .unwrap();
+        let receiver = SplunkReceiver::new(&config);
+        assert_eq!(receiver.url, "https://splunk:8088/services/collector/event");
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1_700_000_000_123_456_789, "1234", fields, vec![1500.0]);
+        assert_eq!(
+            receiver.encode(&update),
+            vec![json!({
+                "time": 1700000000.123,
+                "sourcetype": "solar:inverter",
+                "source": "sunsniff",
+                "index": "solar",
+                "event": {"serial": "1234", "pv_power": 1500.0},
+            })]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Persistent queue of updates on disk, which holds updates while a backend
+//! is unavailable and survives restarts.
+//!
+//! Updates are appended to the file as JSON lines. The offset of the first
+//! update not yet written to the backend is kept in a second file, with
+//! `.pos` appended to the name, which is replaced atomically so that a crash
+//! cannot leave it half-written. Once everything has been written, the file
+//! is truncated. The file is not allowed to grow beyond a given size. Lines
+//! that cannot be decoded are moved to a third file, with `.corrupt`
+//! appended to the name, and skipped.
+//!
+//! The file is accessed with blocking calls, so these should be made on a
+//! thread where blocking is allowed (see [tokio::task::spawn_blocking]).
+
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::json::{Interner, UpdateRecord};
+use crate::receiver::Update;
+
+pub(crate) struct Spool {
+    path: PathBuf,
+    file: File,
+    /// Offset of the first update that has not been written
+    position: u64,
+    /// Number of updates from `position` onwards
+    len: usize,
+    /// End offset and count of the updates returned by [Spool::read]
+    read: (u64, usize),
+    /// Size of the file
+    size: u64,
+    /// Size beyond which the file may not grow
+    max_size: u64,
+    interner: Interner,
+}
+
+impl Spool {
+    pub fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut size = file.metadata()?.len();
+        // Finish a line that was partially written when the process stopped,
+        // so that it does not corrupt the next update.
+        if size > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::Start(size - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+                size += 1;
+            }
+        }
+        let pos_path = position_path(path);
+        let position = match fs::read_to_string(&pos_path) {
+            Ok(text) => text.trim().parse().map_err(|err| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid position in {pos_path:?} ({err})"),
+                )
+            })?,
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        let mut spool = Self {
+            path: path.to_owned(),
+            file,
+            position: if position <= size { position } else { 0 },
+            len: 0,
+            read: (0, 0),
+            size,
+            max_size,
+            interner: Interner::new(),
+        };
+        spool.len = reader(&mut spool.file, spool.position)?
+            .split(b'\n')
+            .try_fold(0, |n, line| {
+                line?;
+                Ok::<_, io::Error>(n + 1)
+            })?;
+        Ok(spool)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append updates to the end of the queue. Either all of them are
+    /// appended, or (if there is no room for them all) none are.
+    pub fn push(&mut self, records: &[UpdateRecord]) -> io::Result<()> {
+        let mut lines = vec![];
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        let size = self.size + lines.len() as u64;
+        if size > self.max_size {
+            return Err(io::Error::new(
+                ErrorKind::StorageFull,
+                format!("the limit of {} bytes has been reached", self.max_size),
+            ));
+        }
+        self.file.write_all(&lines)?;
+        self.size = size;
+        self.len += records.len();
+        Ok(())
+    }
+
+    /// Read up to `n` updates from the front of the queue. They are not
+    /// removed until [Spool::commit] is called.
+    pub fn read(&mut self, n: usize) -> io::Result<Vec<Update<'static>>> {
+        let mut end = self.position;
+        let mut records = vec![];
+        let mut count = 0;
+        let mut corrupt = vec![];
+        let mut reader = reader(&mut self.file, self.position)?;
+        let mut line = vec![];
+        while count < n {
+            line.clear();
+            let bytes = reader.read_until(b'\n', &mut line)?;
+            if bytes == 0 {
+                break;
+            }
+            end += bytes as u64;
+            count += 1;
+            match serde_json::from_slice::<UpdateRecord>(&line) {
+                Ok(record) => records.push(record),
+                Err(err) => {
+                    let corrupt_path = corrupt_path(&self.path);
+                    warn!(
+                        "Moving corrupt update in {:?} to {corrupt_path:?} ({err})",
+                        self.path
+                    );
+                    corrupt.extend_from_slice(&line);
+                }
+            }
+        }
+        if !corrupt.is_empty() {
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(corrupt_path(&self.path))?
+                .write_all(&corrupt)?;
+        }
+        self.read = (end, count);
+        Ok(records
+            .into_iter()
+            .map(|record| self.interner.decode(record))
+            .collect())
+    }
+
+    /// Remove the updates returned by the last call to [Spool::read]
+    pub fn commit(&mut self) -> io::Result<()> {
+        let (end, count) = std::mem::take(&mut self.read);
+        self.position = end;
+        self.len -= count;
+        if self.len == 0 {
+            self.file.set_len(0)?;
+            self.position = 0;
+            self.size = 0;
+        }
+        write_position(&self.path, self.position)
+    }
+}
+
+/// Replace the position file, by writing a temporary file and renaming it
+/// over the old one
+fn write_position(path: &Path, position: u64) -> io::Result<()> {
+    let pos_path = position_path(path);
+    let mut tmp_name = pos_path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(position.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &pos_path)
+}
+
+/// Reader for the updates from `position` onwards
+fn reader(file: &mut File, position: u64) -> io::Result<BufReader<&File>> {
+    file.seek(SeekFrom::Start(position))?;
+    Ok(BufReader::new(file))
+}
+
+fn position_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".pos");
+    PathBuf::from(name)
+}
+
+fn corrupt_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".corrupt");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_util::{grid_power_field, leak};
+
+    #[test]
+    fn test_spool() {
+        let fields = leak([grid_power_field()]);
+        let dir = std::env::temp_dir().join(format!("sunsniff-spool-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spool.jsonl");
+        let timestamps = |updates: Vec<Update<'static>>| -> Vec<i64> {
+            updates.iter().map(|update| update.timestamp).collect()
+        };
+
+        let records: Vec<UpdateRecord> = (0..5)
+            .map(|i| {
+                let value = if i == 2 { f64::NAN } else { i as f64 };
+                UpdateRecord::new(&Update::new(i, "1234", fields, vec![value]))
+            })
+            .collect();
+        let mut spool = Spool::open(&path, u64::MAX).unwrap();
+        assert!(spool.is_empty());
+        spool.push(&records[..1]).unwrap();
+        spool.push(&records[1..]).unwrap();
+        let updates = spool.read(2).unwrap();
+        assert_eq!(updates[1].fields[0].id, "grid_power");
+        assert_eq!(timestamps(updates), vec![0, 1]);
+        spool.commit().unwrap();
+        // Reading without committing leaves the updates in place
+        assert_eq!(timestamps(spool.read(1).unwrap()), vec![2]);
+        drop(spool);
+
+        // The position survives reopening
+        let mut spool = Spool::open(&path, u64::MAX).unwrap();
+        let updates = spool.read(10).unwrap();
+        assert!(updates[0].values[0].is_nan());
+        assert_eq!(timestamps(updates), vec![2, 3, 4]);
+        spool.commit().unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        // Updates that would take the file past its limit are rejected
+        let line = serde_json::to_vec(&records[0]).unwrap().len() as u64 + 1;
+        let mut spool = Spool::open(&path, 3 * line).unwrap();
+        let err = spool
+            .push(&records[..2])
+            .and_then(|()| spool.push(&records[..2]));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::StorageFull);
+        spool.push(&records[..1]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * line);
+        // Draining the spool makes room again
+        assert_eq!(timestamps(spool.read(10).unwrap()), vec![0, 1, 0]);
+        spool.commit().unwrap();
+        spool.push(&records[..2]).unwrap();
+        drop(spool);
+
+        // The position file is replaced rather than rewritten in place
+        assert_eq!(fs::read_to_string(position_path(&path)).unwrap(), "0");
+        assert!(!dir.join("spool.jsonl.pos.tmp").exists());
+        // A position that cannot be parsed is an error, rather than causing
+        // the spool to be replayed from the start
+        fs::write(position_path(&path), "12x").unwrap();
+        let err = Spool::open(&path, u64::MAX).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        fs::remove_file(position_path(&path)).unwrap();
+
+        // Lines that cannot be decoded (including invalid UTF-8) are moved
+        // aside, and the updates around them are still read
+        let mut spool = Spool::open(&path, u64::MAX).unwrap();
+        assert_eq!(timestamps(spool.read(10).unwrap()), vec![0, 1]);
+        spool.commit().unwrap();
+        spool.push(&records[..1]).unwrap();
+        drop(spool);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"bad\xff\n").unwrap();
+        drop(file);
+        let mut spool = Spool::open(&path, u64::MAX).unwrap();
+        spool.push(&records[1..2]).unwrap();
+        drop(spool);
+        let mut spool = Spool::open(&path, u64::MAX).unwrap();
+        assert_eq!(timestamps(spool.read(10).unwrap()), vec![0, 1]);
+        spool.commit().unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(fs::read(corrupt_path(&path)).unwrap(), b"{\"bad\xff\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
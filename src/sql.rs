@@ -0,0 +1,151 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Support for SQL backends, which bind the values in a [Record] to
+//! prepared statements.
+
+use std::iter::zip;
+
+use crate::receiver::Update;
+
+/// Value of a field in a [Record]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    /// Value of a numeric field, with its label if it is an enum field
+    Number(f64, Option<String>),
+    Text(String),
+}
+
+impl Value {
+    /// The `value` and `text` columns of a row in the narrow layout (where
+    /// `text` holds the label of an enum field, or the value of a text
+    /// field). Non-finite values are not supported by all databases, so
+    /// they are stored as NULL.
+    pub(crate) fn columns(&self) -> (Option<f64>, Option<&str>) {
+        match self {
+            Value::Number(value, label) => (
+                Some(*value).filter(|value| value.is_finite()),
+                label.as_deref(),
+            ),
+            Value::Text(text) => (None, Some(text)),
+        }
+    }
+}
+
+/// The values in an update, which unlike an [Update] can be held after the
+/// update has been dropped
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Record {
+    /// Nanoseconds since UNIX epoch
+    pub timestamp: i64,
+    pub serial: String,
+    /// Field ID and value of each field
+    pub values: Vec<(String, Value)>,
+}
+
+impl Record {
+    pub(crate) fn new(update: &Update<'_>) -> Self {
+        let numeric = zip(update.fields.iter(), update.values.iter()).map(|(field, value)| {
+            let label = field.label(*value).map(str::to_owned);
+            (field.id.to_owned(), Value::Number(*value, label))
+        });
+        let text = zip(update.text_fields.iter(), update.text.iter())
+            .map(|(field, text)| (field.id.to_owned(), Value::Text(text.clone())));
+        Self {
+            timestamp: update.timestamp,
+            serial: update.serial.clone(),
+            values: numeric.chain(text).collect(),
+        }
+    }
+}
+
+/// Quote an identifier (such as a table or column name)
+pub(crate) fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Quote a string literal
+#[cfg(feature = "postgres")]
+pub(crate) fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    /// Update with an enum field and a text field
+    pub(crate) fn update() -> Update<'static> {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "Inverter",
+            name: "State",
+            id: "inverter_state",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(2, "Normal")],
+            bit: None,
+        }]));
+        let text_fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Text,
+            group: "Inverter",
+            name: "Version",
+            id: "version",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        Update::new(0, "1234", fields, vec![2.0]).with_text(text_fields, vec!["1.0".to_owned()])
+    }
+
+    #[test]
+    fn test_record() {
+        let record = Record::new(&update());
+        assert_eq!(record.serial, "1234");
+        assert_eq!(
+            record.values,
+            vec![
+                (
+                    "inverter_state".to_owned(),
+                    Value::Number(2.0, Some("Normal".to_owned()))
+                ),
+                ("version".to_owned(), Value::Text("1.0".to_owned())),
+            ]
+        );
+        assert_eq!(record.values[0].1.columns(), (Some(2.0), Some("Normal")));
+        assert_eq!(record.values[1].1.columns(), (None, Some("1.0")));
+        assert_eq!(Value::Number(f64::NAN, None).columns(), (None, None));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    #[cfg(feature = "postgres")]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("it's"), "'it''s'");
+    }
+}
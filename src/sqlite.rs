@@ -0,0 +1,233 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that inserts updates into a local SQLite database.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use rusqlite::{Connection, ErrorCode};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+use super::rotate::utc;
+use super::sql::{quote_ident, Record};
+
+/// Errors that clear up once another process stops using the database, so
+/// that the write is retried rather than the batch being discarded
+const TRANSIENT_ERRORS: [ErrorCode; 2] = [ErrorCode::DatabaseBusy, ErrorCode::DatabaseLocked];
+
+/// Statements to configure the database and create the table if necessary
+fn setup(table: &str) -> String {
+    let index = quote_ident(&format!("{table}_time"));
+    let table = quote_ident(table);
+    // WAL mode allows the database to be read while it is being written.
+    format!(
+        "PRAGMA journal_mode = WAL;\n\
+         PRAGMA busy_timeout = 10000;\n\
+         CREATE TABLE IF NOT EXISTS {table} \
+         (time TEXT NOT NULL, serial TEXT NOT NULL, id TEXT NOT NULL, value REAL, text TEXT);\n\
+         CREATE INDEX IF NOT EXISTS {index} ON {table} (time);\n"
+    )
+}
+
+fn insert_query(table: &str) -> String {
+    format!(
+        "INSERT INTO {} (time, serial, id, value, text) VALUES (?1, ?2, ?3, ?4, ?5)",
+        quote_ident(table)
+    )
+}
+
+/// Format a timestamp (in nanoseconds since the UNIX epoch) as ISO 8601 text
+/// in UTC
+fn timestamp_text(timestamp: i64) -> String {
+    utc(timestamp).format("%Y-%m-%dT%H:%M:%S%.fZ").to_string()
+}
+
+pub struct SqliteReceiver {
+    path: PathBuf,
+    setup: String,
+    insert: String,
+    /// Opened by the first write
+    conn: Arc<Mutex<Option<Connection>>>,
+    batch: Options,
+}
+
+impl SqliteReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            path: config.path.clone(),
+            setup: setup(&config.table),
+            insert: insert_query(&config.table),
+            conn: Arc::new(Mutex::new(None)),
+            batch: config.batch.clone(),
+        }
+    }
+
+    fn open(path: &Path, setup: &str) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(setup)?;
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl BatchWriter for SqliteReceiver {
+    type Item = Record;
+    type Error = rusqlite::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Record> {
+        vec![Record::new(update)]
+    }
+
+    async fn write(&self, records: Vec<Record>) -> rusqlite::Result<()> {
+        let conn = Arc::clone(&self.conn);
+        let path = self.path.clone();
+        let setup = self.setup.clone();
+        let insert = self.insert.clone();
+        // rusqlite is synchronous, so use a separate thread
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            if conn.is_none() {
+                *conn = Some(Self::open(&path, &setup)?);
+            }
+            // A batch is written completely or not at all, so that retrying
+            // it does not duplicate rows
+            let tx = conn.as_mut().unwrap().transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(&insert)?;
+                for record in records.iter() {
+                    let time = timestamp_text(record.timestamp);
+                    for (id, value) in record.values.iter() {
+                        let (value, text) = value.columns();
+                        stmt.execute((&time, &record.serial, id, value, text))?;
+                    }
+                }
+            }
+            tx.commit()
+        })
+        .await
+        .unwrap()
+    }
+
+    fn is_fatal(&self, err: &rusqlite::Error) -> bool {
+        !err.sqlite_error_code()
+            .is_some_and(|code| TRANSIENT_ERRORS.contains(&code))
+    }
+}
+
+#[async_trait]
+impl Receiver for SqliteReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+
+    async fn probe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path.clone();
+        let setup = self.setup.clone();
+        tokio::task::spawn_blocking(move || Self::open(&path, &setup))
+            .await
+            .unwrap()?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Database file, which is created if necessary
+    pub path: PathBuf,
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_table() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sql::test::update;
+
+    #[test]
+    fn test_setup() {
+        let sql = setup("my_table");
+        assert!(sql.starts_with("PRAGMA journal_mode = WAL;\n"));
+        assert!(sql.contains("CREATE TABLE IF NOT EXISTS \"my_table\" (time TEXT NOT NULL"));
+        assert!(
+            sql.contains("CREATE INDEX IF NOT EXISTS \"my_table_time\" ON \"my_table\" (time);")
+        );
+        assert!(toml::from_str::<Config>("path = \"x.db\"\nsqlite3 = \"sqlite3\"").is_err());
+    }
+
+    #[test]
+    fn test_timestamp_text() {
+        assert_eq!(
+            timestamp_text(1_700_000_000_123_000_000),
+            "2023-11-14T22:13:20.123Z"
+        );
+        assert_eq!(timestamp_text(0), "1970-01-01T00:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let dir = std::env::temp_dir().join(format!("sunsniff-sqlite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.db");
+        let _ = std::fs::remove_file(&path);
+        let config: Config =
+            toml::from_str(&format!("path = {:?}\ntable = \"solar\"", path)).unwrap();
+        let mut receiver = SqliteReceiver::new(&config);
+        receiver.probe().await.unwrap();
+        let records = receiver.encode(&update());
+        receiver.write(records.clone()).await.unwrap();
+        receiver.write(records).await.unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT time, serial, id, value, text FROM solar ORDER BY rowid")
+            .unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        let row = rows.next().unwrap().unwrap();
+        assert_eq!(row.get::<_, String>(0).unwrap(), "1970-01-01T00:00:00Z");
+        assert_eq!(row.get::<_, String>(1).unwrap(), "1234");
+        assert_eq!(row.get::<_, String>(2).unwrap(), "inverter_state");
+        assert_eq!(row.get::<_, Option<f64>>(3).unwrap(), Some(2.0));
+        assert_eq!(
+            row.get::<_, Option<String>>(4).unwrap().as_deref(),
+            Some("Normal")
+        );
+        let row = rows.next().unwrap().unwrap();
+        assert_eq!(row.get::<_, Option<f64>>(3).unwrap(), None);
+        assert_eq!(
+            row.get::<_, Option<String>>(4).unwrap().as_deref(),
+            Some("1.0")
+        );
+        drop(rows);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM solar", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends each value as a StatsD gauge over UDP.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::warn;
+use serde::Deserialize;
+use std::iter::zip;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+use super::health::record_write;
+use super::receiver::{Receiver, Update};
+
+/// Maximum payload of a datagram, chosen to avoid fragmentation on typical
+/// networks
+const MAX_PACKET: usize = 1432;
+
+/// How tags are attached to metrics
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagStyle {
+    /// No tags: the serial number is part of the metric name
+    #[default]
+    None,
+    /// `name:value|g|#serial:1234` (DogStatsD)
+    Datadog,
+    /// `name,serial=1234:value|g` (Telegraf)
+    Influx,
+    /// `name;serial=1234:value|g` (Graphite)
+    Graphite,
+}
+
+/// Make a string safe to use in a metric name or tag
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Encode an update as one line per field
+fn encode(update: &Update<'_>, prefix: &str, style: TagStyle) -> Vec<String> {
+    let mut base = String::new();
+    if !prefix.is_empty() {
+        base = prefix.to_owned() + ".";
+    }
+    let serial = sanitize(&update.serial);
+    if style == TagStyle::None {
+        base += &serial;
+        base += ".";
+    }
+    let mut lines = vec![];
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if !value.is_finite() {
+            continue;
+        }
+        let name = base.clone() + sanitize(field.id).as_str();
+        let (name, suffix) = match style {
+            TagStyle::None => (name, String::new()),
+            TagStyle::Datadog => (name, format!("|#serial:{serial}")),
+            TagStyle::Influx => (format!("{name},serial={serial}"), String::new()),
+            TagStyle::Graphite => (format!("{name};serial={serial}"), String::new()),
+        };
+        // Apart from DogStatsD, a sign makes a gauge value relative, so a
+        // negative value has to be sent as a reset to zero and a decrement.
+        if *value < 0.0 && style != TagStyle::Datadog {
+            lines.push(format!("{name}:0|g{suffix}"));
+        }
+        lines.push(format!("{name}:{value}|g{suffix}"));
+    }
+    lines
+}
+
+/// Combine lines into as few datagrams as possible
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = vec![];
+    let mut packet = String::new();
+    for line in lines.iter() {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+pub struct StatsdReceiver {
+    address: String,
+    prefix: String,
+    tags: TagStyle,
+    socket: Option<UdpSocket>,
+}
+
+impl StatsdReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            address: format!("{}:{}", config.host, config.port),
+            prefix: config.prefix.clone(),
+            tags: config.tags,
+            socket: None,
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<UdpSocket> {
+        let addr = tokio::net::lookup_host(&self.address)
+            .await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address found"))?;
+        let bind = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+
+    async fn send(&mut self, update: &Update<'_>) -> std::io::Result<()> {
+        if self.socket.is_none() {
+            self.socket = Some(self.connect().await?);
+        }
+        let socket = self.socket.as_ref().unwrap();
+        for packet in packets(&encode(update, &self.prefix, self.tags)) {
+            socket.send(packet.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Receiver for StatsdReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            match self.send(&update).await {
+                Ok(()) => record_write(None),
+                Err(err) => {
+                    warn!("Failed to send to StatsD at {}: {err}", self.address);
+                    // Resolve the address again next time
+                    self.socket = None;
+                    record_write(Some(err.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// First component of each metric name (may be empty)
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub tags: TagStyle,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    8125
+}
+
+fn default_prefix() -> String {
+    "sunsniff".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "Grid",
+            name: "Power",
+            id: "grid_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(0, "1234", fields, vec![-2.5]);
+        assert_eq!(
+            encode(&update, "solar", TagStyle::None),
+            vec!["solar.1234.grid_power:0|g", "solar.1234.grid_power:-2.5|g"]
+        );
+        assert_eq!(
+            encode(&update, "", TagStyle::Datadog),
+            vec!["grid_power:-2.5|g|#serial:1234"]
+        );
+        let update = Update::new(0, "1234", fields, vec![100.0]);
+        assert_eq!(
+            encode(&update, "solar", TagStyle::Influx),
+            vec!["solar.grid_power,serial=1234:100|g"]
+        );
+        assert_eq!(
+            encode(&update, "solar", TagStyle::Graphite),
+            vec!["solar.grid_power;serial=1234:100|g"]
+        );
+    }
+
+    #[test]
+    fn test_packets() {
+        let line = "x".repeat(700);
+        let lines = vec![line.clone(), line.clone(), line.clone()];
+        assert_eq!(packets(&lines), vec![format!("{line}\n{line}"), line]);
+        assert!(packets(&[]).is_empty());
+    }
+}
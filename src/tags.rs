@@ -0,0 +1,127 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Static tags (such as a site name) attached to every update. They are
+//! added as text fields, which backends already report alongside the values
+//! (for example, as tags in Influxdb).
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::fields::{leak_str, Field, FieldType};
+use super::receiver::{Update, UpdateItem};
+use super::transform::{FieldListCache, Transform};
+
+/// Transform that appends text fields with fixed values
+pub struct Tags {
+    fields: Vec<Field<'static>>,
+    values: Vec<String>,
+    cache: FieldListCache<&'static [Field<'static>]>,
+}
+
+impl Tags {
+    pub fn new(tags: &BTreeMap<String, String>) -> Self {
+        let fields = tags
+            .keys()
+            .map(|key| {
+                let key = leak_str(key);
+                Field {
+                    field_type: FieldType::Text,
+                    group: "Tags",
+                    name: key,
+                    id: key,
+                    scale: 1.0,
+                    bias: 0.0,
+                    signed: false,
+                    unit: "",
+                    requires: None,
+                    labels: &[],
+                    bit: None,
+                }
+            })
+            .collect();
+        Self {
+            fields,
+            values: tags.values().cloned().collect(),
+            cache: FieldListCache::new(),
+        }
+    }
+}
+
+impl Transform for Tags {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        if self.fields.is_empty() {
+            return Some(update);
+        }
+        let tag_fields = &self.fields;
+        let text_fields = *self.cache.get(update.text_fields, |text_fields| {
+            // Tags replace text fields with the same ID
+            let mut fields: Vec<Field<'static>> = text_fields
+                .iter()
+                .filter(|field| !tag_fields.iter().any(|tag| tag.id == field.id))
+                .cloned()
+                .collect();
+            fields.extend(tag_fields.iter().cloned());
+            Box::leak(fields.into_boxed_slice())
+        });
+        let mut text: Vec<String> = update
+            .text_fields
+            .iter()
+            .zip(update.text.iter())
+            .filter(|(field, _)| !tag_fields.iter().any(|tag| tag.id == field.id))
+            .map(|(_, text)| text.clone())
+            .collect();
+        text.extend(self.values.iter().cloned());
+        let new_update = Update::new(
+            update.timestamp,
+            update.serial.clone(),
+            update.fields,
+            update.values.clone(),
+        )
+        .with_text(text_fields, text);
+        Some(Arc::new(new_update))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::leak;
+
+    #[test]
+    fn test_tags() {
+        let tags: BTreeMap<String, String> =
+            toml::from_str("site = \"Cabin\"\nzone = \"TOU-1\"").unwrap();
+        let mut transform = Tags::new(&tags);
+        let text_fields = leak([
+            Field {
+                id: "version",
+                ..transform.fields[0].clone()
+            },
+            Field {
+                id: "site",
+                ..transform.fields[0].clone()
+            },
+        ]);
+        let update = Update::new(0, "1234", &[], vec![])
+            .with_text(text_fields, vec!["1.0".to_owned(), "Other".to_owned()]);
+        let update = transform.apply(Arc::new(update)).unwrap();
+        let ids: Vec<&str> = update.text_fields.iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec!["version", "site", "zone"]);
+        assert_eq!(update.text, vec!["1.0", "Cabin", "TOU-1"]);
+        assert_eq!(update.text_fields[2].group, "Tags");
+    }
+}
@@ -0,0 +1,330 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends messages to Telegram chats through a bot. Messages
+//! are sent when alerts fire and clear (see [super::alerts]), and optionally
+//! once a day with a summary of the previous day's energy totals.
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Write as _};
+use std::iter::zip;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use super::alerts::AlertConfig;
+use super::alerts::{default_min_interval, run_alerts, Alerts, Level, Notification, Notifier};
+use super::fields::FieldType;
+use super::receiver::{Receiver, Update};
+use super::units::to_default_unit;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Statistics for one inverter over one (local) day
+#[derive(Debug, Default)]
+struct DayStats {
+    /// Latest value of each daily energy counter (label and kWh)
+    energy: Vec<(String, f64)>,
+    min_soc: Option<f64>,
+}
+
+impl DayStats {
+    fn add(&mut self, update: &Update<'_>) {
+        for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+            if !value.is_finite() {
+                continue;
+            }
+            if field.field_type == FieldType::Energy && field.id.ends_with("_today") {
+                let label = format!("{} {}", field.group, field.name.trim_end_matches(" today"));
+                let value = to_default_unit(field, *value);
+                match self.energy.iter_mut().find(|(l, _)| *l == label) {
+                    Some((_, v)) => *v = value,
+                    None => self.energy.push((label, value)),
+                }
+            } else if field.id == "battery_soc" {
+                self.min_soc = Some(self.min_soc.map_or(*value, |soc| soc.min(*value)));
+            }
+        }
+    }
+
+    fn summary(&self, serial: &str, date: NaiveDate) -> Notification {
+        let mut body = format!("Inverter: {serial}\n\n");
+        for (label, value) in self.energy.iter() {
+            writeln!(body, "{label}: {value:.1} kWh").unwrap();
+        }
+        if let Some(soc) = self.min_soc {
+            writeln!(body, "Battery minimum SOC: {soc} %").unwrap();
+        }
+        Notification {
+            level: Level::Info,
+            subject: format!("Daily summary for {serial} on {date}"),
+            body,
+        }
+    }
+}
+
+/// Accumulates daily statistics for each inverter
+struct Summary {
+    timezone: Tz,
+    days: HashMap<String, (NaiveDate, DayStats)>,
+}
+
+impl Summary {
+    /// Add an update, returning the summary of the previous day if the
+    /// update is the first of a new day
+    fn add(&mut self, update: &Update<'_>) -> Option<Notification> {
+        let date = self.timezone.timestamp_nanos(update.timestamp).date_naive();
+        let mut summary = None;
+        let (day, stats) = self
+            .days
+            .entry(update.serial.clone())
+            .or_insert_with(|| (date, DayStats::default()));
+        if *day != date {
+            if *day < date {
+                summary = Some(stats.summary(&update.serial, *day));
+            }
+            *day = date;
+            *stats = DayStats::default();
+        }
+        stats.add(update);
+        summary
+    }
+}
+
+/// Chat identifier: either a numeric ID or a public channel name
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ChatId {
+    Id(i64),
+    Name(String),
+}
+
+impl Display for ChatId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatId::Id(id) => write!(f, "{id}"),
+            ChatId::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    description: Option<String>,
+}
+
+struct Bot {
+    client: Client,
+    url: String,
+    chat_ids: Vec<ChatId>,
+    summary: Option<Summary>,
+}
+
+#[async_trait]
+impl Notifier for Bot {
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let text = format!("{}\n\n{}", notification.subject, notification.body);
+        for chat_id in self.chat_ids.iter() {
+            let response = self
+                .client
+                .post(&self.url)
+                .form(&[("chat_id", chat_id.to_string()), ("text", text.clone())])
+                .send()
+                .await
+                .map_err(|err| format!("Telegram: {err}"))?;
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            let response: Response = serde_json::from_str(&body)
+                .map_err(|_| format!("Telegram: {status} for chat {chat_id}"))?;
+            if !response.ok {
+                return Err(format!(
+                    "Telegram: {} for chat {chat_id}",
+                    response.description.unwrap_or_else(|| status.to_string())
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn extra(&mut self, update: &Update<'_>) -> Vec<Notification> {
+        self.summary
+            .as_mut()
+            .and_then(|summary| summary.add(update))
+            .into_iter()
+            .collect()
+    }
+}
+
+pub struct TelegramReceiver {
+    bot: Bot,
+    alerts: Alerts,
+}
+
+impl TelegramReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        if config.chat_ids.is_empty() {
+            return Err("Telegram backend needs at least one chat ID".to_owned());
+        }
+        let summary = if config.daily_summary {
+            let timezone = config
+                .timezone
+                .ok_or("Telegram daily_summary requires a timezone")?;
+            Some(Summary {
+                timezone,
+                days: HashMap::new(),
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            bot: Bot {
+                client: Client::builder()
+                    .timeout(TIMEOUT)
+                    .build()
+                    .map_err(|err| err.to_string())?,
+                url: format!(
+                    "{}/bot{}/sendMessage",
+                    config.api_url.trim_end_matches('/'),
+                    config.token
+                ),
+                chat_ids: config.chat_ids.clone(),
+                summary,
+            },
+            alerts: Alerts::new(&config.alerts, config.min_interval)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for TelegramReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_alerts(&mut self.bot, &mut self.alerts, receiver).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Bot token, as provided by @BotFather
+    pub token: String,
+    /// Chats to send to
+    pub chat_ids: Vec<ChatId>,
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// Minimum number of seconds between messages for the same alert and
+    /// inverter
+    #[serde(default = "default_min_interval")]
+    pub min_interval: u64,
+    #[serde(default)]
+    pub alerts: Vec<AlertConfig>,
+    /// Send a summary of each day's energy totals after midnight
+    #[serde(default)]
+    pub daily_summary: bool,
+    /// Time zone that determines when days start (needed for `daily_summary`)
+    pub timezone: Option<Tz>,
+}
+
+fn default_api_url() -> String {
+    "https://api.telegram.org".to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::Field;
+    use crate::test_util::{field, leak};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!("token = \"123:abc\"\n{toml}")).unwrap()
+    }
+
+    #[test]
+    fn test_config() {
+        let receiver = TelegramReceiver::new(&config(
+            "chat_ids = [12345, -1001234, \"@channel\"]\ndaily_summary = true\n\
+             timezone = \"Africa/Johannesburg\"",
+        ))
+        .unwrap();
+        assert_eq!(
+            receiver.bot.url,
+            "https://api.telegram.org/bot123:abc/sendMessage"
+        );
+        assert_eq!(
+            receiver.bot.chat_ids,
+            vec![
+                ChatId::Id(12345),
+                ChatId::Id(-1001234),
+                ChatId::Name("@channel".to_owned())
+            ]
+        );
+        assert_eq!(receiver.bot.chat_ids[1].to_string(), "-1001234");
+        assert!(TelegramReceiver::new(&config("chat_ids = []")).is_err());
+        assert!(TelegramReceiver::new(&config("chat_ids = [1]\ndaily_summary = true")).is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let fields = leak([
+            Field {
+                group: "PV",
+                name: "Production today",
+                ..field(FieldType::Energy, "pv_production_today")
+            },
+            Field {
+                group: "Load",
+                name: "Consumption today",
+                ..field(FieldType::Energy, "load_consumption_today")
+            },
+            Field {
+                group: "Battery",
+                name: "SOC",
+                ..field(FieldType::StateOfCharge, "battery_soc")
+            },
+        ]);
+        let mut receiver = TelegramReceiver::new(&config(
+            "chat_ids = [1]\ndaily_summary = true\ntimezone = \"Africa/Johannesburg\"",
+        ))
+        .unwrap();
+        let hour = 3_600_000_000_000;
+        // 2023-11-14 00:00 SAST
+        let midnight = 1_699_912_800_000_000_000;
+        let mut add = |hours: i64, values: Vec<f64>| {
+            let update = Update::new(midnight + hours * hour, "1234", fields, values);
+            receiver.bot.extra(&update)
+        };
+        assert!(add(1, vec![0.0, 1.0, 60.0]).is_empty());
+        assert!(add(12, vec![15.2, 8.0, 35.0]).is_empty());
+        assert!(add(23, vec![20.5, 14.25, 80.0]).is_empty());
+        let summary = add(25, vec![0.0, 0.5, 78.0]);
+        assert_eq!(
+            summary,
+            vec![Notification {
+                level: Level::Info,
+                subject: "Daily summary for 1234 on 2023-11-14".to_owned(),
+                body: "Inverter: 1234\n\nPV Production: 20.5 kWh\nLoad Consumption: 14.2 kWh\n\
+                       Battery minimum SOC: 35 %\n"
+                    .to_owned(),
+            }]
+        );
+        assert!(add(26, vec![0.0, 0.5, 78.0]).is_empty());
+    }
+}
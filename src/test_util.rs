@@ -0,0 +1,63 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Fixtures shared by the unit tests
+
+use crate::fields::{Field, FieldType};
+
+/// A field in the `Test` group, named after its ID, with the usual unit for
+/// its type. Use struct update syntax to change anything else.
+pub(crate) fn field(field_type: FieldType, id: &'static str) -> Field<'static> {
+    Field {
+        field_type,
+        group: "Test",
+        name: id,
+        id,
+        scale: 1.0,
+        bias: 0.0,
+        signed: false,
+        unit: field_type.unit(),
+        requires: None,
+        labels: &[],
+        bit: None,
+    }
+}
+
+/// Give fields a static lifetime (as the field set has), so that updates can
+/// refer to them
+pub(crate) fn leak<const N: usize>(fields: [Field<'static>; N]) -> &'static [Field<'static>] {
+    Box::leak(Box::new(fields))
+}
+
+/// The `grid_power` field
+pub(crate) fn grid_power_field() -> Field<'static> {
+    Field {
+        group: "Grid",
+        name: "Power",
+        signed: true,
+        ..field(FieldType::Power, "grid_power")
+    }
+}
+
+/// The `inverter_state` field, with a label for the value 2
+pub(crate) fn state_field() -> Field<'static> {
+    Field {
+        group: "Inverter",
+        name: "State",
+        labels: &[(2, "Normal")],
+        ..field(FieldType::Enum, "inverter_state")
+    }
+}
@@ -0,0 +1,91 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Trait to be implemented by stages that modify updates between the
+//! frontend and the backends
+
+use std::collections::HashMap;
+
+use super::fields::Field;
+use super::receiver::{Update, UpdateItem};
+
+/// Trait to be implemented by transform stages
+pub trait Transform {
+    /// Transform an update. Returning `None` drops the update.
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem>;
+}
+
+/// Apply a sequence of transforms to an update
+pub fn apply_all(transforms: &mut [Box<dyn Transform>], update: UpdateItem) -> Option<UpdateItem> {
+    transforms
+        .iter_mut()
+        .try_fold(update, |update, transform| transform.apply(update))
+}
+
+/// Cache of per-field-list state, for transforms that need to precompute
+/// something for each list of fields (such as a list with extra fields
+/// appended). The field lists are leaked by the frontends, so they are
+/// identified by their address.
+pub struct FieldListCache<T> {
+    cache: HashMap<usize, T>,
+}
+
+impl<T> FieldListCache<T> {
+    pub fn new() -> Self {
+        Self {
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Get the state for a field list, computing it with `f` if necessary
+    pub fn get(
+        &mut self,
+        fields: &'static [Field<'static>],
+        f: impl FnOnce(&'static [Field<'static>]) -> T,
+    ) -> &T {
+        self.cache
+            .entry(fields.as_ptr() as usize)
+            .or_insert_with(|| f(fields))
+    }
+
+    /// Mutable version of [FieldListCache::get]
+    pub fn get_mut(
+        &mut self,
+        fields: &'static [Field<'static>],
+        f: impl FnOnce(&'static [Field<'static>]) -> T,
+    ) -> &mut T {
+        self.cache
+            .entry(fields.as_ptr() as usize)
+            .or_insert_with(|| f(fields))
+    }
+}
+
+impl<T> Default for FieldListCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a new update with the same timestamp, serial and text as an
+/// existing one, but with different numeric fields.
+pub fn replace_values(
+    update: &Update<'static>,
+    fields: &'static [Field<'static>],
+    values: Vec<f64>,
+) -> Update<'static> {
+    Update::new(update.timestamp, update.serial.clone(), fields, values)
+        .with_text(update.text_fields, update.text.clone())
+}
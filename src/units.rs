@@ -0,0 +1,174 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Conversion of values to user-selected units
+
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::fields::{leak_fields, Field, FieldType};
+use super::receiver::UpdateItem;
+use super::transform::{replace_values, FieldListCache, Transform};
+
+/// Supported conversions, as (from, to, scale, bias)
+const CONVERSIONS: &[(&str, &str, f64, f64)] = &[
+    ("W", "kW", 0.001, 0.0),
+    ("W", "MW", 0.000001, 0.0),
+    ("kWh", "Wh", 1000.0, 0.0),
+    ("kWh", "MWh", 0.001, 0.0),
+    ("°C", "°F", 1.8, 32.0),
+    ("°C", "K", 1.0, 273.15),
+    ("s", "min", 1.0 / 60.0, 0.0),
+    ("s", "h", 1.0 / 3600.0, 0.0),
+];
+
+/// Look up the scale and bias to convert between two units
+fn conversion(from: &str, to: &str) -> Option<(f64, f64)> {
+    if from == to {
+        return Some((1.0, 0.0));
+    }
+    CONVERSIONS
+        .iter()
+        .find(|(f, t, _, _)| *f == from && *t == to)
+        .map(|(_, _, scale, bias)| (*scale, *bias))
+}
+
+/// Convert a value back to the default unit for its field type, undoing any
+/// conversion from `[units]`. This is for backends whose destination
+/// requires particular units.
+pub fn to_default_unit(field: &Field<'_>, value: f64) -> f64 {
+    match conversion(field.field_type.unit(), field.unit) {
+        Some((scale, bias)) => (value - bias) / scale,
+        None => value,
+    }
+}
+
+/// Scale and bias for each field in a list
+type Conversions = Vec<(f64, f64)>;
+
+/// Transform that converts values to the units given in the `[units]`
+/// section of the configuration file.
+pub struct UnitConversion {
+    /// Target unit for each field type
+    units: HashMap<FieldType, &'static str>,
+    /// For each list of fields: the list with units replaced, and the
+    /// scale and bias to apply to each field
+    cache: FieldListCache<(&'static [Field<'static>], Conversions)>,
+}
+
+impl UnitConversion {
+    pub fn new(config: &HashMap<FieldType, String>) -> Result<Self, String> {
+        let mut units = HashMap::new();
+        for (field_type, unit) in config.iter() {
+            let from = field_type.unit();
+            let (_, to, _, _) = CONVERSIONS
+                .iter()
+                .find(|(f, t, _, _)| *f == from && t == unit)
+                .ok_or_else(|| {
+                    format!("Cannot convert {field_type:?} from {from:?} to {unit:?}")
+                })?;
+            units.insert(*field_type, *to);
+        }
+        Ok(Self {
+            units,
+            cache: FieldListCache::new(),
+        })
+    }
+}
+
+impl Transform for UnitConversion {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        if self.units.is_empty() {
+            return Some(update);
+        }
+        let units = &self.units;
+        let (fields, conversions) = self.cache.get(update.fields, |fields| {
+            let mut new_fields = fields.to_vec();
+            let mut conversions = vec![];
+            for field in new_fields.iter_mut() {
+                let mut conv = (1.0, 0.0);
+                if let Some(unit) = units.get(&field.field_type) {
+                    match conversion(field.unit, unit) {
+                        Some(c) => {
+                            conv = c;
+                            field.unit = unit;
+                        }
+                        None => warn!(
+                            "Cannot convert {} from {:?} to {:?}",
+                            field.id, field.unit, unit
+                        ),
+                    }
+                }
+                conversions.push(conv);
+            }
+            let new_fields = leak_fields(new_fields);
+            (new_fields, conversions)
+        });
+        let values = update
+            .values
+            .iter()
+            .zip(conversions.iter())
+            .map(|(value, (scale, bias))| value * scale + bias)
+            .collect();
+        Some(Arc::new(replace_values(&update, fields, values)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::receiver::Update;
+    use crate::test_util::{field, leak};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_unit_conversion() {
+        let config: HashMap<FieldType, String> =
+            toml::from_str("Power = \"kW\"\nTemperature = \"°F\"").unwrap();
+        let mut conv = UnitConversion::new(&config).unwrap();
+        let fields = leak([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Temperature, "battery_temperature"),
+            field(FieldType::Voltage, "battery_voltage"),
+        ]);
+        let update = Arc::new(Update::new(0, "1234", fields, vec![1500.0, 25.0, 53.0]));
+        let update = conv.apply(update).unwrap();
+        assert_eq!(update.fields[0].unit, "kW");
+        assert_eq!(update.fields[1].unit, "°F");
+        assert_eq!(update.fields[2].unit, "V");
+        assert_approx_eq!(update.values[0], 1.5);
+        assert_approx_eq!(update.values[1], 77.0);
+        assert_eq!(update.values[2], 53.0);
+    }
+
+    #[test]
+    fn test_to_default_unit() {
+        let mut temperature = field(FieldType::Temperature, "battery_temperature");
+        assert_eq!(to_default_unit(&temperature, 25.0), 25.0);
+        temperature.unit = "°F";
+        assert_approx_eq!(to_default_unit(&temperature, 77.0), 25.0);
+        let mut energy = field(FieldType::Energy, "pv_production_today");
+        energy.unit = "Wh";
+        assert_approx_eq!(to_default_unit(&energy, 1500.0), 1.5);
+    }
+
+    #[test]
+    fn test_unsupported_conversion() {
+        let config: HashMap<FieldType, String> = toml::from_str("Voltage = \"kW\"").unwrap();
+        assert!(UnitConversion::new(&config).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Rejection of implausible values (e.g. from corrupted packets)
+
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::fields::{Field, FieldType};
+use super::receiver::UpdateItem;
+use super::transform::{FieldListCache, Transform};
+
+/// What to do with an update containing an out-of-range value
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Log a warning and drop the update
+    #[default]
+    Drop,
+    /// Log a warning but keep the update
+    Warn,
+    /// Do not check values
+    Off,
+}
+
+/// Range of valid values for a field (inclusive)
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Bounds {
+    const fn new(min: f64, max: f64) -> Self {
+        Self {
+            min: Some(min),
+            max: Some(max),
+        }
+    }
+
+    /// Default bounds for a field type, if any
+    fn for_type(field_type: FieldType) -> Option<Self> {
+        match field_type {
+            FieldType::StateOfCharge => Some(Self::new(0.0, 100.0)),
+            FieldType::Frequency => Some(Self::new(45.0, 65.0)),
+            FieldType::Temperature => Some(Self::new(-40.0, 150.0)),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, field_type: FieldType, value: f64) -> bool {
+        // A frequency of zero indicates that there is no signal (e.g. the
+        // grid is down).
+        if field_type == FieldType::Frequency && value == 0.0 {
+            return true;
+        }
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+/// Structure corresponding to the `[validation]` section of the
+/// configuration file. It is constructed from the config file by serde.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    action: Action,
+    /// Bounds for individual fields, replacing the defaults for the type
+    #[serde(default)]
+    fields: HashMap<String, Bounds>,
+}
+
+/// Transform that checks values against their bounds
+pub struct Validation {
+    action: Action,
+    fields: HashMap<String, Bounds>,
+    /// Bounds for each field in a list
+    cache: FieldListCache<Vec<Option<Bounds>>>,
+}
+
+impl Validation {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            action: config.action,
+            fields: config.fields.clone(),
+            cache: FieldListCache::new(),
+        }
+    }
+}
+
+impl Transform for Validation {
+    fn apply(&mut self, update: UpdateItem) -> Option<UpdateItem> {
+        if self.action == Action::Off {
+            return Some(update);
+        }
+        let overrides = &self.fields;
+        let bounds = self.cache.get(update.fields, |fields| {
+            fields
+                .iter()
+                .map(|field: &Field| match overrides.get(field.id) {
+                    Some(bounds) => Some(*bounds),
+                    None => Bounds::for_type(field.field_type),
+                })
+                .collect()
+        });
+        let mut valid = true;
+        for ((field, value), bounds) in update
+            .fields
+            .iter()
+            .zip(update.values.iter())
+            .zip(bounds.iter())
+        {
+            if let Some(bounds) = bounds {
+                if !bounds.contains(field.field_type, *value) {
+                    warn!(
+                        "Value {value} for {} from inverter {} is out of range",
+                        field.id, update.serial
+                    );
+                    valid = false;
+                }
+            }
+        }
+        if valid || self.action == Action::Warn {
+            Some(update)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::receiver::Update;
+    use crate::test_util::{field, leak};
+    use std::sync::Arc;
+
+    fn fields() -> &'static [Field<'static>] {
+        leak([
+            field(FieldType::StateOfCharge, "battery_soc"),
+            field(FieldType::Frequency, "grid_frequency"),
+            field(FieldType::Voltage, "battery_voltage"),
+        ])
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut validation = Validation::new(&Config::default());
+        let fields = fields();
+        let check = |validation: &mut Validation, values: Vec<f64>| {
+            let update = Arc::new(Update::new(0, "1234", fields, values));
+            validation.apply(update).is_some()
+        };
+        assert!(check(&mut validation, vec![50.0, 50.0, 53.0]));
+        assert!(check(&mut validation, vec![50.0, 0.0, 53.0]));
+        assert!(!check(&mut validation, vec![150.0, 50.0, 53.0]));
+        assert!(!check(&mut validation, vec![50.0, 6553.5, 53.0]));
+        assert!(check(&mut validation, vec![50.0, 50.0, 6553.5]));
+    }
+
+    #[test]
+    fn test_validation_config() {
+        let config: Config = toml::from_str(
+            r#"
+            action = "warn"
+            fields = { battery_voltage = { min = 40.0, max = 65.0 } }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.action, Action::Warn);
+        let mut validation = Validation::new(&Config {
+            action: Action::Drop,
+            ..config
+        });
+        let update = Arc::new(Update::new(0, "1234", fields(), vec![50.0, 50.0, 6553.5]));
+        assert!(validation.apply(update).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that posts the Influxdb line protocol to an HTTP endpoint, such as
+//! the `/write` endpoint of VictoriaMetrics.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::batch::{is_fatal_status, run_batched, BatchWriter, Options};
+use super::line_protocol;
+use super::receiver::{Receiver, Update};
+
+pub struct VictoriaMetricsReceiver {
+    client: Client,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    batch: Options,
+}
+
+impl VictoriaMetricsReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: Client::new(),
+            url: config.url.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+
+    fn write_request(&self, lines: Vec<String>) -> RequestBuilder {
+        let request = self.client.post(&self.url).body(lines.concat());
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl BatchWriter for VictoriaMetricsReceiver {
+    type Item = String;
+    type Error = reqwest::Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<String> {
+        // String fields are not supported, so labels are omitted
+        let lines = line_protocol::encode(update, false);
+        if lines.is_empty() {
+            vec![]
+        } else {
+            vec![lines]
+        }
+    }
+
+    async fn write(&self, lines: Vec<String>) -> reqwest::Result<()> {
+        self.write_request(lines).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    fn is_fatal(&self, err: &reqwest::Error) -> bool {
+        err.status()
+            .is_some_and(|status| is_fatal_status(status.as_u16()))
+    }
+}
+
+#[async_trait]
+impl Receiver for VictoriaMetricsReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// URL of the endpoint that accepts line protocol
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_url() -> String {
+    "http://localhost:8428/write".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    fn parse(config: &str) -> VictoriaMetricsReceiver {
+        VictoriaMetricsReceiver::new(&toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(parse("").url, "http://localhost:8428/write");
+        let receiver = parse("url = \"http://vm:8428/write\"\nusername = \"user\"");
+        assert_eq!(receiver.url, "http://vm:8428/write");
+        assert_eq!(receiver.username.as_deref(), Some("user"));
+        assert!(receiver.password.is_none());
+        assert!(toml::from_str::<Config>("database = \"solar\"").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Enum,
+            group: "Inverter",
+            name: "State",
+            id: "inverter_state",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "",
+            requires: None,
+            labels: &[(2, "Normal")],
+            bit: None,
+        }]));
+        let receiver = parse("");
+        // Labels are omitted, since string fields are not supported
+        assert_eq!(
+            receiver.encode(&Update::new(1234, "5678", fields, vec![2.0])),
+            vec!["inverter,serial=5678,group=Inverter,name=State value=2.0 1234\n"]
+        );
+        assert!(receiver
+            .encode(&Update::new(1234, "5678", &[], vec![]))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_write_request() {
+        let receiver = parse("username = \"user\"\npassword = \"secret\"");
+        let request = receiver
+            .write_request(vec!["a 1\n".to_owned(), "b 2\n".to_owned()])
+            .build()
+            .unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.url().as_str(), "http://localhost:8428/write");
+        // base64 of "user:secret"
+        assert_eq!(request.headers()["authorization"], "Basic dXNlcjpzZWNyZXQ=");
+        assert_eq!(request.body().unwrap().as_bytes().unwrap(), b"a 1\nb 2\n");
+        let request = parse("").write_request(vec![]).build().unwrap();
+        assert!(request.headers().get("authorization").is_none());
+    }
+}
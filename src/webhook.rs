@@ -0,0 +1,275 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends updates to an arbitrary HTTP endpoint. The body is
+//! either the JSON [UpdateRecord] or a template with placeholders for the
+//! values. Updates can be throttled, in which case only the latest update
+//! for each inverter is sent at each interval.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::iter::zip;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::health::record_write;
+use super::json::UpdateRecord;
+use super::receiver::{Receiver, Update};
+use super::rotate::utc;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Replace each `{name}` in a template with its value, where `name` consists
+/// of ASCII letters, digits and underscores. Unknown names are replaced by an
+/// empty string, and other braces (such as those of a JSON body) are kept.
+fn expand(template: &str, values: &HashMap<&str, String>) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if len > 0 && rest[len..].starts_with('}') {
+            if let Some(value) = values.get(&rest[..len]) {
+                out.push_str(value);
+            }
+            rest = &rest[len + 1..];
+        } else {
+            out.push('{');
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Values available to body templates
+fn template_values<'a>(update: &'a Update<'a>) -> HashMap<&'a str, String> {
+    let mut values = HashMap::new();
+    for (field, value) in zip(update.fields.iter(), update.values.iter()) {
+        if value.is_finite() {
+            values.insert(field.id, value.to_string());
+        }
+    }
+    for (field, text) in zip(update.text_fields.iter(), update.text.iter()) {
+        values.insert(field.id, text.clone());
+    }
+    values.insert("serial", update.serial.clone());
+    values.insert(
+        "timestamp",
+        update.timestamp.div_euclid(1_000_000).to_string(),
+    );
+    values.insert(
+        "time",
+        utc(update.timestamp)
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            .to_string(),
+    );
+    values.insert(
+        "record",
+        serde_json::to_string(&UpdateRecord::new(update)).unwrap(),
+    );
+    values
+}
+
+pub struct WebhookReceiver {
+    client: Client,
+    url: String,
+    method: Method,
+    headers: HeaderMap,
+    template: Option<String>,
+    interval: Option<Duration>,
+}
+
+impl WebhookReceiver {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let method = Method::from_bytes(config.method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| format!("Invalid HTTP method {:?}", config.method))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(&config.content_type)
+                .map_err(|_| format!("Invalid content type {:?}", config.content_type))?,
+        );
+        for (name, value) in config.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid HTTP header name {name:?}"))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid value for HTTP header {name}"))?;
+            headers.insert(name, value);
+        }
+        Ok(Self {
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .map_err(|err| err.to_string())?,
+            url: config.url.clone(),
+            method,
+            headers,
+            template: config.template.clone(),
+            interval: config
+                .interval
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+        })
+    }
+
+    fn body(&self, update: &Update<'_>) -> String {
+        match &self.template {
+            Some(template) => expand(template, &template_values(update)),
+            None => serde_json::to_string(&UpdateRecord::new(update)).unwrap(),
+        }
+    }
+
+    async fn send(&self, update: &Update<'_>) {
+        let result = self
+            .client
+            .request(self.method.clone(), &self.url)
+            .headers(self.headers.clone())
+            .body(self.body(update))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = &result {
+            warn!("Webhook request to {} failed: {err}", self.url);
+        }
+        record_write(result.err().map(|err| err.to_string()));
+    }
+}
+
+#[async_trait]
+impl Receiver for WebhookReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        let Some(interval) = self.interval else {
+            while let Some(update) = receiver.next().await {
+                self.send(&update).await;
+            }
+            return;
+        };
+        // Latest update for each inverter since the last send
+        let mut latest = BTreeMap::new();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                update = receiver.next() => match update {
+                    Some(update) => {
+                        latest.insert(update.serial.clone(), update);
+                    }
+                    None => break,
+                },
+                _ = ticker.tick() => {
+                    for (_, update) in std::mem::take(&mut latest) {
+                        self.send(&update).await;
+                    }
+                }
+            }
+        }
+        for (_, update) in latest {
+            self.send(&update).await;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Extra HTTP headers (such as for authentication)
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Body template (defaults to the JSON update record)
+    pub template: Option<String>,
+    /// Send at most one update per inverter in this many seconds
+    pub interval: Option<u64>,
+}
+
+fn default_method() -> String {
+    "POST".to_owned()
+}
+
+fn default_content_type() -> String {
+    "application/json".to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!("url = \"http://localhost/hook\"\n{toml}")).unwrap()
+    }
+
+    #[test]
+    fn test_expand() {
+        let values = HashMap::from([("a", "1".to_owned()), ("b", "two".to_owned())]);
+        assert_eq!(expand("{a}-{b}-{c}", &values), "1-two-");
+        assert_eq!(expand("{{a}}", &values), "{1}");
+        assert_eq!(expand("x {a", &values), "x {a");
+        assert_eq!(expand("{ a }{}", &values), "{ a }{}");
+    }
+
+    #[test]
+    fn test_body() {
+        let receiver = WebhookReceiver::new(&config(
+            r#"template = '{"value1": "{serial}", "value2": {pv_power}, "value3": "{time}"}'"#,
+        ))
+        .unwrap();
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([Field {
+            field_type: FieldType::Power,
+            group: "PV",
+            name: "Power",
+            id: "pv_power",
+            scale: 1.0,
+            bias: 0.0,
+            signed: false,
+            unit: "W",
+            requires: None,
+            labels: &[],
+            bit: None,
+        }]));
+        let update = Update::new(1_700_000_000_500_000_000, "1234", fields, vec![1500.0]);
+        assert_eq!(
+            receiver.body(&update),
+            r#"{"value1": "1234", "value2": 1500, "value3": "2023-11-14T22:13:20.500Z"}"#
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let receiver = WebhookReceiver::new(&config(
+            "method = \"put\"\nheaders = { Authorization = \"Bearer xyz\" }\ninterval = 60",
+        ))
+        .unwrap();
+        assert_eq!(receiver.method, Method::PUT);
+        assert_eq!(receiver.headers["authorization"], "Bearer xyz");
+        assert_eq!(receiver.headers["content-type"], "application/json");
+        assert_eq!(receiver.interval, Some(Duration::from_secs(60)));
+        assert!(WebhookReceiver::new(&config("headers = { \"bad header\" = \"x\" }")).is_err());
+    }
+}
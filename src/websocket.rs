@@ -0,0 +1,310 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that runs a WebSocket server and pushes each update to every
+//! connected client as a JSON text message (see [UpdateRecord]).
+//!
+//! Only the small part of RFC 6455 that a server which just broadcasts needs
+//! is implemented: the opening handshake, unfragmented text frames, and
+//! replies to ping and close frames. Anything else sent by clients is
+//! ignored.
+
+use async_trait::async_trait;
+use base64::Engine;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::oneshot;
+use futures::stream::StreamExt;
+use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+
+use super::health::ServerStatus;
+use super::json::UpdateRecord;
+use super::receiver::{Receiver, Update};
+
+/// Number of messages to hold for a slow client before it misses some
+const QUEUE_SIZE: usize = 16;
+/// Number of replies (to pings) that can wait to be sent before the client
+/// is disconnected
+const REPLY_QUEUE_SIZE: usize = 8;
+/// Largest frame accepted from a client
+const MAX_FRAME: u64 = 65536;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Compute the `Sec-WebSocket-Accept` header for a `Sec-WebSocket-Key`
+fn accept_key(key: &[u8]) -> String {
+    const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let mut context = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(key);
+    context.update(GUID);
+    base64::engine::general_purpose::STANDARD.encode(context.finish())
+}
+
+/// Encode a complete, unmasked frame (as sent by a server)
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x80 | opcode];
+    let len = payload.len();
+    if len < 126 {
+        out.push(len as u8);
+    } else if len < 65536 {
+        out.push(126);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Read a frame from a client, returning the opcode and unmasked payload
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    let len = match header[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_FRAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut mask = [0u8; 4];
+    if header[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+/// Handle frames from the client, passing replies to the writer. Returns
+/// when the client disconnects, or when it sends pings faster than it reads
+/// the replies.
+async fn read_loop<R: AsyncRead + Unpin>(mut reader: R, replies: mpsc::Sender<Vec<u8>>) {
+    loop {
+        let reply = match read_frame(&mut reader).await {
+            Ok((OPCODE_PING, payload)) => frame(OPCODE_PONG, &payload),
+            // Echo the status code, after which the writer stops
+            Ok((OPCODE_CLOSE, payload)) => frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]),
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        if replies.try_send(reply).is_err() {
+            debug!("WebSocket client is not reading replies; disconnecting it");
+            break;
+        }
+    }
+}
+
+/// Send updates and replies to the client
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut updates: broadcast::Receiver<Arc<Vec<u8>>>,
+    mut replies: mpsc::Receiver<Vec<u8>>,
+) -> std::io::Result<()> {
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(message) => writer.write_all(&message).await?,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    debug!("WebSocket client is too slow; skipped {n} updates");
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            reply = replies.recv() => match reply {
+                Some(reply) => {
+                    writer.write_all(&reply).await?;
+                    if reply[0] & 0x0f == OPCODE_CLOSE {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+fn handle(sender: &broadcast::Sender<Arc<Vec<u8>>>, request: Request<Body>) -> Response<Body> {
+    let is_upgrade = request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let key = request.headers().get(SEC_WEBSOCKET_KEY);
+    let (true, Some(key)) = (request.method() == Method::GET && is_upgrade, key) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Expected a WebSocket connection\n"))
+            .unwrap();
+    };
+    let accept = accept_key(key.as_bytes());
+    let updates = sender.subscribe();
+    tokio::spawn(async move {
+        match hyper::upgrade::on(request).await {
+            Ok(upgraded) => {
+                let (reader, writer) = tokio::io::split(upgraded);
+                let (reply_sender, reply_receiver) = mpsc::channel(REPLY_QUEUE_SIZE);
+                tokio::select! {
+                    _ = read_loop(reader, reply_sender) => {}
+                    _ = write_loop(writer, updates, reply_receiver) => {}
+                }
+                debug!("WebSocket client disconnected");
+            }
+            Err(err) => info!("WebSocket upgrade failed: {err}"),
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, HeaderValue::from_static("websocket"))
+        .header(CONNECTION, HeaderValue::from_static("Upgrade"))
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub struct WebSocketReceiver {
+    sender: broadcast::Sender<Arc<Vec<u8>>>,
+    /// Whether the server has failed
+    status: ServerStatus,
+    /// Stops the server when dropped
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl WebSocketReceiver {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sender, _) = broadcast::channel(QUEUE_SIZE);
+        let state = sender.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = handle(&state, request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&config.listen)?.serve(make_service);
+        info!("Serving WebSocket updates on {}", server.local_addr());
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            let _ = stop.await;
+        });
+        let status = ServerStatus::default();
+        let server_status = status.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("WebSocket server failed: {err}");
+                server_status.fail(err.to_string());
+            }
+        });
+        Ok(Self {
+            sender,
+            status,
+            _shutdown: shutdown,
+        })
+    }
+}
+
+#[async_trait]
+impl Receiver for WebSocketReceiver {
+    async fn run<'a>(&mut self, mut receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        while let Some(update) = receiver.next().await {
+            if self.sender.receiver_count() > 0 {
+                let json = serde_json::to_vec(&UpdateRecord::new(&update)).unwrap();
+                // This can only fail if all the clients have just gone
+                let _ = self.sender.send(Arc::new(frame(OPCODE_TEXT, &json)));
+            }
+            self.status.record_write();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Address on which to accept WebSocket connections
+    pub listen: SocketAddr,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame(OPCODE_TEXT, b"Hello"), b"\x81\x05Hello");
+        let long = frame(OPCODE_TEXT, &[0u8; 300]);
+        assert_eq!(&long[..4], &[0x81, 126, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        // Masked "Hello" from RFC 6455
+        let data: &[u8] = &[
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let mut reader = data;
+        let (opcode, payload) = read_frame(&mut reader).await.unwrap();
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(payload, b"Hello");
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_loop() {
+        // Masked pings with empty payloads
+        let pings = [0x89u8, 0x80, 0, 0, 0, 0].repeat(REPLY_QUEUE_SIZE + 5);
+        let mut reader = pings.as_slice();
+        let (sender, mut receiver) = mpsc::channel(REPLY_QUEUE_SIZE);
+        read_loop(&mut reader, sender).await;
+        // The client is disconnected once the queue is full, without reading
+        // the rest of its frames
+        assert_eq!(reader.len(), 6 * 4);
+        for _ in 0..REPLY_QUEUE_SIZE {
+            assert_eq!(receiver.recv().await.unwrap(), b"\x8a\x00");
+        }
+        assert!(receiver.recv().await.is_none());
+    }
+}
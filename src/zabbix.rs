@@ -0,0 +1,243 @@
+/* Copyright 2023 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Backend that sends values to [Zabbix](https://www.zabbix.com) trapper
+//! items using the sender protocol.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+use std::iter::zip;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::batch::{run_batched, BatchWriter, Options};
+use super::receiver::{Receiver, Update};
+
+/// Time to wait for the server to respond, unless overridden by the
+/// `timeout` in the retry configuration
+const TIMEOUT: Duration = Duration::from_secs(30);
+const HEADER: &[u8] = b"ZBXD\x01";
+
+/// Frame a JSON payload with the Zabbix protocol header
+fn frame(payload: &Value) -> Vec<u8> {
+    let data = payload.to_string().into_bytes();
+    let mut out = HEADER.to_vec();
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]); // Reserved
+    out.extend_from_slice(&data);
+    out
+}
+
+/// Decode a framed response into JSON
+fn unframe(data: &[u8]) -> Result<Value, Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_owned());
+    if data.len() < 13 || &data[..4] != b"ZBXD" {
+        return Err(invalid("Invalid Zabbix response header"));
+    }
+    let len = u32::from_le_bytes(data[5..9].try_into().unwrap()) as usize;
+    let body = data
+        .get(13..13 + len)
+        .ok_or_else(|| invalid("Truncated Zabbix response"))?;
+    serde_json::from_slice(body).map_err(|err| invalid(&err.to_string()))
+}
+
+/// Check the server's response. Items that the server failed to process
+/// (typically because there is no matching trapper item) are only logged,
+/// since sending them again will not help.
+fn check_response(response: &Value) -> Result<(), Error> {
+    let info = response["info"].as_str().unwrap_or_default();
+    if response["response"] != "success" {
+        return Err(Error::other(format!("Zabbix server error: {info}")));
+    }
+    let failed = info
+        .split(';')
+        .filter_map(|part| part.trim().strip_prefix("failed: "))
+        .find_map(|n| n.parse::<u64>().ok())
+        .unwrap_or(0);
+    if failed > 0 {
+        warn!("Zabbix server did not accept some items ({info})");
+    }
+    Ok(())
+}
+
+pub struct ZabbixReceiver {
+    address: String,
+    host: String,
+    batch: Options,
+}
+
+impl ZabbixReceiver {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            address: format!("{}:{}", config.server, config.port),
+            host: config.host.clone(),
+            batch: config.batch.clone(),
+        }
+    }
+
+    async fn send(&self, request: &[u8]) -> Result<Value, Error> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(request).await?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+        unframe(&response)
+    }
+}
+
+#[async_trait]
+impl BatchWriter for ZabbixReceiver {
+    type Item = Value;
+    type Error = Error;
+
+    fn encode(&self, update: &Update<'_>) -> Vec<Value> {
+        let host = self.host.replace("{serial}", &update.serial);
+        let clock = update.timestamp.div_euclid(1_000_000_000);
+        let ns = update.timestamp.rem_euclid(1_000_000_000);
+        let item = |key: &str, value: String| {
+            json!({
+                "host": host,
+                "key": key,
+                "value": value,
+                "clock": clock,
+                "ns": ns,
+            })
+        };
+        let mut items: Vec<Value> = zip(update.fields.iter(), update.values.iter())
+            .filter(|(_, value)| value.is_finite())
+            .map(|(field, value)| item(field.id, value.to_string()))
+            .collect();
+        items.extend(
+            zip(update.text_fields.iter(), update.text.iter())
+                .map(|(field, value)| item(field.id, value.clone())),
+        );
+        items
+    }
+
+    async fn write(&self, items: Vec<Value>) -> Result<(), Error> {
+        let request = frame(&json!({"request": "sender data", "data": items}));
+        let timeout = self
+            .batch
+            .retry
+            .timeout
+            .map_or(TIMEOUT, Duration::from_secs);
+        let response = tokio::time::timeout(timeout, self.send(&request))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Timed out talking to Zabbix"))??;
+        check_response(&response)
+    }
+}
+
+#[async_trait]
+impl Receiver for ZabbixReceiver {
+    async fn run<'a>(&mut self, receiver: UnboundedReceiver<Arc<Update<'a>>>) {
+        run_batched(self, receiver, &self.batch).await;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Zabbix server or proxy
+    pub server: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Host name in Zabbix, with an optional `{serial}` placeholder
+    pub host: String,
+    /// Batching, buffering and retrying of updates, and where to put those
+    /// that cannot be written
+    #[serde(flatten)]
+    pub batch: Options,
+}
+
+fn default_port() -> u16 {
+    10051
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::{Field, FieldType};
+
+    #[test]
+    fn test_encode() {
+        let receiver = ZabbixReceiver::new(&Config {
+            server: "zabbix".to_owned(),
+            port: default_port(),
+            host: "inverter-{serial}".to_owned(),
+            batch: Options::default(),
+        });
+        let field = |field_type, id| Field {
+            field_type,
+            group: "Test",
+            name: id,
+            id,
+            scale: 1.0,
+            bias: 0.0,
+            signed: true,
+            unit: "",
+            requires: None,
+            labels: &[],
+            bit: None,
+        };
+        let fields: &'static [Field<'static>] = Box::leak(Box::new([
+            field(FieldType::Power, "pv_power"),
+            field(FieldType::Power, "load_power"),
+        ]));
+        let text_fields: &'static [Field<'static>] =
+            Box::leak(Box::new([field(FieldType::Text, "fault")]));
+        let mut update = Update::new(
+            1_700_000_000_500_000_000,
+            "1234",
+            fields,
+            vec![-2.5, f64::NAN],
+        );
+        update.text_fields = text_fields;
+        update.text = vec!["OK".to_owned()];
+        let items = receiver.encode(&update);
+        assert_eq!(
+            items,
+            vec![
+                json!({"host": "inverter-1234", "key": "pv_power", "value": "-2.5",
+                       "clock": 1_700_000_000i64, "ns": 500_000_000i64}),
+                json!({"host": "inverter-1234", "key": "fault", "value": "OK",
+                       "clock": 1_700_000_000i64, "ns": 500_000_000i64}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame() {
+        let payload = json!({"response": "success"});
+        let data = frame(&payload);
+        assert_eq!(&data[..13], b"ZBXD\x01\x16\0\0\0\0\0\0\0");
+        assert_eq!(unframe(&data).unwrap(), payload);
+        assert!(unframe(&data[..20]).is_err());
+        assert!(unframe(b"HTTP/1.1 400 Bad Request").is_err());
+    }
+
+    #[test]
+    fn test_check_response() {
+        let info = "processed: 1; failed: 2; total: 3; seconds spent: 0.000055";
+        assert!(check_response(&json!({"response": "success", "info": info})).is_ok());
+        assert!(check_response(&json!({"response": "failed", "info": "bad"})).is_err());
+    }
+}